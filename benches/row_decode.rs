@@ -0,0 +1,123 @@
+//! Decoding values out of result rows: how long it takes, and how many heap
+//! allocations it makes, which should be none for fixed-size values since
+//! they are parsed straight from the shared frame body.
+//!
+//! Runs with `cargo bench --bench row_decode`. It counts allocations with a
+//! wrapper around the system allocator, so it needs no nightly features and
+//! no server: the rows come from a result body built in memory.
+
+extern crate byteorder;
+extern crate cassandra;
+extern crate uuid;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use cassandra::protocol::QueryResult;
+use uuid::Uuid;
+
+const ROWS: usize = 10000;
+const ROUNDS: usize = 20;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn string(body: &mut Vec<u8>, s: &str) {
+    body.write_u16::<BigEndian>(s.len() as u16).unwrap();
+    body.extend_from_slice(s.as_bytes());
+}
+
+fn value(body: &mut Vec<u8>, bytes: &[u8]) {
+    body.write_i32::<BigEndian>(bytes.len() as i32).unwrap();
+    body.extend_from_slice(bytes);
+}
+
+/// A RESULT body of `ROWS` rows of an int, a bigint, a boolean, a uuid and
+/// a text column, as a server would send for `SELECT * FROM ks.t`.
+fn rows_body() -> Vec<u8> {
+    let columns = [("id", 0x0009), ("total", 0x0002), ("active", 0x0004), ("uid", 0x000C), ("name", 0x000D)];
+    let mut body = Vec::new();
+    body.write_i32::<BigEndian>(0x0002).unwrap();
+    body.write_i32::<BigEndian>(0x0001).unwrap();
+    body.write_i32::<BigEndian>(columns.len() as i32).unwrap();
+    string(&mut body, "ks");
+    string(&mut body, "t");
+    for &(name, kind) in &columns {
+        string(&mut body, name);
+        body.write_u16::<BigEndian>(kind).unwrap();
+    }
+    body.write_i32::<BigEndian>(ROWS as i32).unwrap();
+    for i in 0..ROWS {
+        let mut int = Vec::new();
+        int.write_i32::<BigEndian>(i as i32).unwrap();
+        value(&mut body, &int);
+        let mut bigint = Vec::new();
+        bigint.write_i64::<BigEndian>(i as i64 * 1000).unwrap();
+        value(&mut body, &bigint);
+        value(&mut body, &[(i % 2) as u8]);
+        value(&mut body, &[i as u8; 16]);
+        value(&mut body, format!("row number {}", i).as_bytes());
+    }
+    body
+}
+
+/// Reads every row with `read`, `ROUNDS` times over, and prints the time
+/// and allocations per row.
+fn measure<F: Fn(&QueryResult) -> usize>(name: &str, result: &QueryResult, read: F) -> usize {
+    let mut checksum = 0;
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        checksum += read(result);
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - allocations;
+    let rows = (ROWS * ROUNDS) as u64;
+    let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    println!("{:<12} {:>8} ns/row {:>8.2} allocations/row (checksum {})",
+             name, nanos / rows, allocations as f64 / rows as f64, checksum);
+    allocations
+}
+
+fn main() {
+    let result = QueryResult::from_body(rows_body(), None).unwrap();
+    assert_eq!(result.rows.len(), ROWS);
+
+    let fixed = measure("fixed-size", &result, |result| {
+        let mut sum = 0;
+        for row in &result.rows {
+            let id: i32 = row.get("id").unwrap().unwrap();
+            let total: i64 = row.get("total").unwrap().unwrap();
+            let active: bool = row.get("active").unwrap().unwrap();
+            let uid: Uuid = row.get("uid").unwrap().unwrap();
+            sum += id as usize + total as usize + active as usize + uid.as_bytes()[0] as usize;
+        }
+        sum
+    });
+    measure("text", &result, |result| {
+        let mut sum = 0;
+        for row in &result.rows {
+            let name: String = row.get("name").unwrap().unwrap();
+            sum += name.len();
+        }
+        sum
+    });
+    assert_eq!(fixed, 0, "decoding fixed-size values allocated");
+}
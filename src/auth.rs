@@ -0,0 +1,108 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use errors::MyError;
+use protocol::Result;
+
+/// Creates an `Authenticator` for each connection that the server asks to
+/// authenticate.
+pub trait AuthProvider: Send + Sync {
+    /// `authenticator` is the class name the server sent in AUTHENTICATE,
+    /// such as `org.apache.cassandra.auth.PasswordAuthenticator`, and
+    /// `host` the server being connected to.
+    fn new_authenticator(&self, authenticator: &str, host: SocketAddr) -> Result<Box<Authenticator>>;
+}
+
+impl fmt::Debug for AuthProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuthProvider")
+    }
+}
+
+/// The client side of one SASL exchange. The initial response is sent
+/// first, then each AUTH_CHALLENGE is answered until the server sends
+/// AUTH_SUCCESS.
+pub trait Authenticator: Send {
+    fn initial_response(&mut self) -> Result<Vec<u8>>;
+
+    fn evaluate_challenge(&mut self, challenge: Option<&[u8]>) -> Result<Vec<u8>>;
+
+    /// Receives the final token sent with AUTH_SUCCESS, if any.
+    fn on_success(&mut self, _token: Option<&[u8]>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A username and password for clusters using `PasswordAuthenticator`,
+/// sent with the SASL PLAIN mechanism.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    /// The role to act as, for DSE proxy authentication. The user logs in
+    /// with its own password but is then authorized as this role, which it
+    /// needs the PROXY.LOGIN permission on.
+    pub authorization_id: Option<String>,
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+            authorization_id: None,
+        }
+    }
+
+    /// Logs in as `username` but acts as `role`; see `authorization_id`.
+    pub fn proxy(username: &str, password: &str, role: &str) -> Credentials {
+        let mut credentials = Credentials::new(username, password);
+        credentials.authorization_id = Some(role.to_string());
+        credentials
+    }
+
+    /// The PLAIN response: the authorization id, empty unless proxying,
+    /// then the username and password, each preceded by a NUL byte.
+    pub fn plain_response(&self) -> Vec<u8> {
+        let authorization_id = self.authorization_id.as_ref().map(|a| a.as_bytes()).unwrap_or(&[]);
+        let mut response = Vec::with_capacity(2 + authorization_id.len() + self.username.len() + self.password.len());
+        response.extend_from_slice(authorization_id);
+        response.push(0);
+        response.extend_from_slice(self.username.as_bytes());
+        response.push(0);
+        response.extend_from_slice(self.password.as_bytes());
+        response
+    }
+}
+
+/// Leaves the password out of logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .field("authorization_id", &self.authorization_id)
+            .finish()
+    }
+}
+
+impl AuthProvider for Credentials {
+    fn new_authenticator(&self, _authenticator: &str, _host: SocketAddr) -> Result<Box<Authenticator>> {
+        Ok(Box::new(PlainAuthenticator { response: self.plain_response() }))
+    }
+}
+
+/// PLAIN takes a single round, so any challenge is an error.
+struct PlainAuthenticator {
+    response: Vec<u8>,
+}
+
+impl Authenticator for PlainAuthenticator {
+    fn initial_response(&mut self) -> Result<Vec<u8>> {
+        Ok(self.response.clone())
+    }
+
+    fn evaluate_challenge(&mut self, _challenge: Option<&[u8]>) -> Result<Vec<u8>> {
+        Err(MyError::Protocol("Unexpected challenge for PLAIN authentication".to_string()))
+    }
+}
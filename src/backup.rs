@@ -0,0 +1,417 @@
+//! Backing up a table to newline-delimited JSON, and restoring it.
+//!
+//! ```ignore
+//! let file = BufWriter::new(try!(File::create("users.ndjson")));
+//! let rows = try!(session.export("ks.users", file));
+//!
+//! let file = BufReader::new(try!(File::open("users.ndjson")));
+//! let done = try!(session.import("ks.users", file, &CopyOptions::default()));
+//! ```
+//!
+//! `export` scans the table one token range at a time, with as many ranges
+//! as `system.size_estimates` suggests, and writes each row as a JSON
+//! object on its own line. Values keep their exact type:
+//!
+//! * text is a string, and integers, booleans, floats and timestamps (in
+//!   milliseconds since the epoch) are JSON literals. Infinite and NaN
+//!   floats are the strings `"inf"`, `"-inf"` and `"NaN"`. Readers that
+//!   parse every number as a double lose precision past 2^53.
+//! * UUIDs and addresses are strings in their usual form, dates are
+//!   `"2024-01-31"`, and times are `"13:45:00.123000000"`.
+//! * blobs are `0x` and hex digits, as are the serialized bytes of every
+//!   other type, such as collections and decimals.
+//! * nulls are `null`, as are empty values, which rows read as null.
+//!
+//! `import` writes each line back with `copy::write_chunk`, so it batches
+//! rows by partition over several threads just like a CSV copy does. Null
+//! values are left unset rather than written as tombstones.
+
+use std::collections::HashSet;
+use std::io::{BufRead, Cursor, Write};
+use std::iter::Peekable;
+use std::net::IpAddr;
+use std::result;
+use std::str::Chars;
+use byteorder::{BigEndian, ReadBytesExt};
+use uuid::Uuid;
+
+use copy::{parse_field, write_chunk, CopyOptions, CopyProgress};
+use ddl::quote_identifier;
+use errors::MyError;
+use estimates::{SizeEstimate, DEFAULT_SPLIT_SIZE};
+use protocol::{ColumnSpec, PreparedStatement, Result, Row};
+use session::Session;
+use statement::Statement;
+use types::{CQLType, Date, FromCQL, Time};
+
+/// Rows fetched per page while exporting.
+pub const EXPORT_PAGE_SIZE: i32 = 1000;
+
+/// Writes every row of `table`, which must be qualified with its
+/// keyspace, to `writer` as one JSON object per line. Returns how many
+/// rows were written.
+pub fn export<W: Write>(session: &Session, table: &str, mut writer: W) -> Result<usize> {
+    let (keyspace, name) = match table.find('.') {
+        Some(dot) => (&table[..dot], &table[dot + 1..]),
+        None => return Err(MyError::Protocol(format!("Table {} must be qualified with its keyspace", table))),
+    };
+    let (key, _) = try!(primary_key(session, keyspace, name));
+    if key.is_empty() {
+        return Err(MyError::Protocol(format!("Table {} not found", table)));
+    }
+    let estimate = try!(SizeEstimate::fetch(session, keyspace, name));
+    let key: Vec<String> = key.iter().map(|column| quote_identifier(column)).collect();
+    let token = format!("token({})", key.join(", "));
+    let mut count = 0;
+    for (i, (start, end)) in token_ranges(estimate.splits(DEFAULT_SPLIT_SIZE)).into_iter().enumerate() {
+        // Ranges exclude their start, so the first takes in the lowest token.
+        let query = format!("SELECT * FROM {} WHERE {} {} ? AND {} <= ?",
+                            table, token, if i == 0 { ">=" } else { ">" }, token);
+        let mut statement = Statement::new(&query);
+        statement.set_page_size(EXPORT_PAGE_SIZE);
+        loop {
+            let result = try!(session.query_statement(&statement, &[&start, &end]));
+            for row in &result.rows {
+                let line = try!(row_json(result.columns(), row));
+                try!(writer.write_all(line.as_bytes()));
+                try!(writer.write_all(b"\n"));
+                count += 1;
+            }
+            match result.paging_state() {
+                Some(state) => statement.set_paging_state(Some(state)),
+                None => break,
+            }
+        }
+    }
+    try!(writer.flush());
+    Ok(count)
+}
+
+/// Inserts every line `export` wrote into `table`. Every option but
+/// `columns` applies; the columns come from each line's keys.
+pub fn import<R: BufRead>(session: &Session, table: &str, reader: R, options: &CopyOptions)
+                          -> Result<CopyProgress> {
+    let mut progress = CopyProgress::default();
+    let mut columns: Vec<String> = Vec::new();
+    let mut prepared: Option<PreparedStatement> = None;
+    let mut chunk = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = try!(parse_object(&line).map_err(|err| {
+            MyError::Protocol(format!("JSON line {}: {}", index + 1, err))
+        }));
+        if prepared.is_none() || fields.iter().map(|&(ref name, _)| name).ne(columns.iter()) {
+            if let Some(ref prepared) = prepared {
+                try!(write_chunk(session, prepared, chunk, options, &mut progress));
+                chunk = Vec::new();
+            }
+            columns = fields.iter().map(|&(ref name, _)| name.clone()).collect();
+            let names: Vec<String> = columns.iter().map(|column| quote_identifier(column)).collect();
+            let markers: Vec<&str> = columns.iter().map(|_| "?").collect();
+            prepared = Some(try!(session.prepare(&format!("INSERT INTO {} ({}) VALUES ({})",
+                                                          table, names.join(", "), markers.join(", ")))));
+        }
+        let params = prepared.as_ref().unwrap().params();
+        let mut row = Vec::with_capacity(fields.len());
+        for (&(_, ref value), spec) in fields.iter().zip(params) {
+            let text = match *value {
+                JsonValue::Null => {
+                    row.push(None);
+                    continue;
+                },
+                JsonValue::Text(ref text) | JsonValue::Literal(ref text) => text,
+            };
+            match parse_field(spec.datatype(), text) {
+                Ok(value) => row.push(Some(value)),
+                Err(MyError::Protocol(message)) => {
+                    return Err(MyError::Protocol(format!("JSON line {}, column {}: {}", index + 1, spec.name(), message)));
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        chunk.push(row);
+        if chunk.len() >= options.chunk_size.max(1) {
+            try!(write_chunk(session, prepared.as_ref().unwrap(), chunk, options, &mut progress));
+            chunk = Vec::new();
+        }
+    }
+    if let Some(ref prepared) = prepared {
+        if !chunk.is_empty() {
+            try!(write_chunk(session, prepared, chunk, options, &mut progress));
+        }
+    }
+    Ok(progress)
+}
+
+/// The table's partition key columns and clustering columns, each in
+/// order. Both are empty if the table doesn't exist.
+pub fn primary_key(session: &Session, keyspace: &str, table: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let result = try!(session.query("SELECT column_name, kind, position FROM system_schema.columns \
+                                     WHERE keyspace_name = ? AND table_name = ?", &[&keyspace, &table]));
+    let mut partition_key = Vec::new();
+    let mut clustering = Vec::new();
+    for row in &result.rows {
+        let kind: Option<String> = try!(row.get("kind"));
+        let key = match kind.as_ref().map(|kind| &kind[..]) {
+            Some("partition_key") => &mut partition_key,
+            Some("clustering") => &mut clustering,
+            _ => continue,
+        };
+        let column: Option<String> = try!(row.get("column_name"));
+        let position: Option<i32> = try!(row.get("position"));
+        key.push((position.unwrap_or(0), column.unwrap_or_default()));
+    }
+    partition_key.sort();
+    clustering.sort();
+    Ok((partition_key.into_iter().map(|(_, column)| column).collect(),
+        clustering.into_iter().map(|(_, column)| column).collect()))
+}
+
+/// Splits the Murmur3 ring into `count` ranges of equal width, each
+/// running from an exclusive start to an inclusive end.
+pub fn token_ranges(count: usize) -> Vec<(i64, i64)> {
+    let count = count.max(1) as u64;
+    let width = u64::max_value() / count;
+    let mut ranges = Vec::with_capacity(count as usize);
+    let mut start = i64::min_value();
+    for i in 1..count + 1 {
+        let end = if i == count { i64::max_value() } else { i64::min_value().wrapping_add((width * i) as i64) };
+        ranges.push((start, end));
+        start = end;
+    }
+    ranges
+}
+
+fn row_json(columns: &[ColumnSpec], row: &Row) -> Result<String> {
+    let mut json = String::from("{");
+    for (i, spec) in columns.iter().enumerate() {
+        if i > 0 {
+            json.push_str(", ");
+        }
+        json.push_str(&json_string(spec.name()));
+        json.push_str(": ");
+        match try!(row.get_reader(spec.name())) {
+            Some(value) => json.push_str(&try!(value_json(spec.datatype(), value.get_ref()))),
+            None => json.push_str("null"),
+        }
+    }
+    json.push('}');
+    Ok(json)
+}
+
+fn value_json(datatype: &CQLType, value: &[u8]) -> Result<String> {
+    Ok(match *datatype {
+        CQLType::Ascii | CQLType::Varchar => json_string(&try!(String::parse(value))),
+        CQLType::Tinyint => try!(i8::parse(value)).to_string(),
+        CQLType::Smallint => try!(i16::parse(value)).to_string(),
+        CQLType::Int => try!(i32::parse(value)).to_string(),
+        CQLType::Bigint | CQLType::Counter | CQLType::Timestamp => try!(i64::parse(value)).to_string(),
+        CQLType::Boolean => try!(bool::parse(value)).to_string(),
+        CQLType::Float if value.len() == 4 => {
+            let float = try!(Cursor::new(value).read_f32::<BigEndian>());
+            float_json(float as f64, format!("{:?}", float))
+        },
+        CQLType::Double if value.len() == 8 => {
+            let double = try!(Cursor::new(value).read_f64::<BigEndian>());
+            float_json(double, format!("{:?}", double))
+        },
+        CQLType::Uuid | CQLType::Timeuuid => json_string(&try!(Uuid::parse(value)).to_string()),
+        CQLType::Inet => json_string(&try!(IpAddr::parse(value)).to_string()),
+        CQLType::Date => json_string(&format_date(try!(Date::parse(value)).0)),
+        CQLType::Time => json_string(&format_time(try!(Time::parse(value)).0)),
+        _ => {
+            let mut hex = String::with_capacity(2 * value.len() + 4);
+            hex.push_str("\"0x");
+            for byte in value {
+                hex.push_str(&format!("{:02x}", byte));
+            }
+            hex.push('"');
+            hex
+        },
+    })
+}
+
+/// JSON has no literals for infinities or NaN.
+fn float_json(value: f64, literal: String) -> String {
+    if value.is_nan() {
+        "\"NaN\"".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "\"inf\"".to_string() } else { "\"-inf\"".to_string() }
+    } else {
+        literal
+    }
+}
+
+/// A `YYYY-MM-DD` date from days since the epoch.
+fn format_date(days: i32) -> String {
+    // Howard Hinnant's civil_from_days.
+    let days = days as i64 + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// An `HH:MM:SS.fffffffff` time from nanoseconds since midnight.
+fn format_time(nanos: i64) -> String {
+    let seconds = nanos / 1_000_000_000;
+    format!("{:02}:{:02}:{:02}.{:09}", seconds / 3600, seconds / 60 % 60, seconds % 60, nanos % 1_000_000_000)
+}
+
+/// Quotes and escapes `value` as a JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A value in an exported line. Numbers and booleans keep their literal
+/// text, which is parsed as whatever type the column has.
+enum JsonValue {
+    Null,
+    Text(String),
+    Literal(String),
+}
+
+/// Parses a flat JSON object, keeping its keys in order.
+fn parse_object(line: &str) -> result::Result<Vec<(String, JsonValue)>, String> {
+    let mut chars = line.chars().peekable();
+    let mut fields = Vec::new();
+    skip_whitespace(&mut chars);
+    if chars.next() != Some('{') {
+        return Err("expected a JSON object".to_string());
+    }
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            if chars.next() != Some('"') {
+                return Err("expected a quoted key".to_string());
+            }
+            let key = try!(parse_string(&mut chars));
+            skip_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return Err(format!("expected ':' after key {:?}", key));
+            }
+            skip_whitespace(&mut chars);
+            let value = match chars.peek().cloned() {
+                Some('"') => {
+                    chars.next();
+                    JsonValue::Text(try!(parse_string(&mut chars)))
+                },
+                Some('{') | Some('[') => return Err(format!("nested values aren't supported, in key {:?}", key)),
+                _ => {
+                    let mut literal = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c == ',' || c == '}' || c.is_whitespace() {
+                            break;
+                        }
+                        literal.push(c);
+                        chars.next();
+                    }
+                    match &literal[..] {
+                        "null" => JsonValue::Null,
+                        "" => return Err(format!("missing value for key {:?}", key)),
+                        _ => JsonValue::Literal(literal),
+                    }
+                },
+            };
+            fields.push((key, value));
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return Err("expected ',' or '}'".to_string()),
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err("unexpected text after the object".to_string());
+    }
+    let mut seen = HashSet::new();
+    for &(ref key, _) in &fields {
+        if !seen.insert(key) {
+            return Err(format!("duplicate key {:?}", key));
+        }
+    }
+    Ok(fields)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// Parses the rest of a string whose opening quote has been read.
+fn parse_string(chars: &mut Peekable<Chars>) -> result::Result<String, String> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(text),
+            Some('\\') => {
+                let escaped = match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let mut code = try!(parse_hex4(chars));
+                        // Characters outside the BMP are escaped as a
+                        // surrogate pair.
+                        if code >= 0xD800 && code < 0xDC00 {
+                            if chars.next() != Some('\\') || chars.next() != Some('u') {
+                                return Err("unpaired surrogate in string".to_string());
+                            }
+                            let low = try!(parse_hex4(chars));
+                            if low < 0xDC00 || low >= 0xE000 {
+                                return Err("unpaired surrogate in string".to_string());
+                            }
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        }
+                        try!(::std::char::from_u32(code).ok_or_else(|| "invalid \\u escape".to_string()))
+                    },
+                    _ => return Err("invalid escape in string".to_string()),
+                };
+                text.push(escaped);
+            },
+            Some(c) => text.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> result::Result<u32, String> {
+    let mut code = 0;
+    for _ in 0..4 {
+        match chars.next().and_then(|c| c.to_digit(16)) {
+            Some(digit) => code = code * 16 + digit,
+            None => return Err("invalid \\u escape".to_string()),
+        }
+    }
+    Ok(code)
+}
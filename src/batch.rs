@@ -0,0 +1,140 @@
+use protocol::{Consistency, PreparedStatement};
+use statement;
+use types::ToCQL;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BatchType {
+    /// Applied atomically through the batch log.
+    Logged,
+    Unlogged,
+    /// Only counter updates.
+    Counter,
+}
+
+#[derive(Clone)]
+pub enum BatchStatement<'a> {
+    Query(&'a str, Vec<&'a ToCQL>),
+    Prepared(&'a PreparedStatement, Vec<&'a ToCQL>),
+}
+
+impl<'a> BatchStatement<'a> {
+    pub fn params(&self) -> &[&'a ToCQL] {
+        match *self {
+            BatchStatement::Query(_, ref params) => params,
+            BatchStatement::Prepared(_, ref params) => params,
+        }
+    }
+}
+
+/// Several INSERT, UPDATE or DELETE statements sent in one round trip.
+#[derive(Clone)]
+pub struct Batch<'a> {
+    batch_type: BatchType,
+    consistency: Consistency,
+    timestamp: Option<i64>,
+    statements: Vec<BatchStatement<'a>>,
+    idempotent: bool,
+    tags: Vec<(String, String)>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn batch_type(&self) -> BatchType {
+        self.batch_type
+    }
+
+    pub fn consistency(&self) -> Consistency {
+        self.consistency
+    }
+
+    pub fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    /// Whether the batch is safe to retry after errors that leave it
+    /// unknown whether it was applied. See `Statement::set_idempotent`.
+    pub fn idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Counted in the connection's stats, but never sent to the server;
+    /// the tags of the statements in the batch are ignored.
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
+    }
+
+    pub fn statements(&self) -> &[BatchStatement<'a>] {
+        &self.statements
+    }
+
+    /// The query text of every statement in the batch.
+    pub fn queries(&self) -> Vec<&str> {
+        self.statements.iter().map(|statement| match *statement {
+            BatchStatement::Query(query, _) => query,
+            BatchStatement::Prepared(prepared, _) => prepared.query(),
+        }).collect()
+    }
+}
+
+pub struct BatchBuilder<'a> {
+    batch: Batch<'a>,
+}
+
+impl<'a> BatchBuilder<'a> {
+    pub fn new(batch_type: BatchType) -> BatchBuilder<'a> {
+        BatchBuilder {
+            batch: Batch {
+                batch_type: batch_type,
+                consistency: Consistency::One,
+                timestamp: None,
+                statements: Vec::new(),
+                idempotent: false,
+                tags: Vec::new(),
+            },
+        }
+    }
+
+    pub fn consistency(mut self, consistency: Consistency) -> BatchBuilder<'a> {
+        self.batch.consistency = consistency;
+        self
+    }
+
+    /// Writes every statement with `timestamp`, in microseconds since the
+    /// epoch, rather than the coordinator's time. Statements with their own
+    /// USING TIMESTAMP keep theirs.
+    pub fn timestamp(mut self, timestamp: i64) -> BatchBuilder<'a> {
+        self.batch.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Marks the batch as safe to apply more than once: no counter updates,
+    /// list appends or other statements whose effect depends on how often
+    /// they run.
+    pub fn idempotent(mut self, idempotent: bool) -> BatchBuilder<'a> {
+        self.batch.idempotent = idempotent;
+        self
+    }
+
+    /// Tags the batch with `key` set to `value`. See `Statement::set_tag`.
+    pub fn tag(mut self, key: &str, value: &str) -> BatchBuilder<'a> {
+        statement::set_tag(&mut self.batch.tags, key, value);
+        self
+    }
+
+    pub fn query(mut self, query: &'a str, params: &[&'a ToCQL]) -> BatchBuilder<'a> {
+        self.batch.statements.push(BatchStatement::Query(query, params.to_vec()));
+        self
+    }
+
+    pub fn prepared(mut self, prepared: &'a PreparedStatement, params: &[&'a ToCQL]) -> BatchBuilder<'a> {
+        self.batch.statements.push(BatchStatement::Prepared(prepared, params.to_vec()));
+        self
+    }
+
+    pub fn build(self) -> Batch<'a> {
+        self.batch
+    }
+}
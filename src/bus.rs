@@ -0,0 +1,88 @@
+//! A broadcast channel of what happens to the cluster and the session's
+//! connections, so that several parts of an application can each follow
+//! topology and schema changes without sharing one event callback.
+//!
+//! Every subscriber gets its own `Receiver` and sees every event published
+//! after it subscribed. Subscribers that drop their receiver are forgotten
+//! at the next event.
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use events::{Event, SchemaChange, StatusChange, TopologyChange};
+use warning::{Warning, WarningListener};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClusterEvent {
+    HostUp(SocketAddr),
+    HostDown(SocketAddr),
+    /// A node joined the cluster.
+    HostAdded(SocketAddr),
+    /// A node left the cluster.
+    HostRemoved(SocketAddr),
+    /// A node's tokens moved.
+    HostMoved(SocketAddr),
+    SchemaChanged(SchemaChange),
+    PoolStateChanged(PoolState),
+    /// Something the session worked around, or the linter found. See the
+    /// `warning` module.
+    Warning(Warning),
+}
+
+impl From<Event> for ClusterEvent {
+    fn from(event: Event) -> ClusterEvent {
+        match event {
+            Event::StatusChange(StatusChange::Up, addr) => ClusterEvent::HostUp(addr),
+            Event::StatusChange(StatusChange::Down, addr) => ClusterEvent::HostDown(addr),
+            Event::TopologyChange(TopologyChange::NewNode, addr) => ClusterEvent::HostAdded(addr),
+            Event::TopologyChange(TopologyChange::RemovedNode, addr) => ClusterEvent::HostRemoved(addr),
+            Event::TopologyChange(TopologyChange::MovedNode, addr) => ClusterEvent::HostMoved(addr),
+            Event::SchemaChange(change) => ClusterEvent::SchemaChanged(change),
+        }
+    }
+}
+
+/// The health of a session's connection pool, as seen by its heartbeats.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PoolState {
+    /// Every connection answered its last heartbeat, or reconnected.
+    Healthy,
+    /// Some connections failed their heartbeat and couldn't reconnect yet.
+    Degraded { broken: usize },
+    Closed,
+}
+
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<ClusterEvent>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// A receiver for every event published from now on.
+    pub fn subscribe(&self) -> Receiver<ClusterEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends `event` to every subscriber still listening.
+    pub fn publish(&self, event: ClusterEvent) {
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Subscribers that haven't been found gone yet.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+impl WarningListener for EventBus {
+    fn on_warning(&self, warning: &Warning) {
+        self.publish(ClusterEvent::Warning(warning.clone()));
+    }
+}
@@ -0,0 +1,130 @@
+//! A key/value table for using Cassandra as a cache or session store.
+//!
+//! ```ignore
+//! let cache = try!(CacheTable::create(&session, "app.sessions"));
+//! try!(cache.put("token", b"user 42", Some(Duration::from_secs(3600))));
+//! let user = try!(cache.get("token"));
+//! ```
+//!
+//! Entries expire through Cassandra's own TTLs, so expired entries simply
+//! stop being returned. The table is `(key text PRIMARY KEY, value blob)`;
+//! `put_if_absent` and `compare_and_set` use lightweight transactions, which
+//! cost several round trips between replicas and shouldn't be mixed with
+//! plain writes to the same key.
+
+use std::time::Duration;
+
+use errors::MyError;
+use protocol::{PreparedStatement, Result};
+use session::Session;
+use transaction::applied;
+
+/// Cassandra refuses TTLs longer than 20 years.
+pub const MAX_TTL_SECS: u64 = 20 * 365 * 24 * 60 * 60;
+
+/// Column the remaining TTL of the value is read into.
+const TTL_COLUMN: &'static str = "ttl(value)";
+
+/// A table of blobs by text key, with the statements to use it prepared
+/// up front.
+pub struct CacheTable {
+    session: Session,
+    table: String,
+    get: PreparedStatement,
+    put: PreparedStatement,
+    put_if_absent: PreparedStatement,
+    compare_and_set: PreparedStatement,
+    delete: PreparedStatement,
+}
+
+impl CacheTable {
+    /// Uses an existing table, which must have a text `key` primary key and
+    /// a blob `value` column.
+    pub fn new(session: &Session, table: &str) -> Result<CacheTable> {
+        Ok(CacheTable {
+            get: try!(session.prepare(&format!("SELECT value, TTL(value) FROM {} WHERE key = ?", table))),
+            put: try!(session.prepare(&format!("INSERT INTO {} (key, value) VALUES (?, ?) USING TTL ?", table))),
+            put_if_absent: try!(session.prepare(&format!("INSERT INTO {} (key, value) VALUES (?, ?) IF NOT EXISTS USING TTL ?",
+                                                         table))),
+            compare_and_set: try!(session.prepare(&format!("UPDATE {} USING TTL ? SET value = ? WHERE key = ? IF value = ?",
+                                                           table))),
+            delete: try!(session.prepare(&format!("DELETE FROM {} WHERE key = ?", table))),
+            session: session.clone(),
+            table: table.to_string(),
+        })
+    }
+
+    /// Creates the table if it doesn't exist yet, then uses it.
+    pub fn create(session: &Session, table: &str) -> Result<CacheTable> {
+        try!(session.execute(&format!("CREATE TABLE IF NOT EXISTS {} (key text PRIMARY KEY, value blob)", table), &[]));
+        CacheTable::new(session, table)
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(try!(self.get_with_ttl(key)).map(|(value, _)| value))
+    }
+
+    /// The value along with how long it has left, or `None` for the time
+    /// if it never expires.
+    pub fn get_with_ttl(&self, key: &str) -> Result<Option<(Vec<u8>, Option<Duration>)>> {
+        let result = try!(self.session.execute_prepared(&self.get, &[&key]));
+        let row = match result.rows.first() {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let value: Vec<u8> = match try!(row.get("value")) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let ttl: Option<i32> = try!(row.get(TTL_COLUMN));
+        Ok(Some((value, ttl.map(|secs| Duration::from_secs(secs as u64)))))
+    }
+
+    /// Stores `value`, replacing any earlier one. With a `ttl` the entry
+    /// expires after it; without one it is kept until deleted.
+    pub fn put(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        let ttl = try!(ttl_secs(ttl));
+        try!(self.session.execute_prepared(&self.put, &[&key, &value, &ttl]));
+        Ok(())
+    }
+
+    /// Stores `value` only if the key has no live entry. Returns whether
+    /// it was stored.
+    pub fn put_if_absent(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<bool> {
+        let ttl = try!(ttl_secs(ttl));
+        let result = try!(self.session.execute_prepared(&self.put_if_absent, &[&key, &value, &ttl]));
+        applied(&result)
+    }
+
+    /// Replaces the value only if it is still `expected`, restarting the
+    /// TTL. Returns whether it was replaced; it isn't if the entry changed,
+    /// expired or was deleted.
+    pub fn compare_and_set(&self, key: &str, expected: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<bool> {
+        let ttl = try!(ttl_secs(ttl));
+        let result = try!(self.session.execute_prepared(&self.compare_and_set, &[&ttl, &value, &key, &expected]));
+        applied(&result)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        try!(self.session.execute_prepared(&self.delete, &[&key]));
+        Ok(())
+    }
+}
+
+/// A TTL of 0 means the entry never expires. Anything shorter than a whole
+/// second is rounded up, so a short TTL doesn't turn into no TTL at all.
+fn ttl_secs(ttl: Option<Duration>) -> Result<i32> {
+    let ttl = match ttl {
+        Some(ttl) => ttl,
+        None => return Ok(0),
+    };
+    let secs = (ttl.as_secs() + if ttl.subsec_nanos() > 0 { 1 } else { 0 }).max(1);
+    if secs > MAX_TTL_SECS {
+        return Err(MyError::Protocol(format!("TTL of {}s is longer than the maximum of {}s", secs, MAX_TTL_SECS)));
+    }
+    Ok(secs as i32)
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use errors::MyError;
+use protocol::ProtocolVersion;
+
+/// SUPPORTED option ScyllaDB advertises its rate limit error code under,
+/// as `ERROR_CODE=<code>`.
+const RATE_LIMIT_ERROR_OPTION: &'static str = "SCYLLA_RATE_LIMIT_ERROR";
+
+/// What a server said it supports in reply to OPTIONS.
+#[derive(Debug, Clone, Default)]
+pub struct ServerCapabilities {
+    pub cql_versions: Vec<String>,
+    /// Compression algorithm names, such as `lz4`.
+    pub compression: Vec<String>,
+    /// Versions the server lists under PROTOCOL_VERSIONS, oldest first.
+    /// Beta versions and ones this driver doesn't speak are left out, and
+    /// servers before Cassandra 4.0 don't list any.
+    pub protocol_versions: Vec<ProtocolVersion>,
+    /// The error code the server rejects requests over a per-partition
+    /// rate limit with, if it has such limits.
+    pub rate_limit_error: Option<u32>,
+    /// Every option as sent, including ones not decoded above.
+    pub options: HashMap<String, Vec<String>>,
+}
+
+impl ServerCapabilities {
+    pub fn from_options(options: &HashMap<String, Vec<String>>) -> ServerCapabilities {
+        let list = |key: &str| options.get(key).cloned().unwrap_or_default();
+        let mut protocol_versions: Vec<ProtocolVersion> = list("PROTOCOL_VERSIONS").iter()
+            .filter(|version| !version.ends_with("-beta"))
+            .filter_map(|version| version.split('/').next().and_then(|number| number.parse().ok()))
+            .filter_map(ProtocolVersion::from_number)
+            .collect();
+        protocol_versions.sort();
+        let rate_limit_error = list(RATE_LIMIT_ERROR_OPTION).iter()
+            .filter_map(|value| {
+                let mut parts = value.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some("ERROR_CODE"), Some(code)) => code.parse().ok(),
+                    _ => None,
+                }
+            })
+            .next();
+        ServerCapabilities {
+            cql_versions: list("CQL_VERSION"),
+            compression: list("COMPRESSION"),
+            protocol_versions: protocol_versions,
+            rate_limit_error: rate_limit_error,
+            options: options.clone(),
+        }
+    }
+
+    /// Whether the server listed `version`, or `None` if it didn't list
+    /// its versions at all.
+    pub fn supports_version(&self, version: ProtocolVersion) -> Option<bool> {
+        if self.protocol_versions.is_empty() {
+            None
+        } else {
+            Some(self.protocol_versions.contains(&version))
+        }
+    }
+
+    /// Whether `err` is the server turning a request down for going over a
+    /// rate limit. These are safe to retry after backing off.
+    pub fn is_rate_limit_error(&self, err: &MyError) -> bool {
+        match (err.root(), self.rate_limit_error) {
+            (&MyError::Server(code, _, _), Some(rate_limit_error)) => code == rate_limit_error,
+            _ => false,
+        }
+    }
+}
@@ -1,56 +1,1442 @@
-use std::io::{Cursor, Read};
-use std::collections::HashMap;
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, BufWriter, Cursor, Write};
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::ops::Deref;
+use std::sync::mpsc::{self, Receiver};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+use byteorder::{BigEndian, ByteOrder};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 
 use protocol::*;
 use types::ToCQL;
-use errors::MyError;
+use batch::{Batch, BatchStatement};
+use errors::{ErrorCode, ErrorContext, MyError};
+use histogram::{Histogram, LatencyHistogram};
+use stats::ConnectionStats;
+use statement::{self, Statement, Template};
+use pager::Pager;
+use trace::QueryTrace;
+use explain::Explanation;
+use events::{Event, EventType, SchemaChange, SchemaTarget, StatusChange, TopologyChange};
+use hosts::{HostState, HostStates};
+use compression::Compression;
+use capabilities::ServerCapabilities;
+use diagnostics::ProbeReport;
+use framing;
+use warning::{Warning, WarningListener};
+use graph::GraphOptions;
+use auth::{AuthProvider, Credentials};
+use resolver::{self, Resolver};
+use retry::{self, DefaultRetryPolicy, RetryDecision, RetryPolicy};
+use ddl;
+use durability;
+use socket::{Closer, Connection, SocketOptions};
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+#[cfg(feature = "native-tls")]
+use nativetls::NativeTlsConfig;
+
+const HEADER_LENGTH: usize = 9;
+/// Events beyond this many are dropped, oldest first, until taken.
+const MAX_QUEUED_EVENTS: usize = 1024;
 
 pub struct Client {
-    conn: TcpStream,
+    addrs: Vec<SocketAddr>,
+    host_states: HostStates,
+    // Resolved again before each reconnect, if set. `resolved` is what the
+    // contact points resolved to last time, to tell stale addresses from
+    // hosts learned through topology events.
+    resolver: Option<Arc<Resolver>>,
+    contact_points: Vec<String>,
+    resolved: Vec<SocketAddr>,
+    conn: Connection,
+    closer: Arc<Closer>,
+    // Lowered during `initialize` if the server doesn't support it.
+    version: ProtocolVersion,
+    stats: ConnectionStats,
+    compression_preference: Vec<Compression>,
+    // Negotiated during STARTUP; None until the server has answered it.
+    compression: Compression,
+    // From the SUPPORTED reply of the last handshake.
+    capabilities: ServerCapabilities,
+    // From v5, frames are wrapped in the framing layer once STARTUP has
+    // been answered. Payload bytes past the last whole frame wait here,
+    // and the wire bytes read since the last frame was returned are
+    // counted in `received_wire`.
+    framing: bool,
+    received: Vec<u8>,
+    received_wire: usize,
+    max_statement_length: usize,
+    auth: Option<Arc<AuthProvider>>,
+    retry_policy: Arc<RetryPolicy>,
+    connect_timeout: Option<Duration>,
+    init_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    socket_options: SocketOptions,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<TlsConfig>>,
+    #[cfg(feature = "native-tls")]
+    native_tls: Option<Arc<NativeTlsConfig>>,
+    send_tags: bool,
+    warnings: Option<Arc<WarningListener>>,
+    // Set when a response didn't arrive in time, or a request failed to
+    // send. The response may still arrive, or the server may be holding
+    // part of a frame, so nothing is sent on the connection until it's
+    // replaced.
+    quarantined: bool,
+    // Set by USE statements; prepared ids are only valid in the keyspace
+    // they were prepared in.
+    keyspace: Option<String>,
+    // Keyed by the hash of the query text. The statements keep their own
+    // text, which is checked on lookup, so a collision is only a miss.
+    prepared: HashMap<(Option<String>, u64), PreparedStatement>,
+    events: VecDeque<Event>,
 }
 
 impl Client {
     pub fn new<A: ToSocketAddrs>(addr: A) -> Client {
-        Client {
-            conn: TcpStream::connect(addr).unwrap(),
+        Client::connect(addr).unwrap()
+    }
+
+    /// Opens a connection without initializing it, returning an error
+    /// rather than panicking if the address doesn't resolve or connect.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client> {
+        Client::open(try!(addr.to_socket_addrs()).collect(), None)
+    }
+
+    /// Like `connect`, but gives up on each address that doesn't accept
+    /// the connection within `timeout`, with `MyError::Timeout` if none
+    /// did. Reconnects wait as long.
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Client> {
+        Client::open(try!(addr.to_socket_addrs()).collect(), Some(timeout))
+    }
+
+    fn open(addrs: Vec<SocketAddr>, connect_timeout: Option<Duration>) -> Result<Client> {
+        let mut host_states = HostStates::new();
+        let conn = try!(connect_any(&addrs, &mut host_states, connect_timeout));
+        let socket_options = SocketOptions::default();
+        try!(socket_options.apply(&conn));
+        let closer = Arc::new(Closer::new());
+        try!(closer.track(&conn));
+        Ok(Client {
+            conn: Connection::Plain(conn),
+            closer: closer,
+            version: ProtocolVersion::latest(),
+            addrs: addrs,
+            host_states: host_states,
+            resolver: None,
+            contact_points: Vec::new(),
+            resolved: Vec::new(),
+            stats: ConnectionStats::new(),
+            compression_preference: Compression::available(),
+            compression: Compression::None,
+            capabilities: ServerCapabilities::default(),
+            framing: false,
+            received: Vec::new(),
+            received_wire: 0,
+            max_statement_length: statement::DEFAULT_MAX_STATEMENT_LENGTH,
+            auth: None,
+            retry_policy: Arc::new(DefaultRetryPolicy),
+            connect_timeout: connect_timeout,
+            init_timeout: None,
+            request_timeout: None,
+            socket_options: socket_options,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "native-tls")]
+            native_tls: None,
+            send_tags: false,
+            warnings: None,
+            quarantined: false,
+            keyspace: None,
+            prepared: HashMap::new(),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Opens a connection to one of the addresses `resolver` gives for
+    /// `contact_points`, which are resolved again on every reconnect.
+    /// Contact points that don't resolve this first time are skipped
+    /// without a warning, as there is no listener yet.
+    pub fn resolve(contact_points: &[String], resolver: Arc<Resolver>) -> Result<Client> {
+        let addrs = try!(resolver::resolve_all(&*resolver, contact_points, None));
+        let mut client = try!(Client::connect(&addrs[..]));
+        client.set_resolver(contact_points.to_vec(), resolver);
+        Ok(client)
+    }
+
+    /// Resolves `contact_points` with `resolver` before each reconnect.
+    /// The connection's current addresses are taken to be what they
+    /// resolved to so far.
+    pub fn set_resolver(&mut self, contact_points: Vec<String>, resolver: Arc<Resolver>) {
+        self.resolved = self.addrs.clone();
+        self.contact_points = contact_points;
+        self.resolver = Some(resolver);
+    }
+
+    /// Connects to `addr` and asks what it supports with OPTIONS, without
+    /// sending STARTUP, so no credentials are needed and nothing shows up
+    /// in the server's client list. Steps down from the newest protocol
+    /// version while the server rejects them, like `initialize`. Meant for
+    /// deployment tooling checking a cluster and driver are compatible.
+    pub fn probe<A: ToSocketAddrs>(addr: A) -> Result<ProbeReport> {
+        let start = Instant::now();
+        let mut client = try!(Client::connect(addr));
+        let peer = try!(client.conn.tcp().peer_addr());
+        let mut result = client.get_options();
+        while let Err(err) = result {
+            let previous = match (err.error_code(), client.version.previous()) {
+                (Some(ErrorCode::ProtocolError), Some(previous)) => previous,
+                _ => return Err(err),
+            };
+            try!(client.downgrade(peer, previous));
+            result = client.get_options();
+        }
+        client.shutdown();
+        let capabilities = ServerCapabilities::from_options(&try!(result));
+        Ok(ProbeReport {
+            addr: peer,
+            protocol_version: client.version,
+            compression: Compression::negotiate(&Compression::available(), &capabilities.compression),
+            capabilities: capabilities,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Replaces the connection with a fresh one, runs the startup
+    /// handshake on it again and selects the keyspace the connection was
+    /// using. Hosts marked down are only tried once every other host has
+    /// failed. With a resolver, the contact points are resolved again
+    /// first.
+    pub fn reconnect(&mut self) -> Result<()> {
+        if self.closer.is_closed() {
+            return Err(MyError::Closed);
+        }
+        self.resolve_again();
+        let conn = try!(connect_any(&self.addrs, &mut self.host_states, self.connect_timeout));
+        self.conn = try!(self.set_up(conn));
+        self.quarantined = false;
+        self.compression = Compression::None;
+        self.framing = false;
+        self.received.clear();
+        self.received_wire = 0;
+        let keyspace = self.keyspace.take();
+        self.prepared.clear();
+        try!(self.initialize());
+        if let Some(keyspace) = keyspace {
+            let query = format!("USE \"{}\"", keyspace.replace('"', "\"\""));
+            try!(self.send(QueryRequest::new(&query, QueryParameters::new(&[]))));
+            let frame = try!(self.recv());
+            let result = try!(self.decode_body::<NonRowResult>(frame));
+            self.track_keyspace(result.keyspace());
         }
+        Ok(())
+    }
+
+    /// The algorithms to offer when the connection is initialized, best
+    /// first. The first one the server supports is used, and frames go
+    /// uncompressed if there is none. Defaults to every compiled-in
+    /// algorithm.
+    pub fn set_compression_preference(&mut self, preference: Vec<Compression>) {
+        self.compression_preference = preference;
+    }
+
+    /// The newest protocol version to try when the connection is
+    /// initialized. Older versions are tried in turn if the server rejects
+    /// it. Defaults to the newest version this driver speaks.
+    pub fn set_protocol_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// The protocol version in use; after `initialize`, the one the server
+    /// accepted.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.version
     }
 
+    /// Refuses to send query text longer than `length` bytes.
+    pub fn set_max_statement_length(&mut self, length: usize) {
+        self.max_statement_length = length;
+    }
+
+    /// Decides which failed requests are sent again. `DefaultRetryPolicy`
+    /// unless set.
+    pub fn set_retry_policy(&mut self, policy: Arc<RetryPolicy>) {
+        self.retry_policy = policy;
+    }
+
+    /// How long to wait for each response before giving up with
+    /// `MyError::Timeout`. The connection is then replaced before the next
+    /// request, since the late response could otherwise be taken for the
+    /// answer to it. `None`, the default, waits indefinitely.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        try!(self.conn.tcp().set_read_timeout(timeout));
+        self.request_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// How long `reconnect` waits for each address to accept the
+    /// connection. `None`, the default unless connected with
+    /// `connect_timeout`, leaves it to the operating system.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// How long `initialize` may take altogether, including every protocol
+    /// version it tries and authentication, before failing with
+    /// `MyError::Timeout`. `None`, the default, waits indefinitely.
+    pub fn set_init_timeout(&mut self, timeout: Option<Duration>) {
+        self.init_timeout = timeout;
+    }
+
+    pub fn init_timeout(&self) -> Option<Duration> {
+        self.init_timeout
+    }
+
+    /// Sets `options` on the current connection, and on every one opened
+    /// after it. Defaults to `SocketOptions::default()`.
+    pub fn set_socket_options(&mut self, options: SocketOptions) -> Result<()> {
+        try!(options.apply(self.conn.tcp()));
+        self.socket_options = options;
+        Ok(())
+    }
+
+    pub fn socket_options(&self) -> &SocketOptions {
+        &self.socket_options
+    }
+
+    /// Encrypts the connection with TLS, and every one opened after it.
+    /// Call it before `initialize`, while nothing has been sent on the
+    /// connection yet; the TLS handshake runs right away. `None`, the
+    /// default, leaves connections in plaintext.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: Option<Arc<TlsConfig>>) -> Result<()> {
+        if let Some(ref tls) = tls {
+            let conn = try!(self.conn.tcp().try_clone());
+            self.conn = try!(tls.connect(conn));
+        }
+        self.tls = tls;
+        Ok(())
+    }
+
+    /// Like `set_tls`, but with the platform's TLS library and trust
+    /// store. Connections use rustls instead while `set_tls` is set too.
+    #[cfg(feature = "native-tls")]
+    pub fn set_native_tls(&mut self, tls: Option<Arc<NativeTlsConfig>>) -> Result<()> {
+        if let Some(ref tls) = tls {
+            let conn = try!(self.conn.tcp().try_clone());
+            self.conn = try!(tls.connect(conn));
+        }
+        self.native_tls = tls;
+        Ok(())
+    }
+
+    /// Also sends the tags of statements and prepared statements to the
+    /// server, as custom payload entries, for server-side plugins or audit
+    /// logs to attribute requests by. Needs native protocol v4; requests
+    /// with tags fail on older versions. Off by default, when tags are
+    /// only counted in the stats.
+    pub fn set_send_tags(&mut self, send: bool) {
+        self.send_tags = send;
+    }
+
+    pub fn send_tags(&self) -> bool {
+        self.send_tags
+    }
+
+    /// Where to report the problems this connection works around, and
+    /// warnings the server raises with no result to carry them. See the
+    /// `warning` module. They are dropped if unset.
+    pub fn set_warning_listener(&mut self, listener: Option<Arc<WarningListener>>) {
+        self.warnings = listener;
+    }
+
+    fn warn(&self, warning: Warning) {
+        if let Some(ref listener) = self.warnings {
+            listener.on_warning(&warning);
+        }
+    }
+
+    /// The username and password to log in with if the server asks for
+    /// authentication during `initialize`.
+    pub fn set_credentials(&mut self, credentials: Option<Credentials>) {
+        self.auth = credentials.map(|credentials| Arc::new(credentials) as Arc<AuthProvider>);
+    }
+
+    /// Like `set_credentials`, for other SASL mechanisms.
+    pub fn set_auth_provider(&mut self, provider: Option<Arc<AuthProvider>>) {
+        self.auth = provider;
+    }
+
+    /// The keyspace selected by the last USE statement on this connection.
+    pub fn keyspace(&self) -> Option<&str> {
+        self.keyspace.as_ref().map(|k| k.as_ref())
+    }
+
+    /// The compression negotiated with the server.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// What the server said it supports when the connection was
+    /// initialized.
+    pub fn capabilities(&self) -> &ServerCapabilities {
+        &self.capabilities
+    }
+
+    /// Closes the socket in both directions, for good. Any request
+    /// blocked on it, and any later one, fails with `MyError::Closed`.
+    pub fn shutdown(&mut self) {
+        self.closer.close();
+        let _ = self.conn.shutdown();
+    }
+
+    /// Closes this client's connection like `shutdown` does, from any
+    /// thread, even while a request holds the client.
+    pub fn closer(&self) -> Arc<Closer> {
+        self.closer.clone()
+    }
+
+    /// Round-trips an OPTIONS request to check the connection is still alive.
+    pub fn heartbeat(&mut self) -> Result<()> {
+        self.get_options().map(|_| ())
+    }
+
+    /// Runs the startup handshake, first with the configured protocol
+    /// version and then with each older one while the server answers
+    /// OPTIONS or STARTUP with a protocol error. Whichever version the
+    /// server accepts is used for every later request on the connection.
     pub fn initialize(&mut self) -> Result<()> {
+        // Once the server has hung up, the socket no longer knows its peer.
+        let peer = try!(self.conn.tcp().peer_addr());
+        let deadline = self.init_timeout.map(|timeout| Instant::now() + timeout);
+        let result = self.initialize_until(peer, deadline);
+        // Only the handshake's responses are bounded by the deadline.
+        try!(self.conn.tcp().set_read_timeout(self.request_timeout));
+        result
+    }
+
+    fn initialize_until(&mut self, peer: SocketAddr, deadline: Option<Instant>) -> Result<()> {
+        loop {
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(MyError::Timeout(format!("initializing the connection to {} took longer than {:?}",
+                                                        peer, self.init_timeout.unwrap_or_default())));
+                }
+                try!(self.conn.tcp().set_read_timeout(Some(deadline - now)));
+            }
+            let err = match self.handshake() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            // Servers reject protocol versions they don't support as a
+            // malformed frame.
+            let previous = match (err.error_code(), self.version.previous()) {
+                (Some(ErrorCode::ProtocolError), Some(previous)) => previous,
+                _ => return Err(err),
+            };
+            try!(self.downgrade(peer, previous));
+        }
+    }
+
+    fn handshake(&mut self) -> Result<()> {
         let options = try!(self.get_options());
+        self.capabilities = ServerCapabilities::from_options(&options);
         let cql_version = &options["CQL_VERSION"][0];
-        let req = StartupRequest::new(cql_version);
-        try!(req.encode(&mut self.conn));
-        let ready = try!(Header::decode(&mut self.conn));
-        println!("Connection initialized with CQL version {}", cql_version);
-        assert_eq!(ready.opcode, Opcode::Ready);
-        match ready.opcode {
+        let compression = match options.get("COMPRESSION") {
+            Some(supported) => Compression::negotiate(&self.compression_preference, supported),
+            None => Compression::None,
+        };
+        try!(self.send(StartupRequest::new(cql_version, compression.name())));
+        // Everything after STARTUP, including the reply to it, may be
+        // compressed. From v5 the reply itself is the last unframed frame,
+        // and compression applies to the framing layer instead.
+        self.compression = compression;
+        let ready = try!(self.recv());
+        self.framing = self.version >= ProtocolVersion::V5;
+        match ready.header.opcode {
+            Opcode::Ready => {},
+            Opcode::Authenticate => try!(self.authenticate(ready)),
+            _ => return Err(MyError::Protocol(format!("Expected Ready opcode, got {:?}", ready.header.opcode))),
+        }
+        println!("Connection initialized with CQL version {} over protocol v{}", cql_version, self.version.number());
+        Ok(())
+    }
+
+    /// The addresses `reconnect` tries, in order. Registering for topology
+    /// changes keeps this up to date as nodes join and leave.
+    pub fn hosts(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Whether `addr` is believed to be up, going by STATUS_CHANGE events
+    /// and connection attempts so far.
+    pub fn host_state(&self, addr: &SocketAddr) -> HostState {
+        self.host_states.state(addr)
+    }
+
+    /// Asks the server to push the given kinds of event on this
+    /// connection. They are read along with responses and can be collected
+    /// with `take_events`.
+    pub fn register(&mut self, events: &[EventType]) -> Result<()> {
+        try!(self.send(RegisterRequest::new(events)));
+        let ready = try!(self.recv());
+        match ready.header.opcode {
             Opcode::Ready => Ok(()),
-            _ => Err(MyError::Protocol(format!("Expected Ready opcode, got {:?}", ready.opcode)))
+            _ => Err(MyError::Protocol(format!("Expected Ready opcode, got {:?}", ready.header.opcode)))
         }
     }
 
+    /// Opens a separate connection registered for `kinds` and delivers its
+    /// events on the returned channel as they arrive, independently of
+    /// this connection's requests. The background thread stops when the
+    /// subscription is closed or dropped, or when the connection fails,
+    /// which is reported to this connection's warning listener.
+    pub fn subscribe_events(&self, kinds: &[EventType]) -> Result<EventSubscription> {
+        let mut client = try!(Client::open(self.addrs.clone(), self.connect_timeout));
+        client.set_init_timeout(self.init_timeout);
+        try!(client.set_socket_options(self.socket_options.clone()));
+        #[cfg(feature = "tls")]
+        try!(client.set_tls(self.tls.clone()));
+        #[cfg(feature = "native-tls")]
+        try!(client.set_native_tls(self.native_tls.clone()));
+        if let Some(ref resolver) = self.resolver {
+            client.set_resolver(self.contact_points.clone(), resolver.clone());
+            client.resolved = self.resolved.clone();
+        }
+        client.set_compression_preference(self.compression_preference.clone());
+        client.set_protocol_version(self.version);
+        client.set_auth_provider(self.auth.clone());
+        client.set_warning_listener(self.warnings.clone());
+        try!(client.initialize());
+        try!(client.register(kinds));
+        let (sender, receiver) = mpsc::channel();
+        let closer = client.closer();
+        let handle = thread::spawn(move || {
+            loop {
+                let event = match client.next_event() {
+                    Ok(event) => event,
+                    Err(MyError::Closed) => return,
+                    Err(err) => {
+                        client.warn(Warning::EventConnectionFailed { error: err.to_string() });
+                        return;
+                    }
+                };
+                if sender.send(event).is_err() {
+                    client.shutdown();
+                    return;
+                }
+            }
+        });
+        Ok(EventSubscription {
+            events: receiver,
+            closer: closer,
+            handle: Some(handle),
+        })
+    }
+
+    /// Puts what the contact points resolve to now first in `addrs`, and
+    /// drops the addresses they no longer resolve to. Keeps the old ones if
+    /// resolving fails.
+    fn resolve_again(&mut self) {
+        let resolver = match self.resolver {
+            Some(ref resolver) => resolver.clone(),
+            None => return,
+        };
+        let warnings = self.warnings.as_ref().map(|warnings| &**warnings);
+        let addrs = match resolver::resolve_all(&*resolver, &self.contact_points, warnings) {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                self.warn(Warning::ResolveFailed { error: err.to_string() });
+                return;
+            },
+        };
+        let previous = mem::replace(&mut self.resolved, addrs.clone());
+        let learned: Vec<SocketAddr> = self.addrs.drain(..)
+            .filter(|addr| !previous.contains(addr) && !addrs.contains(addr))
+            .collect();
+        self.addrs = addrs;
+        self.addrs.extend(learned);
+    }
+
+    /// Removes and returns the events received so far.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events.drain(..).collect()
+    }
+
     pub fn query(&mut self, query: &str, params: &[&ToCQL]) -> Result<QueryResult> {
-        let req = QueryRequest::new(query, params);
-        try!(req.encode(&mut self.conn));
-        QueryResult::decode(&mut self.conn)
+        self.query_statement(&Statement::new(query), params)
+    }
+
+    pub fn query_statement(&mut self, statement: &Statement, params: &[&ToCQL]) -> Result<QueryResult> {
+        self.request(statement.query(), RequestOptions::of(statement), |client, downgraded| {
+            try!(client.check_statement_length(statement.query()));
+            let mut parameters = statement.parameters(params);
+            parameters.consistency = downgraded.unwrap_or(parameters.consistency);
+            if client.send_tags {
+                parameters.custom_payload.extend(statement.tags().iter().map(|&(ref key, ref value)| {
+                    (&key[..], value.as_bytes())
+                }));
+            }
+            try!(client.send(QueryRequest::new(statement.query(), parameters)));
+            let frame = try!(client.recv());
+            let result = QueryResult::from_frame(frame, statement.projection());
+            let result = try!(client.check_decode(result));
+            client.track_keyspace(result.keyspace());
+            Ok(result)
+        })
+    }
+
+    /// Runs a template with `params` bound to its remaining markers.
+    pub fn query_template(&mut self, template: &Template, params: &[&ToCQL]) -> Result<QueryResult> {
+        let values = try!(template.values(params));
+        self.query_statement(template.statement(), &values)
+    }
+
+    /// Runs `query` a page at a time, returning an iterator over all of its
+    /// rows.
+    pub fn query_iter<'a>(&'a mut self, query: &str, params: &'a [&'a ToCQL]) -> Pager<'a> {
+        self.query_statement_iter(Statement::new(query), params)
+    }
+
+    pub fn query_statement_iter<'a>(&'a mut self, statement: Statement, params: &'a [&'a ToCQL]) -> Pager<'a> {
+        Pager::new(self, statement, params)
+    }
+
+    /// Has the server parse `query` once so it can be run by id later.
+    /// Statements are cached per keyspace, so preparing the same query again
+    /// doesn't go to the server.
+    pub fn prepare(&mut self, query: &str) -> Result<PreparedStatement> {
+        // Preparing changes nothing but the server's cache.
+        let options = RequestOptions { idempotent: true, ..RequestOptions::default() };
+        self.request(query, options, |client, _| client.prepare_cached(query))
+    }
+
+    /// Runs a prepared statement with the given bound values.
+    pub fn execute_prepared(&mut self, prepared: &PreparedStatement, params: &[&ToCQL]) -> Result<QueryResult> {
+        self.execute_prepared_at(prepared, params, Consistency::One)
+    }
+
+    /// Like `execute_prepared`, at `consistency`.
+    pub fn execute_prepared_at(&mut self, prepared: &PreparedStatement, params: &[&ToCQL],
+                               consistency: Consistency) -> Result<QueryResult> {
+        let options = RequestOptions {
+            idempotent: prepared.idempotent(),
+            tables: durability::accessed_tables(prepared.query()),
+            tags: prepared.tags().to_vec(),
+            ..RequestOptions::default()
+        };
+        let tags = prepared.tags();
+        self.request(prepared.query(), options, |client, downgraded| {
+            let consistency = downgraded.unwrap_or(consistency);
+            if params.len() != prepared.params().len() {
+                return Err(MyError::Protocol(format!("Prepared statement expects {} values, got {}",
+                                                     prepared.params().len(), params.len())));
+            }
+            // Either the keyspace changed since this was prepared, so its id
+            // may resolve tables differently, or a schema change dropped it
+            // from the cache; prepare it again in the current keyspace.
+            let current;
+            let prepared = if !client.is_current(prepared) {
+                current = try!(client.prepare_cached(prepared.query()));
+                &current
+            } else {
+                prepared
+            };
+            match client.execute_once(prepared, params, consistency, tags) {
+                Err(ref err) if err.unprepared_id() == Some(prepared.id()) => {
+                    // The server lost the statement, most likely in a
+                    // restart; prepare it again and resend once.
+                    client.prepared.retain(|_, cached| cached.id() != prepared.id());
+                    let prepared = try!(client.prepare_cached(prepared.query()));
+                    client.stats.record_retry();
+                    client.execute_once(&prepared, params, consistency, tags)
+                },
+                result => result,
+            }
+        })
+    }
+
+    fn execute_once(&mut self, prepared: &PreparedStatement, params: &[&ToCQL], consistency: Consistency,
+                    tags: &[(String, String)]) -> Result<QueryResult> {
+        let mut parameters = QueryParameters::new(params);
+        parameters.consistency = consistency;
+        if self.send_tags {
+            parameters.custom_payload = tags.iter().map(|&(ref key, ref value)| (&key[..], value.as_bytes())).collect();
+        }
+        // The columns were sent with the Prepared result already, so
+        // there's no need to have them in every response.
+        parameters.skip_metadata = !prepared.columns().is_empty();
+        try!(self.send(ExecuteRequest::new(prepared, parameters)));
+        let frame = try!(self.recv());
+        let result = QueryResult::from_frame_with(frame, None, Some(prepared.columns()));
+        if result.is_err() {
+            // Most likely the table was altered; prepare it again next
+            // time to learn its columns.
+            self.prepared.retain(|_, cached| cached.id() != prepared.id());
+        }
+        let result = try!(self.check_decode(result));
+        if result.new_metadata_id().is_some() {
+            // The rows came with the new columns, but the cached
+            // statement still describes the old ones.
+            self.prepared.retain(|_, cached| cached.id() != prepared.id());
+        }
+        self.track_keyspace(result.keyspace());
+        Ok(result)
+    }
+
+    /// Sends every statement of `batch` in a single BATCH request. The
+    /// result only has rows for conditional batches.
+    pub fn batch(&mut self, batch: &Batch) -> Result<QueryResult> {
+        let description = format!("BATCH of {} statements", batch.statements().len());
+        let mut tables: Vec<String> = Vec::new();
+        for table in batch.queries().into_iter().flat_map(durability::accessed_tables) {
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+        let options = RequestOptions {
+            idempotent: batch.idempotent(),
+            tables: tables,
+            tags: batch.tags().to_vec(),
+            ..RequestOptions::default()
+        };
+        self.request(&description, options, |client, downgraded| {
+            let downgraded_batch;
+            let batch = match downgraded {
+                Some(consistency) => {
+                    let mut copy = batch.clone();
+                    copy.set_consistency(consistency);
+                    downgraded_batch = copy;
+                    &downgraded_batch
+                },
+                None => batch,
+            };
+            for statement in batch.statements() {
+                if let BatchStatement::Query(query, _) = *statement {
+                    try!(client.check_statement_length(query));
+                }
+            }
+            try!(client.send(BatchRequest::new(batch)));
+            let frame = match client.recv() {
+                Err(err) => {
+                    let lost = batch.statements().iter().filter_map(|statement| match *statement {
+                        BatchStatement::Prepared(prepared, _) if err.unprepared_id() == Some(prepared.id()) => Some(prepared),
+                        _ => None,
+                    }).next();
+                    let lost = match lost {
+                        Some(lost) => lost,
+                        None => return Err(err),
+                    };
+                    // Prepare the statement the server lost again. Its id
+                    // only depends on the query and keyspace, so the batch
+                    // can be resent as it is.
+                    client.prepared.retain(|_, cached| cached.id() != lost.id());
+                    try!(client.prepare_cached(lost.query()));
+                    client.stats.record_retry();
+                    try!(client.send(BatchRequest::new(batch)));
+                    try!(client.recv())
+                },
+                Ok(frame) => frame,
+            };
+            let result = QueryResult::from_frame(frame, None);
+            client.check_decode(result)
+        })
+    }
+
+    /// Describes how `statement` would be sent on this connection, without
+    /// sending it.
+    pub fn explain(&self, statement: &Statement, params: &[&ToCQL]) -> Result<Explanation> {
+        let mut explanation = try!(Explanation::for_statement(statement, params));
+        self.describe_connection(&mut explanation);
+        Ok(explanation)
+    }
+
+    /// Like `explain`, for a prepared statement, which also gives the
+    /// routing key and token.
+    pub fn explain_prepared(&self, prepared: &PreparedStatement, params: &[&ToCQL], consistency: Consistency)
+                            -> Result<Explanation> {
+        let mut explanation = try!(Explanation::for_prepared(prepared, params, consistency));
+        self.describe_connection(&mut explanation);
+        Ok(explanation)
+    }
+
+    fn describe_connection(&self, explanation: &mut Explanation) {
+        let hosts: Vec<(SocketAddr, HostState)> = self.addrs.iter()
+            .map(|&addr| (addr, self.host_states.state(&addr)))
+            .collect();
+        explanation.set_connection(self.conn.tcp().peer_addr().ok(), &hosts, self.version);
+    }
+
+    /// Reads the trace of a request made with tracing enabled, given the
+    /// id from `QueryResult::tracing_id`.
+    pub fn get_trace(&mut self, tracing_id: Uuid) -> Result<QueryTrace> {
+        QueryTrace::fetch(self, tracing_id)
     }
 
     pub fn execute(&mut self, statement: &str, params: &[&ToCQL]) -> Result<()> {
-        let statement = QueryRequest::new(statement, params);
-        try!(statement.encode(&mut self.conn));
-        NonRowResult::decode(&mut self.conn).map(|_| ())
+        self.execute_statement(&Statement::new(statement), params)
+    }
+
+    /// Runs a statement whose result is of no interest, such as a write or
+    /// a schema change.
+    pub fn execute_statement(&mut self, statement: &Statement, params: &[&ToCQL]) -> Result<()> {
+        try!(self.request(statement.query(), RequestOptions::of(statement), |client, downgraded| {
+            client.run_statement(statement, params, downgraded)
+        }));
+        Ok(())
+    }
+
+    /// Runs the Gremlin script `script` on DSE Graph. See the `graph`
+    /// module.
+    pub fn execute_graph(&mut self, script: &str, params: &[&ToCQL], options: &GraphOptions) -> Result<QueryResult> {
+        self.query_statement(&options.statement(script), params)
+    }
+
+    /// Runs a CREATE, ALTER or DROP statement, then waits until every node
+    /// that's up agrees on the new schema, so that statements using it
+    /// don't fail on nodes that haven't heard of it yet. Returns what was
+    /// changed, or `None` if nothing was, as with IF NOT EXISTS. Nodes
+    /// still disagreeing after the wait is a warning, not an error.
+    pub fn alter_schema(&mut self, statement: &str) -> Result<Option<SchemaChange>> {
+        let statement = Statement::new(statement);
+        let result = try!(self.request(statement.query(), RequestOptions::of(&statement), |client, downgraded| {
+            client.run_statement(&statement, &[], downgraded)
+        }));
+        let timeout = Duration::from_secs(ddl::SCHEMA_AGREEMENT_TIMEOUT_SECS);
+        if !try!(ddl::wait_for_agreement(self, timeout)) {
+            self.warn(Warning::SchemaDisagreement { query: statement.query().to_string(), timeout: timeout });
+        }
+        Ok(result.schema_change().cloned())
+    }
+
+    fn run_statement(&mut self, statement: &Statement, params: &[&ToCQL],
+                     downgraded: Option<Consistency>) -> Result<NonRowResult> {
+        try!(self.check_statement_length(statement.query()));
+        let mut parameters = statement.parameters(params);
+        parameters.consistency = downgraded.unwrap_or(parameters.consistency);
+        try!(self.send(QueryRequest::new(statement.query(), parameters)));
+        let frame = try!(self.recv());
+        // There's no result to hand the warnings back on, so don't let them
+        // go unseen.
+        for warning in &frame.warnings {
+            self.warn(Warning::Server { query: statement.query().to_string(), message: warning.clone() });
+        }
+        let result = try!(self.decode_body::<NonRowResult>(frame));
+        self.track_keyspace(result.keyspace());
+        Ok(result)
+    }
+
+    /// Whether to keep an HDR histogram of request latencies. Changing it
+    /// drops the latencies recorded so far.
+    pub fn set_latency_histogram(&mut self, histogram: LatencyHistogram) {
+        self.stats.latency = match histogram {
+            LatencyHistogram::Buckets => None,
+            LatencyHistogram::Hdr { significant_figures } => Some(Histogram::new(significant_figures)),
+        };
+    }
+
+    /// The latencies recorded since the last call, leaving an empty
+    /// histogram in their place. `None` unless an HDR histogram is kept.
+    pub fn take_latency_histogram(&mut self) -> Option<Histogram> {
+        self.stats.latency.as_mut().map(|latency| {
+            let taken = latency.clone();
+            latency.reset();
+            taken
+        })
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Answers an AUTHENTICATE, then each AUTH_CHALLENGE, until the server
+    /// sends AUTH_SUCCESS.
+    fn authenticate(&mut self, frame: Frame) -> Result<()> {
+        let name: String = try!(self.decode_body(frame));
+        let host = try!(self.conn.tcp().peer_addr());
+        let mut authenticator = match self.auth {
+            Some(ref provider) => try!(provider.new_authenticator(&name, host)),
+            None => return Err(MyError::Protocol(format!("Server requires authentication with {} but no credentials were set",
+                                                         name))),
+        };
+        let mut response = try!(authenticator.initial_response());
+        loop {
+            try!(self.send(AuthResponseRequest::new(&response)));
+            let reply = try!(self.recv());
+            match reply.header.opcode {
+                Opcode::AuthChallenge => {
+                    let challenge: Option<Vec<u8>> = try!(self.decode_body(reply));
+                    response = try!(authenticator.evaluate_challenge(challenge.as_ref().map(|c| &c[..])));
+                },
+                Opcode::AuthSuccess => {
+                    let token: Option<Vec<u8>> = try!(self.decode_body(reply));
+                    return authenticator.on_success(token.as_ref().map(|t| &t[..]));
+                },
+                _ => return Err(MyError::Protocol(format!("Expected AuthChallenge or AuthSuccess opcode, got {:?}",
+                                                          reply.header.opcode))),
+            }
+        }
     }
 
     fn get_options(&mut self) -> Result<HashMap<String, Vec<String>>> {
-        let req = OptionsRequest::new();
-        try!(req.encode(&mut self.conn));
+        try!(self.send(OptionsRequest::new()));
+        let frame = try!(self.recv());
+        self.decode_body(frame)
+    }
+
+    /// Applies the read timeout and socket options to a new connection,
+    /// and starts TLS on it if configured.
+    fn set_up(&self, conn: TcpStream) -> Result<Connection> {
+        try!(self.closer.track(&conn));
+        try!(conn.set_read_timeout(self.request_timeout));
+        try!(self.socket_options.apply(&conn));
+        #[cfg(feature = "tls")]
+        {
+            if let Some(ref tls) = self.tls {
+                return tls.connect(conn);
+            }
+        }
+        #[cfg(feature = "native-tls")]
+        {
+            if let Some(ref tls) = self.native_tls {
+                return tls.connect(conn);
+            }
+        }
+        Ok(Connection::Plain(conn))
+    }
+
+    /// Servers close the connection after rejecting a version, so this
+    /// opens a new one to the same host, with nothing negotiated yet.
+    fn downgrade(&mut self, peer: SocketAddr, version: ProtocolVersion) -> Result<()> {
+        let conn = try!(connect_to(peer, self.connect_timeout));
+        self.conn = try!(self.set_up(conn));
+        self.version = version;
+        self.compression = Compression::None;
+        self.framing = false;
+        self.received.clear();
+        self.received_wire = 0;
+        Ok(())
+    }
+
+    /// Runs `f`, sending it again for as long as the retry policy says to
+    /// and the deadline hasn't passed. `f` is given the consistency to send
+    /// at instead of the request's own once the policy lowered it. Each
+    /// attempt first moves off the connected host if it has been marked
+    /// down, and an IO error marks it down. The error returned carries the
+    /// statement, the last host, the number of attempts and the time taken.
+    fn request<T, F>(&mut self, statement: &str, options: RequestOptions, mut f: F) -> Result<T>
+        where F: FnMut(&mut Client, Option<Consistency>) -> Result<T>
+    {
+        let start = Instant::now();
+        let policy = options.retry_policy.unwrap_or_else(|| self.retry_policy.clone());
+        let tables: Vec<String> = options.tables.iter().map(|table| match self.keyspace {
+            Some(ref keyspace) if !table.contains('.') => format!("{}.{}", keyspace, table),
+            _ => table.clone(),
+        }).collect();
+        let hash = statement::statement_hash(statement);
+        let mut retries = 0;
+        let mut downgraded = None;
+        loop {
+            if self.closer.is_closed() {
+                return Err(MyError::Closed);
+            }
+            if let Some(deadline) = options.deadline {
+                if Instant::now() >= deadline {
+                    let context = ErrorContext::new(statement, self.conn.tcp().peer_addr().ok(), retries,
+                                                    start.elapsed());
+                    let err = MyError::Timeout("request deadline passed".to_string());
+                    return Err(MyError::Request(context, Box::new(err)));
+                }
+            }
+            let attempt_start = Instant::now();
+            if self.quarantined {
+                let peer = self.conn.tcp().peer_addr().ok();
+                try!(self.reconnect_elsewhere(peer));
+            }
+            try!(self.avoid_down_host());
+            let peer = self.conn.tcp().peer_addr().ok();
+            let result = match self.limit_to(options.deadline) {
+                Ok(()) => f(self, downgraded),
+                Err(err) => Err(err),
+            };
+            if options.deadline.is_some() {
+                let _ = self.conn.tcp().set_read_timeout(self.request_timeout);
+                let _ = self.conn.tcp().set_write_timeout(None);
+            }
+            let elapsed = attempt_start.elapsed();
+            self.stats.record_request(elapsed, result.as_ref().err());
+            for table in &tables {
+                self.stats.record_table_request(table, elapsed, result.is_err());
+            }
+            for &(ref key, ref value) in &options.tags {
+                self.stats.record_tagged_request(key, value, elapsed, result.is_err());
+            }
+            self.stats.record_statement_request(hash, elapsed, result.is_err());
+            let err = match result {
+                Ok(value) => return Ok(value),
+                Err(_) if self.closer.is_closed() => return Err(MyError::Closed),
+                Err(err) => err,
+            };
+            if let (&MyError::IO(_), Some(peer)) = (err.root(), peer) {
+                self.host_states.mark_down(peer);
+            }
+            let decision = match options.deadline {
+                Some(deadline) if Instant::now() >= deadline => RetryDecision::Rethrow,
+                // Whatever the policy says, a statement that mustn't be
+                // applied twice is only sent again if it can't have been
+                // applied the first time.
+                _ if !options.idempotent && !err.is_idempotent_safe_retry() => RetryDecision::Rethrow,
+                _ => retry::decide(&*policy, &err, options.idempotent, retries),
+            };
+            let retrying = match decision {
+                RetryDecision::Retry => true,
+                RetryDecision::RetryAt(consistency) => {
+                    downgraded = Some(consistency);
+                    true
+                },
+                RetryDecision::RetryNextHost => match self.reconnect_elsewhere(peer) {
+                    Ok(()) => true,
+                    Err(reconnect_err) => {
+                        self.warn(Warning::RetryReconnectFailed { error: reconnect_err.to_string() });
+                        false
+                    },
+                },
+                RetryDecision::Rethrow => false,
+            };
+            if !retrying {
+                let context = ErrorContext::new(statement, peer, retries + 1, start.elapsed());
+                return Err(MyError::Request(context, Box::new(err)));
+            }
+            retries += 1;
+            self.stats.record_retry();
+        }
+    }
+
+    /// Makes the socket's reads and writes time out at `deadline`, or after
+    /// the request timeout if that's sooner, for one attempt.
+    fn limit_to(&self, deadline: Option<Instant>) -> Result<()> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(MyError::Timeout("request deadline passed".to_string()));
+        }
+        let remaining = deadline - now;
+        let read_timeout = match self.request_timeout {
+            Some(timeout) if timeout < remaining => timeout,
+            _ => remaining,
+        };
+        try!(self.conn.tcp().set_read_timeout(Some(read_timeout)));
+        try!(self.conn.tcp().set_write_timeout(Some(remaining)));
+        Ok(())
+    }
+
+    /// Reconnects, trying hosts other than `peer` first.
+    fn reconnect_elsewhere(&mut self, peer: Option<SocketAddr>) -> Result<()> {
+        if let Some(position) = self.addrs.iter().position(|addr| Some(*addr) == peer) {
+            let addr = self.addrs.remove(position);
+            self.addrs.push(addr);
+        }
+        self.reconnect()
+    }
+
+    /// Whether every host this client knows of is marked down, as after a
+    /// reconnect that reached none of them.
+    pub fn all_hosts_down(&self) -> bool {
+        !self.addrs.iter().any(|addr| self.host_states.is_up(addr))
+    }
+
+    /// Reconnects if the connected host is marked down and some other host
+    /// isn't, keeping the keyspace.
+    fn avoid_down_host(&mut self) -> Result<()> {
+        let peer = match self.conn.tcp().peer_addr() {
+            Ok(peer) => peer,
+            Err(_) => return Ok(()),
+        };
+        if self.host_states.is_up(&peer) || self.all_hosts_down() {
+            return Ok(());
+        }
+        self.reconnect_elsewhere(Some(peer))
+    }
+
+    fn prepare_cached(&mut self, query: &str) -> Result<PreparedStatement> {
+        let key = (self.keyspace.clone(), statement::statement_hash(query));
+        if let Some(prepared) = self.prepared.get(&key) {
+            if prepared.query() == query {
+                self.stats.record_prepared_lookup(true);
+                return Ok(prepared.clone());
+            }
+        }
+        self.stats.record_prepared_lookup(false);
+        try!(self.check_statement_length(query));
+        try!(self.send(PrepareRequest::new(query)));
+        let frame = try!(self.recv());
+        let result = PreparedStatement::decode(&mut Cursor::new(frame.body), query, self.keyspace(), self.version);
+        let prepared = try!(self.check_decode(result));
+        self.prepared.insert(key, prepared.clone());
+        Ok(prepared)
+    }
+
+    /// Whether `prepared` is what the cache holds for its query in the
+    /// current keyspace.
+    fn is_current(&self, prepared: &PreparedStatement) -> bool {
+        let key = (self.keyspace.clone(), statement::statement_hash(prepared.query()));
+        self.prepared.get(&key).map_or(false, |cached| cached.id() == prepared.id())
+    }
+
+    fn track_keyspace(&mut self, keyspace: Option<&str>) {
+        if let Some(keyspace) = keyspace {
+            self.keyspace = Some(keyspace.to_string());
+        }
+    }
+
+    fn check_statement_length(&self, query: &str) -> Result<()> {
+        if query.len() > self.max_statement_length {
+            return Err(MyError::Protocol(format!("Statement is {} bytes, longer than the limit of {}",
+                                                 query.len(), self.max_statement_length)));
+        }
+        Ok(())
+    }
+
+    fn send<R: Request>(&mut self, mut req: R) -> Result<()> {
+        if self.closer.is_closed() {
+            return Err(MyError::Closed);
+        }
+        if self.quarantined {
+            return Err(MyError::Protocol("Connection is unusable after a request timed out or failed to send".to_string()));
+        }
+        req.set_version(self.version);
+        let result = if self.framing {
+            self.send_framed(&req)
+        } else if self.compression != Compression::None {
+            self.send_compressed(&req)
+        } else {
+            self.send_plain(&req)
+        };
+        // Part of the frame may have gone out, and the server would read
+        // the next one from the middle of it.
+        if let Err(MyError::IO(_)) = result {
+            self.quarantined = true;
+        }
+        result
+    }
+
+    fn send_plain<R: Request>(&mut self, req: &R) -> Result<()> {
+        let mut writer = BufWriter::new(CountingWriter::new(&mut self.conn));
+        try!(req.encode(&mut writer));
+        try!(writer.flush());
+        let sent = writer.get_ref().count();
+        self.stats.record_sent(req.opcode(), sent);
+        Ok(())
+    }
+
+    /// Encodes the whole frame up front so its body can be compressed and
+    /// the header rewritten with the compressed length.
+    fn send_compressed<R: Request>(&mut self, req: &R) -> Result<()> {
+        let mut frame = Vec::new();
+        try!(req.encode(&mut frame));
+        let body = try!(self.compression.compress(&frame[HEADER_LENGTH..]));
+        let uncompressed_len = frame.len() - HEADER_LENGTH;
+        let body_len = body.len();
+        let mut header = try!(Header::decode(&mut Cursor::new(&frame[..HEADER_LENGTH])));
+        header.flags.compression = true;
+        header.length = body.len() as u32;
+        frame.clear();
+        try!(header.encode(&mut frame));
+        frame.extend(body);
+        try!(self.conn.write_all(&frame));
+        self.stats.record_sent(req.opcode(), frame.len());
+        self.stats.record_compressed_sent(uncompressed_len, body_len);
+        Ok(())
+    }
+
+    /// Wraps the encoded frame in the v5 framing layer, which also takes
+    /// care of compression.
+    fn send_framed<R: Request>(&mut self, req: &R) -> Result<()> {
+        let mut envelope = Vec::new();
+        try!(req.encode(&mut envelope));
+        let mut frames = Vec::with_capacity(envelope.len() + 16);
+        let payload_len = try!(framing::encode(&envelope, self.compression, &mut frames));
+        try!(self.conn.write_all(&frames));
+        self.stats.record_sent(req.opcode(), frames.len());
+        if self.compression != Compression::None {
+            self.stats.record_compressed_sent(envelope.len(), payload_len);
+        }
+        Ok(())
+    }
+
+    /// Reads framing layer frames until a whole protocol frame has
+    /// arrived. A frame may hold several protocol frames, or only part of
+    /// one.
+    fn recv_framed(&mut self) -> Result<Frame> {
+        loop {
+            if self.received.len() >= HEADER_LENGTH {
+                let length = BigEndian::read_u32(&self.received[5..HEADER_LENGTH]) as usize;
+                if self.received.len() >= HEADER_LENGTH + length {
+                    let rest = self.received.split_off(HEADER_LENGTH + length);
+                    let envelope = mem::replace(&mut self.received, rest);
+                    return Frame::decode(&mut Cursor::new(envelope));
+                }
+            }
+            let segment = try!(framing::decode(self.compression, &mut self.conn));
+            if segment.self_contained && !self.received.is_empty() {
+                return Err(MyError::Protocol("Self-contained frame arrived in the middle of another frame".to_string()));
+            }
+            self.received_wire += segment.wire_len;
+            if self.compression != Compression::None {
+                self.stats.record_compressed_received(segment.payload.len(), segment.wire_payload_len);
+            }
+            self.received.extend(segment.payload);
+        }
+    }
+
+    /// Reads the response to the last request. Events the server pushed in
+    /// the meantime are queued rather than mistaken for the response.
+    fn recv(&mut self) -> Result<Frame> {
+        loop {
+            let frame = try!(self.recv_frame());
+            if frame.header.opcode != Opcode::Event {
+                return frame.into_response();
+            }
+            match self.decode_body::<Event>(frame) {
+                Ok(event) => {
+                    self.update_hosts(&event);
+                    if let Event::SchemaChange(ref change) = event {
+                        self.invalidate_prepared(change);
+                    }
+                    if self.events.len() >= MAX_QUEUED_EVENTS {
+                        self.events.pop_front();
+                    }
+                    self.events.push_back(event);
+                },
+                Err(err) => self.warn(Warning::EventDropped { error: err.to_string() }),
+            }
+        }
+    }
+
+    /// Adds nodes that joined the cluster to the host list and drops ones
+    /// that left, though never the last one. Status changes mark hosts up
+    /// or down.
+    fn update_hosts(&mut self, event: &Event) {
+        match *event {
+            Event::TopologyChange(TopologyChange::NewNode, addr) => {
+                if !self.addrs.contains(&addr) {
+                    self.addrs.push(addr);
+                }
+            },
+            Event::TopologyChange(TopologyChange::RemovedNode, addr) => {
+                if self.addrs.len() > 1 {
+                    self.addrs.retain(|a| *a != addr);
+                    self.host_states.remove(&addr);
+                }
+            },
+            Event::StatusChange(StatusChange::Up, addr) => self.host_states.mark_up(addr),
+            Event::StatusChange(StatusChange::Down, addr) => self.host_states.mark_down(addr),
+            _ => {},
+        }
+    }
+
+    /// Drops cached prepared statements that refer to whatever `change`
+    /// altered, so they are prepared again with fresh result metadata
+    /// instead of decoding rows with a stale column layout.
+    fn invalidate_prepared(&mut self, change: &SchemaChange) {
+        self.prepared.retain(|&(ref keyspace, _), cached| {
+            let query = cached.query();
+            let in_keyspace = |ks: &str| keyspace.as_ref().map_or(false, |k| k == ks) || mentions(query, ks);
+            let stale = match change.target {
+                SchemaTarget::Keyspace(ref ks) => in_keyspace(ks),
+                SchemaTarget::Table(ref ks, ref name) | SchemaTarget::Type(ref ks, ref name) => {
+                    mentions(query, name) && in_keyspace(ks)
+                },
+                SchemaTarget::Function(_, ref name, _) | SchemaTarget::Aggregate(_, ref name, _) => mentions(query, name),
+            };
+            !stale
+        });
+    }
+
+    /// Blocks until the server pushes an event. Only used on connections
+    /// that send no requests, so any other frame is unexpected.
+    fn next_event(&mut self) -> Result<Event> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let frame = try!(self.recv_frame());
+            if frame.header.opcode != Opcode::Event {
+                return Err(MyError::Protocol(format!("Expected Event opcode, got {:?}", frame.header.opcode)));
+            }
+            match self.decode_body(frame) {
+                Ok(event) => return Ok(event),
+                Err(err) => self.warn(Warning::EventDropped { error: err.to_string() }),
+            }
+        }
+    }
+
+    fn recv_frame(&mut self) -> Result<Frame> {
+        let frame = if self.framing { self.recv_framed() } else { Frame::decode(&mut self.conn) };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(_) if self.closer.is_closed() => return Err(MyError::Closed),
+            Err(MyError::IO(ref err)) if err.kind() == io::ErrorKind::WouldBlock ||
+                                         err.kind() == io::ErrorKind::TimedOut => {
+                self.quarantined = true;
+                let peer = self.conn.tcp().peer_addr().map(|peer| peer.to_string()).unwrap_or_else(|_| "server".to_string());
+                let timeout = self.conn.tcp().read_timeout().ok().and_then(|timeout| timeout).unwrap_or_default();
+                return Err(MyError::Timeout(format!("no response from {} within {:?}", peer, timeout)));
+            },
+            Err(err) => {
+                if let MyError::Protocol(_) = err {
+                    self.stats.record_decode_error();
+                }
+                return Err(err);
+            }
+        };
+        // Without the framing layer the body is still compressed here, so
+        // its size is what was read off the wire.
+        let wire_len = if self.framing { mem::replace(&mut self.received_wire, 0) } else { HEADER_LENGTH + frame.body.len() };
+        self.stats.record_received(frame.header.opcode, wire_len);
+        let frame = if frame.header.flags.compression {
+            let body = self.compression.decompress(&frame.body);
+            let body = try!(self.check_decode(body));
+            self.stats.record_compressed_received(body.len(), frame.body.len());
+            let mut header = frame.header;
+            header.flags.compression = false;
+            header.length = body.len() as u32;
+            Frame { header: header, body: body, tracing_id: None, warnings: Vec::new(), custom_payload: None }
+        } else {
+            // The server may leave bodies compression wouldn't shrink as
+            // they are.
+            if self.compression != Compression::None && !self.framing {
+                self.stats.record_compressed_received(frame.body.len(), frame.body.len());
+            }
+            frame
+        };
+        self.check_decode(frame.split_prefix())
+    }
+
+    fn decode_body<R: FromWire>(&mut self, frame: Frame) -> Result<R> {
+        let result = R::decode(&mut Cursor::new(frame.body));
+        self.check_decode(result)
+    }
+
+    fn check_decode<R>(&mut self, result: Result<R>) -> Result<R> {
+        if result.is_err() {
+            self.stats.record_decode_error();
+        }
+        result
+    }
+}
+
+/// The events of a connection opened by `Client::subscribe_events`, read
+/// like the `Receiver` it derefs to. Closing or dropping it closes the
+/// connection and waits for its thread to finish.
+pub struct EventSubscription {
+    events: Receiver<Event>,
+    closer: Arc<Closer>,
+    handle: Option<thread::JoinHandle<()>>,
+}
 
-        let header = try!(Header::decode(&mut self.conn));
-        let mut bytes = vec![0; header.length as usize];
-        try!(self.conn.read_exact(&mut bytes));
-        let mut body = Cursor::new(bytes);
-        StringMultiMap::decode(&mut body)
+impl EventSubscription {
+    /// Closes the event connection from another thread, even while its
+    /// thread is waiting for an event.
+    pub fn closer(&self) -> Arc<Closer> {
+        self.closer.clone()
     }
+
+    /// The same as dropping it.
+    pub fn close(self) {}
+}
+
+impl Deref for EventSubscription {
+    type Target = Receiver<Event>;
+
+    fn deref(&self) -> &Receiver<Event> {
+        &self.events
+    }
+}
+
+impl Drop for EventSubscription {
+    fn drop(&mut self) {
+        self.closer.close();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// How `Client::request` may retry a request, and which tables and tags
+/// to record it for.
+#[derive(Default)]
+struct RequestOptions {
+    idempotent: bool,
+    deadline: Option<Instant>,
+    /// Instead of the client's own.
+    retry_policy: Option<Arc<RetryPolicy>>,
+    tables: Vec<String>,
+    tags: Vec<(String, String)>,
+}
+
+impl RequestOptions {
+    fn of(statement: &Statement) -> RequestOptions {
+        RequestOptions {
+            idempotent: statement.idempotent(),
+            deadline: statement.deadline(),
+            retry_policy: statement.retry_policy().cloned(),
+            tables: durability::accessed_tables(statement.query()),
+            tags: statement.tags().to_vec(),
+        }
+    }
+}
+
+/// Connects to the first host in `addrs` that accepts, trying hosts marked
+/// down last, and records which attempts failed.
+fn connect_any(addrs: &[SocketAddr], host_states: &mut HostStates, timeout: Option<Duration>) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in host_states.connect_order(addrs) {
+        match connect_to(addr, timeout) {
+            Ok(conn) => {
+                host_states.mark_up(addr);
+                return Ok(conn);
+            },
+            Err(err) => {
+                host_states.mark_down(addr);
+                last_err = Some(err);
+            },
+        }
+    }
+    match last_err {
+        Some(err) => Err(err),
+        None => Err(MyError::Protocol("No addresses to connect to".to_string())),
+    }
+}
+
+fn connect_to(addr: SocketAddr, timeout: Option<Duration>) -> Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(try!(TcpStream::connect(addr))),
+    };
+    TcpStream::connect_timeout(&addr, timeout).map_err(|err| match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            MyError::Timeout(format!("couldn't connect to {} within {:?}", addr, timeout))
+        },
+        _ => MyError::IO(err),
+    })
+}
+
+/// Whether `name` appears in `query` as a whole identifier, ignoring case
+/// and quotes.
+fn mentions(query: &str, name: &str) -> bool {
+    query.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|word| word.to_lowercase() == name.to_lowercase())
 }
@@ -0,0 +1,186 @@
+//! Sharing one request between identical reads that are in flight at the
+//! same time. When many callers miss an application cache at once and
+//! all ask for the same row, only the first read goes to the cluster; the
+//! rest wait for it and get a copy of its result.
+//!
+//! Reads are identical when their query text, bound values, consistency
+//! and page size are. Only SELECTs are coalesced, and only those without
+//! per-request options that change what comes back, such as a paging
+//! state, tracing or a projection. A read arriving just after the shared
+//! one finished is sent on its own, so results are never older than the
+//! request they came from.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use errors::MyError;
+use protocol::{Consistency, PreparedStatement, QueryResult, Result};
+use statement::{statement_hash, Statement};
+use types::ToCQL;
+
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    in_flight: Mutex<HashMap<Key, Arc<Shared>>>,
+    coalesced: AtomicUsize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    query: u64,
+    values: Vec<Vec<u8>>,
+    consistency: Consistency,
+    page_size: Option<i32>,
+}
+
+/// The result of a request in flight, once it's known.
+#[derive(Debug, Default)]
+struct Shared {
+    result: Mutex<Option<Result<QueryResult>>>,
+    done: Condvar,
+}
+
+impl Coalescer {
+    pub fn new() -> Coalescer {
+        Coalescer::default()
+    }
+
+    /// Runs `request` unless an identical read is already in flight, in
+    /// which case this waits for that one, until `deadline` at the latest,
+    /// and returns a copy of its result or error.
+    pub fn run<F>(&self, query: &str, params: &[&ToCQL], consistency: Consistency, page_size: Option<i32>,
+                  deadline: Option<Instant>, request: F) -> Result<QueryResult>
+        where F: FnOnce() -> Result<QueryResult>
+    {
+        let mut values = Vec::with_capacity(params.len());
+        for param in params {
+            let mut value = Vec::new();
+            try!(param.serialize(&mut value));
+            values.push(value);
+        }
+        let key = Key {
+            query: statement_hash(query),
+            values: values,
+            consistency: consistency,
+            page_size: page_size,
+        };
+        let shared = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(shared) = in_flight.get(&key) {
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+                Some(shared.clone())
+            } else {
+                in_flight.insert(key.clone(), Arc::new(Shared::default()));
+                None
+            }
+        };
+        match shared {
+            Some(shared) => shared.wait(deadline),
+            None => {
+                let mut leader = Leader { coalescer: self, key: Some(key) };
+                let result = request();
+                leader.finish(&result);
+                result
+            },
+        }
+    }
+
+    /// Reads answered with the result of another one instead of being
+    /// sent.
+    pub fn coalesced(&self) -> usize {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    /// Distinct reads in flight right now.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+/// Whether `statement` may share its result with identical ones.
+pub fn coalescable(statement: &Statement) -> bool {
+    is_select(statement.query()) && statement.paging_state().is_none() && !statement.tracing() &&
+        statement.projection().is_none() && statement.execute_as().is_none() && statement.keyspace().is_none() &&
+        statement.now_in_seconds().is_none()
+}
+
+/// Whether executions of `prepared` may share their results.
+pub fn coalescable_prepared(prepared: &PreparedStatement) -> bool {
+    is_select(prepared.query())
+}
+
+fn is_select(query: &str) -> bool {
+    query.split_whitespace().next().map_or(false, |word| word.eq_ignore_ascii_case("select"))
+}
+
+impl Shared {
+    fn wait(&self, deadline: Option<Instant>) -> Result<QueryResult> {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            if let Some(ref result) = *result {
+                return share(result);
+            }
+            result = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(MyError::Timeout("request deadline passed waiting for an identical read".to_string()));
+                    }
+                    self.done.wait_timeout(result, deadline - now).unwrap().0
+                },
+                None => self.done.wait(result).unwrap(),
+            };
+        }
+    }
+}
+
+/// Hands the result to the waiting readers, even if the request panicked.
+struct Leader<'a> {
+    coalescer: &'a Coalescer,
+    key: Option<Key>,
+}
+
+impl<'a> Leader<'a> {
+    fn finish(&mut self, result: &Result<QueryResult>) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        let shared = self.coalescer.in_flight.lock().unwrap().remove(&key);
+        // Once it's out of the map, only readers already waiting hold it.
+        if let Some(shared) = shared {
+            if Arc::strong_count(&shared) > 1 {
+                *shared.result.lock().unwrap() = Some(share(result));
+                shared.done.notify_all();
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Leader<'a> {
+    fn drop(&mut self) {
+        self.finish(&Err(MyError::Protocol("The shared read failed without a result".to_string())));
+    }
+}
+
+/// A copy of `result` for another reader. IO errors keep their kind and
+/// message, but not their OS error.
+fn share(result: &Result<QueryResult>) -> Result<QueryResult> {
+    match *result {
+        Ok(ref result) => Ok(result.clone()),
+        Err(ref err) => Err(share_error(err)),
+    }
+}
+
+fn share_error(err: &MyError) -> MyError {
+    match *err {
+        MyError::IO(ref err) => MyError::IO(io::Error::new(err.kind(), err.to_string())),
+        MyError::Protocol(ref message) => MyError::Protocol(message.clone()),
+        MyError::Server(code, ref message, ref details) => MyError::Server(code, message.clone(), details.clone()),
+        MyError::Timeout(ref message) => MyError::Timeout(message.clone()),
+        MyError::Closed => MyError::Closed,
+        MyError::Request(ref context, ref cause) => MyError::Request(context.clone(), Box::new(share_error(cause))),
+    }
+}
@@ -0,0 +1,759 @@
+//! Generates Rust structs and typed query functions from table schemas, so
+//! applications can get compile-time checked access to their tables. Meant
+//! to be called from a build script, writing into `OUT_DIR` for the crate
+//! to `include!`. The generated code refers to `::cassandra` and `::uuid`,
+//! so the including crate needs both.
+
+use std::fmt::{self, Write};
+
+use client::Client;
+use errors::MyError;
+use protocol::Result;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnSchema {
+    pub name: String,
+    /// The CQL type as written in the schema, such as `set<text>`.
+    pub cql_type: String,
+    pub primary_key: bool,
+    /// Part of the partition key, not just a clustering column.
+    pub partition_key: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSchema {
+    pub keyspace: Option<String>,
+    pub name: String,
+    pub columns: Vec<ColumnSchema>,
+}
+
+impl TableSchema {
+    /// Reads every CREATE TABLE statement in a CQL file. Other statements
+    /// are skipped.
+    pub fn parse_cql(cql: &str) -> Result<Vec<TableSchema>> {
+        let mut tables = Vec::new();
+        for statement in cql.split(';') {
+            let statement = statement.trim();
+            let lower = statement.to_lowercase();
+            if !lower.starts_with("create table") {
+                continue;
+            }
+            tables.push(try!(parse_create_table(statement)));
+        }
+        Ok(tables)
+    }
+
+    /// Reads the tables of `keyspace` from a Cassandra 3 cluster's
+    /// `system_schema` tables.
+    pub fn fetch(client: &mut Client, keyspace: &str) -> Result<Vec<TableSchema>> {
+        let result = try!(client.query("SELECT table_name, column_name, kind, position, type \
+                                        FROM system_schema.columns WHERE keyspace_name = ?", &[&keyspace]));
+        let mut rows = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            let table: String = try!(required(row.get("table_name")));
+            let column: String = try!(required(row.get("column_name")));
+            let kind: String = try!(required(row.get("kind")));
+            let position: i32 = try!(required(row.get("position")));
+            let cql_type: String = try!(required(row.get("type")));
+            rows.push((table, kind_order(&kind), position, column, cql_type));
+        }
+        // Key columns first, in key order, then the rest by name, which is
+        // how Cassandra lays tables out.
+        rows.sort();
+        let mut tables: Vec<TableSchema> = Vec::new();
+        for (table, kind, _, column, cql_type) in rows {
+            if tables.last().map_or(true, |t| t.name != table) {
+                tables.push(TableSchema {
+                    keyspace: Some(keyspace.to_string()),
+                    name: table,
+                    columns: Vec::new(),
+                });
+            }
+            tables.last_mut().unwrap().columns.push(ColumnSchema {
+                name: column,
+                cql_type: cql_type,
+                primary_key: kind < 2,
+                partition_key: kind == 0,
+            });
+        }
+        Ok(tables)
+    }
+
+    fn qualified_name(&self) -> String {
+        match self.keyspace {
+            Some(ref keyspace) => format!("{}.{}", quote_identifier(keyspace), quote_identifier(&self.name)),
+            None => quote_identifier(&self.name),
+        }
+    }
+}
+
+/// Generates a struct per table, with `from_row`, `select_all`,
+/// `select_by_key` and `insert`. Key columns are plain fields and the rest
+/// are `Option`s, since any regular column may be null.
+pub fn generate(tables: &[TableSchema]) -> Result<String> {
+    let mut out = String::new();
+    out.push_str("// Generated by cassandra::codegen. Do not edit.\n");
+    for table in tables {
+        let mut fields = Vec::with_capacity(table.columns.len());
+        for column in &table.columns {
+            let rust_type = try!(rust_type(&column.cql_type).map_err(|err| {
+                MyError::Protocol(format!("Column {} of {}: {}", column.name, table.name, err))
+            }));
+            fields.push(Field {
+                name: field_name(&column.name),
+                column: quote_identifier(&column.name),
+                raw_column: column.name.clone(),
+                rust_type: rust_type,
+                key: column.primary_key,
+            });
+        }
+        try!(write_table(&mut out, table, &fields).map_err(|err| MyError::Protocol(format!("{}", err))));
+    }
+    Ok(out)
+}
+
+struct Field {
+    name: String,
+    /// The column name as written in queries.
+    column: String,
+    /// The column name as it appears in result metadata.
+    raw_column: String,
+    rust_type: String,
+    key: bool,
+}
+
+fn write_table(out: &mut String, table: &TableSchema, fields: &[Field]) -> fmt::Result {
+    let struct_name = camel_case(&table.name);
+    let columns: Vec<&str> = fields.iter().map(|f| f.column.as_ref()).collect();
+    let keys: Vec<&Field> = fields.iter().filter(|f| f.key).collect();
+
+    try!(writeln!(out, "\n#[derive(Debug, Clone, PartialEq)]"));
+    try!(writeln!(out, "pub struct {} {{", struct_name));
+    for field in fields {
+        if field.key {
+            try!(writeln!(out, "    pub {}: {},", field.name, field.rust_type));
+        } else {
+            try!(writeln!(out, "    pub {}: Option<{}>,", field.name, field.rust_type));
+        }
+    }
+    try!(writeln!(out, "}}\n"));
+
+    try!(writeln!(out, "impl {} {{", struct_name));
+    try!(writeln!(out, "    pub fn from_row(row: &::cassandra::protocol::Row) -> ::cassandra::protocol::Result<{}> {{", struct_name));
+    try!(writeln!(out, "        Ok({} {{", struct_name));
+    for field in fields {
+        if field.key {
+            try!(writeln!(out, "            {}: match try!(row.get({:?})) {{", field.name, field.raw_column));
+            try!(writeln!(out, "                Some(value) => value,"));
+            try!(writeln!(out, "                None => return Err(::cassandra::errors::MyError::Protocol({:?}.to_string())),",
+                          format!("key column {} is null", field.raw_column)));
+            try!(writeln!(out, "            }},"));
+        } else {
+            try!(writeln!(out, "            {}: try!(row.get({:?})),", field.name, field.raw_column));
+        }
+    }
+    try!(writeln!(out, "        }})"));
+    try!(writeln!(out, "    }}\n"));
+
+    let select = format!("SELECT {} FROM {}", columns.join(", "), table.qualified_name());
+    try!(writeln!(out, "    pub fn select_all(session: &::cassandra::session::Session) -> ::cassandra::protocol::Result<Vec<{}>> {{", struct_name));
+    try!(writeln!(out, "        let result = try!(session.query({:?}, &[]));", select));
+    try!(writeln!(out, "        result.rows.iter().map({}::from_row).collect()", struct_name));
+    try!(writeln!(out, "    }}\n"));
+
+    if !keys.is_empty() {
+        let args: Vec<String> = keys.iter().map(|k| format!("{}: &{}", k.name, k.rust_type)).collect();
+        let restrictions: Vec<String> = keys.iter().map(|k| format!("{} = ?", k.column)).collect();
+        let values: Vec<&str> = keys.iter().map(|k| k.name.as_ref()).collect();
+        try!(writeln!(out, "    pub fn select_by_key(session: &::cassandra::session::Session, {}) -> ::cassandra::protocol::Result<Option<{}>> {{",
+                      args.join(", "), struct_name));
+        try!(writeln!(out, "        let result = try!(session.query({:?}, &[{}]));",
+                      format!("{} WHERE {}", select, restrictions.join(" AND ")), values.join(", ")));
+        try!(writeln!(out, "        match result.rows.first() {{"));
+        try!(writeln!(out, "            Some(row) => {}::from_row(row).map(Some),", struct_name));
+        try!(writeln!(out, "            None => Ok(None),"));
+        try!(writeln!(out, "        }}"));
+        try!(writeln!(out, "    }}\n"));
+    }
+
+    // Null values can't be bound yet, so only columns that are set are
+    // written.
+    try!(writeln!(out, "    pub fn insert(&self, session: &::cassandra::session::Session) -> ::cassandra::protocol::Result<()> {{"));
+    try!(writeln!(out, "        let mut columns: Vec<&str> = Vec::new();"));
+    try!(writeln!(out, "        let mut values: Vec<&::cassandra::types::ToCQL> = Vec::new();"));
+    for field in fields {
+        if field.key {
+            try!(writeln!(out, "        columns.push({:?});", field.column));
+            try!(writeln!(out, "        values.push(&self.{});", field.name));
+        } else {
+            try!(writeln!(out, "        if let Some(ref value) = self.{} {{", field.name));
+            try!(writeln!(out, "            columns.push({:?});", field.column));
+            try!(writeln!(out, "            values.push(value);"));
+            try!(writeln!(out, "        }}"));
+        }
+    }
+    try!(writeln!(out, "        let markers: Vec<&str> = columns.iter().map(|_| \"?\").collect();"));
+    try!(writeln!(out, "        let query = format!({:?}, columns.join(\", \"), markers.join(\", \"));",
+                  format!("INSERT INTO {} ({{}}) VALUES ({{}})", table.qualified_name())));
+    try!(writeln!(out, "        session.execute(&query, &values)"));
+    try!(writeln!(out, "    }}"));
+    writeln!(out, "}}")
+}
+
+/// Checks that the table `query` reads or writes is in `tables` and that
+/// every column it names belongs to that table, so a build script can
+/// reject queries that don't match a schema snapshot. The table is the one
+/// after FROM, INTO or UPDATE; any other identifier that isn't a keyword,
+/// function name, alias or named bind marker is taken to be a column.
+pub fn check_query(tables: &[TableSchema], query: &str) -> Result<()> {
+    let tokens = tokenize(query);
+    let (name, table, start, end) = match query_table(tables, &tokens) {
+        Some(found) => found,
+        None => return Err(MyError::Protocol(format!("No table in query: {}", query))),
+    };
+    let table = match table {
+        Some(table) => table,
+        None => return Err(MyError::Protocol(format!("Unknown table {} in query: {}", name, query))),
+    };
+    for (_, column) in columns(&tokens, start, end) {
+        if !table.columns.iter().any(|c| c.name == column) {
+            return Err(MyError::Protocol(format!("Table {} has no column {} in query: {}", table.name, column, query)));
+        }
+    }
+    Ok(())
+}
+
+/// Something about a query that works but is likely a mistake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lint {
+    UnknownTable(String),
+    UnknownColumn { table: String, column: String },
+    /// The WHERE clause doesn't restrict these partition key columns, so
+    /// the query has to scan every partition, and needs ALLOW FILTERING
+    /// unless an index covers it.
+    MissingPartitionKey { table: String, columns: Vec<String> },
+    /// A SELECT with neither a LIMIT nor the whole partition key, which can
+    /// return any number of rows.
+    UnboundedSelect { table: String },
+    /// A timestamp written without a time zone, which the coordinator reads
+    /// in its own, so the same query stores different instants depending
+    /// on the node it reaches.
+    NaiveTimestamp { table: String, column: String, literal: String },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Lint::UnknownTable(ref table) => write!(f, "unknown table {}", table),
+            Lint::UnknownColumn { ref table, ref column } => write!(f, "table {} has no column {}", table, column),
+            Lint::MissingPartitionKey { ref table, ref columns } => {
+                write!(f, "WHERE doesn't restrict partition key {} of table {}", columns.join(", "), table)
+            },
+            Lint::UnboundedSelect { ref table } => write!(f, "SELECT from {} has no LIMIT", table),
+            Lint::NaiveTimestamp { ref table, ref column, ref literal } => {
+                write!(f, "timestamp {} for {}.{} has no time zone", literal, table, column)
+            },
+        }
+    }
+}
+
+/// Checks `query` against `tables` the way `check_query` does, and also
+/// for the kinds of query that put load on the whole cluster. Unlike
+/// `check_query` it reports every problem rather than failing on the
+/// first, and queries on keyspaces none of `tables` are in are skipped,
+/// since there's nothing to check them against.
+pub fn lint_query(tables: &[TableSchema], query: &str) -> Vec<Lint> {
+    let tokens = tokenize(query);
+    let mut lints = Vec::new();
+    let (name, table, start, end) = match query_table(tables, &tokens) {
+        Some(found) => found,
+        None => return lints,
+    };
+    let table = match table {
+        Some(table) => table,
+        None => {
+            let keyspace = if end == start + 3 { Some(unquote(&tokens[start])) } else { None };
+            if keyspace.is_none() || tables.iter().any(|t| t.keyspace.is_none() || t.keyspace == keyspace) {
+                lints.push(Lint::UnknownTable(name));
+            }
+            return lints;
+        },
+    };
+    let named = columns(&tokens, start, end);
+    for &(_, ref column) in &named {
+        if !table.columns.iter().any(|c| c.name == *column) {
+            lints.push(Lint::UnknownColumn {
+                table: table.name.clone(),
+                column: column.clone(),
+            });
+        }
+    }
+
+    let select = tokens.first().map_or(false, |t| is_keyword(t, &["select"]));
+    let keyed = tokens.first().map_or(false, |t| is_keyword(t, &["select", "update", "delete"]));
+    let where_at = tokens.iter().position(|t| is_keyword(t, &["where"]));
+    // Columns compared with = or IN, up to whatever follows the WHERE
+    // clause. Ranges and functions such as token() don't pick a partition.
+    let mut restricted = Vec::new();
+    if let Some(where_at) = where_at {
+        let clause_end = tokens[where_at..].iter()
+            .position(|t| is_keyword(t, &["if", "group", "order", "limit", "per", "allow"]))
+            .map_or(tokens.len(), |position| where_at + position);
+        for &(i, ref column) in &named {
+            let compared = tokens.get(i + 1).map_or(false, |next| next == "=" || is_keyword(next, &["in"]));
+            if i > where_at && i < clause_end && compared {
+                restricted.push(column.clone());
+            }
+        }
+    }
+    let missing: Vec<String> = table.columns.iter()
+        .filter(|c| c.partition_key && !restricted.contains(&c.name))
+        .map(|c| c.name.clone())
+        .collect();
+    if keyed && where_at.is_some() && !missing.is_empty() {
+        lints.push(Lint::MissingPartitionKey {
+            table: table.name.clone(),
+            columns: missing.clone(),
+        });
+    }
+    // PER PARTITION LIMIT doesn't bound the number of partitions.
+    let limited = tokens.iter().enumerate()
+        .any(|(i, t)| is_keyword(t, &["limit"]) && (i == 0 || !is_keyword(&tokens[i - 1], &["partition"])));
+    if select && !limited && (where_at.is_none() || !missing.is_empty()) {
+        lints.push(Lint::UnboundedSelect { table: table.name.clone() });
+    }
+    for (column, literal) in column_literals(&tokens, &named) {
+        let timestamp = table.columns.iter().any(|c| c.name == column && c.cql_type.to_lowercase() == "timestamp");
+        if timestamp && naive_timestamp(&literal) {
+            lints.push(Lint::NaiveTimestamp {
+                table: table.name.clone(),
+                column: column,
+                literal: literal,
+            });
+        }
+    }
+    lints
+}
+
+/// The string literals given for columns in `tokens`: compared or set with
+/// `=`, `<` or `>`, listed after IN, or in the same place in an INSERT's
+/// VALUES as the column in its column list.
+fn column_literals(tokens: &[String], named: &[(usize, String)]) -> Vec<(String, String)> {
+    let mut literals = Vec::new();
+    for &(i, ref column) in named {
+        let mut next = i + 1;
+        if tokens.get(next).map_or(false, |t| is_keyword(t, &["in"])) {
+            for token in tokens[next + 1..].iter().skip_while(|t| *t == "(").take_while(|t| *t != ")") {
+                if token.starts_with('\'') {
+                    literals.push((column.clone(), token.clone()));
+                }
+            }
+            continue;
+        }
+        while tokens.get(next).map_or(false, |t| t == "=" || t == "<" || t == ">") {
+            next += 1;
+        }
+        match tokens.get(next) {
+            Some(token) if next > i + 1 && token.starts_with('\'') => literals.push((column.clone(), token.clone())),
+            _ => {},
+        }
+    }
+    if let Some(values) = tokens.iter().position(|t| is_keyword(t, &["values"])) {
+        let listed: Vec<&String> = named.iter().filter(|&&(i, _)| i < values).map(|&(_, ref column)| column).collect();
+        let mut position = 0;
+        let mut depth = 0;
+        for token in &tokens[values + 1..] {
+            match token.as_ref() {
+                "(" => depth += 1,
+                ")" if depth == 1 => break,
+                ")" => depth -= 1,
+                "," if depth == 1 => position += 1,
+                _ if depth == 1 && token.starts_with('\'') => {
+                    if let Some(column) = listed.get(position) {
+                        literals.push((column.to_string(), token.clone()));
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+    literals
+}
+
+/// Whether a timestamp literal, such as `'2017-03-01 12:00:00'`, leaves
+/// out its time zone.
+fn naive_timestamp(literal: &str) -> bool {
+    let text = literal.trim_matches('\'');
+    let time = text.get(10..).unwrap_or("");
+    !(time.ends_with('Z') || time.ends_with('z') || time.contains('+') || time.contains('-'))
+}
+
+/// Finds the table after FROM, INTO or UPDATE, returning its name, its
+/// schema if it's one of `tables`, and the range of tokens naming it.
+fn query_table<'t>(tables: &'t [TableSchema], tokens: &[String])
+                   -> Option<(String, Option<&'t TableSchema>, usize, usize)> {
+    let start = match tokens.iter().position(|t| is_keyword(t, &["from", "into", "update"])) {
+        Some(position) => position + 1,
+        None => return None,
+    };
+    let (keyspace, name, end) = match (tokens.get(start), tokens.get(start + 1), tokens.get(start + 2)) {
+        (Some(keyspace), Some(dot), Some(name)) if dot == "." => (Some(unquote(keyspace)), unquote(name), start + 3),
+        (Some(name), _, _) => (None, unquote(name), start + 1),
+        _ => return None,
+    };
+    let table = tables.iter().find(|table| {
+        table.name == name && (keyspace.is_none() || table.keyspace.is_none() || table.keyspace == keyspace)
+    });
+    Some((name, table, start, end))
+}
+
+/// The columns named in `tokens` outside of `start..end`, with their
+/// positions. Any identifier that isn't a keyword, function name, alias
+/// or named bind marker is taken to be a column.
+fn columns(tokens: &[String], start: usize, end: usize) -> Vec<(usize, String)> {
+    let mut columns = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if (i >= start && i < end) || !is_identifier(token) || is_keyword(token, KEYWORDS) {
+            continue;
+        }
+        let function = tokens.get(i + 1).map_or(false, |next| next == "(");
+        let alias = i > 0 && is_keyword(&tokens[i - 1], &["as"]);
+        let marker = i > 0 && tokens[i - 1] == ":";
+        if function || alias || marker {
+            continue;
+        }
+        columns.push((i, unquote(token)));
+    }
+    columns
+}
+
+const KEYWORDS: &'static [&'static str] = &[
+    "select", "distinct", "json", "from", "where", "and", "in", "contains", "key", "insert", "into", "values",
+    "update", "set", "delete", "using", "ttl", "timestamp", "if", "not", "exists", "limit", "per", "partition",
+    "order", "group", "by", "asc", "desc", "allow", "filtering", "as", "null", "true", "false",
+];
+
+fn is_keyword(token: &str, keywords: &[&str]) -> bool {
+    let lower = token.to_lowercase();
+    !token.starts_with('"') && keywords.iter().any(|k| lower == *k)
+}
+
+fn is_identifier(token: &str) -> bool {
+    token.starts_with('"') || token.starts_with(|c: char| c.is_alphabetic() || c == '_')
+}
+
+/// Splits a query into identifiers, quoted identifiers, numbers and single
+/// punctuation characters. String literals become a single token, quotes
+/// included.
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut token = c.to_string();
+        match c {
+            '\'' => {
+                // Quotes inside a literal are doubled, which reads as the
+                // literal ending and another one starting right away.
+                while let Some(next) = chars.next() {
+                    token.push(next);
+                    if next == '\'' {
+                        break;
+                    }
+                }
+            },
+            '"' => {
+                while let Some(next) = chars.next() {
+                    token.push(next);
+                    if next == '"' {
+                        break;
+                    }
+                }
+            },
+            _ if c.is_alphanumeric() || c == '_' => {
+                while let Some(&next) = chars.peek() {
+                    if !(next.is_alphanumeric() || next == '_') {
+                        break;
+                    }
+                    token.push(next);
+                    chars.next();
+                }
+            },
+            _ => {},
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// The Rust type rows of `cql_type` decode into.
+pub fn rust_type(cql_type: &str) -> Result<String> {
+    let cql_type = cql_type.trim();
+    let lower = cql_type.to_lowercase();
+    if let Some(inner) = type_argument(&lower, "frozen") {
+        return rust_type(inner);
+    }
+    if let Some(inner) = type_argument(&lower, "set") {
+        return Ok(format!("::std::collections::HashSet<{}>", try!(rust_type(inner))));
+    }
+    let rust_type = match lower.as_ref() {
+        "tinyint" => "i8",
+        "smallint" => "i16",
+        "int" => "i32",
+        "bigint" | "counter" => "i64",
+        "date" => "::cassandra::types::Date",
+        "time" => "::cassandra::types::Time",
+        "text" | "varchar" | "ascii" => "String",
+        "uuid" | "timeuuid" => "::uuid::Uuid",
+        "boolean" => "bool",
+        "blob" => "Vec<u8>",
+        "inet" => "::std::net::IpAddr",
+        _ => return Err(MyError::Protocol(format!("Unsupported type {}", cql_type))),
+    };
+    Ok(rust_type.to_string())
+}
+
+fn type_argument<'a>(cql_type: &'a str, name: &str) -> Option<&'a str> {
+    if cql_type.starts_with(name) && cql_type.ends_with('>') {
+        let rest = cql_type[name.len()..].trim_left();
+        if rest.starts_with('<') {
+            return Some(&rest[1..rest.len() - 1]);
+        }
+    }
+    None
+}
+
+fn parse_create_table(statement: &str) -> Result<TableSchema> {
+    let open = match statement.find('(') {
+        Some(open) => open,
+        None => return Err(MyError::Protocol(format!("No column list in {}", statement))),
+    };
+    let close = match matching_paren(statement, open) {
+        Some(close) => close,
+        None => return Err(MyError::Protocol(format!("Unbalanced parentheses in {}", statement))),
+    };
+    // Skip CREATE TABLE and an optional IF NOT EXISTS.
+    let header: Vec<&str> = statement[..open].split_whitespace().collect();
+    let full_name = match header.last() {
+        Some(name) if header.len() >= 3 => *name,
+        _ => return Err(MyError::Protocol(format!("No table name in {}", statement))),
+    };
+    let (keyspace, name) = match full_name.find('.') {
+        Some(dot) => (Some(unquote(&full_name[..dot])), unquote(&full_name[dot + 1..])),
+        None => (None, unquote(full_name)),
+    };
+
+    let mut columns = Vec::new();
+    let mut key = Vec::new();
+    let mut partition_key = Vec::new();
+    for item in split_top_level(&statement[open + 1..close]) {
+        let item = item.trim();
+        let lower = item.to_lowercase();
+        if lower.starts_with("primary key") {
+            // The partition key is the first column, or the columns of a
+            // nested list: PRIMARY KEY ((a, b), c).
+            let definition = item["primary key".len()..].trim();
+            let list = if definition.starts_with('(') { definition[1..].trim_left() } else { definition };
+            if list.starts_with('(') {
+                let end = list.find(')').unwrap_or(list.len());
+                partition_key.extend(list[1..end].split(',').map(|c| unquote(c.trim())));
+            }
+            let columns = definition.replace('(', " ").replace(')', " ");
+            key.extend(columns.split(',').map(|c| unquote(c.trim())));
+            continue;
+        }
+        let mut parts = item.splitn(2, char::is_whitespace);
+        let column = match parts.next() {
+            Some(column) if !column.is_empty() => unquote(column),
+            _ => continue,
+        };
+        let mut cql_type = parts.next().unwrap_or("").trim().to_string();
+        for suffix in &["primary key", "static"] {
+            if cql_type.to_lowercase().ends_with(suffix) {
+                if *suffix == "primary key" {
+                    key.push(column.clone());
+                }
+                let len = cql_type.len() - suffix.len();
+                cql_type.truncate(len);
+                cql_type = cql_type.trim().to_string();
+            }
+        }
+        columns.push(ColumnSchema {
+            name: column,
+            cql_type: cql_type,
+            primary_key: false,
+            partition_key: false,
+        });
+    }
+    if partition_key.is_empty() {
+        partition_key.extend(key.first().cloned());
+    }
+    for column in &mut columns {
+        column.primary_key = key.contains(&column.name);
+        column.partition_key = partition_key.contains(&column.name);
+    }
+    Ok(TableSchema {
+        keyspace: keyspace,
+        name: name,
+        columns: columns,
+    })
+}
+
+fn matching_paren(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text[open..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + i);
+                }
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Splits on commas that aren't nested in parentheses or type arguments.
+fn split_top_level(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '<' => depth += 1,
+            ')' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&text[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    items.push(&text[start..]);
+    items
+}
+
+/// Quoted identifiers keep their case; unquoted ones are case-insensitive.
+fn unquote(identifier: &str) -> String {
+    if identifier.len() >= 2 && identifier.starts_with('"') && identifier.ends_with('"') {
+        identifier[1..identifier.len() - 1].to_string()
+    } else {
+        identifier.to_lowercase()
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    let plain = identifier.chars().all(|c| c.is_lowercase() || c.is_digit(10) || c == '_');
+    if plain {
+        identifier.to_string()
+    } else {
+        format!("\"{}\"", identifier)
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn field_name(column: &str) -> String {
+    let field = column.to_lowercase();
+    match field.as_ref() {
+        "as" | "box" | "break" | "const" | "continue" | "crate" | "else" | "enum" | "extern" | "false" |
+        "fn" | "for" | "if" | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move" | "mut" |
+        "pub" | "ref" | "return" | "self" | "static" | "struct" | "super" | "trait" | "true" | "type" |
+        "unsafe" | "use" | "where" | "while" => format!("{}_", field),
+        _ => field,
+    }
+}
+
+fn kind_order(kind: &str) -> u8 {
+    match kind {
+        "partition_key" => 0,
+        "clustering" => 1,
+        _ => 2,
+    }
+}
+
+fn required<T>(value: Result<Option<T>>) -> Result<T> {
+    match try!(value) {
+        Some(value) => Ok(value),
+        None => Err(MyError::Protocol("Unexpected null in system_schema.columns".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColumnSchema, TableSchema};
+
+    fn column(name: &str, cql_type: &str, primary_key: bool, partition_key: bool) -> ColumnSchema {
+        ColumnSchema {
+            name: name.to_string(),
+            cql_type: cql_type.to_string(),
+            primary_key: primary_key,
+            partition_key: partition_key,
+        }
+    }
+
+    #[test]
+    fn parses_create_table_statements() {
+        let tables = TableSchema::parse_cql("
+            CREATE KEYSPACE ks WITH replication = {'class': 'SimpleStrategy', 'replication_factor': 1};
+            CREATE TABLE IF NOT EXISTS ks.\"Events\" (
+                tenant text,
+                Day date,
+                at timestamp,
+                \"Payload\" map<text, frozen<list<int>>>,
+                note text static,
+                PRIMARY KEY ((tenant, day), at)
+            ) WITH CLUSTERING ORDER BY (at DESC);
+            create table users (id uuid PRIMARY KEY, emails set<text>);
+            INSERT INTO users (id) VALUES (now());
+        ").unwrap();
+        assert_eq!(tables, vec![
+            TableSchema {
+                keyspace: Some("ks".to_string()),
+                name: "Events".to_string(),
+                columns: vec![
+                    column("tenant", "text", true, true),
+                    column("day", "date", true, true),
+                    column("at", "timestamp", true, false),
+                    column("Payload", "map<text, frozen<list<int>>>", false, false),
+                    column("note", "text", false, false),
+                ],
+            },
+            TableSchema {
+                keyspace: None,
+                name: "users".to_string(),
+                columns: vec![column("id", "uuid", true, true), column("emails", "set<text>", false, false)],
+            },
+        ]);
+    }
+
+    #[test]
+    fn first_key_column_is_the_partition_key() {
+        let tables = TableSchema::parse_cql("CREATE TABLE t (a int, b int, c int, PRIMARY KEY (a, b))").unwrap();
+        let keys: Vec<(bool, bool)> = tables[0].columns.iter().map(|c| (c.primary_key, c.partition_key)).collect();
+        assert_eq!(keys, vec![(true, true), (true, false), (false, false)]);
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        assert!(TableSchema::parse_cql("CREATE TABLE t").is_err());
+        assert!(TableSchema::parse_cql("CREATE TABLE t (a int PRIMARY KEY").is_err());
+        assert!(TableSchema::parse_cql("CREATE TABLE (a int PRIMARY KEY)").is_err());
+    }
+}
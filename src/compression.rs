@@ -0,0 +1,115 @@
+#[cfg(feature = "lz4")]
+use std::io::Cursor;
+#[cfg(feature = "lz4")]
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "lz4")]
+use lz4;
+
+use errors::MyError;
+use protocol::Result;
+
+/// Frame body compression, negotiated in the STARTUP request. The header is
+/// never compressed, and neither is the STARTUP body itself.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compression {
+    None,
+    /// Requires the `lz4` cargo feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+impl Compression {
+    /// Every algorithm compiled in, best first.
+    pub fn available() -> Vec<Compression> {
+        #[allow(unused_mut)]
+        let mut available = Vec::new();
+        #[cfg(feature = "lz4")]
+        available.push(Compression::Lz4);
+        available
+    }
+
+    /// Picks the first algorithm in `preference` that the server listed
+    /// under COMPRESSION in its SUPPORTED response.
+    pub fn negotiate(preference: &[Compression], supported: &[String]) -> Compression {
+        for compression in preference {
+            if let Some(name) = compression.name() {
+                if supported.iter().any(|s| s == name) {
+                    return *compression;
+                }
+            }
+        }
+        Compression::None
+    }
+
+    /// The algorithm's name in SUPPORTED and STARTUP options.
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some("lz4"),
+        }
+    }
+
+    pub fn compress(&self, body: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            Compression::None => Ok(body.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                // Cassandra expects the uncompressed length as a big-endian
+                // int ahead of the raw lz4 block.
+                let mut compressed = Vec::with_capacity(4 + body.len());
+                try!(compressed.write_i32::<BigEndian>(body.len() as i32));
+                compressed.extend(try!(self.compress_block(body)));
+                Ok(compressed)
+            },
+        }
+    }
+
+    /// Compresses without a length prefix, as v5 frames carry the
+    /// uncompressed length in their header.
+    pub fn compress_block(&self, body: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            Compression::None => Ok(body.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(try!(lz4::block::compress(body, None, false))),
+        }
+    }
+
+    /// Reverses `compress_block`, given the uncompressed length.
+    pub fn decompress_block(&self, body: &[u8], len: usize) -> Result<Vec<u8>> {
+        let decompressed = match *self {
+            Compression::None => body.to_vec(),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => try!(lz4::block::decompress(body, Some(len as i32))),
+        };
+        if decompressed.len() != len {
+            return Err(MyError::Protocol(format!("expected {} bytes after decompression, got {}",
+                                                 len, decompressed.len())));
+        }
+        Ok(decompressed)
+    }
+
+    pub fn decompress(&self, body: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            Compression::None => {
+                Err(MyError::Protocol(format!("received a compressed body of {} bytes, but compression is off",
+                                              body.len())))
+            },
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let mut cursor = Cursor::new(body);
+                let len = try!(cursor.read_i32::<BigEndian>());
+                if len < 0 {
+                    return Err(MyError::Protocol(format!("invalid uncompressed length {}", len)));
+                }
+                self.decompress_block(&body[4..], len as usize)
+            },
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Throwaway Cassandra servers in Docker for black-box tests.
+//!
+//! ```ignore
+//! let cassandra = try!(CassandraContainer::start());
+//! let session = try!(cassandra.session());
+//! try!(session.execute("CREATE KEYSPACE ...", &[]));
+//! ```
+//!
+//! Containers are started with the `docker` command line client, which
+//! must be on the `PATH`, and are removed when the `CassandraContainer` is
+//! dropped.
+
+use std::io;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use client::Client;
+use errors::MyError;
+use protocol::Result;
+use session::{Session, SessionConfig};
+
+pub const DEFAULT_IMAGE: &'static str = "cassandra:3.11";
+/// Cassandra takes a while to start, more so on a busy CI machine.
+pub const DEFAULT_STARTUP_TIMEOUT_SECS: u64 = 180;
+
+const CQL_PORT: &'static str = "9042/tcp";
+/// Pause between attempts to reach a starting server.
+const POLL_INTERVAL_MILLIS: u64 = 1000;
+
+/// Options for starting a container.
+#[derive(Debug, Clone)]
+pub struct ContainerBuilder {
+    image: String,
+    env: Vec<(String, String)>,
+    startup_timeout: Duration,
+}
+
+impl ContainerBuilder {
+    /// The default image, with a small heap so several test servers fit
+    /// on one machine.
+    pub fn new() -> ContainerBuilder {
+        ContainerBuilder {
+            image: DEFAULT_IMAGE.to_string(),
+            env: vec![("MAX_HEAP_SIZE".to_string(), "512M".to_string()),
+                      ("HEAP_NEWSIZE".to_string(), "128M".to_string())],
+            startup_timeout: Duration::from_secs(DEFAULT_STARTUP_TIMEOUT_SECS),
+        }
+    }
+
+    pub fn image(mut self, image: &str) -> ContainerBuilder {
+        self.image = image.to_string();
+        self
+    }
+
+    /// Sets an environment variable in the container, replacing any
+    /// earlier value.
+    pub fn env(mut self, name: &str, value: &str) -> ContainerBuilder {
+        self.env.retain(|&(ref n, _)| n != name);
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// How long to wait for the server to answer queries.
+    pub fn startup_timeout(mut self, timeout: Duration) -> ContainerBuilder {
+        self.startup_timeout = timeout;
+        self
+    }
+
+    /// Starts the container and waits until the server answers queries.
+    /// The container is removed again if it doesn't in time.
+    pub fn start(self) -> Result<CassandraContainer> {
+        let mut args = vec!["run".to_string(), "-d".to_string(), "-p".to_string(), CQL_PORT.to_string()];
+        for &(ref name, ref value) in &self.env {
+            args.push("-e".to_string());
+            args.push(format!("{}={}", name, value));
+        }
+        args.push(self.image.clone());
+        let id = try!(docker(&args));
+        let mut container = CassandraContainer {
+            id: id,
+            addr: "127.0.0.1:9042".parse().unwrap(),
+        };
+        container.addr = try!(container.mapped_port());
+        try!(container.wait_ready(self.startup_timeout));
+        Ok(container)
+    }
+}
+
+impl Default for ContainerBuilder {
+    fn default() -> ContainerBuilder {
+        ContainerBuilder::new()
+    }
+}
+
+/// A running Cassandra container, removed on drop.
+#[derive(Debug)]
+pub struct CassandraContainer {
+    id: String,
+    addr: SocketAddr,
+}
+
+impl CassandraContainer {
+    /// Starts a container with the default options.
+    pub fn start() -> Result<CassandraContainer> {
+        ContainerBuilder::new().start()
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Where the container's native protocol port is published.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn session(&self) -> Result<Session> {
+        Session::new(self.addr)
+    }
+
+    pub fn session_with_config(&self, config: SessionConfig) -> Result<Session> {
+        Session::with_config(self.addr, config)
+    }
+
+    /// The container's output so far, for working out why a test failed.
+    pub fn logs(&self) -> Result<String> {
+        docker(&["logs".to_string(), self.id.clone()])
+    }
+
+    /// `docker port` prints one `host:port` line per address the port is
+    /// published on; an IPv4 one is used, with the wildcard address
+    /// replaced by localhost.
+    fn mapped_port(&self) -> Result<SocketAddr> {
+        let output = try!(docker(&["port".to_string(), self.id.clone(), CQL_PORT.to_string()]));
+        for line in output.lines() {
+            let line = line.trim().replace("0.0.0.0:", "127.0.0.1:");
+            if let Ok(addr) = line.parse::<SocketAddr>() {
+                if addr.is_ipv4() {
+                    return Ok(addr);
+                }
+            }
+        }
+        Err(MyError::Protocol(format!("Couldn't find the published CQL port in {:?}", output)))
+    }
+
+    /// The port is published before the server listens on it, so this
+    /// keeps connecting until a query succeeds.
+    fn wait_ready(&self, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        loop {
+            let error = match Client::connect(self.addr) {
+                Ok(mut client) => match client.query("SELECT release_version FROM system.local", &[]) {
+                    Ok(_) => return Ok(()),
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+            if started.elapsed() >= timeout {
+                return Err(MyError::Timeout(format!("Cassandra in container {} wasn't ready after {:?}, last error: {}",
+                                                    self.id, timeout, error)));
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+        }
+    }
+}
+
+impl Drop for CassandraContainer {
+    fn drop(&mut self) {
+        let _ = docker(&["rm".to_string(), "-f".to_string(), "-v".to_string(), self.id.clone()]);
+    }
+}
+
+/// Runs a docker command, returning its trimmed standard output.
+fn docker(args: &[String]) -> Result<String> {
+    let output = try!(Command::new("docker").args(args).output());
+    if !output.status.success() {
+        let message = format!("docker {} failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim());
+        return Err(MyError::IO(io::Error::new(io::ErrorKind::Other, message)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
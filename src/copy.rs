@@ -0,0 +1,421 @@
+//! Loading CSV data into a table, like cqlsh's `COPY ... FROM`.
+//!
+//! ```ignore
+//! let file = BufReader::new(try!(File::open("users.csv")));
+//! let mut options = CopyOptions::default();
+//! options.progress = Some(Arc::new(|progress: &CopyProgress| println!("{} rows", progress.rows)));
+//! let done = try!(session.copy_from_csv("ks.users", file, &options));
+//! ```
+//!
+//! The first line names the table columns the fields go to, unless
+//! `CopyOptions::columns` does. Fields are converted to the types the
+//! server reports when the INSERT is prepared, so a file that doesn't fit
+//! the table fails on the first bad line rather than after a partial load.
+//! Empty unquoted fields are left unset, which needs protocol v4, and
+//! doesn't write a tombstone the way a null would.
+//!
+//! Rows are read a chunk at a time. Rows of a chunk that share a partition
+//! are written together in UNLOGGED batches, which the coordinator applies
+//! as a single mutation, and the batches are spread over several threads.
+//! Grouping needs the partition key, which servers only send from v4 on;
+//! before that every row is written on its own.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use byteorder::{BigEndian, WriteBytesExt};
+use uuid::Uuid;
+
+use batch::{BatchBuilder, BatchType};
+use errors::MyError;
+use protocol::{Consistency, PreparedStatement, Result};
+use session::Session;
+use types::{CQLType, Date, Time, ToCQL, Unset};
+
+/// Rows per batch unless configured otherwise. Cassandra warns about
+/// batches over 5kB by default.
+pub const DEFAULT_BATCH_SIZE: usize = 20;
+
+/// Rows read at a time unless configured otherwise.
+pub const DEFAULT_CHUNK_SIZE: usize = 10000;
+
+/// How far a copy has got.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct CopyProgress {
+    /// Rows written so far.
+    pub rows: usize,
+    /// Requests sent so far, each a batch or a single row.
+    pub requests: usize,
+}
+
+#[derive(Clone)]
+pub struct CopyOptions {
+    /// The table columns the fields of each line go to, in order. If
+    /// `None`, the first line names them.
+    pub columns: Option<Vec<String>>,
+    /// Most rows written in one batch.
+    pub batch_size: usize,
+    /// Rows read and grouped by partition before being written. Larger
+    /// chunks find more rows to batch together, at the cost of memory.
+    pub chunk_size: usize,
+    /// Threads writing a chunk at once.
+    pub workers: usize,
+    pub consistency: Consistency,
+    /// Called after each chunk is written.
+    pub progress: Option<Arc<Fn(&CopyProgress) + Send + Sync>>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions {
+            columns: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            workers: 4,
+            consistency: Consistency::One,
+            progress: None,
+        }
+    }
+}
+
+/// One row's values, serialized. `None` leaves the column unset.
+type CsvRow = Vec<Option<Vec<u8>>>;
+
+/// Inserts every line of `reader` into `table`, returning how many rows
+/// and requests it took. Stops at the first line that doesn't convert or
+/// write; rows of earlier chunks have been written by then, and some of
+/// the failing chunk may have been.
+pub fn copy_from_csv<R: BufRead>(session: &Session, table: &str, reader: R, options: &CopyOptions)
+                                 -> Result<CopyProgress> {
+    let mut lines = reader.lines().enumerate();
+    let columns = match options.columns {
+        Some(ref columns) => columns.clone(),
+        None => {
+            let header = loop {
+                match lines.next() {
+                    Some((_, line)) => {
+                        let line = try!(line);
+                        if !line.trim().is_empty() {
+                            break line;
+                        }
+                    },
+                    None => return Ok(CopyProgress::default()),
+                }
+            };
+            let mut columns = Vec::new();
+            for column in parse_csv_line(&header) {
+                match column {
+                    Some(column) => columns.push(column.trim().to_string()),
+                    None => return Err(MyError::Protocol("CSV header has an empty column name".to_string())),
+                }
+            }
+            columns
+        },
+    };
+    let markers: Vec<&str> = columns.iter().map(|_| "?").collect();
+    let insert = format!("INSERT INTO {} ({}) VALUES ({})", table, columns.join(", "), markers.join(", "));
+    let prepared = try!(session.prepare(&insert));
+
+    let mut progress = CopyProgress::default();
+    let mut chunk = Vec::new();
+    for (index, line) in lines {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = parse_csv_line(&line);
+        if fields.len() != columns.len() {
+            return Err(MyError::Protocol(format!("CSV line {} has {} fields, expected {}",
+                                                 index + 1, fields.len(), columns.len())));
+        }
+        let mut row = Vec::with_capacity(fields.len());
+        for (field, spec) in fields.iter().zip(prepared.params()) {
+            let value = match *field {
+                Some(ref field) => parse_field(spec.datatype(), field),
+                None => {
+                    row.push(None);
+                    continue;
+                },
+            };
+            match value {
+                Ok(value) => row.push(Some(value)),
+                Err(MyError::Protocol(message)) => {
+                    return Err(MyError::Protocol(format!("CSV line {}, column {}: {}", index + 1, spec.name(), message)));
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        chunk.push(row);
+        if chunk.len() >= options.chunk_size.max(1) {
+            try!(write_chunk(session, &prepared, chunk, options, &mut progress));
+            chunk = Vec::new();
+        }
+    }
+    if !chunk.is_empty() {
+        try!(write_chunk(session, &prepared, chunk, options, &mut progress));
+    }
+    Ok(progress)
+}
+
+/// Writes rows of values for `prepared`'s bind markers, grouped and
+/// batched by partition the same way CSV lines are, then adds them to
+/// `progress` and reports it. `None` values are left unset.
+pub fn write_chunk(session: &Session, prepared: &PreparedStatement, chunk: Vec<Vec<Option<Vec<u8>>>>,
+                   options: &CopyOptions, progress: &mut CopyProgress) -> Result<()> {
+    let rows = chunk.len();
+    // Group rows by their partition key values, in the order each
+    // partition first appears.
+    let mut partitions: Vec<Vec<CsvRow>> = Vec::new();
+    let mut index: HashMap<CsvRow, usize> = HashMap::new();
+    for row in chunk {
+        if prepared.partition_key().is_empty() {
+            partitions.push(vec![row]);
+            continue;
+        }
+        let key: CsvRow = prepared.partition_key().iter().map(|&i| row[i as usize].clone()).collect();
+        let next = partitions.len();
+        let position = *index.entry(key).or_insert(next);
+        if position == next {
+            partitions.push(Vec::new());
+        }
+        partitions[position].push(row);
+    }
+    let mut requests = Vec::new();
+    for mut partition in partitions {
+        while !partition.is_empty() {
+            let rest = partition.split_off(partition.len().min(options.batch_size.max(1)));
+            requests.push(partition);
+            partition = rest;
+        }
+    }
+    let count = requests.len();
+
+    let workers = options.workers.max(1).min(count);
+    let pending = Arc::new(Mutex::new(requests.into_iter()));
+    let handles: Vec<_> = (0..workers).map(|_| {
+        let session = session.clone();
+        let prepared = prepared.clone();
+        let pending = pending.clone();
+        let consistency = options.consistency;
+        thread::spawn(move || -> Result<()> {
+            loop {
+                let next = pending.lock().unwrap().next();
+                let request = match next {
+                    Some(request) => request,
+                    None => return Ok(()),
+                };
+                if let Err(err) = write_partition(&session, &prepared, &request, consistency) {
+                    // Leave the rest of the chunk unwritten.
+                    pending.lock().unwrap().by_ref().count();
+                    return Err(err);
+                }
+            }
+        })
+    }).collect();
+    let mut first_err = None;
+    for handle in handles {
+        let result = match handle.join() {
+            Ok(result) => result,
+            Err(_) => Err(MyError::Protocol("CSV copy worker panicked".to_string())),
+        };
+        if let Err(err) = result {
+            first_err = first_err.or(Some(err));
+        }
+    }
+    if let Some(err) = first_err {
+        return Err(err);
+    }
+    progress.rows += rows;
+    progress.requests += count;
+    if let Some(ref callback) = options.progress {
+        callback(progress);
+    }
+    Ok(())
+}
+
+/// Writes rows of one partition, as a batch if there's more than one.
+fn write_partition(session: &Session, prepared: &PreparedStatement, rows: &[CsvRow], consistency: Consistency)
+                   -> Result<()> {
+    let values: Vec<Vec<&ToCQL>> = rows.iter().map(|row| {
+        row.iter().map(|value| match *value {
+            Some(ref value) => value as &ToCQL,
+            None => &Unset as &ToCQL,
+        }).collect()
+    }).collect();
+    if values.len() == 1 {
+        return session.execute_prepared_at(prepared, &values[0], consistency).map(|_| ());
+    }
+    let mut builder = BatchBuilder::new(BatchType::Unlogged).consistency(consistency);
+    for row in &values {
+        builder = builder.prepared(prepared, row);
+    }
+    session.batch(&builder.build()).map(|_| ())
+}
+
+/// Serializes a CSV field as a value of `datatype`, written the way cqlsh
+/// prints it: numbers, `true`/`false`, UUIDs and addresses as usual,
+/// blobs as `0x` and hex digits, dates as `2024-01-31`, times as
+/// `13:45:00.123`, and timestamps as milliseconds since the epoch or a UTC
+/// date and time like `2024-01-31 13:45:00.123Z`. Values of other types,
+/// such as collections and decimals, are given as their serialized bytes
+/// in hex, the way `backup::export` writes them.
+pub fn parse_field(datatype: &CQLType, field: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let trimmed = field.trim();
+    let invalid = || MyError::Protocol(format!("Invalid {:?} value {:?}", datatype, field));
+    match *datatype {
+        CQLType::Ascii | CQLType::Varchar => buf.extend_from_slice(field.as_bytes()),
+        CQLType::Int => try!(try!(trimmed.parse::<i32>().map_err(|_| invalid())).serialize(&mut buf)),
+        CQLType::Bigint | CQLType::Counter => try!(try!(trimmed.parse::<i64>().map_err(|_| invalid())).serialize(&mut buf)),
+        CQLType::Smallint => try!(try!(trimmed.parse::<i16>().map_err(|_| invalid())).serialize(&mut buf)),
+        CQLType::Tinyint => try!(try!(trimmed.parse::<i8>().map_err(|_| invalid())).serialize(&mut buf)),
+        CQLType::Boolean => try!(try!(trimmed.to_lowercase().parse::<bool>().map_err(|_| invalid())).serialize(&mut buf)),
+        CQLType::Float => try!(buf.write_f32::<BigEndian>(try!(trimmed.parse().map_err(|_| invalid())))),
+        CQLType::Double => try!(buf.write_f64::<BigEndian>(try!(trimmed.parse().map_err(|_| invalid())))),
+        CQLType::Uuid | CQLType::Timeuuid => try!(try!(Uuid::parse_str(trimmed).map_err(|_| invalid())).serialize(&mut buf)),
+        CQLType::Inet => try!(try!(trimmed.parse::<IpAddr>().map_err(|_| invalid())).serialize(&mut buf)),
+        CQLType::Blob => buf = try!(parse_hex(trimmed).ok_or_else(invalid)),
+        CQLType::Date => try!(Date(try!(parse_date(trimmed).ok_or_else(invalid))).serialize(&mut buf)),
+        CQLType::Time => try!(Time(try!(parse_time(trimmed).ok_or_else(invalid))).serialize(&mut buf)),
+        CQLType::Timestamp => {
+            let millis = match trimmed.parse::<i64>() {
+                Ok(millis) => millis,
+                Err(_) => try!(parse_timestamp(trimmed).ok_or_else(invalid)),
+            };
+            try!(millis.serialize(&mut buf));
+        },
+        _ => buf = try!(parse_hex(trimmed).ok_or_else(|| {
+            MyError::Protocol(format!("{:?} values must be given as serialized bytes in hex, not {:?}", datatype, field))
+        })),
+    }
+    Ok(buf)
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = if text.starts_with("0x") || text.starts_with("0X") { &text[2..] } else { return None };
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len() / 2).map(|i| u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).ok()).collect()
+}
+
+/// Days since the epoch of a `YYYY-MM-DD` date.
+fn parse_date(text: &str) -> Option<i32> {
+    // A leading minus sign belongs to the year.
+    let (sign, text) = if text.starts_with('-') { (-1, &text[1..]) } else { (1, text) };
+    let parts: Vec<&str> = text.split('-').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let numbers = match parse_numbers(&parts) {
+        Some(numbers) => numbers,
+        None => return None,
+    };
+    let (year, month, day) = (sign * numbers[0], numbers[1], numbers[2]);
+    if month < 1 || month > 12 || day < 1 || day > 31 {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some((era * 146097 + day_of_era - 719468) as i32)
+}
+
+/// Nanoseconds since midnight of an `HH:MM:SS[.fraction]` time.
+fn parse_time(text: &str) -> Option<i64> {
+    let (clock, fraction) = match text.find('.') {
+        Some(dot) => (&text[..dot], &text[dot + 1..]),
+        None => (text, ""),
+    };
+    let parts: Vec<&str> = clock.split(':').collect();
+    if parts.len() != 3 || fraction.len() > 9 || !fraction.chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+    let numbers = match parse_numbers(&parts) {
+        Some(numbers) => numbers,
+        None => return None,
+    };
+    let (hours, minutes, seconds) = (numbers[0], numbers[1], numbers[2]);
+    if hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    let mut nanos = 0;
+    for (i, digit) in fraction.chars().enumerate() {
+        nanos += digit.to_digit(10).unwrap() as i64 * 10i64.pow(8 - i as u32);
+    }
+    Some(((hours * 60 + minutes) * 60 + seconds) * 1_000_000_000 + nanos)
+}
+
+/// Milliseconds since the epoch of a UTC `YYYY-MM-DD[ HH:MM:SS[.fff]][Z]`
+/// timestamp, with a space or `T` between the date and time.
+fn parse_timestamp(text: &str) -> Option<i64> {
+    let text = text.trim_right_matches('Z').trim_right_matches("+0000").trim_right_matches("+00:00");
+    let (date, time) = match text.find(|c| c == ' ' || c == 'T') {
+        Some(at) => (&text[..at], Some(&text[at + 1..])),
+        None => (text, None),
+    };
+    let days = match parse_date(date) {
+        Some(days) => days as i64,
+        None => return None,
+    };
+    let nanos = match time.map(parse_time) {
+        Some(Some(nanos)) => nanos,
+        Some(None) => return None,
+        None => 0,
+    };
+    Some(days * 86_400_000 + nanos / 1_000_000)
+}
+
+/// Parses each part as an unsigned decimal number.
+fn parse_numbers(parts: &[&str]) -> Option<Vec<i64>> {
+    parts.iter()
+        .map(|part| if !part.is_empty() && part.chars().all(|c| c.is_digit(10)) { part.parse().ok() } else { None })
+        .collect()
+}
+
+/// Splits one CSV line. Quoted fields may contain commas and doubled
+/// quotes; an empty unquoted field is `None`.
+pub fn parse_csv_line(line: &str) -> Vec<Option<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            },
+            '"' if field.is_empty() => {
+                in_quotes = true;
+                quoted = true;
+            },
+            ',' if !in_quotes => {
+                fields.push(csv_field(&field, quoted));
+                field.clear();
+                quoted = false;
+            },
+            _ => field.push(c),
+        }
+    }
+    fields.push(csv_field(&field, quoted));
+    fields
+}
+
+fn csv_field(field: &str, quoted: bool) -> Option<String> {
+    if field.is_empty() && !quoted {
+        None
+    } else {
+        Some(field.to_string())
+    }
+}
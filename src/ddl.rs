@@ -0,0 +1,251 @@
+//! Creating keyspaces and tables, and adding columns, without writing the
+//! CQL by hand. Names are quoted wherever CQL needs them to be, and every
+//! statement waits for the cluster to agree on the new schema before it
+//! returns what the server reported changing.
+//!
+//! Table names may be qualified with their keyspace, as `keyspace.table`;
+//! otherwise they're in the session's keyspace.
+
+use std::collections::HashSet;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use client::Client;
+use durability::Replication;
+use errors::MyError;
+use events::SchemaChange;
+use protocol::Result;
+use session::Session;
+use widerow::Order;
+
+/// How long a schema change waits for the nodes to agree on the new
+/// schema before going on regardless.
+pub const SCHEMA_AGREEMENT_TIMEOUT_SECS: u64 = 10;
+
+const SCHEMA_AGREEMENT_INTERVAL_MS: u64 = 200;
+
+/// Words CQL doesn't accept as names unless they're quoted.
+const RESERVED: &'static [&'static str] = &[
+    "add", "allow", "alter", "and", "apply", "asc", "authorize", "batch", "begin", "by", "columnfamily",
+    "create", "default", "delete", "desc", "describe", "drop", "entries", "execute", "from", "full", "grant",
+    "if", "in", "index", "infinity", "insert", "into", "is", "keyspace", "limit", "materialized", "mbean",
+    "mbeans", "modify", "nan", "norecursive", "not", "null", "of", "on", "or", "order", "primary", "rename",
+    "replace", "revoke", "schema", "select", "set", "table", "to", "token", "truncate", "unlogged", "unset",
+    "update", "use", "using", "view", "where", "with",
+];
+
+/// Creates keyspace `name`, failing if it already exists.
+pub fn create_keyspace(session: &Session, name: &str, replication: &Replication) -> Result<Option<SchemaChange>> {
+    session.alter_schema(&format!("CREATE KEYSPACE {} WITH replication = {}",
+                                  quote_identifier(name), replication_map(replication)))
+}
+
+/// Creates the table `spec` describes.
+pub fn create_table(session: &Session, spec: TableSpecBuilder) -> Result<Option<SchemaChange>> {
+    session.alter_schema(&try!(spec.cql()))
+}
+
+/// Adds a column of `cql_type`, which is written as in CQL, such as
+/// `text` or `map<text, int>`.
+pub fn alter_table_add_column(session: &Session, table: &str, column: &str, cql_type: &str)
+                              -> Result<Option<SchemaChange>> {
+    session.alter_schema(&format!("ALTER TABLE {} ADD {} {}", table_name(table), quote_identifier(column), cql_type))
+}
+
+/// The columns, primary key and options of a table to create.
+#[derive(Debug, Clone)]
+pub struct TableSpecBuilder {
+    name: String,
+    if_not_exists: bool,
+    columns: Vec<(String, String)>,
+    partition_key: Vec<String>,
+    clustering: Vec<(String, Order)>,
+    options: Vec<(String, String)>,
+}
+
+impl TableSpecBuilder {
+    pub fn new(name: &str) -> TableSpecBuilder {
+        TableSpecBuilder {
+            name: name.to_string(),
+            if_not_exists: false,
+            columns: Vec::new(),
+            partition_key: Vec::new(),
+            clustering: Vec::new(),
+            options: Vec::new(),
+        }
+    }
+
+    /// Leaves an existing table with the same name as it is, rather than
+    /// failing. No schema change is returned then.
+    pub fn if_not_exists(mut self) -> TableSpecBuilder {
+        self.if_not_exists = true;
+        self
+    }
+
+    /// Adds a column of `cql_type`, which is written as in CQL, such as
+    /// `text` or `frozen<list<int>>`.
+    pub fn column(mut self, name: &str, cql_type: &str) -> TableSpecBuilder {
+        self.columns.push((name.to_string(), cql_type.to_string()));
+        self
+    }
+
+    /// Adds `column` to the partition key, after those added before it.
+    pub fn partition_key(mut self, column: &str) -> TableSpecBuilder {
+        self.partition_key.push(column.to_string());
+        self
+    }
+
+    /// Adds `column` to the clustering columns, after those added before
+    /// it, sorting rows within a partition in `order`.
+    pub fn clustering_column(mut self, column: &str, order: Order) -> TableSpecBuilder {
+        self.clustering.push((column.to_string(), order));
+        self
+    }
+
+    /// Sets a table option such as `gc_grace_seconds`. The value is CQL, so
+    /// strings need their quotes and maps their braces, as in
+    /// `{'class': 'LeveledCompactionStrategy'}`.
+    pub fn option(mut self, name: &str, value: &str) -> TableSpecBuilder {
+        self.options.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The CREATE TABLE statement. Fails without a partition key, or if a
+    /// key column isn't one of the columns.
+    pub fn cql(&self) -> Result<String> {
+        if self.partition_key.is_empty() {
+            return Err(MyError::Protocol(format!("Table {} has no partition key", self.name)));
+        }
+        let keys = self.partition_key.iter().chain(self.clustering.iter().map(|&(ref column, _)| column));
+        let mut seen = HashSet::new();
+        for key in keys {
+            if !self.columns.iter().any(|&(ref column, _)| column == key) {
+                return Err(MyError::Protocol(format!("Key column {} of table {} isn't one of its columns",
+                                                     key, self.name)));
+            }
+            if !seen.insert(key) {
+                return Err(MyError::Protocol(format!("Column {} is in the primary key of table {} twice",
+                                                     key, self.name)));
+            }
+        }
+
+        let mut definitions: Vec<String> = self.columns.iter()
+            .map(|&(ref column, ref cql_type)| format!("{} {}", quote_identifier(column), cql_type))
+            .collect();
+        let partition_key: Vec<String> = self.partition_key.iter().map(|column| quote_identifier(column)).collect();
+        let mut primary_key = if partition_key.len() == 1 {
+            vec![partition_key[0].clone()]
+        } else {
+            vec![format!("({})", partition_key.join(", "))]
+        };
+        primary_key.extend(self.clustering.iter().map(|&(ref column, _)| quote_identifier(column)));
+        definitions.push(format!("PRIMARY KEY ({})", primary_key.join(", ")));
+
+        let mut options: Vec<String> = Vec::new();
+        if self.clustering.iter().any(|&(_, order)| order == Order::Descending) {
+            let order: Vec<String> = self.clustering.iter()
+                .map(|&(ref column, order)| format!("{} {}", quote_identifier(column), order.keyword()))
+                .collect();
+            options.push(format!("CLUSTERING ORDER BY ({})", order.join(", ")));
+        }
+        options.extend(self.options.iter().map(|&(ref name, ref value)| format!("{} = {}", name, value)));
+
+        let mut cql = format!("CREATE TABLE {}{} ({})", if self.if_not_exists { "IF NOT EXISTS " } else { "" },
+                              table_name(&self.name), definitions.join(", "));
+        if !options.is_empty() {
+            cql.push_str(" WITH ");
+            cql.push_str(&options.join(" AND "));
+        }
+        Ok(cql)
+    }
+}
+
+/// Waits until the connected node and every peer it knows of report the
+/// same schema version, checking a few times a second. Peers that are
+/// down still report their last version, so they hold agreement up until
+/// `timeout`. Returns whether the nodes agreed in time.
+pub fn wait_for_agreement(client: &mut Client, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if try!(schema_versions(client)).len() <= 1 {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(SCHEMA_AGREEMENT_INTERVAL_MS));
+    }
+}
+
+fn schema_versions(client: &mut Client) -> Result<HashSet<Uuid>> {
+    let mut versions = HashSet::new();
+    for query in &["SELECT schema_version FROM system.local", "SELECT schema_version FROM system.peers"] {
+        let result = try!(client.query(query, &[]));
+        for row in &result.rows {
+            if let Some(version) = try!(row.get::<Uuid>("schema_version")) {
+                versions.insert(version);
+            }
+        }
+    }
+    Ok(versions)
+}
+
+/// Quotes `name` unless it's a lowercase identifier CQL accepts as it is.
+pub fn quote_identifier(name: &str) -> String {
+    let plain = name.chars().next().map_or(false, |c| c.is_ascii_lowercase()) &&
+                name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') &&
+                !RESERVED.contains(&name);
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+/// `table` with each of its keyspace and table names quoted as needed.
+fn table_name(table: &str) -> String {
+    match table.find('.') {
+        Some(dot) => format!("{}.{}", quote_identifier(&table[..dot]), quote_identifier(&table[dot + 1..])),
+        None => quote_identifier(table),
+    }
+}
+
+fn quote_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// The replication map of a CREATE KEYSPACE statement.
+fn replication_map(replication: &Replication) -> String {
+    let options = match *replication {
+        Replication::Simple(factor) => {
+            vec!["'class': 'SimpleStrategy'".to_string(), format!("'replication_factor': {}", factor)]
+        },
+        Replication::NetworkTopology(ref datacenters) => {
+            let mut names: Vec<&String> = datacenters.keys().collect();
+            names.sort();
+            let mut options = vec!["'class': 'NetworkTopologyStrategy'".to_string()];
+            options.extend(names.into_iter().map(|name| format!("{}: {}", quote_string(name), datacenters[name])));
+            options
+        },
+        Replication::Other(ref class) => vec![format!("'class': {}", quote_string(class))],
+    };
+    format!("{{{}}}", options.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quote_identifier;
+
+    #[test]
+    fn quotes_only_when_needed() {
+        assert_eq!(quote_identifier("events_2017"), "events_2017");
+        assert_eq!(quote_identifier("Events"), "\"Events\"");
+        assert_eq!(quote_identifier("2017_events"), "\"2017_events\"");
+        assert_eq!(quote_identifier("user-id"), "\"user-id\"");
+        assert_eq!(quote_identifier("select"), "\"select\"");
+        assert_eq!(quote_identifier("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote_identifier("é"), "\"é\"");
+        assert_eq!(quote_identifier(""), "\"\"");
+    }
+}
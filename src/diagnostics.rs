@@ -0,0 +1,156 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use auth::AuthProvider;
+use capabilities::ServerCapabilities;
+use client::Client;
+use compression::Compression;
+use errors::MyError;
+use protocol::ProtocolVersion;
+
+/// How far the handshake with one host got.
+#[derive(Debug)]
+pub enum HostStatus {
+    /// The TCP connection could not be opened.
+    ConnectFailed(MyError),
+    /// Connected, but OPTIONS or STARTUP failed.
+    StartupFailed(MyError),
+    Ready,
+}
+
+#[derive(Debug)]
+pub struct HostReport {
+    pub addr: SocketAddr,
+    pub status: HostStatus,
+    pub elapsed: Duration,
+}
+
+impl HostReport {
+    /// Opens a new connection to `addr` and runs the startup handshake on it.
+    pub fn probe(addr: SocketAddr, compression: &[Compression], auth: Option<&Arc<AuthProvider>>) -> HostReport {
+        let start = Instant::now();
+        let status = match Client::connect(addr) {
+            Err(err) => HostStatus::ConnectFailed(err),
+            Ok(mut client) => {
+                client.set_compression_preference(compression.to_vec());
+                client.set_auth_provider(auth.cloned());
+                let status = match client.initialize() {
+                    Ok(()) => HostStatus::Ready,
+                    Err(err) => HostStatus::StartupFailed(err),
+                };
+                client.shutdown();
+                status
+            },
+        };
+        HostReport {
+            addr: addr,
+            status: status,
+            elapsed: start.elapsed(),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        match self.status {
+            HostStatus::Ready => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for HostReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let elapsed = self.elapsed.as_secs() * 1000 + self.elapsed.subsec_nanos() as u64 / 1_000_000;
+        match self.status {
+            HostStatus::ConnectFailed(ref err) => write!(f, "{}: connect failed after {}ms: {}", self.addr, elapsed, err),
+            HostStatus::StartupFailed(ref err) => write!(f, "{}: startup failed after {}ms: {}", self.addr, elapsed, err),
+            HostStatus::Ready => write!(f, "{}: ready in {}ms", self.addr, elapsed),
+        }
+    }
+}
+
+/// The outcome of connecting to every address the contact point resolved to.
+#[derive(Debug)]
+pub struct ConnectionReport {
+    pub hosts: Vec<HostReport>,
+}
+
+impl ConnectionReport {
+    pub fn probe(addrs: &[SocketAddr], compression: &[Compression], auth: Option<&Arc<AuthProvider>>)
+                 -> ConnectionReport {
+        ConnectionReport {
+            hosts: addrs.iter().map(|&addr| HostReport::probe(addr, compression, auth)).collect(),
+        }
+    }
+
+    /// Whether at least one host completed the handshake.
+    pub fn is_reachable(&self) -> bool {
+        self.hosts.iter().any(|host| host.is_ready())
+    }
+}
+
+impl fmt::Display for ConnectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.hosts.is_empty() {
+            return write!(f, "contact point resolved to no addresses");
+        }
+        for host in &self.hosts {
+            try!(writeln!(f, "{}", host));
+        }
+        Ok(())
+    }
+}
+
+/// What a server said it supports, learned without logging in. See
+/// `Client::probe`.
+#[derive(Debug)]
+pub struct ProbeReport {
+    pub addr: SocketAddr,
+    /// The newest version the server answered OPTIONS over.
+    pub protocol_version: ProtocolVersion,
+    pub capabilities: ServerCapabilities,
+    /// What this build of the driver would negotiate with the server.
+    pub compression: Compression,
+    pub elapsed: Duration,
+}
+
+impl ProbeReport {
+    /// Why a session couldn't be used with this server, such as it not
+    /// offering a CQL version this driver speaks.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let cql_versions = &self.capabilities.cql_versions;
+        if cql_versions.is_empty() {
+            problems.push("server offers no CQL_VERSION".to_string());
+        } else if !cql_versions.iter().any(|version| version.starts_with("3.")) {
+            problems.push(format!("server only offers CQL {}, not 3.x", cql_versions.join(", ")));
+        }
+        problems
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.problems().is_empty()
+    }
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let elapsed = self.elapsed.as_secs() * 1000 + self.elapsed.subsec_nanos() as u64 / 1_000_000;
+        try!(writeln!(f, "{}: answered OPTIONS over protocol v{} in {}ms", self.addr,
+                      self.protocol_version.number(), elapsed));
+        try!(writeln!(f, "  CQL versions: {}", self.capabilities.cql_versions.join(", ")));
+        let protocol_versions: Vec<String> = self.capabilities.protocol_versions.iter()
+            .map(|version| format!("v{}", version.number()))
+            .collect();
+        if !protocol_versions.is_empty() {
+            try!(writeln!(f, "  protocol versions: {}", protocol_versions.join(", ")));
+        }
+        try!(writeln!(f, "  compression: {} (would use {})", self.capabilities.compression.join(", "),
+                      self.compression.name().unwrap_or("none")));
+        for problem in self.problems() {
+            try!(writeln!(f, "  problem: {}", problem));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+//! Minimum write consistency per table.
+//!
+//! Some tables need every write to be durable on more than one replica no
+//! matter which code path writes to them:
+//!
+//! ```ignore
+//! let mut config = SessionConfig::default();
+//! config.consistency_policy = ConsistencyPolicy::new().require("billing.payments", Consistency::Quorum);
+//! ```
+//!
+//! The session checks the table of every INSERT, UPDATE and DELETE it sends,
+//! including those in batches, and raises a lower consistency to the one
+//! required, publishing a `Warning::ConsistencyRaised` to its subscribers
+//! so the offending code can be fixed. Reads are left alone.
+//!
+//! With `SessionConfig::check_replication` set, the session also checks
+//! that the keyspace written to has enough replicas for the consistency a
+//! write uses, e.g. that a keyspace with one replica isn't written at
+//! `TWO`, or one without replicas in the local datacenter at
+//! `LOCAL_QUORUM`. Such writes fail up front with an error naming the
+//! keyspace instead of reaching the server and failing as unavailable.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use errors::MyError;
+use protocol::{Consistency, Result};
+use session::Session;
+use warning::{Warning, WarningListener};
+
+/// How long a keyspace's replication settings are trusted before being
+/// read again, so an ALTER KEYSPACE is picked up.
+pub const REPLICATION_CACHE_SECS: u64 = 60;
+
+/// Tables and the lowest consistency writes to them may use.
+#[derive(Debug, Clone, Default)]
+pub struct ConsistencyPolicy {
+    required: HashMap<String, Consistency>,
+}
+
+impl ConsistencyPolicy {
+    pub fn new() -> ConsistencyPolicy {
+        ConsistencyPolicy::default()
+    }
+
+    /// Requires writes to `table` to use at least `consistency`. An
+    /// unqualified name applies to the table in every keyspace, and a
+    /// qualified one also applies to statements that leave the keyspace
+    /// out, since the session can't always tell which keyspace those run in.
+    pub fn require(mut self, table: &str, consistency: Consistency) -> ConsistencyPolicy {
+        self.required.insert(normalize(table), consistency);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.required.is_empty()
+    }
+
+    /// The strongest consistency required by any table `query` writes to.
+    pub fn required(&self, query: &str) -> Option<Consistency> {
+        let mut strongest: Option<Consistency> = None;
+        for table in written_tables(query) {
+            for (name, &consistency) in &self.required {
+                if same_table(name, &table) && strongest.map_or(true, |s| strength(consistency) > strength(s)) {
+                    strongest = Some(consistency);
+                }
+            }
+        }
+        strongest
+    }
+
+    /// The consistency to send the statements in `queries` at, which is
+    /// `requested` unless one of them writes to a table requiring more.
+    /// Raising it is reported to `warnings`.
+    pub fn enforce<'q, I>(&self, queries: I, requested: Consistency, warnings: &WarningListener) -> Consistency
+        where I: IntoIterator<Item = &'q str>
+    {
+        if self.is_empty() {
+            return requested;
+        }
+        let mut enforced = requested;
+        let mut offending = None;
+        for query in queries {
+            if let Some(required) = self.required(query) {
+                if strength(required) > strength(enforced) {
+                    enforced = required;
+                    offending = Some(query);
+                }
+            }
+        }
+        if let Some(query) = offending {
+            warnings.on_warning(&Warning::ConsistencyRaised {
+                query: query.to_string(),
+                requested: requested,
+                enforced: enforced,
+            });
+        }
+        enforced
+    }
+}
+
+/// Orders consistency levels by how many replicas must acknowledge a write.
+/// The serial levels only apply to the Paxos phase of conditional updates,
+/// so they don't count as a write consistency at all.
+fn strength(consistency: Consistency) -> u8 {
+    match consistency {
+        Consistency::Any | Consistency::Serial | Consistency::LocalSerial => 0,
+        Consistency::One | Consistency::LocalOne => 1,
+        Consistency::Two => 2,
+        Consistency::Three => 3,
+        Consistency::LocalQuorum => 4,
+        Consistency::Quorum => 5,
+        Consistency::EachQuorum => 6,
+        Consistency::All => 7,
+    }
+}
+
+/// The tables written to by the INSERT, UPDATE and DELETE statements in
+/// `query`, which may be a BEGIN BATCH block. Names inside string literals
+/// are ignored.
+pub fn written_tables(query: &str) -> Vec<String> {
+    tables(query, false)
+}
+
+/// Like `written_tables`, but also the tables SELECT statements read.
+pub fn accessed_tables(query: &str) -> Vec<String> {
+    tables(query, true)
+}
+
+fn tables(query: &str, reads: bool) -> Vec<String> {
+    let words = words(query);
+    let mut tables = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        // DELETE and SELECT name their table after FROM.
+        let names_from = is_keyword(&words[i], "DELETE") || (reads && is_keyword(&words[i], "SELECT"));
+        let table = if is_keyword(&words[i], "INSERT") && words.get(i + 1).map_or(false, |w| is_keyword(w, "INTO")) {
+            words.get(i + 2)
+        } else if is_keyword(&words[i], "UPDATE") {
+            words.get(i + 1)
+        } else if names_from {
+            match words[i + 1..].iter().position(|w| is_keyword(w, "FROM")) {
+                Some(from) => {
+                    i += from + 1;
+                    words.get(i + 1)
+                },
+                None => None,
+            }
+        } else {
+            None
+        };
+        if let Some(table) = table {
+            tables.push(normalize(table));
+        }
+        i += 1;
+    }
+    tables
+}
+
+/// Splits `query` into words outside of string literals. Parentheses,
+/// commas and semicolons separate words, so `t(a, b)` yields `t`.
+fn words(query: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_literal = false;
+    for c in query.chars() {
+        if c == '\'' {
+            in_literal = !in_literal;
+        }
+        if in_literal || c == '\'' || c.is_whitespace() || c == '(' || c == ')' || c == ',' || c == ';' {
+            if !word.is_empty() {
+                words.push(word.clone());
+                word.clear();
+            }
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn is_keyword(word: &str, keyword: &str) -> bool {
+    word.eq_ignore_ascii_case(keyword)
+}
+
+/// Lowercases unquoted identifiers and strips the quotes from quoted ones,
+/// the way Cassandra resolves them.
+fn normalize(name: &str) -> String {
+    let parts: Vec<String> = name.split('.').map(|part| {
+        if part.len() >= 2 && part.starts_with('"') && part.ends_with('"') {
+            part[1..part.len() - 1].to_string()
+        } else {
+            part.to_lowercase()
+        }
+    }).collect();
+    parts.join(".")
+}
+
+fn same_table(required: &str, written: &str) -> bool {
+    if required == written {
+        return true;
+    }
+    let table_name = |name: &str| name.rsplit('.').next().unwrap_or("").to_string();
+    let qualified = |name: &str| name.contains('.');
+    (!qualified(required) || !qualified(written)) && table_name(required) == table_name(written)
+}
+
+/// A keyspace's replication strategy and how many replicas it keeps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Replication {
+    Simple(u32),
+    /// Replicas per datacenter.
+    NetworkTopology(HashMap<String, u32>),
+    /// Any other strategy, such as the `LocalStrategy` of system keyspaces,
+    /// by class name. Writes to these aren't checked.
+    Other(String),
+}
+
+impl Replication {
+    /// Reads the replication of `keyspace` from a Cassandra 3 cluster's
+    /// `system_schema` tables.
+    pub fn fetch(session: &Session, keyspace: &str) -> Result<Replication> {
+        let result = try!(session.query("SELECT replication FROM system_schema.keyspaces WHERE keyspace_name = ?",
+                                        &[&keyspace]));
+        let options: Option<HashMap<String, String>> = match result.rows.first() {
+            Some(row) => try!(row.get("replication")),
+            None => return Err(MyError::Protocol(format!("Keyspace {} doesn't exist", keyspace))),
+        };
+        Replication::from_options(&options.unwrap_or_default())
+    }
+
+    /// Parses the options of a keyspace's `replication` map.
+    pub fn from_options(options: &HashMap<String, String>) -> Result<Replication> {
+        let class = match options.get("class") {
+            Some(class) => class.rsplit('.').next().unwrap_or(""),
+            None => return Err(MyError::Protocol("Replication options have no class".to_string())),
+        };
+        match class {
+            "SimpleStrategy" => {
+                let factor = try!(options.get("replication_factor").ok_or_else(|| {
+                    MyError::Protocol("SimpleStrategy replication has no replication_factor".to_string())
+                }));
+                Ok(Replication::Simple(try!(replica_count(factor))))
+            },
+            "NetworkTopologyStrategy" => {
+                let mut datacenters = HashMap::new();
+                for (name, factor) in options {
+                    if name != "class" {
+                        datacenters.insert(name.clone(), try!(replica_count(factor)));
+                    }
+                }
+                Ok(Replication::NetworkTopology(datacenters))
+            },
+            _ => Ok(Replication::Other(class.to_string())),
+        }
+    }
+
+    /// Replicas across all datacenters.
+    pub fn replicas(&self) -> Option<u32> {
+        match *self {
+            Replication::Simple(factor) => Some(factor),
+            Replication::NetworkTopology(ref datacenters) => Some(datacenters.values().sum()),
+            Replication::Other(_) => None,
+        }
+    }
+
+    /// Fails if writes to `keyspace` can never reach `consistency`.
+    /// `local_dc` is the datacenter of the coordinator, which the local
+    /// levels count replicas in; without it they count every replica.
+    pub fn check(&self, keyspace: &str, consistency: Consistency, local_dc: Option<&str>) -> Result<()> {
+        let total = match self.replicas() {
+            Some(total) => total,
+            None => return Ok(()),
+        };
+        let needed = match consistency {
+            Consistency::Any => 0,
+            Consistency::Two => 2,
+            Consistency::Three => 3,
+            _ => 1,
+        };
+        let local = match consistency {
+            Consistency::LocalOne | Consistency::LocalQuorum | Consistency::LocalSerial => local_dc,
+            _ => None,
+        };
+        let (available, place) = match (self, local) {
+            (&Replication::NetworkTopology(ref datacenters), Some(dc)) => {
+                (datacenters.get(dc).cloned().unwrap_or(0), format!(" in datacenter {}", dc))
+            },
+            (&Replication::Simple(_), _) if consistency == Consistency::EachQuorum => {
+                return Err(MyError::Protocol(format!("Keyspace {} uses SimpleStrategy, which doesn't support \
+                                                      consistency {}", keyspace, consistency)));
+            },
+            _ => (total, String::new()),
+        };
+        if available < needed {
+            return Err(MyError::Protocol(format!("Keyspace {} has {} replicas{}, too few for consistency {}",
+                                                 keyspace, available, place, consistency)));
+        }
+        Ok(())
+    }
+}
+
+/// Replica counts like `3`, or `3/1` for a keyspace with transient
+/// replicas, which still count towards the consistency.
+fn replica_count(factor: &str) -> Result<u32> {
+    let total = factor.split('/').next().unwrap_or("").trim();
+    total.parse().map_err(|_| MyError::Protocol(format!("Invalid replication factor {:?}", factor)))
+}
+
+/// The replication of each keyspace a session writes to, read on first use.
+#[derive(Debug, Default)]
+pub struct ReplicationCache {
+    keyspaces: Mutex<HashMap<String, (Replication, Instant)>>,
+    local_dc: Mutex<Option<Option<String>>>,
+}
+
+impl ReplicationCache {
+    pub fn new() -> ReplicationCache {
+        ReplicationCache::default()
+    }
+
+    /// Checks every keyspace the statements in `queries` write to. Tables
+    /// without a keyspace are taken to be in `keyspace`, and are skipped if
+    /// that isn't given either.
+    pub fn check<'q, I>(&self, session: &Session, queries: I, keyspace: Option<&str>, consistency: Consistency)
+                        -> Result<()>
+        where I: IntoIterator<Item = &'q str>
+    {
+        for query in queries {
+            for table in written_tables(query) {
+                let table_keyspace = match table.rfind('.') {
+                    Some(dot) => table[..dot].to_string(),
+                    None => match keyspace {
+                        Some(keyspace) => normalize(keyspace),
+                        None => continue,
+                    },
+                };
+                let replication = try!(self.replication(session, &table_keyspace));
+                let local_dc = match replication {
+                    Replication::NetworkTopology(_) => try!(self.local_dc(session)),
+                    _ => None,
+                };
+                try!(replication.check(&table_keyspace, consistency, local_dc.as_ref().map(|dc| dc.as_ref())));
+            }
+        }
+        Ok(())
+    }
+
+    fn replication(&self, session: &Session, keyspace: &str) -> Result<Replication> {
+        if let Some(&(ref replication, fetched)) = self.keyspaces.lock().unwrap().get(keyspace) {
+            if fetched.elapsed() < Duration::from_secs(REPLICATION_CACHE_SECS) {
+                return Ok(replication.clone());
+            }
+        }
+        let replication = try!(Replication::fetch(session, keyspace));
+        self.keyspaces.lock().unwrap().insert(keyspace.to_string(), (replication.clone(), Instant::now()));
+        Ok(replication)
+    }
+
+    /// The datacenter of the node the session is connected to.
+    fn local_dc(&self, session: &Session) -> Result<Option<String>> {
+        if let Some(ref dc) = *self.local_dc.lock().unwrap() {
+            return Ok(dc.clone());
+        }
+        let result = try!(session.query("SELECT data_center FROM system.local", &[]));
+        let dc: Option<String> = match result.rows.first() {
+            Some(row) => try!(row.get("data_center")),
+            None => None,
+        };
+        *self.local_dc.lock().unwrap() = Some(dc.clone());
+        Ok(dc)
+    }
+}
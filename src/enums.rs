@@ -0,0 +1,115 @@
+//! Storing fieldless Rust enums in CQL columns, for the status and kind
+//! columns most schemas have. Enums are declared with `cql_enum!`, which
+//! implements `ToCQL`, `FromCQL` and `CQLEnum` for them.
+//!
+//! ```ignore
+//! cql_enum! {
+//!     #[cql(rename_all = "snake_case")]
+//!     #[derive(Debug, Clone, Copy, PartialEq)]
+//!     pub enum Status { Active, OnHold, Closed }
+//! }
+//! ```
+//!
+//! Variants are stored as text: their names as written, or converted with
+//! `rename_all` to one of the cases `lowercase`, `UPPERCASE`,
+//! `PascalCase`, `camelCase`, `snake_case`, `SCREAMING_SNAKE_CASE`,
+//! `kebab-case` or `SCREAMING-KEBAB-CASE`. Each variant can instead be
+//! given its value, which also stores it in an `int` column:
+//!
+//! ```ignore
+//! cql_enum! {
+//!     #[derive(Debug, Clone, Copy)]
+//!     pub enum Priority { Low = 1, Normal = 5, High = 10 }
+//! }
+//! ```
+//!
+//! Use `1i16` or `1i8` for `smallint` and `tinyint` columns. Reading a
+//! value no variant is stored as fails rather than picking a default, and
+//! `check` catches two variants stored as the same value, e.g. after a
+//! rename, before any are written. Enums read with `Row::get` and
+//! `cql_row!` need to derive `Clone`.
+
+use std::collections::HashMap;
+use std::str;
+
+use errors::MyError;
+use protocol::Result;
+use types::{FromCQL, ToCQL};
+
+/// An enum declared with `cql_enum!`.
+pub trait CQLEnum: ToCQL + FromCQL {
+    /// Every variant, with its name as `Type::Variant`.
+    fn variants() -> Vec<(&'static str, Self)>;
+}
+
+/// Checks that every variant of `T` is stored as a value of its own and
+/// reads back as itself.
+pub fn check<T: CQLEnum>() -> Result<()> {
+    let mut seen: HashMap<Vec<u8>, &'static str> = HashMap::new();
+    for (name, variant) in T::variants() {
+        let value = try!(to_bytes(&variant));
+        if let Some(other) = seen.insert(value.clone(), name) {
+            return Err(MyError::Protocol(format!("{} and {} are both stored as {}", other, name, describe(&value))));
+        }
+        let read = try!(T::parse(&value));
+        if try!(to_bytes(&read)) != value {
+            return Err(MyError::Protocol(format!("{} doesn't read back as itself", name)));
+        }
+    }
+    Ok(())
+}
+
+/// `variant`, an enum variant's name, in the naming convention `case`.
+/// Words start at each uppercase letter.
+pub fn rename_variant(variant: &str, case: &str) -> Result<String> {
+    let mut words: Vec<String> = Vec::new();
+    for c in variant.chars() {
+        if c.is_uppercase() || words.is_empty() {
+            words.push(String::new());
+        }
+        words.last_mut().unwrap().push(c);
+    }
+    let lower: Vec<String> = words.iter().map(|word| word.to_lowercase()).collect();
+    let upper: Vec<String> = words.iter().map(|word| word.to_uppercase()).collect();
+    Ok(match case {
+        "lowercase" => lower.concat(),
+        "UPPERCASE" => upper.concat(),
+        "PascalCase" => variant.to_string(),
+        "camelCase" => {
+            let mut chars = variant.chars();
+            match chars.next() {
+                Some(first) => first.to_lowercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        },
+        "snake_case" => lower.join("_"),
+        "SCREAMING_SNAKE_CASE" => upper.join("_"),
+        "kebab-case" => lower.join("-"),
+        "SCREAMING-KEBAB-CASE" => upper.join("-"),
+        _ => return Err(MyError::Protocol(format!("Unknown rename_all case {}", case))),
+    })
+}
+
+/// The error for reading `value` into `name`, an enum none of whose
+/// variants is stored as it.
+pub fn unknown_variant(name: &str, value: &[u8]) -> MyError {
+    MyError::Protocol(format!("No variant of {} is stored as {}", name, describe(value)))
+}
+
+/// Whether `value` is what `variant` is stored as.
+pub fn stored_as<T: ToCQL>(variant: &T, value: &[u8]) -> Result<bool> {
+    Ok(try!(to_bytes(variant)) == value)
+}
+
+fn to_bytes<T: ToCQL>(value: &T) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    try!(value.serialize(&mut buf));
+    Ok(buf)
+}
+
+fn describe(value: &[u8]) -> String {
+    match str::from_utf8(value) {
+        Ok(text) if !text.chars().any(char::is_control) => format!("'{}'", text),
+        _ => format!("{:?}", value),
+    }
+}
@@ -1,11 +1,414 @@
 use std::io;
 use std::fmt;
 use std::error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use protocol::Consistency;
+use statement::statement_hash;
+
+/// Longest statement snippet kept in an `ErrorContext`.
+const MAX_STATEMENT_LENGTH: usize = 120;
 
 #[derive(Debug)]
 pub enum MyError {
     IO(io::Error),
     Protocol(String),
+    /// An error response from the server: its error code, message, and
+    /// the details sent with errors of some codes. See `ErrorCode` for
+    /// what the codes mean.
+    Server(u32, String, Option<ErrorDetails>),
+    Timeout(String),
+    Closed,
+    /// A request failed; the context says which one and where.
+    Request(ErrorContext, Box<MyError>),
+}
+
+impl MyError {
+    /// The underlying error, without any request context.
+    pub fn root(&self) -> &MyError {
+        match *self {
+            MyError::Request(_, ref cause) => cause.root(),
+            _ => self,
+        }
+    }
+
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match *self {
+            MyError::Request(ref context, _) => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The code of a server error, for matching on what went wrong.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match *self.root() {
+            MyError::Server(code, _, _) => Some(ErrorCode::from_code(code)),
+            _ => None,
+        }
+    }
+
+    /// What the server said about a server error beyond its message.
+    pub fn details(&self) -> Option<&ErrorDetails> {
+        match *self.root() {
+            MyError::Server(_, _, ref details) => details.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Whether sending the same request again might succeed: connection
+    /// failures, timeouts, and server errors caused by load or availability.
+    pub fn is_retryable(&self) -> bool {
+        match *self.root() {
+            MyError::IO(_) | MyError::Timeout(_) => true,
+            _ => match self.error_code() {
+                Some(ErrorCode::ServerError) | Some(ErrorCode::Unavailable) | Some(ErrorCode::Overloaded) |
+                Some(ErrorCode::IsBootstrapping) | Some(ErrorCode::TruncateError) |
+                Some(ErrorCode::WriteTimeout) | Some(ErrorCode::ReadTimeout) | Some(ErrorCode::Unprepared) => true,
+                _ => false,
+            },
+        }
+    }
+
+    /// Whether sending the same request again is safe even for a statement
+    /// that isn't idempotent, because the first attempt can't have been
+    /// applied: the coordinator turned it down before asking any replica,
+    /// it was a read, or it timed out writing a batch log. Connection
+    /// failures and other timeouts may have left a write applied, so
+    /// `is_retryable` alone only suits idempotent statements.
+    pub fn is_idempotent_safe_retry(&self) -> bool {
+        match self.error_code() {
+            Some(ErrorCode::Unavailable) | Some(ErrorCode::Overloaded) | Some(ErrorCode::IsBootstrapping) |
+            Some(ErrorCode::ReadTimeout) | Some(ErrorCode::Unprepared) => true,
+            Some(ErrorCode::WriteTimeout) => match self.details() {
+                Some(&ErrorDetails::WriteTimeout(ref timeout)) => timeout.worth_retrying(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// A client-side timeout or a server read/write timeout.
+    pub fn is_timeout(&self) -> bool {
+        match *self.root() {
+            MyError::Timeout(_) => true,
+            _ => match self.error_code() {
+                Some(ErrorCode::WriteTimeout) | Some(ErrorCode::ReadTimeout) => true,
+                _ => false,
+            },
+        }
+    }
+
+    /// Not enough replicas were alive to meet the requested consistency.
+    pub fn is_unavailable(&self) -> bool {
+        self.error_code() == Some(ErrorCode::Unavailable)
+    }
+
+    /// The keyspace or table a CREATE statement tried to create already
+    /// exists. See `details` for which one.
+    pub fn is_already_exists(&self) -> bool {
+        self.error_code() == Some(ErrorCode::AlreadyExists)
+    }
+
+    /// The id of the prepared statement the server didn't know.
+    pub fn unprepared_id(&self) -> Option<&[u8]> {
+        match self.details() {
+            Some(&ErrorDetails::Unprepared(ref unprepared)) => Some(&unprepared.id),
+            _ => None,
+        }
+    }
+
+    /// The user-defined function that failed, for a function failure.
+    pub fn function_failure(&self) -> Option<&FunctionFailure> {
+        match self.details() {
+            Some(&ErrorDetails::FunctionFailure(ref failure)) => Some(failure),
+            _ => None,
+        }
+    }
+
+    /// Configuration errors, already existing keyspaces or tables, and
+    /// statements the server no longer has prepared.
+    pub fn is_schema_related(&self) -> bool {
+        match self.error_code() {
+            Some(ErrorCode::ConfigError) | Some(ErrorCode::AlreadyExists) | Some(ErrorCode::Unprepared) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The error codes a server may answer a request with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    ServerError,
+    ProtocolError,
+    BadCredentials,
+    Unavailable,
+    Overloaded,
+    IsBootstrapping,
+    TruncateError,
+    WriteTimeout,
+    ReadTimeout,
+    ReadFailure,
+    FunctionFailure,
+    WriteFailure,
+    CdcWriteFailure,
+    CasWriteUnknown,
+    SyntaxError,
+    Unauthorized,
+    Invalid,
+    ConfigError,
+    AlreadyExists,
+    Unprepared,
+    /// A code outside the protocol spec, such as a vendor extension.
+    Other(u32),
+}
+
+/// The fields that follow the message for some error codes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorDetails {
+    Unavailable(Unavailable),
+    ReadTimeout(ReadTimeout),
+    WriteTimeout(WriteTimeout),
+    FunctionFailure(FunctionFailure),
+    AlreadyExists(AlreadyExists),
+    Unprepared(Unprepared),
+}
+
+/// Why a request failed as unavailable, before any replica was asked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unavailable {
+    pub consistency: Consistency,
+    /// Replicas the consistency needed to be alive.
+    pub required: i32,
+    /// Replicas the coordinator believed were alive.
+    pub alive: i32,
+}
+
+/// How far a read got before the coordinator gave up waiting on replicas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadTimeout {
+    pub consistency: Consistency,
+    /// Replicas that answered.
+    pub received: i32,
+    /// Replicas the consistency needed to answer.
+    pub block_for: i32,
+    /// Whether the replica asked for the data itself, rather than a
+    /// digest, answered.
+    pub data_present: bool,
+}
+
+impl ReadTimeout {
+    /// Enough replicas answered, but only with digests. Reading again
+    /// usually succeeds, since the data replica was most likely just slow.
+    pub fn worth_retrying(&self) -> bool {
+        self.received >= self.block_for && !self.data_present
+    }
+}
+
+/// How far a write got before the coordinator gave up waiting on replicas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteTimeout {
+    pub consistency: Consistency,
+    /// Replicas that acknowledged the write.
+    pub received: i32,
+    /// Replicas the consistency needed to acknowledge it.
+    pub block_for: i32,
+    pub write_type: WriteType,
+    /// Times a CAS write lost to a competing one, sent from v5 on with
+    /// `WriteType::Cas`.
+    pub contentions: Option<u16>,
+}
+
+impl WriteTimeout {
+    /// Only a timeout writing the batch log is safe to retry no matter the
+    /// statement: the batch wasn't applied anywhere yet. Other writes may
+    /// have been applied on some replicas.
+    pub fn worth_retrying(&self) -> bool {
+        self.write_type == WriteType::BatchLog
+    }
+}
+
+/// A user-defined function that threw while the server ran it, sent from
+/// v4 on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionFailure {
+    pub keyspace: String,
+    pub function: String,
+    /// The CQL types of the function's arguments, which tell overloads of
+    /// the same name apart.
+    pub arg_types: Vec<String>,
+}
+
+impl FunctionFailure {
+    /// The function's qualified name and argument types, such as
+    /// `ks.avg_state(tuple<int, bigint>, int)`.
+    pub fn signature(&self) -> String {
+        format!("{}.{}({})", self.keyspace, self.function, self.arg_types.join(", "))
+    }
+}
+
+/// The keyspace or table a CREATE statement tried to create.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlreadyExists {
+    pub keyspace: String,
+    /// `None` if the keyspace itself already exists.
+    pub table: Option<String>,
+}
+
+/// A prepared statement the server doesn't know, usually because it
+/// restarted or evicted the statement from its cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unprepared {
+    /// The id the request was sent with.
+    pub id: Vec<u8>,
+}
+
+/// The kind of write that timed out.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WriteType {
+    /// A write other than a batch or a counter update.
+    Simple,
+    /// A logged batch, after its batch log was written, so it will be
+    /// applied eventually.
+    Batch,
+    UnloggedBatch,
+    Counter,
+    /// Writing a logged batch's batch log.
+    BatchLog,
+    /// The commit of a lightweight transaction.
+    Cas,
+    /// Updating a materialized view.
+    View,
+    /// Writing to a table with CDC enabled.
+    Cdc,
+    /// A write type newer than this driver.
+    Other(String),
+}
+
+const WRITE_TYPES: &'static [(&'static str, WriteType)] = &[
+    ("SIMPLE", WriteType::Simple),
+    ("BATCH", WriteType::Batch),
+    ("UNLOGGED_BATCH", WriteType::UnloggedBatch),
+    ("COUNTER", WriteType::Counter),
+    ("BATCH_LOG", WriteType::BatchLog),
+    ("CAS", WriteType::Cas),
+    ("VIEW", WriteType::View),
+    ("CDC", WriteType::Cdc),
+];
+
+impl WriteType {
+    pub fn from_name(name: &str) -> WriteType {
+        WRITE_TYPES.iter()
+            .find(|&&(known, _)| known == name)
+            .map_or_else(|| WriteType::Other(name.to_string()), |&(_, ref write_type)| write_type.clone())
+    }
+
+    /// The name the protocol uses, e.g. `BATCH_LOG`.
+    pub fn name(&self) -> &str {
+        match *self {
+            WriteType::Other(ref name) => name,
+            _ => WRITE_TYPES.iter().find(|&&(_, ref write_type)| write_type == self).map_or("", |&(name, _)| name),
+        }
+    }
+}
+
+const ERROR_CODES: &'static [(ErrorCode, u32, &'static str)] = &[
+    (ErrorCode::ServerError, 0x0000, "server error"),
+    (ErrorCode::ProtocolError, 0x000A, "protocol error"),
+    (ErrorCode::BadCredentials, 0x0100, "bad credentials"),
+    (ErrorCode::Unavailable, 0x1000, "unavailable"),
+    (ErrorCode::Overloaded, 0x1001, "overloaded"),
+    (ErrorCode::IsBootstrapping, 0x1002, "is bootstrapping"),
+    (ErrorCode::TruncateError, 0x1003, "truncate error"),
+    (ErrorCode::WriteTimeout, 0x1100, "write timeout"),
+    (ErrorCode::ReadTimeout, 0x1200, "read timeout"),
+    (ErrorCode::ReadFailure, 0x1300, "read failure"),
+    (ErrorCode::FunctionFailure, 0x1400, "function failure"),
+    (ErrorCode::WriteFailure, 0x1500, "write failure"),
+    (ErrorCode::CdcWriteFailure, 0x1600, "CDC write failure"),
+    (ErrorCode::CasWriteUnknown, 0x1700, "CAS write unknown"),
+    (ErrorCode::SyntaxError, 0x2000, "syntax error"),
+    (ErrorCode::Unauthorized, 0x2100, "unauthorized"),
+    (ErrorCode::Invalid, 0x2200, "invalid"),
+    (ErrorCode::ConfigError, 0x2300, "config error"),
+    (ErrorCode::AlreadyExists, 0x2400, "already exists"),
+    (ErrorCode::Unprepared, 0x2500, "unprepared"),
+];
+
+impl ErrorCode {
+    pub fn from_code(code: u32) -> ErrorCode {
+        ERROR_CODES.iter()
+            .find(|&&(_, known, _)| known == code)
+            .map_or(ErrorCode::Other(code), |&(error_code, _, _)| error_code)
+    }
+
+    pub fn code(&self) -> u32 {
+        match *self {
+            ErrorCode::Other(code) => code,
+            _ => ERROR_CODES.iter().find(|&&(error_code, _, _)| error_code == *self).map_or(0, |&(_, code, _)| code),
+        }
+    }
+
+    /// How the protocol spec describes the error, or `None` for `Other`.
+    pub fn description(&self) -> Option<&'static str> {
+        ERROR_CODES.iter().find(|&&(error_code, _, _)| error_code == *self).map(|&(_, _, description)| description)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ErrorContext {
+    /// The statement text, truncated and with string literals redacted.
+    pub statement: String,
+    /// Hash of the full statement text, see `statement::statement_hash`.
+    pub statement_hash: u64,
+    pub host: Option<SocketAddr>,
+    pub attempt: u32,
+    pub elapsed: Duration,
+}
+
+impl ErrorContext {
+    pub fn new(statement: &str, host: Option<SocketAddr>, attempt: u32, elapsed: Duration) -> ErrorContext {
+        ErrorContext {
+            statement: redact(statement),
+            statement_hash: statement_hash(statement),
+            host: host,
+            attempt: attempt,
+            elapsed: elapsed,
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "statement {:016x} \"{}\"", self.statement_hash, self.statement));
+        if let Some(host) = self.host {
+            try!(write!(f, " on {}", host));
+        }
+        write!(f, ", attempt {}, after {}ms", self.attempt,
+               self.elapsed.as_secs() * 1000 + self.elapsed.subsec_nanos() as u64 / 1_000_000)
+    }
+}
+
+/// Blanks out string literals, which may hold user data, and truncates the
+/// statement so it stays readable in logs.
+fn redact(statement: &str) -> String {
+    let mut redacted = String::with_capacity(statement.len().min(MAX_STATEMENT_LENGTH));
+    let mut in_literal = false;
+    for c in statement.chars() {
+        if c == '\'' {
+            in_literal = !in_literal;
+            if in_literal {
+                redacted.push_str("'...'");
+            }
+        } else if !in_literal {
+            redacted.push(c);
+        }
+        if redacted.len() >= MAX_STATEMENT_LENGTH {
+            redacted.push_str("...");
+            break;
+        }
+    }
+    redacted
 }
 
 impl From<io::Error> for MyError {
@@ -19,6 +422,13 @@ impl fmt::Display for MyError {
         match *self {
             MyError::IO(ref err) => write!(f, "IO error: {}", err),
             MyError::Protocol(ref desc) => write!(f, "Protocol error: {}", desc),
+            MyError::Server(code, ref message, _) => match ErrorCode::from_code(code).description() {
+                Some(description) => write!(f, "Server error 0x{:04X} ({}): {}", code, description, message),
+                None => write!(f, "Server error 0x{:04X}: {}", code, message),
+            },
+            MyError::Timeout(ref desc) => write!(f, "Timed out: {}", desc),
+            MyError::Closed => write!(f, "Session closed"),
+            MyError::Request(ref context, ref cause) => write!(f, "{} ({})", cause, context),
         }
     }
 }
@@ -28,6 +438,10 @@ impl error::Error for MyError {
         match *self {
             MyError::IO(ref err) => err.description(),
             MyError::Protocol(ref desc) => desc,
+            MyError::Server(_, ref message, _) => message,
+            MyError::Timeout(ref desc) => desc,
+            MyError::Closed => "session closed",
+            MyError::Request(_, ref cause) => cause.description(),
         }
     }
 
@@ -35,6 +449,10 @@ impl error::Error for MyError {
         match *self {
             MyError::IO(ref err) => Some(err),
             MyError::Protocol(_) => None,
+            MyError::Server(..) => None,
+            MyError::Timeout(_) => None,
+            MyError::Closed => None,
+            MyError::Request(_, ref cause) => Some(&**cause),
         }
     }
 }
@@ -0,0 +1,133 @@
+//! Estimating the size of a table from `system.size_estimates`.
+//!
+//! Each node periodically records, for every token range it owns, roughly
+//! how many partitions a table has in the range and how large they are on
+//! average. Reading that table is far cheaper than counting, and is enough
+//! to decide how many pieces to split a full table scan into:
+//!
+//! ```ignore
+//! let estimate = try!(SizeEstimate::fetch(&session, "ks", "events"));
+//! let splits = estimate.splits(DEFAULT_SPLIT_SIZE);
+//! ```
+//!
+//! The estimates are local to the node queried, so they only cover the
+//! ranges it owns. For Murmur3 tokens the totals are scaled up by the
+//! share of the ring those ranges cover; the figures are still only as
+//! fresh as the node's last flush, and may be empty for a new table.
+
+use protocol::Result;
+use session::Session;
+
+/// Bytes of partition data per split `SizeEstimate::splits` aims for.
+pub const DEFAULT_SPLIT_SIZE: i64 = 64 * 1024 * 1024;
+
+/// A table's estimated size across the whole ring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeEstimate {
+    /// How many token ranges the node had estimates for.
+    pub ranges: usize,
+    pub partitions: i64,
+    /// In bytes, averaged over every partition.
+    pub mean_partition_size: i64,
+    /// The share of the ring the node's ranges cover, between 0 and 1, or
+    /// `None` if the tokens aren't Murmur3 tokens. Partitions are only
+    /// scaled up to the whole ring if this is known.
+    pub ring_fraction: Option<f64>,
+}
+
+impl SizeEstimate {
+    /// Reads the estimates of the node the session is connected to. A
+    /// table the node hasn't flushed yet has no ranges and no partitions.
+    pub fn fetch(session: &Session, keyspace: &str, table: &str) -> Result<SizeEstimate> {
+        let result = try!(session.query("SELECT range_start, range_end, mean_partition_size, partitions_count \
+                                         FROM system.size_estimates WHERE keyspace_name = ? AND table_name = ?",
+                                        &[&keyspace, &table]));
+        let mut ranges = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            let start: Option<String> = try!(row.get("range_start"));
+            let end: Option<String> = try!(row.get("range_end"));
+            let mean_size: Option<i64> = try!(row.get("mean_partition_size"));
+            let count: Option<i64> = try!(row.get("partitions_count"));
+            ranges.push(RangeEstimate {
+                start: start.unwrap_or_default(),
+                end: end.unwrap_or_default(),
+                partitions: count.unwrap_or(0),
+                mean_partition_size: mean_size.unwrap_or(0),
+            });
+        }
+        Ok(SizeEstimate::from_ranges(&ranges))
+    }
+
+    /// Combines the estimates of each range one node owns.
+    pub fn from_ranges(ranges: &[RangeEstimate]) -> SizeEstimate {
+        let partitions: i64 = ranges.iter().map(|range| range.partitions).sum();
+        let bytes: i64 = ranges.iter()
+            .map(|range| range.partitions.saturating_mul(range.mean_partition_size))
+            .sum();
+        let mut ring_fraction = Some(0.0);
+        for range in ranges {
+            ring_fraction = match (ring_fraction, range.ring_fraction()) {
+                (Some(total), Some(fraction)) => Some(total + fraction),
+                _ => None,
+            };
+        }
+        let ring_fraction = ring_fraction.map(|fraction| fraction.min(1.0));
+        let scaled = match ring_fraction {
+            Some(fraction) if fraction > 0.0 => (partitions as f64 / fraction) as i64,
+            _ => partitions,
+        };
+        SizeEstimate {
+            ranges: ranges.len(),
+            partitions: scaled,
+            mean_partition_size: if partitions > 0 { bytes / partitions } else { 0 },
+            ring_fraction: ring_fraction,
+        }
+    }
+
+    /// Estimated bytes of partition data in the table.
+    pub fn total_bytes(&self) -> i64 {
+        self.partitions.saturating_mul(self.mean_partition_size)
+    }
+
+    /// How many splits to scan the table in so each covers about
+    /// `split_size` bytes. Always at least one.
+    pub fn splits(&self, split_size: i64) -> usize {
+        if split_size <= 0 {
+            return 1;
+        }
+        let splits = self.total_bytes().saturating_add(split_size - 1) / split_size;
+        splits.max(1) as usize
+    }
+}
+
+/// One row of `system.size_estimates`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeEstimate {
+    /// The range's tokens as the partitioner formats them. The start is
+    /// exclusive and the end inclusive.
+    pub start: String,
+    pub end: String,
+    pub partitions: i64,
+    pub mean_partition_size: i64,
+}
+
+impl RangeEstimate {
+    /// The share of the Murmur3 ring the range covers. A range may wrap
+    /// around the end of the ring, and one from a token to itself covers
+    /// all of it.
+    pub fn ring_fraction(&self) -> Option<f64> {
+        let start = match self.start.parse::<i64>() {
+            Ok(start) => start,
+            Err(_) => return None,
+        };
+        let end = match self.end.parse::<i64>() {
+            Ok(end) => end,
+            Err(_) => return None,
+        };
+        if start == end {
+            return Some(1.0);
+        }
+        let width = end.wrapping_sub(start) as u64;
+        Some(width as f64 / 18446744073709551616.0)
+    }
+}
@@ -0,0 +1,170 @@
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use byteorder::{BigEndian, ReadBytesExt};
+
+use errors::MyError;
+use protocol::{FromWire, Result};
+
+/// The kinds of event a connection can REGISTER for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum EventType {
+    TopologyChange,
+    StatusChange,
+    SchemaChange,
+}
+
+impl EventType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventType::TopologyChange => "TOPOLOGY_CHANGE",
+            EventType::StatusChange => "STATUS_CHANGE",
+            EventType::SchemaChange => "SCHEMA_CHANGE",
+        }
+    }
+}
+
+/// A notification the server pushes to registered connections.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    TopologyChange(TopologyChange, SocketAddr),
+    StatusChange(StatusChange, SocketAddr),
+    SchemaChange(SchemaChange),
+}
+
+impl Event {
+    pub fn event_type(&self) -> EventType {
+        match *self {
+            Event::TopologyChange(..) => EventType::TopologyChange,
+            Event::StatusChange(..) => EventType::StatusChange,
+            Event::SchemaChange(..) => EventType::SchemaChange,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TopologyChange {
+    NewNode,
+    RemovedNode,
+    MovedNode,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StatusChange {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChangeType {
+    Created,
+    Updated,
+    Dropped,
+}
+
+/// What a schema change applied to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaTarget {
+    Keyspace(String),
+    Table(String, String),
+    Type(String, String),
+    /// Keyspace, name and argument types.
+    Function(String, String, Vec<String>),
+    Aggregate(String, String, Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaChange {
+    pub change_type: ChangeType,
+    pub target: SchemaTarget,
+}
+
+impl FromWire for Event {
+    fn decode<T: Read>(buffer: &mut T) -> Result<Event> {
+        let kind = try!(String::decode(buffer));
+        match kind.as_ref() {
+            "TOPOLOGY_CHANGE" => {
+                let change = match try!(String::decode(buffer)).as_ref() {
+                    "NEW_NODE" => TopologyChange::NewNode,
+                    "REMOVED_NODE" => TopologyChange::RemovedNode,
+                    "MOVED_NODE" => TopologyChange::MovedNode,
+                    other => return Err(MyError::Protocol(format!("Unknown topology change: {}", other))),
+                };
+                Ok(Event::TopologyChange(change, try!(decode_inet(buffer))))
+            },
+            "STATUS_CHANGE" => {
+                let change = match try!(String::decode(buffer)).as_ref() {
+                    "UP" => StatusChange::Up,
+                    "DOWN" => StatusChange::Down,
+                    other => return Err(MyError::Protocol(format!("Unknown status change: {}", other))),
+                };
+                Ok(Event::StatusChange(change, try!(decode_inet(buffer))))
+            },
+            "SCHEMA_CHANGE" => Ok(Event::SchemaChange(try!(SchemaChange::decode(buffer)))),
+            other => Err(MyError::Protocol(format!("Unknown event type: {}", other))),
+        }
+    }
+}
+
+/// Also the body of a SCHEMA_CHANGE result.
+impl FromWire for SchemaChange {
+    fn decode<T: Read>(buffer: &mut T) -> Result<SchemaChange> {
+        let change_type = match try!(String::decode(buffer)).as_ref() {
+            "CREATED" => ChangeType::Created,
+            "UPDATED" => ChangeType::Updated,
+            "DROPPED" => ChangeType::Dropped,
+            other => return Err(MyError::Protocol(format!("Unknown schema change type: {}", other))),
+        };
+        let target = try!(String::decode(buffer));
+        let keyspace = try!(String::decode(buffer));
+        let target = match target.as_ref() {
+            "KEYSPACE" => SchemaTarget::Keyspace(keyspace),
+            "TABLE" => SchemaTarget::Table(keyspace, try!(String::decode(buffer))),
+            "TYPE" => SchemaTarget::Type(keyspace, try!(String::decode(buffer))),
+            "FUNCTION" => {
+                let name = try!(String::decode(buffer));
+                SchemaTarget::Function(keyspace, name, try!(decode_string_list(buffer)))
+            },
+            "AGGREGATE" => {
+                let name = try!(String::decode(buffer));
+                SchemaTarget::Aggregate(keyspace, name, try!(decode_string_list(buffer)))
+            },
+            other => return Err(MyError::Protocol(format!("Unknown schema change target: {}", other))),
+        };
+        Ok(SchemaChange {
+            change_type: change_type,
+            target: target,
+        })
+    }
+}
+
+/// An [inet]: address length, address bytes and an [int] port.
+fn decode_inet<T: Read>(buffer: &mut T) -> Result<SocketAddr> {
+    let len = try!(buffer.read_u8());
+    let ip = match len {
+        4 => {
+            let mut octets = [0u8; 4];
+            try!(buffer.read_exact(&mut octets));
+            IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+        },
+        16 => {
+            let mut segments = [0u16; 8];
+            for segment in segments.iter_mut() {
+                *segment = try!(buffer.read_u16::<BigEndian>());
+            }
+            IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                     segments[4], segments[5], segments[6], segments[7]))
+        },
+        _ => return Err(MyError::Protocol(format!("Invalid inet address length: {}", len))),
+    };
+    let port = try!(buffer.read_i32::<BigEndian>());
+    Ok(SocketAddr::new(ip, port as u16))
+}
+
+fn decode_string_list<T: Read>(buffer: &mut T) -> Result<Vec<String>> {
+    let count = try!(buffer.read_u16::<BigEndian>());
+    let mut list = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        list.push(try!(String::decode(buffer)));
+    }
+    Ok(list)
+}
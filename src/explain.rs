@@ -0,0 +1,271 @@
+//! Describing how a statement would be sent, without sending it.
+//!
+//! `Session::explain` and `Session::explain_prepared` resolve everything
+//! the session would decide for a request (the host, the consistency after
+//! the consistency policy, the paging and timestamp options) and serialize
+//! its values, then stop short of sending it:
+//!
+//! ```ignore
+//! let prepared = try!(session.prepare("SELECT * FROM ks.users WHERE id = ?"));
+//! println!("{}", try!(session.explain_prepared(&prepared, &[&id], Consistency::Quorum)));
+//! ```
+//!
+//! Values are summarized by type and size only, so explanations can be
+//! logged without leaking the data itself.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use errors::MyError;
+use hosts::HostState;
+use protocol::{Consistency, PreparedStatement, ProtocolVersion, Result};
+use statement::{statement_hash, Statement};
+use types::{CQLType, ToCQL};
+
+/// One bound value, as it would go over the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamSummary {
+    /// The bind marker's column, for prepared statements.
+    pub name: Option<String>,
+    pub datatype: Option<CQLType>,
+    /// Serialized size in bytes; `None` for `Unset`.
+    pub len: Option<usize>,
+}
+
+impl fmt::Display for ParamSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.name.as_ref().map_or("?", |n| n.as_ref())));
+        if let Some(ref datatype) = self.datatype {
+            try!(write!(f, " {:?}", datatype));
+        }
+        match self.len {
+            Some(len) => write!(f, ", {} bytes", len),
+            None => write!(f, ", unset"),
+        }
+    }
+}
+
+/// Everything that decides where and how a request runs.
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub query: String,
+    pub statement_hash: u64,
+    /// The connected host the request would go to, then the hosts a
+    /// reconnect would try, in order.
+    pub hosts: Vec<SocketAddr>,
+    pub protocol_version: Option<ProtocolVersion>,
+    pub keyspace: Option<String>,
+    pub consistency: Consistency,
+    pub page_size: Option<i32>,
+    pub timestamp: Option<i64>,
+    pub tracing: bool,
+    /// The serialized partition key, for prepared statements that bind
+    /// all of it. Sent as-is for a single column key, or as a composite
+    /// of every column otherwise.
+    pub routing_key: Option<Vec<u8>>,
+    /// The Murmur3 token of `routing_key`, which decides the replicas.
+    pub token: Option<i64>,
+    pub params: Vec<ParamSummary>,
+}
+
+impl Explanation {
+    pub fn for_statement(statement: &Statement, params: &[&ToCQL]) -> Result<Explanation> {
+        let mut summaries = Vec::with_capacity(params.len());
+        for param in params {
+            summaries.push(ParamSummary {
+                name: None,
+                datatype: None,
+                len: try!(param_len(*param)),
+            });
+        }
+        Ok(Explanation {
+            query: statement.query().to_string(),
+            statement_hash: statement.hash(),
+            hosts: Vec::new(),
+            protocol_version: None,
+            keyspace: statement.keyspace().map(|k| k.to_string()),
+            consistency: statement.consistency(),
+            page_size: statement.page_size(),
+            timestamp: statement.timestamp(),
+            tracing: statement.tracing(),
+            routing_key: None,
+            token: None,
+            params: summaries,
+        })
+    }
+
+    pub fn for_prepared(prepared: &PreparedStatement, params: &[&ToCQL], consistency: Consistency)
+                        -> Result<Explanation> {
+        if params.len() != prepared.params().len() {
+            return Err(MyError::Protocol(format!("Prepared statement expects {} values, got {}",
+                                                 prepared.params().len(), params.len())));
+        }
+        let mut summaries = Vec::with_capacity(params.len());
+        for (param, spec) in params.iter().zip(prepared.params()) {
+            summaries.push(ParamSummary {
+                name: Some(spec.name().to_string()),
+                datatype: Some(spec.datatype().clone()),
+                len: try!(param_len(*param)),
+            });
+        }
+        let routing_key = try!(routing_key(prepared, params));
+        Ok(Explanation {
+            query: prepared.query().to_string(),
+            statement_hash: statement_hash(prepared.query()),
+            hosts: Vec::new(),
+            protocol_version: None,
+            keyspace: prepared.keyspace().map(|k| k.to_string()),
+            consistency: consistency,
+            page_size: None,
+            timestamp: None,
+            tracing: false,
+            token: routing_key.as_ref().map(|key| murmur3_token(key)),
+            routing_key: routing_key,
+            params: summaries,
+        })
+    }
+
+    /// Fills in the connection the request would use: `peer` first, then
+    /// the other hosts that are up, then those marked down.
+    pub fn set_connection(&mut self, peer: Option<SocketAddr>, hosts: &[(SocketAddr, HostState)],
+                          version: ProtocolVersion) {
+        self.hosts = peer.into_iter().collect();
+        for state in &[HostState::Up, HostState::Down] {
+            for &(addr, host_state) in hosts {
+                if host_state == *state && Some(addr) != peer {
+                    self.hosts.push(addr);
+                }
+            }
+        }
+        self.protocol_version = Some(version);
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "statement {:016x} \"{}\"", self.statement_hash, self.query));
+        let hosts: Vec<String> = self.hosts.iter().map(|host| host.to_string()).collect();
+        try!(writeln!(f, "  hosts: {}", if hosts.is_empty() { "unknown".to_string() } else { hosts.join(", ") }));
+        if let Some(version) = self.protocol_version {
+            try!(writeln!(f, "  protocol: v{}", version.number()));
+        }
+        if let Some(ref keyspace) = self.keyspace {
+            try!(writeln!(f, "  keyspace: {}", keyspace));
+        }
+        try!(writeln!(f, "  consistency: {}", self.consistency));
+        if let Some(page_size) = self.page_size {
+            try!(writeln!(f, "  page size: {}", page_size));
+        }
+        if let Some(timestamp) = self.timestamp {
+            try!(writeln!(f, "  timestamp: {}", timestamp));
+        }
+        if self.tracing {
+            try!(writeln!(f, "  tracing: on"));
+        }
+        if let (Some(ref key), Some(token)) = (self.routing_key.as_ref(), self.token) {
+            let hex: Vec<String> = key.iter().map(|b| format!("{:02x}", b)).collect();
+            try!(writeln!(f, "  routing key: 0x{} (token {})", hex.join(""), token));
+        }
+        for (index, param) in self.params.iter().enumerate() {
+            try!(writeln!(f, "  param {}: {}", index, param));
+        }
+        Ok(())
+    }
+}
+
+fn param_len(param: &ToCQL) -> Result<Option<usize>> {
+    if param.is_unset() {
+        Ok(None)
+    } else {
+        param.serialized_len().map(Some)
+    }
+}
+
+/// The routing key of a prepared statement's values, or `None` if its
+/// partition key isn't known or not all bound.
+fn routing_key(prepared: &PreparedStatement, params: &[&ToCQL]) -> Result<Option<Vec<u8>>> {
+    let indexes = prepared.partition_key();
+    if indexes.is_empty() {
+        return Ok(None);
+    }
+    let mut components = Vec::with_capacity(indexes.len());
+    for &index in indexes {
+        let param = match params.get(index as usize) {
+            Some(param) if !param.is_unset() => param,
+            _ => return Ok(None),
+        };
+        let mut component = Vec::new();
+        try!(param.serialize(&mut component));
+        components.push(component);
+    }
+    if components.len() == 1 {
+        return Ok(components.pop());
+    }
+    // Composite keys are each component as a [short bytes] followed by an
+    // end-of-component byte.
+    let mut key = Vec::new();
+    for component in components {
+        key.push((component.len() >> 8) as u8);
+        key.push(component.len() as u8);
+        key.extend_from_slice(&component);
+        key.push(0);
+    }
+    Ok(Some(key))
+}
+
+/// The token `Murmur3Partitioner` assigns to a routing key: the first half
+/// of Cassandra's variant of MurmurHash3 x64_128, which sign-extends the
+/// trailing bytes.
+pub fn murmur3_token(key: &[u8]) -> i64 {
+    const C1: u64 = 0x87c37b91114253d5;
+    const C2: u64 = 0x4cf5ad432745937f;
+    let block = |i: usize| (0..8).fold(0u64, |word, b| word | (key[i + b] as u64) << (8 * b));
+    let (mut h1, mut h2) = (0u64, 0u64);
+    let blocks = key.len() / 16;
+    for i in 0..blocks {
+        let mut k1 = block(i * 16);
+        let mut k2 = block(i * 16 + 8);
+        k1 = k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+        h1 ^= k1;
+        h1 = h1.rotate_left(27).wrapping_add(h2).wrapping_mul(5).wrapping_add(0x52dce729);
+        k2 = k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+        h2 ^= k2;
+        h2 = h2.rotate_left(31).wrapping_add(h1).wrapping_mul(5).wrapping_add(0x38495ab5);
+    }
+    let tail = &key[blocks * 16..];
+    let signed = |i: usize| tail[i] as i8 as i64 as u64;
+    let (mut k1, mut k2) = (0u64, 0u64);
+    for i in (8..tail.len()).rev() {
+        k2 ^= signed(i) << (8 * (i - 8));
+    }
+    if tail.len() > 8 {
+        h2 ^= k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+    }
+    for i in (0..tail.len().min(8)).rev() {
+        k1 ^= signed(i) << (8 * i);
+    }
+    if !tail.is_empty() {
+        h1 ^= k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    }
+    h1 ^= key.len() as u64;
+    h2 ^= key.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix(h1);
+    h2 = fmix(h2);
+    h1 = h1.wrapping_add(h2);
+    // The partitioner keeps the minimum token for itself.
+    match h1 as i64 {
+        ::std::i64::MIN => ::std::i64::MAX,
+        token => token,
+    }
+}
+
+fn fmix(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51afd7ed558ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ceb9fe1a85ec53);
+    k ^= k >> 33;
+    k
+}
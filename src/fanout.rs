@@ -0,0 +1,203 @@
+use std::thread;
+use std::sync::{Arc, Mutex};
+
+use errors::MyError;
+use protocol::{PreparedStatement, QueryResult, Result};
+use session::Session;
+use types::ToCQL;
+
+/// A query restricted with `IN ?`, rewritten to select a single partition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitQuery {
+    /// The query with `IN ?` replaced by `= ?`.
+    pub query: String,
+    /// Position of the rewritten bind marker among all of the query's
+    /// markers.
+    pub key_index: usize,
+}
+
+impl SplitQuery {
+    /// Finds the single `IN ?` restriction in `query`. Markers and keywords
+    /// inside string literals are ignored.
+    pub fn parse(query: &str) -> Result<SplitQuery> {
+        let mut found = None;
+        let mut markers = 0;
+        let mut in_literal = false;
+        for (i, c) in query.char_indices() {
+            match c {
+                '\'' => in_literal = !in_literal,
+                '?' if !in_literal => {
+                    if is_in_restriction(&query[..i]) {
+                        if found.is_some() {
+                            return Err(MyError::Protocol("Can only split queries with one IN ?".to_string()));
+                        }
+                        found = Some((i, markers));
+                    }
+                    markers += 1;
+                },
+                _ => {},
+            }
+        }
+        match found {
+            Some((position, key_index)) => {
+                let before = query[..position].trim_right();
+                let keyword = before.len() - 2;
+                Ok(SplitQuery {
+                    query: format!("{}= ?{}", &query[..keyword], &query[position + 1..]),
+                    key_index: key_index,
+                })
+            },
+            None => Err(MyError::Protocol("Query has no IN ? restriction to split".to_string())),
+        }
+    }
+}
+
+/// Whether the text before a bind marker ends with the IN keyword.
+fn is_in_restriction(before: &str) -> bool {
+    let before = before.trim_right();
+    if before.len() < 3 || !before.is_char_boundary(before.len() - 2) {
+        return false;
+    }
+    let (rest, keyword) = before.split_at(before.len() - 2);
+    keyword.to_lowercase() == "in" && rest.ends_with(char::is_whitespace)
+}
+
+/// Runs `split` once per key, spreading the keys over up to `workers`
+/// threads, and merges the results in key order.
+pub fn run(session: &Session, split: &SplitQuery, keys: &[&ToCQL], params: &[&ToCQL], workers: usize)
+           -> Result<QueryResult> {
+    if keys.is_empty() {
+        return Err(MyError::Protocol("IN list to split is empty".to_string()));
+    }
+    if split.key_index > params.len() {
+        return Err(MyError::Protocol(format!("Query expects its IN marker at position {}, but only {} other values were given",
+                                             split.key_index, params.len())));
+    }
+    // Bound values are borrowed, so serialize them up front to hand them
+    // to the worker threads.
+    let keys = try!(serialize_all(keys));
+    let params = try!(serialize_all(params));
+    let workers = workers.max(1).min(keys.len());
+    let chunk_size = (keys.len() + workers - 1) / workers;
+
+    let handles: Vec<_> = keys.chunks(chunk_size).map(|chunk| {
+        let session = session.clone();
+        let split = split.clone();
+        let chunk = chunk.to_vec();
+        let params = params.clone();
+        thread::spawn(move || -> Result<Vec<QueryResult>> {
+            let mut results = Vec::with_capacity(chunk.len());
+            for key in chunk {
+                let mut values: Vec<&ToCQL> = params.iter().map(|p| p as &ToCQL).collect();
+                values.insert(split.key_index, &key);
+                results.push(try!(session.query(&split.query, &values)));
+            }
+            Ok(results)
+        })
+    }).collect();
+
+    let mut results = Vec::with_capacity(keys.len());
+    for handle in handles {
+        match handle.join() {
+            Ok(chunk) => results.extend(try!(chunk)),
+            Err(_) => return Err(MyError::Protocol("IN split worker panicked".to_string())),
+        }
+    }
+    QueryResult::merge(results)
+}
+
+/// Executes `prepared` once for each set of key values, on up to `workers`
+/// threads at a time, and returns each key's result in the order the keys
+/// were given. A failed lookup doesn't stop the others.
+pub fn get_many<'a, I>(session: &Session, prepared: &PreparedStatement, keys: I, workers: usize)
+                       -> Vec<Result<QueryResult>>
+    where I: IntoIterator<Item = &'a [&'a ToCQL]>
+{
+    let mut results: Vec<Option<Result<QueryResult>>> = Vec::new();
+    let mut pending = Vec::new();
+    for key in keys {
+        match serialize_all(key) {
+            Ok(values) => pending.push((results.len(), values)),
+            Err(err) => {
+                results.push(Some(Err(err)));
+                continue;
+            },
+        }
+        results.push(None);
+    }
+    // Workers take the next key as they finish the last one, so a slow
+    // lookup only holds up its own thread.
+    let workers = workers.max(1).min(pending.len());
+    let pending = Arc::new(Mutex::new(pending.into_iter()));
+    let handles: Vec<_> = (0..workers).map(|_| {
+        let session = session.clone();
+        let prepared = prepared.clone();
+        let pending = pending.clone();
+        thread::spawn(move || -> Vec<(usize, Result<QueryResult>)> {
+            let mut done = Vec::new();
+            loop {
+                let next = pending.lock().unwrap().next();
+                let (index, values) = match next {
+                    Some(key) => key,
+                    None => return done,
+                };
+                let values: Vec<&ToCQL> = values.iter().map(|v| v as &ToCQL).collect();
+                done.push((index, session.execute_prepared(&prepared, &values)));
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        // A panicked worker's keys are left unanswered and reported below.
+        if let Ok(done) = handle.join() {
+            for (index, result) in done {
+                results[index] = Some(result);
+            }
+        }
+    }
+    results.into_iter().map(|result| match result {
+        Some(result) => result,
+        None => Err(MyError::Protocol("Lookup worker panicked".to_string())),
+    }).collect()
+}
+
+fn serialize_all(values: &[&ToCQL]) -> Result<Vec<Vec<u8>>> {
+    let mut serialized = Vec::with_capacity(values.len());
+    for value in values {
+        let mut buf = Vec::new();
+        try!(value.serialize(&mut buf));
+        serialized.push(buf);
+    }
+    Ok(serialized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SplitQuery;
+
+    #[test]
+    fn rewrites_in_marker() {
+        let split = SplitQuery::parse("SELECT * FROM t WHERE a = ? AND id IN ? AND b = ?").unwrap();
+        assert_eq!(split.query, "SELECT * FROM t WHERE a = ? AND id = ? AND b = ?");
+        assert_eq!(split.key_index, 1);
+        let split = SplitQuery::parse("select * from t where (a, b) in  ?").unwrap();
+        assert_eq!(split.query, "select * from t where (a, b) = ?");
+        assert_eq!(split.key_index, 0);
+    }
+
+    #[test]
+    fn ignores_string_literals() {
+        let split = SplitQuery::parse("SELECT * FROM t WHERE b = 'in ?' AND c = 'it''s' AND id IN ?").unwrap();
+        assert_eq!(split.query, "SELECT * FROM t WHERE b = 'in ?' AND c = 'it''s' AND id = ?");
+        assert_eq!(split.key_index, 0);
+        assert!(SplitQuery::parse("SELECT * FROM t WHERE id = ? AND b = 'x IN ?'").is_err());
+    }
+
+    #[test]
+    fn needs_exactly_one_in_marker() {
+        assert!(SplitQuery::parse("SELECT * FROM t WHERE a IN ? AND b IN ?").is_err());
+        assert!(SplitQuery::parse("SELECT * FROM t WHERE id = ?").is_err());
+        assert!(SplitQuery::parse("SELECT * FROM t WHERE id IN (?, ?)").is_err());
+        assert!(SplitQuery::parse("SELECT * FROM t WHERE domain = ?").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! The outer framing layer of native protocol v5.
+//!
+//! From v5 on, once the server has answered STARTUP, request and response
+//! frames (called envelopes in the v5 spec) are no longer written to the
+//! socket directly. They are packed into frames of at most
+//! `MAX_PAYLOAD_LENGTH` bytes, each with a small header protected by a
+//! CRC24 and a payload protected by a CRC32:
+//!
+//! - A self-contained frame holds one or more whole envelopes.
+//! - An envelope that doesn't fit is split over several frames that aren't
+//!   self-contained, each carrying the next part of it.
+//!
+//! Compression moves here too: with lz4 negotiated, each frame payload is
+//! compressed on its own and the envelope compression flag is never set.
+
+use std::io::{Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use compression::Compression;
+use errors::MyError;
+use protocol::Result;
+
+/// The largest payload a single frame carries, before compression.
+pub const MAX_PAYLOAD_LENGTH: usize = (1 << 17) - 1;
+
+const CRC24_INIT: u32 = 0x875060;
+const CRC24_POLY: u32 = 0x1974F0B;
+/// Fed through the CRC32 ahead of every payload, so an all-zero payload
+/// doesn't have an all-zero checksum.
+const CRC32_SEED: [u8; 4] = [0xFA, 0x2D, 0x55, 0xCA];
+
+/// A frame read off the wire, decompressed and with its checksums checked.
+#[derive(Debug)]
+pub struct Segment {
+    pub payload: Vec<u8>,
+    /// Whether the payload is whole envelopes, rather than part of one.
+    pub self_contained: bool,
+    /// Bytes the frame took on the wire, header and checksums included.
+    pub wire_len: usize,
+    /// The payload's length on the wire, less than `payload.len()` if it
+    /// was compressed.
+    pub wire_payload_len: usize,
+}
+
+/// Packs the encoded envelopes in `envelopes` into frames. Envelopes that
+/// fit go into one self-contained frame; anything larger is split. Returns
+/// the length of the payloads written, after compression.
+pub fn encode<T: Write>(envelopes: &[u8], compression: Compression, buffer: &mut T) -> Result<usize> {
+    if envelopes.len() <= MAX_PAYLOAD_LENGTH {
+        return encode_frame(envelopes, true, compression, buffer);
+    }
+    let mut written = 0;
+    for part in envelopes.chunks(MAX_PAYLOAD_LENGTH) {
+        written += try!(encode_frame(part, false, compression, buffer));
+    }
+    Ok(written)
+}
+
+fn encode_frame<T: Write>(payload: &[u8], self_contained: bool, compression: Compression,
+                          buffer: &mut T) -> Result<usize> {
+    let self_contained_bit = if self_contained { 1 } else { 0 };
+    if compression == Compression::None {
+        let header = payload.len() as u64 | self_contained_bit << 17;
+        try!(write_header(header, 3, buffer));
+        try!(buffer.write_all(payload));
+        try!(buffer.write_u32::<LittleEndian>(crc32(payload)));
+        return Ok(payload.len());
+    }
+    // An uncompressed length of zero says the payload went uncompressed,
+    // which is worth doing when compression doesn't make it any smaller.
+    let compressed = try!(compression.compress_block(payload));
+    let (body, uncompressed_len) = if compressed.len() < payload.len() {
+        (&compressed[..], payload.len() as u64)
+    } else {
+        (payload, 0)
+    };
+    let header = body.len() as u64 | uncompressed_len << 17 | self_contained_bit << 34;
+    try!(write_header(header, 5, buffer));
+    try!(buffer.write_all(body));
+    try!(buffer.write_u32::<LittleEndian>(crc32(body)));
+    Ok(body.len())
+}
+
+/// Writes the low `len` bytes of `header`, then their CRC24, both little
+/// endian.
+fn write_header<T: Write>(header: u64, len: usize, buffer: &mut T) -> Result<()> {
+    try!(buffer.write_uint::<LittleEndian>(header, len));
+    try!(buffer.write_uint::<LittleEndian>(crc24(header, len) as u64, 3));
+    Ok(())
+}
+
+/// Reads one frame, blocking until all of it has arrived.
+pub fn decode<T: Read>(compression: Compression, buffer: &mut T) -> Result<Segment> {
+    let len = if compression == Compression::None { 3 } else { 5 };
+    let header = try!(buffer.read_uint::<LittleEndian>(len));
+    let header_crc = try!(buffer.read_uint::<LittleEndian>(3)) as u32;
+    if crc24(header, len) != header_crc {
+        return Err(MyError::Protocol(format!("Frame header {:x} failed its checksum", header)));
+    }
+    let payload_len = (header & MAX_PAYLOAD_LENGTH as u64) as usize;
+    let (uncompressed_len, self_contained) = if compression == Compression::None {
+        (0, header & 1 << 17 != 0)
+    } else {
+        (((header >> 17) & MAX_PAYLOAD_LENGTH as u64) as usize, header & 1 << 34 != 0)
+    };
+    let mut payload = vec![0; payload_len];
+    try!(buffer.read_exact(&mut payload));
+    let payload_crc = try!(buffer.read_u32::<LittleEndian>());
+    if crc32(&payload) != payload_crc {
+        return Err(MyError::Protocol(format!("Frame payload of {} bytes failed its checksum", payload_len)));
+    }
+    if uncompressed_len > 0 {
+        payload = try!(compression.decompress_block(&payload, uncompressed_len));
+    }
+    Ok(Segment {
+        payload: payload,
+        self_contained: self_contained,
+        wire_len: len + 3 + payload_len + 4,
+        wire_payload_len: payload_len,
+    })
+}
+
+/// The CRC24 used for frame headers, over the low `len` bytes of `bytes`
+/// taken little endian.
+fn crc24(bytes: u64, len: usize) -> u32 {
+    let mut crc = CRC24_INIT;
+    for i in 0..len {
+        crc ^= (((bytes >> (8 * i)) & 0xFF) as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    crc
+}
+
+/// The standard (zlib) CRC32, over `CRC32_SEED` and then `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in CRC32_SEED.iter().chain(bytes) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc24, crc32, decode, encode, MAX_PAYLOAD_LENGTH};
+    use compression::Compression;
+
+    // Checked against Cassandra's algorithm with its CRC24 init and
+    // polynomial, and against zlib's crc32 over the seed and the payload.
+
+    #[test]
+    fn crc24_of_headers() {
+        assert_eq!(crc24(0, 3), 0x7DE777);
+        assert_eq!(crc24(1 << 17, 3), 0xC4366A);
+        assert_eq!(crc24(5 | 1 << 17, 3), 0x9A9919);
+        assert_eq!(crc24(MAX_PAYLOAD_LENGTH as u64, 3), 0xFE9138);
+        assert_eq!(crc24(10 | 20 << 17 | 1 << 34, 5), 0x14710B);
+    }
+
+    #[test]
+    fn crc32_is_seeded() {
+        assert_eq!(crc32(b""), 0x44777ED3);
+        assert_eq!(crc32(&[0; 4]), 0x5A05E689);
+        assert_eq!(crc32(b"123456789"), 0xE2A261A7);
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut wire = Vec::new();
+        assert_eq!(encode(b"hello", Compression::None, &mut wire).unwrap(), 5);
+        assert_eq!(wire.len(), 3 + 3 + 5 + 4);
+        let segment = decode(Compression::None, &mut &wire[..]).unwrap();
+        assert_eq!(segment.payload, b"hello".to_vec());
+        assert!(segment.self_contained);
+        assert_eq!(segment.wire_len, wire.len());
+
+        let big = vec![7; MAX_PAYLOAD_LENGTH + 10];
+        let mut wire = Vec::new();
+        encode(&big, Compression::None, &mut wire).unwrap();
+        let mut reader = &wire[..];
+        let first = decode(Compression::None, &mut reader).unwrap();
+        let second = decode(Compression::None, &mut reader).unwrap();
+        assert!(!first.self_contained && !second.self_contained);
+        assert_eq!(first.payload.len() + second.payload.len(), big.len());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn corruption_fails_the_checksums() {
+        let mut wire = Vec::new();
+        encode(b"hello", Compression::None, &mut wire).unwrap();
+        let mut header = wire.clone();
+        header[0] ^= 1;
+        assert!(decode(Compression::None, &mut &header[..]).is_err());
+        let mut payload = wire.clone();
+        payload[6] ^= 1;
+        assert!(decode(Compression::None, &mut &payload[..]).is_err());
+    }
+}
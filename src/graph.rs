@@ -0,0 +1,104 @@
+//! Running DSE Graph queries. DSE takes a Gremlin script as the text of a
+//! QUERY request, with the graph to run it on and how in the custom
+//! payload, so this needs native protocol v4.
+//!
+//! ```ignore
+//! let mut options = GraphOptions::default();
+//! options.graph_name = Some("social".to_string());
+//! let result = try!(session.execute_graph("g.V().has('name', name)", &[&r#"{"name":"ann"}"#], &options));
+//! let vertices = try!(graph::results(&result));
+//! ```
+//!
+//! Each row of the result holds one graph result as GraphSON, a JSON
+//! document; `results` returns them as text. Reading them into vertices,
+//! edges and paths is left to a GraphSON library. A script's parameters
+//! are sent as one text value, a JSON object of the parameters by name.
+
+use std::time::Duration;
+use byteorder::{BigEndian, WriteBytesExt};
+
+use protocol::{Consistency, QueryResult, Result};
+use statement::Statement;
+
+/// The language DSE runs scripts in unless told otherwise.
+pub const DEFAULT_GRAPH_LANGUAGE: &'static str = "gremlin-groovy";
+
+/// The traversal source of ordinary, OLTP queries.
+pub const DEFAULT_GRAPH_SOURCE: &'static str = "g";
+
+#[derive(Debug, Clone)]
+pub struct GraphOptions {
+    /// The graph to run scripts on. `None` for system queries, such as
+    /// creating a graph.
+    pub graph_name: Option<String>,
+    /// The traversal source, `g` by default, or `a` for analytics (OLAP)
+    /// queries.
+    pub graph_source: String,
+    pub graph_language: String,
+    /// Overrides the consistency the graph's configuration reads at.
+    pub read_consistency: Option<Consistency>,
+    /// Overrides the consistency the graph's configuration writes at.
+    pub write_consistency: Option<Consistency>,
+    /// How long the server may spend on the script, instead of the
+    /// graph's configured timeout.
+    pub timeout: Option<Duration>,
+    /// The GraphSON version to return results as, such as `graphson-2.0`;
+    /// the server's default if `None`.
+    pub results_format: Option<String>,
+    /// The consistency of the request itself.
+    pub consistency: Consistency,
+}
+
+impl Default for GraphOptions {
+    fn default() -> GraphOptions {
+        GraphOptions {
+            graph_name: None,
+            graph_source: DEFAULT_GRAPH_SOURCE.to_string(),
+            graph_language: DEFAULT_GRAPH_LANGUAGE.to_string(),
+            read_consistency: None,
+            write_consistency: None,
+            timeout: None,
+            results_format: None,
+            consistency: Consistency::One,
+        }
+    }
+}
+
+impl GraphOptions {
+    /// A statement running `script` with these options.
+    pub fn statement(&self, script: &str) -> Statement {
+        let mut statement = Statement::new(script);
+        statement.set_consistency(self.consistency);
+        statement.set_payload("graph-language", self.graph_language.as_bytes());
+        statement.set_payload("graph-source", self.graph_source.as_bytes());
+        if let Some(ref name) = self.graph_name {
+            statement.set_payload("graph-name", name.as_bytes());
+        }
+        if let Some(consistency) = self.read_consistency {
+            statement.set_payload("graph-read-consistency", consistency.name().as_bytes());
+        }
+        if let Some(consistency) = self.write_consistency {
+            statement.set_payload("graph-write-consistency", consistency.name().as_bytes());
+        }
+        if let Some(timeout) = self.timeout {
+            let millis = timeout.as_secs() as i64 * 1000 + (timeout.subsec_nanos() / 1_000_000) as i64;
+            let mut bytes = Vec::with_capacity(8);
+            bytes.write_i64::<BigEndian>(millis).unwrap();
+            statement.set_payload("request-timeout", &bytes);
+        }
+        if let Some(ref format) = self.results_format {
+            statement.set_payload("graph-results", format.as_bytes());
+        }
+        statement
+    }
+}
+
+/// The GraphSON documents in `result`, one per row.
+pub fn results(result: &QueryResult) -> Result<Vec<String>> {
+    let mut results = Vec::with_capacity(result.rows.len());
+    for row in &result.rows {
+        let json: Option<String> = try!(row.get("gremlin"));
+        results.push(json.unwrap_or_default());
+    }
+    Ok(results)
+}
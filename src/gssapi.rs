@@ -0,0 +1,224 @@
+//! Kerberos authentication through the SASL GSSAPI mechanism (RFC 4752),
+//! for clusters using DSE Unified Authentication or DSE's
+//! `KerberosAuthenticator`.
+//!
+//! The Kerberos calls themselves are left to a `GssProvider`, usually a
+//! thin wrapper around the platform's GSS-API library, so this driver
+//! doesn't link against one. This module handles the SASL exchange around
+//! them and renews credentials during long-lived sessions.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use auth::{AuthProvider, Authenticator};
+use errors::MyError;
+use protocol::Result;
+
+/// Service name in the default `dse/<host>` service principal.
+pub const DEFAULT_SERVICE: &'static str = "dse";
+/// How often credentials are renewed, well within the usual ten hour
+/// ticket lifetime.
+pub const DEFAULT_RENEW_INTERVAL_SECS: u64 = 60 * 60;
+
+/// The SASL mechanism name DSE Unified Authentication expects first.
+const MECHANISM: &'static [u8] = b"GSSAPI";
+const DSE_START_CHALLENGE: &'static [u8] = b"GSSAPI-START";
+/// Security layer bit meaning no integrity or confidentiality protection,
+/// which is all the native protocol supports.
+const NO_SECURITY_LAYER: u8 = 0x01;
+
+/// Access to a GSS-API implementation.
+pub trait GssProvider: Send + Sync {
+    /// Starts a security context with `service_principal`, such as
+    /// `dse/node1.example.com`, using the credentials of `principal`, or the
+    /// default ones from the ticket cache when it is `None`.
+    fn init_context(&self, principal: Option<&str>, service_principal: &str) -> Result<Box<GssContext>>;
+
+    /// Refreshes the credentials of `principal`, e.g. by logging in again
+    /// from a keytab.
+    fn renew(&self, principal: Option<&str>) -> Result<()>;
+}
+
+/// One client-side security context, as created by `gss_init_sec_context`.
+pub trait GssContext: Send {
+    /// Processes the server's token, `None` to start, and returns the
+    /// token to send back, which may be empty.
+    fn step(&mut self, token: Option<&[u8]>) -> Result<Vec<u8>>;
+
+    /// Whether the context has been established.
+    fn is_complete(&self) -> bool;
+
+    fn wrap(&mut self, message: &[u8]) -> Result<Vec<u8>>;
+
+    fn unwrap(&mut self, message: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Creates a GSSAPI `Authenticator` for each connection.
+pub struct GssapiAuthProvider {
+    provider: Arc<GssProvider>,
+    principal: Option<String>,
+    authorization_id: Option<String>,
+    service: String,
+    host_names: Vec<(SocketAddr, String)>,
+    renew_interval: Duration,
+    last_renewed: Mutex<Option<Instant>>,
+}
+
+impl GssapiAuthProvider {
+    pub fn new(provider: Arc<GssProvider>) -> GssapiAuthProvider {
+        GssapiAuthProvider {
+            provider: provider,
+            principal: None,
+            authorization_id: None,
+            service: DEFAULT_SERVICE.to_string(),
+            host_names: Vec::new(),
+            renew_interval: Duration::from_secs(DEFAULT_RENEW_INTERVAL_SECS),
+            last_renewed: Mutex::new(None),
+        }
+    }
+
+    /// The client principal, such as `app@EXAMPLE.COM`. Defaults to the
+    /// ticket cache's.
+    pub fn set_principal(&mut self, principal: Option<String>) {
+        self.principal = principal;
+    }
+
+    /// The role to act as once authenticated, for DSE proxy
+    /// authentication. The principal's role needs the PROXY.LOGIN
+    /// permission on it.
+    pub fn set_authorization_id(&mut self, role: Option<String>) {
+        self.authorization_id = role;
+    }
+
+    pub fn set_service(&mut self, service: &str) {
+        self.service = service.to_string();
+    }
+
+    /// The host name to use in the service principal for `addr`. Kerberos
+    /// principals name hosts, not addresses, so this is needed for every
+    /// node unless the realm has principals for the bare addresses.
+    pub fn set_host_name(&mut self, addr: SocketAddr, host_name: &str) {
+        self.host_names.retain(|&(a, _)| a != addr);
+        self.host_names.push((addr, host_name.to_string()));
+    }
+
+    pub fn set_renew_interval(&mut self, interval: Duration) {
+        self.renew_interval = interval;
+    }
+
+    fn service_principal(&self, host: SocketAddr) -> String {
+        let host_name = match self.host_names.iter().find(|&&(addr, _)| addr == host) {
+            Some(&(_, ref name)) => name.clone(),
+            None => host.ip().to_string(),
+        };
+        format!("{}/{}", self.service, host_name)
+    }
+
+    /// Renews credentials once the renew interval has passed since the
+    /// last renewal. The first connection counts as a renewal, since
+    /// credentials are fresh when the provider is set up.
+    fn renew_if_due(&self) -> Result<()> {
+        let mut last_renewed = self.last_renewed.lock().unwrap();
+        match *last_renewed {
+            Some(at) if at.elapsed() < self.renew_interval => Ok(()),
+            Some(_) => {
+                try!(self.provider.renew(self.principal.as_ref().map(|p| p.as_ref())));
+                *last_renewed = Some(Instant::now());
+                Ok(())
+            },
+            None => {
+                *last_renewed = Some(Instant::now());
+                Ok(())
+            },
+        }
+    }
+}
+
+impl AuthProvider for GssapiAuthProvider {
+    fn new_authenticator(&self, authenticator: &str, host: SocketAddr) -> Result<Box<Authenticator>> {
+        try!(self.renew_if_due());
+        let principal = self.principal.as_ref().map(|p| p.as_ref());
+        let context = try!(self.provider.init_context(principal, &self.service_principal(host)));
+        // DSE Unified Authentication supports several mechanisms, so the
+        // client names one first; the older KerberosAuthenticator starts
+        // with the first GSS token right away.
+        let state = if authenticator.ends_with("DseAuthenticator") {
+            State::Mechanism
+        } else {
+            State::Negotiating
+        };
+        Ok(Box::new(GssapiAuthenticator {
+            context: context,
+            state: state,
+            authorization_id: self.authorization_id.clone(),
+        }))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    /// About to name the mechanism.
+    Mechanism,
+    /// Waiting for the server to accept the mechanism.
+    Started,
+    /// Exchanging GSS tokens.
+    Negotiating,
+    /// Security layer agreed, waiting for AUTH_SUCCESS.
+    Done,
+}
+
+struct GssapiAuthenticator {
+    context: Box<GssContext>,
+    state: State,
+    authorization_id: Option<String>,
+}
+
+impl GssapiAuthenticator {
+    /// The last step of RFC 4752: the server offers security layers in a
+    /// wrapped message, and the client picks none, followed by the
+    /// authorization id to act as, if any.
+    fn choose_security_layer(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let offer = try!(self.context.unwrap(challenge));
+        if offer.len() != 4 {
+            return Err(MyError::Protocol(format!("Expected a 4 byte GSSAPI security layer offer, got {}", offer.len())));
+        }
+        if offer[0] & NO_SECURITY_LAYER == 0 {
+            return Err(MyError::Protocol("Server requires a GSSAPI security layer, which isn't supported".to_string()));
+        }
+        self.state = State::Done;
+        let mut choice = vec![NO_SECURITY_LAYER, 0, 0, 0];
+        if let Some(ref role) = self.authorization_id {
+            choice.extend_from_slice(role.as_bytes());
+        }
+        self.context.wrap(&choice)
+    }
+}
+
+impl Authenticator for GssapiAuthenticator {
+    fn initial_response(&mut self) -> Result<Vec<u8>> {
+        match self.state {
+            State::Mechanism => {
+                self.state = State::Started;
+                Ok(MECHANISM.to_vec())
+            },
+            _ => self.context.step(None),
+        }
+    }
+
+    fn evaluate_challenge(&mut self, challenge: Option<&[u8]>) -> Result<Vec<u8>> {
+        let challenge = challenge.unwrap_or(&[]);
+        match self.state {
+            State::Started => {
+                if challenge != DSE_START_CHALLENGE {
+                    return Err(MyError::Protocol("Server did not accept the GSSAPI mechanism".to_string()));
+                }
+                self.state = State::Negotiating;
+                self.context.step(None)
+            },
+            State::Negotiating if self.context.is_complete() => self.choose_security_layer(challenge),
+            State::Negotiating => self.context.step(Some(challenge)),
+            State::Mechanism | State::Done => Err(MyError::Protocol("Unexpected GSSAPI challenge".to_string())),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! High dynamic range latency histograms.
+//!
+//! The fixed buckets in `ConnectionStats` are cheap, but can't tell a p99
+//! of 120ms from one of 240ms. A `Histogram` keeps every recorded latency
+//! to a fixed number of significant figures instead, the way HdrHistogram
+//! does: values are grouped in buckets that double in width, each split
+//! into enough linear sub-buckets to hold the precision. Memory grows with
+//! the log of the largest value: at 3 significant figures, 16kB covers
+//! latencies up to 2ms and each doubling beyond that takes another 8kB.
+//!
+//! Latencies are recorded in microseconds.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Significant figures kept unless configured otherwise.
+pub const DEFAULT_SIGNIFICANT_FIGURES: u8 = 3;
+
+/// How a session records request latencies.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LatencyHistogram {
+    /// Only the fixed `stats::LATENCY_BUCKETS_MS` buckets.
+    Buckets,
+    /// A `Histogram` per connection as well, keeping 1 to 5 significant
+    /// figures of each latency.
+    Hdr { significant_figures: u8 },
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram::Buckets
+    }
+}
+
+#[derive(Clone)]
+pub struct Histogram {
+    significant_figures: u8,
+    /// log2 of the sub-buckets per bucket; the first bucket uses all of
+    /// them, later ones only the upper half, since the lower half is
+    /// covered by the bucket before.
+    sub_bucket_magnitude: u32,
+    counts: Vec<u64>,
+    total: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    /// A histogram keeping `significant_figures` digits, clamped to 1 to 5.
+    pub fn new(significant_figures: u8) -> Histogram {
+        let significant_figures = significant_figures.max(1).min(5);
+        let largest_single_unit = 2 * 10u64.pow(significant_figures as u32);
+        let sub_bucket_magnitude = 64 - (largest_single_unit - 1).leading_zeros();
+        Histogram {
+            significant_figures: significant_figures,
+            sub_bucket_magnitude: sub_bucket_magnitude,
+            counts: Vec::new(),
+            total: 0,
+            sum: 0,
+            min: u64::max_value(),
+            max: 0,
+        }
+    }
+
+    pub fn significant_figures(&self) -> u8 {
+        self.significant_figures
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.record_micros(latency.as_secs() * 1_000_000 + latency.subsec_nanos() as u64 / 1000, 1);
+    }
+
+    /// Records `count` latencies of `micros` microseconds.
+    pub fn record_micros(&mut self, micros: u64, count: u64) {
+        if count == 0 {
+            return;
+        }
+        let index = self.index_of(micros);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += count;
+        self.total += count;
+        self.sum = self.sum.saturating_add(micros.saturating_mul(count));
+        self.min = self.min.min(micros);
+        self.max = self.max.max(micros);
+    }
+
+    /// How many latencies were recorded.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        if self.is_empty() { None } else { Some(micros(self.min)) }
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        if self.is_empty() { None } else { Some(micros(self.max)) }
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.is_empty() { None } else { Some(micros(self.sum / self.total)) }
+    }
+
+    /// The latency at or below which `quantile` of the recorded latencies
+    /// fall, such as 0.999 for p99.9, to within the histogram's precision.
+    pub fn value_at_quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        let quantile = quantile.max(0.0).min(1.0);
+        let target = ((quantile * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Some(micros(self.highest_equivalent(index).min(self.max)));
+            }
+        }
+        Some(micros(self.max))
+    }
+
+    /// Every non-empty sub-bucket, as the highest latency it holds and how
+    /// many latencies fell in it, in increasing order.
+    pub fn recorded(&self) -> Vec<(Duration, u64)> {
+        self.counts.iter().enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(index, &count)| (micros(self.highest_equivalent(index).min(self.max)), count))
+            .collect()
+    }
+
+    /// Adds another histogram's latencies to this one. If the precision
+    /// differs, they're recorded again at this histogram's.
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.sub_bucket_magnitude == self.sub_bucket_magnitude {
+            if other.counts.len() > self.counts.len() {
+                self.counts.resize(other.counts.len(), 0);
+            }
+            for (total, &count) in self.counts.iter_mut().zip(&other.counts) {
+                *total += count;
+            }
+            self.total += other.total;
+            self.sum = self.sum.saturating_add(other.sum);
+            if !other.is_empty() {
+                self.min = self.min.min(other.min);
+                self.max = self.max.max(other.max);
+            }
+        } else {
+            for (index, &count) in other.counts.iter().enumerate() {
+                self.record_micros(other.lowest_equivalent(index), count);
+            }
+        }
+    }
+
+    /// Forgets every recorded latency, keeping the precision.
+    pub fn reset(&mut self) {
+        *self = Histogram::new(self.significant_figures);
+    }
+
+    fn half_count(&self) -> u64 {
+        1 << (self.sub_bucket_magnitude - 1)
+    }
+
+    fn index_of(&self, value: u64) -> usize {
+        let mask = (1u64 << self.sub_bucket_magnitude) - 1;
+        let bucket = (64 - self.sub_bucket_magnitude) - (value | mask).leading_zeros();
+        let sub_bucket = value >> bucket;
+        (((bucket as u64 + 1) << (self.sub_bucket_magnitude - 1)) + sub_bucket - self.half_count()) as usize
+    }
+
+    /// The bucket and sub-bucket a counts index stands for.
+    fn position(&self, index: usize) -> (u32, u64) {
+        let half_magnitude = self.sub_bucket_magnitude - 1;
+        let bucket = (index as u64 >> half_magnitude) as i64 - 1;
+        let sub_bucket = (index as u64 & (self.half_count() - 1)) + self.half_count();
+        if bucket < 0 {
+            (0, sub_bucket - self.half_count())
+        } else {
+            (bucket as u32, sub_bucket)
+        }
+    }
+
+    fn lowest_equivalent(&self, index: usize) -> u64 {
+        let (bucket, sub_bucket) = self.position(index);
+        sub_bucket << bucket
+    }
+
+    fn highest_equivalent(&self, index: usize) -> u64 {
+        let (bucket, _) = self.position(index);
+        self.lowest_equivalent(index) + (1 << bucket) - 1
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new(DEFAULT_SIGNIFICANT_FIGURES)
+    }
+}
+
+/// Summarizes rather than printing every count.
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("significant_figures", &self.significant_figures)
+            .field("count", &self.total)
+            .field("min", &self.min())
+            .field("p50", &self.value_at_quantile(0.5))
+            .field("p99", &self.value_at_quantile(0.99))
+            .field("p999", &self.value_at_quantile(0.999))
+            .field("max", &self.max())
+            .finish()
+    }
+}
+
+fn micros(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000)
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HostState {
+    Up,
+    Down,
+}
+
+/// Whether each known host is believed to accept connections. Hosts start
+/// out up. A STATUS_CHANGE DOWN event or a failed connection marks one
+/// down, and it stays down until a STATUS_CHANGE UP event arrives or a
+/// connection to it succeeds.
+#[derive(Debug, Clone, Default)]
+pub struct HostStates {
+    states: HashMap<SocketAddr, HostState>,
+}
+
+impl HostStates {
+    pub fn new() -> HostStates {
+        HostStates::default()
+    }
+
+    pub fn state(&self, addr: &SocketAddr) -> HostState {
+        self.states.get(addr).cloned().unwrap_or(HostState::Up)
+    }
+
+    pub fn is_up(&self, addr: &SocketAddr) -> bool {
+        self.state(addr) == HostState::Up
+    }
+
+    pub fn mark_up(&mut self, addr: SocketAddr) {
+        self.states.insert(addr, HostState::Up);
+    }
+
+    pub fn mark_down(&mut self, addr: SocketAddr) {
+        self.states.insert(addr, HostState::Down);
+    }
+
+    /// Forgets a host that left the cluster.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.states.remove(addr);
+    }
+
+    /// The order to try `addrs` in: hosts that are up keep their order,
+    /// followed by the ones marked down as a last resort.
+    pub fn connect_order(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let (mut up, down): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().partition(|addr| self.is_up(addr));
+        up.extend(down);
+        up
+    }
+}
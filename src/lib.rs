@@ -1,7 +1,74 @@
 extern crate uuid;
 extern crate byteorder;
+extern crate net2;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate webpki;
+#[cfg(feature = "lz4")]
+extern crate lz4;
+#[cfg(feature = "native-tls")]
+extern crate native_tls;
+#[cfg(feature = "timezones")]
+extern crate chrono;
+#[cfg(feature = "timezones")]
+extern crate chrono_tz;
+
+#[macro_use]
+mod macros;
 
 pub mod client;
 pub mod protocol;
 pub mod types;
 pub mod errors;
+pub mod stats;
+pub mod histogram;
+pub mod statement;
+pub mod session;
+pub mod pool;
+pub mod batch;
+pub mod pager;
+pub mod compression;
+pub mod capabilities;
+pub mod framing;
+pub mod diagnostics;
+pub mod trace;
+pub mod explain;
+pub mod fanout;
+pub mod events;
+pub mod bus;
+pub mod hosts;
+pub mod resolver;
+pub mod socket;
+pub mod stale;
+pub mod coalesce;
+pub mod retry;
+pub mod timeseries;
+pub mod widerow;
+pub mod estimates;
+pub mod copy;
+pub mod backup;
+pub mod purge;
+pub mod codegen;
+pub mod lint;
+pub mod auth;
+pub mod testing;
+pub mod transaction;
+pub mod cache;
+pub mod durability;
+pub mod ddl;
+pub mod enums;
+pub mod graph;
+pub mod warning;
+#[cfg(feature = "gssapi")]
+pub mod gssapi;
+#[cfg(feature = "testcontainers")]
+pub mod container;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "native-tls")]
+pub mod nativetls;
+#[cfg(feature = "timezones")]
+pub mod zoned;
@@ -0,0 +1,84 @@
+//! Checking statements against the schema as a session sends them.
+//!
+//! A `Linter` holds the schema of the tables an application uses and looks
+//! at each distinct statement the first time it's sent or prepared:
+//!
+//! ```ignore
+//! let tables = try!(TableSchema::fetch(&mut client, "ks"));
+//! let mut config = SessionConfig::default();
+//! config.linter = Some(Arc::new(Linter::new(tables)));
+//! ```
+//!
+//! It reports unknown tables and columns, reads and writes that don't
+//! restrict the whole partition key, SELECTs without a LIMIT, and
+//! timestamps written without a time zone, as described by
+//! `codegen::Lint`. Statements are still sent either way. A session
+//! publishes the lints it finds as `warning::Warning::Lint`; they also go
+//! to the linter's `LintListener`, if it has one.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use codegen::{lint_query, Lint, TableSchema};
+use statement::statement_hash;
+
+/// Receives the lints found in a statement.
+pub trait LintListener: Send + Sync {
+    /// Called once for each distinct statement that has lints.
+    fn on_lint(&self, query: &str, lints: &[Lint]);
+}
+
+impl fmt::Debug for LintListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LintListener")
+    }
+}
+
+#[derive(Debug)]
+pub struct Linter {
+    tables: Vec<TableSchema>,
+    listener: Option<Arc<LintListener>>,
+    /// Hashes of the statements already checked.
+    seen: Mutex<HashSet<u64>>,
+}
+
+impl Linter {
+    pub fn new(tables: Vec<TableSchema>) -> Linter {
+        Linter {
+            tables: tables,
+            listener: None,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Also sends lints to `listener`.
+    pub fn listener(mut self, listener: Arc<LintListener>) -> Linter {
+        self.listener = Some(listener);
+        self
+    }
+
+    pub fn tables(&self) -> &[TableSchema] {
+        &self.tables
+    }
+
+    /// The lints in `query`, without reporting them.
+    pub fn lint(&self, query: &str) -> Vec<Lint> {
+        lint_query(&self.tables, query)
+    }
+
+    /// Returns the lints in `query` and reports them to the listener,
+    /// unless the same statement has been checked before.
+    pub fn check(&self, query: &str) -> Vec<Lint> {
+        if !self.seen.lock().unwrap().insert(statement_hash(query)) {
+            return Vec::new();
+        }
+        let lints = self.lint(query);
+        if let Some(ref listener) = self.listener {
+            if !lints.is_empty() {
+                listener.on_lint(query, &lints);
+            }
+        }
+        lints
+    }
+}
@@ -0,0 +1,174 @@
+/// Builds a `Template` with every bind marker of `query` bound to the given
+/// values, in order, returning an error if the number of values doesn't
+/// match the number of markers. Run it with `Client::query_template` or
+/// `Session::query_template` and no further values:
+///
+/// ```ignore
+/// let select = try!(cql!("SELECT id, name FROM people WHERE id = ?", id));
+/// let people: Vec<Person> = try!(try!(client.query_template(&select, &[])).rows_as());
+/// ```
+///
+/// To check queries against a schema snapshot before they reach a server,
+/// run them through `codegen::check_query` from a build script.
+#[macro_export]
+macro_rules! cql {
+    ($query:expr) => {
+        $crate::statement::Template::with_values($crate::statement::Statement::new($query), &[])
+    };
+    ($query:expr, $($value:expr),+ $(,)*) => {
+        $crate::statement::Template::with_values($crate::statement::Statement::new($query),
+                                                 &[$(&$value as &$crate::types::ToCQL),+])
+    };
+}
+
+/// Declares a struct to read query results into. Each field is read from
+/// the column of the same name; `Option` fields may be null, and any other
+/// field fails the conversion when its column is null.
+///
+/// ```ignore
+/// cql_row! {
+///     #[derive(Debug)]
+///     pub struct Person {
+///         id: Uuid,
+///         name: Option<String>,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! cql_row {
+    ($(#[$attr:meta])* pub struct $name:ident { $($field:ident : $ty:ty),+ $(,)* }) => {
+        $(#[$attr])*
+        pub struct $name {
+            $(pub $field: $ty),+
+        }
+
+        cql_row!(@impl $name { $($field),+ });
+    };
+    ($(#[$attr:meta])* struct $name:ident { $($field:ident : $ty:ty),+ $(,)* }) => {
+        $(#[$attr])*
+        struct $name {
+            $($field: $ty),+
+        }
+
+        cql_row!(@impl $name { $($field),+ });
+    };
+    (@impl $name:ident { $($field:ident),+ }) => {
+        impl $crate::protocol::FromRow for $name {
+            fn from_row(row: &$crate::protocol::Row) -> $crate::protocol::Result<$name> {
+                Ok($name {
+                    $($field: try!($crate::protocol::FromColumn::from_column(row, stringify!($field)))),+
+                })
+            }
+        }
+    };
+}
+
+/// Declares a fieldless enum stored in a CQL column, as text or as the
+/// value given for each variant. `#[cql(rename_all = "...")]` goes before
+/// any other attributes. See the `enums` module.
+///
+/// ```ignore
+/// cql_enum! {
+///     #[cql(rename_all = "snake_case")]
+///     #[derive(Debug, Clone, Copy, PartialEq)]
+///     pub enum Status { Active, OnHold, Closed }
+/// }
+/// ```
+#[macro_export]
+macro_rules! cql_enum {
+    (#[cql(rename_all = $case:expr)] $(#[$attr:meta])* pub enum $name:ident { $($variant:ident),+ $(,)* }) => {
+        $(#[$attr])*
+        pub enum $name {
+            $($variant),+
+        }
+
+        cql_enum!(@impl $name { $($variant = try!($crate::enums::rename_variant(stringify!($variant), $case))),+ });
+    };
+    (#[cql(rename_all = $case:expr)] $(#[$attr:meta])* enum $name:ident { $($variant:ident),+ $(,)* }) => {
+        $(#[$attr])*
+        enum $name {
+            $($variant),+
+        }
+
+        cql_enum!(@impl $name { $($variant = try!($crate::enums::rename_variant(stringify!($variant), $case))),+ });
+    };
+    ($(#[$attr:meta])* pub enum $name:ident { $($variant:ident = $value:expr),+ $(,)* }) => {
+        $(#[$attr])*
+        pub enum $name {
+            $($variant),+
+        }
+
+        cql_enum!(@impl $name { $($variant = $value),+ });
+    };
+    ($(#[$attr:meta])* enum $name:ident { $($variant:ident = $value:expr),+ $(,)* }) => {
+        $(#[$attr])*
+        enum $name {
+            $($variant),+
+        }
+
+        cql_enum!(@impl $name { $($variant = $value),+ });
+    };
+    ($(#[$attr:meta])* pub enum $name:ident { $($variant:ident),+ $(,)* }) => {
+        $(#[$attr])*
+        pub enum $name {
+            $($variant),+
+        }
+
+        cql_enum!(@impl $name { $($variant = stringify!($variant)),+ });
+    };
+    ($(#[$attr:meta])* enum $name:ident { $($variant:ident),+ $(,)* }) => {
+        $(#[$attr])*
+        enum $name {
+            $($variant),+
+        }
+
+        cql_enum!(@impl $name { $($variant = stringify!($variant)),+ });
+    };
+    (@impl $name:ident { $($variant:ident = $value:expr),+ }) => {
+        impl $crate::types::ToCQL for $name {
+            fn serialize(&self, buf: &mut ::std::io::Write) -> $crate::protocol::Result<()> {
+                match *self {
+                    $($name::$variant => $crate::types::ToCQL::serialize(&$value, buf)),+
+                }
+            }
+        }
+
+        impl $crate::types::FromCQL for $name {
+            fn parse(buf: &[u8]) -> $crate::protocol::Result<$name> {
+                $(
+                    if try!($crate::enums::stored_as(&$value, buf)) {
+                        return Ok($name::$variant);
+                    }
+                )+
+                Err($crate::enums::unknown_variant(stringify!($name), buf))
+            }
+        }
+
+        impl $crate::enums::CQLEnum for $name {
+            fn variants() -> Vec<(&'static str, $name)> {
+                vec![$((concat!(stringify!($name), "::", stringify!($variant)), $name::$variant)),+]
+            }
+        }
+    };
+}
+
+/// Panics with a readable list of differences unless the given columns of
+/// a `QueryResult` hold the expected rows, in order. Values are anything
+/// that converts into a `testing::Cell`:
+///
+/// ```ignore
+/// assert_rows_eq!(result, ["id", "name", "friends"], [
+///     [id, "John", friends],
+///     [other_id, Cell::Null, HashSet::<String>::new()],
+/// ]);
+/// ```
+#[macro_export]
+macro_rules! assert_rows_eq {
+    ($result:expr, [$($column:expr),+ $(,)*], [$([$($value:expr),+ $(,)*]),* $(,)*]) => {{
+        let expected: Vec<Vec<$crate::testing::Cell>> = vec![$(vec![$($crate::testing::Cell::from($value)),+]),*];
+        let diff = $crate::testing::RowDiff::compare(&$result, &[$($column),+], &expected);
+        if !diff.is_empty() {
+            panic!("rows differ from what was expected:\n{}", diff);
+        }
+    }};
+}
@@ -23,17 +23,17 @@ fn main() {
     assert_eq!(result.rows.len(), 1);
 
     let ref row = result.rows[0];
-    assert_eq!(row.columns.len(), 4);
+    assert_eq!(row.len(), 4);
 
-    let returned_id: Uuid = row.get("id").unwrap();
+    let returned_id: Uuid = row.get("id").unwrap().unwrap();
     assert_eq!(id, returned_id);
 
-    let name: String = row.get("name").unwrap();
+    let name: String = row.get("name").unwrap().unwrap();
     assert_eq!(name, "John".to_string());
 
-    let active: bool = row.get("active").unwrap();
+    let active: bool = row.get("active").unwrap().unwrap();
     assert_eq!(active, false);
 
-    let returned_friends: HashSet<String> = row.get("friends").unwrap();
+    let returned_friends: HashSet<String> = row.get("friends").unwrap().unwrap();
     assert_eq!(friends, returned_friends);
 }
@@ -0,0 +1,99 @@
+//! Encrypting connections with the platform's TLS library, with the
+//! `native-tls` feature: OpenSSL on Linux, Secure Transport on macOS and
+//! SChannel on Windows.
+//!
+//! ```ignore
+//! let mut tls = try!(NativeTlsConfig::new("cassandra.example.com"));
+//! try!(tls.set_identity(&pkcs12, "changeit"));
+//! let mut config = SessionConfig::default();
+//! config.native_tls = Some(Arc::new(tls));
+//! ```
+//!
+//! Unlike the `tls` module, nodes are trusted if the system trust store
+//! trusts them, so a cluster signed by a CA installed on the host needs no
+//! further setup. Client certificates come as PKCS#12 bundles, the form
+//! most corporate PKIs hand them out in. As there, nodes are verified
+//! against the server name given here rather than their address.
+
+use std::fmt;
+use std::net::TcpStream;
+
+use native_tls::{self, Certificate, HandshakeError, Identity, TlsConnector};
+
+use errors::MyError;
+use protocol::Result;
+use socket::Connection;
+
+#[derive(Clone)]
+pub struct NativeTlsConfig {
+    server_name: String,
+    roots: Vec<Certificate>,
+    identity: Option<Identity>,
+    connector: TlsConnector,
+}
+
+impl fmt::Debug for NativeTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NativeTlsConfig({})", self.server_name)
+    }
+}
+
+impl NativeTlsConfig {
+    /// Verifies nodes as `server_name`, trusting the system's root
+    /// certificates.
+    pub fn new(server_name: &str) -> Result<NativeTlsConfig> {
+        Ok(NativeTlsConfig {
+            server_name: server_name.to_string(),
+            roots: Vec::new(),
+            identity: None,
+            connector: try!(TlsConnector::new().map_err(tls_error)),
+        })
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Also trusts the PEM certificate `pem`, for clusters signed by a CA
+    /// the system doesn't know.
+    pub fn add_root_certificate(&mut self, pem: &[u8]) -> Result<()> {
+        let certificate = try!(Certificate::from_pem(pem)
+            .map_err(|err| MyError::Protocol(format!("Invalid root certificate: {}", err))));
+        self.roots.push(certificate);
+        self.rebuild()
+    }
+
+    /// Presents the certificate and private key in the PKCS#12 bundle
+    /// `der`, for clusters with `require_client_auth`.
+    pub fn set_identity(&mut self, der: &[u8], password: &str) -> Result<()> {
+        let identity = try!(Identity::from_pkcs12(der, password)
+            .map_err(|err| MyError::Protocol(format!("Couldn't read the PKCS#12 bundle: {}", err))));
+        self.identity = Some(identity);
+        self.rebuild()
+    }
+
+    /// Runs the TLS handshake on `conn`, bounded by its read timeout.
+    pub fn connect(&self, conn: TcpStream) -> Result<Connection> {
+        match self.connector.connect(&self.server_name, conn) {
+            Ok(stream) => Ok(Connection::NativeTls(Box::new(stream))),
+            Err(HandshakeError::Failure(err)) => Err(MyError::Protocol(format!("TLS handshake failed: {}", err))),
+            Err(HandshakeError::WouldBlock(_)) => Err(MyError::Timeout("TLS handshake timed out".to_string())),
+        }
+    }
+
+    fn rebuild(&mut self) -> Result<()> {
+        let mut builder = TlsConnector::builder();
+        for root in &self.roots {
+            builder.add_root_certificate(root.clone());
+        }
+        if let Some(ref identity) = self.identity {
+            builder.identity(identity.clone());
+        }
+        self.connector = try!(builder.build().map_err(tls_error));
+        Ok(())
+    }
+}
+
+fn tls_error(err: native_tls::Error) -> MyError {
+    MyError::Protocol(format!("Couldn't set up TLS: {}", err))
+}
@@ -0,0 +1,62 @@
+use std::vec;
+
+use client::Client;
+use protocol::{Result, Row};
+use statement::Statement;
+use types::ToCQL;
+
+/// Rows requested per page when the statement doesn't set a page size.
+pub const DEFAULT_PAGE_SIZE: i32 = 5000;
+
+/// Iterates over every row of a query, fetching the next page from the
+/// server once the current one runs out. Iteration stops after the first
+/// error.
+pub struct Pager<'a> {
+    client: &'a mut Client,
+    statement: Statement,
+    params: &'a [&'a ToCQL],
+    rows: vec::IntoIter<Row>,
+    finished: bool,
+}
+
+impl<'a> Pager<'a> {
+    pub fn new(client: &'a mut Client, mut statement: Statement, params: &'a [&'a ToCQL]) -> Pager<'a> {
+        if statement.page_size().is_none() {
+            statement.set_page_size(DEFAULT_PAGE_SIZE);
+        }
+        Pager {
+            client: client,
+            statement: statement,
+            params: params,
+            rows: Vec::new().into_iter(),
+            finished: false,
+        }
+    }
+
+    fn fetch_page(&mut self) -> Result<()> {
+        let result = try!(self.client.query_statement(&self.statement, self.params));
+        self.statement.set_paging_state(result.paging_state());
+        self.finished = !result.has_more_pages();
+        self.rows = result.rows.into_iter();
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Pager<'a> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Result<Row>> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(err) = self.fetch_page() {
+                self.finished = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
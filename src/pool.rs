@@ -0,0 +1,211 @@
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use client::Client;
+use errors::MyError;
+use protocol::Result;
+use socket::Closer;
+
+/// A fixed set of connections handed out one request at a time. Callers that
+/// find every connection busy wait in FIFO order.
+pub struct Pool {
+    connections: Vec<Mutex<Client>>,
+    // To close connections that are in use, whose locks are held.
+    closers: Vec<Arc<Closer>>,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    closed: bool,
+    idle: Vec<usize>,
+    waiters: VecDeque<u64>,
+    next_ticket: u64,
+    metrics: PoolMetrics,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PoolMetrics {
+    pub checkouts: u64,
+    pub timeouts: u64,
+    /// Callers currently waiting for a connection.
+    pub waiting: usize,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+impl PoolMetrics {
+    pub fn mean_wait(&self) -> Duration {
+        if self.checkouts == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total_wait / self.checkouts as u32
+        }
+    }
+}
+
+/// A connection checked out of a `Pool`; it is returned when dropped.
+pub struct PooledClient<'a> {
+    pool: &'a Pool,
+    index: usize,
+    client: Option<MutexGuard<'a, Client>>,
+}
+
+impl Pool {
+    pub fn new(clients: Vec<Client>) -> Pool {
+        Pool {
+            state: Mutex::new(State {
+                closed: false,
+                idle: (0..clients.len()).rev().collect(),
+                waiters: VecDeque::new(),
+                next_ticket: 0,
+                metrics: PoolMetrics::default(),
+            }),
+            closers: clients.iter().map(|client| client.closer()).collect(),
+            connections: clients.into_iter().map(Mutex::new).collect(),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Every connection in the pool, for reading their state, such as their
+    /// stats. Locking one waits for its current request; work that sends
+    /// on a connection checks it out, with `checkout_idle` if it shouldn't
+    /// queue.
+    pub fn connections(&self) -> &[Mutex<Client>] {
+        &self.connections
+    }
+
+    /// Waits for a free connection. Waiters are served in the order they
+    /// arrived; with a `timeout` the wait gives up with `MyError::Timeout`.
+    /// Once the pool is closed every waiter fails with `MyError::Closed`.
+    pub fn checkout<'a>(&'a self, timeout: Option<Duration>) -> Result<PooledClient<'a>> {
+        let start = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(MyError::Closed);
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiters.push_back(ticket);
+        while state.waiters.front() != Some(&ticket) || state.idle.is_empty() {
+            if state.closed {
+                state.waiters.retain(|&t| t != ticket);
+                return Err(MyError::Closed);
+            }
+            state = match timeout {
+                None => self.released.wait(state).unwrap(),
+                Some(timeout) => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= timeout {
+                        state.waiters.retain(|&t| t != ticket);
+                        state.metrics.timeouts += 1;
+                        self.released.notify_all();
+                        return Err(MyError::Timeout(format!("no connection available after {:?}", timeout)));
+                    }
+                    self.released.wait_timeout(state, timeout - elapsed).unwrap().0
+                }
+            };
+        }
+        state.waiters.pop_front();
+        let index = state.idle.pop().unwrap();
+        let waited = start.elapsed();
+        state.metrics.checkouts += 1;
+        state.metrics.total_wait += waited;
+        if waited > state.metrics.max_wait {
+            state.metrics.max_wait = waited;
+        }
+        if !state.idle.is_empty() {
+            // Let the next waiter in line take another idle connection.
+            self.released.notify_all();
+        }
+        drop(state);
+        Ok(PooledClient {
+            pool: self,
+            index: index,
+            client: Some(self.connections[index].lock().unwrap()),
+        })
+    }
+
+    /// Checks out the connection at `index` if it's idle, for maintenance
+    /// work such as heartbeats, which shouldn't wait in the checkout queue
+    /// or count in the metrics. `None` while the connection is in use or
+    /// once the pool is closed.
+    pub fn checkout_idle<'a>(&'a self, index: usize) -> Option<PooledClient<'a>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        let position = match state.idle.iter().position(|&idle| idle == index) {
+            Some(position) => position,
+            None => return None,
+        };
+        state.idle.remove(position);
+        drop(state);
+        Some(PooledClient {
+            pool: self,
+            index: index,
+            client: Some(self.connections[index].lock().unwrap()),
+        })
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.state.lock().unwrap();
+        let mut metrics = state.metrics.clone();
+        metrics.waiting = state.waiters.len();
+        metrics
+    }
+
+    /// Fails every current and future checkout with `MyError::Closed` and
+    /// shuts down the sockets. Requests running on connections that are
+    /// in use fail with `MyError::Closed` too.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for &index in state.idle.iter() {
+            self.connections[index].lock().unwrap().shutdown();
+        }
+        for closer in &self.closers {
+            closer.close();
+        }
+        self.released.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    fn release(&self, index: usize, mut client: MutexGuard<Client>) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            client.shutdown();
+        }
+        // Unlock the connection before making it available again.
+        drop(client);
+        state.idle.push(index);
+        self.released.notify_all();
+    }
+}
+
+impl<'a> Deref for PooledClient<'a> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledClient<'a> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledClient<'a> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.release(self.index, client);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Driver metrics in the Prometheus text exposition format, for the host
+//! application to serve from its metrics endpoint:
+//!
+//! ```ignore
+//! let body = prometheus::render(&session);
+//! response.set_header("Content-Type", prometheus::CONTENT_TYPE);
+//! ```
+//!
+//! Every metric is named `cassandra_*` and covers the whole session since
+//! it connected: the pool's checkouts and waits, and each connection's
+//! requests, latencies, errors by kind, retries, prepared statement cache
+//! hits, bytes and frames. Requests, errors and time are also broken down
+//! by table and by statement tag. Sessions configured with HDR histograms
+//! also get a `cassandra_request_latency_seconds` summary with p50 to
+//! p99.9.
+//! Rendering waits for each connection's current request, like
+//! `Session::connection_stats`.
+
+use std::time::Duration;
+
+use pool::PoolMetrics;
+use session::Session;
+use stats::{ConnectionStats, LATENCY_BUCKETS_MS};
+
+/// The content type of the text format this module writes.
+pub const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+/// The percentiles reported when connections keep HDR histograms.
+const LATENCY_QUANTILES: &'static [f64] = &[0.5, 0.9, 0.99, 0.999];
+
+/// The session's current metrics.
+pub fn render(session: &Session) -> String {
+    encode(&session.pool_metrics(), &session.connection_stats())
+}
+
+/// Writes pool and connection metrics, such as those of a session.
+pub fn encode(pool: &PoolMetrics, stats: &ConnectionStats) -> String {
+    let mut out = String::new();
+    header(&mut out, "cassandra_pool_checkouts_total", "counter", "Connections checked out of the pool.");
+    sample(&mut out, "cassandra_pool_checkouts_total", "", pool.checkouts);
+    header(&mut out, "cassandra_pool_checkout_timeouts_total", "counter",
+           "Checkouts that gave up waiting for a connection.");
+    sample(&mut out, "cassandra_pool_checkout_timeouts_total", "", pool.timeouts);
+    header(&mut out, "cassandra_pool_waiting", "gauge", "Callers waiting for a connection.");
+    sample(&mut out, "cassandra_pool_waiting", "", pool.waiting);
+    header(&mut out, "cassandra_pool_wait_seconds_total", "counter", "Time spent waiting for connections.");
+    sample(&mut out, "cassandra_pool_wait_seconds_total", "", seconds(pool.total_wait));
+    header(&mut out, "cassandra_pool_wait_seconds_max", "gauge", "Longest wait for a connection.");
+    sample(&mut out, "cassandra_pool_wait_seconds_max", "", seconds(pool.max_wait));
+
+    header(&mut out, "cassandra_request_duration_seconds", "histogram", "Request latency, including failed requests.");
+    let mut cumulative = 0;
+    for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+        cumulative += stats.latency_buckets.get(i).cloned().unwrap_or(0);
+        sample(&mut out, "cassandra_request_duration_seconds_bucket",
+               &format!("le=\"{}\"", *bound as f64 / 1000.0), cumulative);
+    }
+    sample(&mut out, "cassandra_request_duration_seconds_bucket", "le=\"+Inf\"", stats.requests);
+    sample(&mut out, "cassandra_request_duration_seconds_sum", "", seconds(stats.total_latency));
+    sample(&mut out, "cassandra_request_duration_seconds_count", "", stats.requests);
+    if let Some(ref latency) = stats.latency {
+        header(&mut out, "cassandra_request_latency_seconds", "summary",
+               "Request latency percentiles, from HDR histograms.");
+        for quantile in LATENCY_QUANTILES {
+            let value = latency.value_at_quantile(*quantile).map(seconds).unwrap_or(0.0);
+            sample(&mut out, "cassandra_request_latency_seconds", &format!("quantile=\"{}\"", quantile), value);
+        }
+        let sum = latency.mean().map(|mean| seconds(mean) * latency.count() as f64).unwrap_or(0.0);
+        sample(&mut out, "cassandra_request_latency_seconds_sum", "", sum);
+        sample(&mut out, "cassandra_request_latency_seconds_count", "", latency.count());
+    }
+    header(&mut out, "cassandra_request_errors_total", "counter", "Failed requests by kind of error.");
+    let mut errors: Vec<(&String, &u64)> = stats.errors.iter().collect();
+    errors.sort();
+    for (kind, count) in errors {
+        sample(&mut out, "cassandra_request_errors_total", &format!("kind=\"{}\"", kind), count);
+    }
+    header(&mut out, "cassandra_retries_total", "counter",
+           "Requests sent again, as the retry policy decided or after the server lost a prepared statement.");
+    sample(&mut out, "cassandra_retries_total", "", stats.retries);
+    header(&mut out, "cassandra_prepared_cache_hits_total", "counter",
+           "Statements found already prepared on their connection.");
+    sample(&mut out, "cassandra_prepared_cache_hits_total", "", stats.prepared_cache_hits);
+    header(&mut out, "cassandra_prepared_cache_misses_total", "counter", "Statements that had to be prepared.");
+    sample(&mut out, "cassandra_prepared_cache_misses_total", "", stats.prepared_cache_misses);
+
+    header(&mut out, "cassandra_sent_bytes_total", "counter", "Bytes written to connections.");
+    sample(&mut out, "cassandra_sent_bytes_total", "", stats.bytes_sent);
+    header(&mut out, "cassandra_received_bytes_total", "counter", "Bytes read from connections.");
+    sample(&mut out, "cassandra_received_bytes_total", "", stats.bytes_received);
+    header(&mut out, "cassandra_uncompressed_bytes_total", "counter",
+           "Frame bodies sent and received with compression on, uncompressed.");
+    sample(&mut out, "cassandra_uncompressed_bytes_total", "direction=\"sent\"", stats.uncompressed_bytes_sent);
+    sample(&mut out, "cassandra_uncompressed_bytes_total", "direction=\"received\"", stats.uncompressed_bytes_received);
+    header(&mut out, "cassandra_compressed_bytes_total", "counter",
+           "Frame bodies sent and received with compression on, as compressed.");
+    sample(&mut out, "cassandra_compressed_bytes_total", "direction=\"sent\"", stats.compressed_bytes_sent);
+    sample(&mut out, "cassandra_compressed_bytes_total", "direction=\"received\"", stats.compressed_bytes_received);
+    header(&mut out, "cassandra_sent_frames_total", "counter", "Frames sent by opcode.");
+    let mut sent: Vec<(String, u64)> = stats.frames_sent.iter()
+        .map(|(opcode, &count)| (format!("{:?}", opcode).to_lowercase(), count))
+        .collect();
+    sent.sort();
+    for (opcode, count) in sent {
+        sample(&mut out, "cassandra_sent_frames_total", &format!("opcode=\"{}\"", opcode), count);
+    }
+    header(&mut out, "cassandra_received_frames_total", "counter", "Frames received by opcode.");
+    let mut received: Vec<(String, u64)> = stats.frames_received.iter()
+        .map(|(opcode, &count)| (format!("{:?}", opcode).to_lowercase(), count))
+        .collect();
+    received.sort();
+    for (opcode, count) in received {
+        sample(&mut out, "cassandra_received_frames_total", &format!("opcode=\"{}\"", opcode), count);
+    }
+    header(&mut out, "cassandra_decode_errors_total", "counter", "Responses that couldn't be decoded.");
+    sample(&mut out, "cassandra_decode_errors_total", "", stats.decode_errors);
+
+    let mut tables: Vec<_> = stats.tables.iter().collect();
+    tables.sort_by(|a, b| a.0.cmp(b.0));
+    header(&mut out, "cassandra_table_requests_total", "counter", "Requests by the table they read or wrote.");
+    for &(table, table_stats) in &tables {
+        sample(&mut out, "cassandra_table_requests_total", &label("table", table), table_stats.requests);
+    }
+    header(&mut out, "cassandra_table_errors_total", "counter", "Failed requests by the table they read or wrote.");
+    for &(table, table_stats) in &tables {
+        sample(&mut out, "cassandra_table_errors_total", &label("table", table), table_stats.errors);
+    }
+    header(&mut out, "cassandra_table_request_seconds_total", "counter",
+           "Time spent on requests by the table they read or wrote.");
+    for &(table, table_stats) in &tables {
+        sample(&mut out, "cassandra_table_request_seconds_total", &label("table", table), seconds(table_stats.total_latency));
+    }
+
+    let mut tags: Vec<_> = stats.tags.iter().map(|(&(ref key, ref value), tag_stats)| {
+        (format!("{},{}", label("tag", key), label("value", value)), tag_stats)
+    }).collect();
+    tags.sort_by(|a, b| a.0.cmp(&b.0));
+    header(&mut out, "cassandra_tagged_requests_total", "counter", "Requests by the tags of their statements.");
+    for &(ref labels, tag_stats) in &tags {
+        sample(&mut out, "cassandra_tagged_requests_total", labels, tag_stats.requests);
+    }
+    header(&mut out, "cassandra_tagged_errors_total", "counter", "Failed requests by the tags of their statements.");
+    for &(ref labels, tag_stats) in &tags {
+        sample(&mut out, "cassandra_tagged_errors_total", labels, tag_stats.errors);
+    }
+    header(&mut out, "cassandra_tagged_request_seconds_total", "counter",
+           "Time spent on requests by the tags of their statements.");
+    for &(ref labels, tag_stats) in &tags {
+        sample(&mut out, "cassandra_tagged_request_seconds_total", labels, seconds(tag_stats.total_latency));
+    }
+    out
+}
+
+/// Quoted table names and tags may hold anything, so escape them as label
+/// values.
+fn label(name: &str, value: &str) -> String {
+    format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+fn sample<T: ToString>(out: &mut String, name: &str, labels: &str, value: T) {
+    if labels.is_empty() {
+        out.push_str(&format!("{} {}\n", name, value.to_string()));
+    } else {
+        out.push_str(&format!("{}{{{}}} {}\n", name, labels, value.to_string()));
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
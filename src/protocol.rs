@@ -1,9 +1,19 @@
+use std::fmt;
 use std::result;
+use std::ops::Range;
+use std::time::Duration;
+use std::sync::Arc;
 use std::collections::HashMap;
-use std::io::{Read, Write, Cursor};
+use std::io::{self, Read, Write, Cursor};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use uuid::Uuid;
 
-use errors::MyError;
+use batch::{Batch, BatchStatement, BatchType};
+use errors::{AlreadyExists, ErrorCode, ErrorDetails, FunctionFailure, MyError, ReadTimeout, Unavailable, Unprepared,
+             WriteTimeout, WriteType};
+use events::{EventType, SchemaChange};
+use statement;
+use stats::ColumnStats;
 use types::{CQLType, FromCQL, ToCQL};
 
 pub type Result<T> = result::Result<T, MyError>;
@@ -16,15 +26,29 @@ pub trait FromWire: Sized {
     fn decode<T: Read>(buffer: &mut T) -> Result<Self>;
 }
 
+/// A complete request frame.
+pub trait Request: ToWire {
+    fn opcode(&self) -> Opcode;
+
+    /// Sets the protocol version the frame is encoded for.
+    fn set_version(&mut self, version: ProtocolVersion);
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Header {
     version: Version,
-    flags: Flags,
+    pub flags: Flags,
     stream: u16,
     pub opcode: Opcode,
     pub length: u32,
 }
 
+impl Header {
+    pub fn version(&self) -> ProtocolVersion {
+        self.version.protocol()
+    }
+}
+
 impl ToWire for Header {
     fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
         try!(self.version.encode(buffer));
@@ -45,29 +69,218 @@ impl FromWire for Header {
             opcode: try!(Opcode::decode(buffer)),
             length: try!(buffer.read_u32::<BigEndian>()),
         };
+        Ok(header)
+    }
+}
 
-        match header.opcode {
+#[derive(Debug)]
+pub struct Frame {
+    pub header: Header,
+    pub body: Vec<u8>,
+    /// Set on responses to traced requests.
+    pub tracing_id: Option<Uuid>,
+    /// Warnings the server attached to the response, since v4.
+    pub warnings: Vec<String>,
+    /// Values server plugins attached to the response, since v4.
+    pub custom_payload: Option<HashMap<String, Vec<u8>>>,
+}
+
+impl Frame {
+    /// Moves the tracing id, warnings and custom payload that precede the
+    /// body of a response, in that order, out of the body. Compressed
+    /// bodies must be decompressed first.
+    pub fn split_prefix(mut self) -> Result<Frame> {
+        let flags = self.header.flags;
+        if !flags.tracing && !flags.warning && !flags.custom_payload {
+            return Ok(self);
+        }
+        let (tracing_id, warnings, custom_payload, consumed) = {
+            let mut cursor = Cursor::new(&self.body[..]);
+            let tracing_id = if flags.tracing {
+                let mut id = [0; 16];
+                try!(cursor.read_exact(&mut id).map_err(|_| {
+                    MyError::Protocol("Traced response is too short for a tracing id".to_string())
+                }));
+                Some(try!(Uuid::parse(&id)))
+            } else {
+                None
+            };
+            let warnings = if flags.warning {
+                try!(Vec::<String>::decode(&mut cursor))
+            } else {
+                Vec::new()
+            };
+            let custom_payload = if flags.custom_payload {
+                Some(try!(HashMap::<String, Vec<u8>>::decode(&mut cursor)))
+            } else {
+                None
+            };
+            (tracing_id, warnings, custom_payload, cursor.position() as usize)
+        };
+        self.body = self.body.split_off(consumed);
+        self.tracing_id = tracing_id;
+        self.warnings = warnings;
+        self.custom_payload = custom_payload;
+        self.header.flags.tracing = false;
+        self.header.flags.warning = false;
+        self.header.flags.custom_payload = false;
+        self.header.length = self.body.len() as u32;
+        Ok(self)
+    }
+
+    /// Turns an Error frame into the error it carries, passing any other frame through.
+    pub fn into_response(self) -> Result<Frame> {
+        match self.header.opcode {
             Opcode::Error => {
-                let code = try!(buffer.read_u32::<BigEndian>());
-                let message = try!(String::decode(buffer));
-                Err(MyError::Protocol(format!("Error 0x{:04X}: {}", code, message)))
+                let mut body = Cursor::new(self.body);
+                let code = try!(body.read_u32::<BigEndian>());
+                let message = try!(String::decode(&mut body));
+                // Details that don't decode are dropped rather than hiding
+                // the error itself.
+                let details = decode_error_details(ErrorCode::from_code(code), &mut body).unwrap_or(None);
+                Err(MyError::Server(code, message, details))
             },
-            _ => Ok(header),
+            _ => Ok(self),
+        }
+    }
+}
+
+/// Reads the fields that follow the message of an error with `code`.
+fn decode_error_details<T: Read>(code: ErrorCode, body: &mut T) -> Result<Option<ErrorDetails>> {
+    match code {
+        ErrorCode::Unavailable => {
+            Ok(Some(ErrorDetails::Unavailable(Unavailable {
+                consistency: try!(Consistency::decode(body)),
+                required: try!(body.read_i32::<BigEndian>()),
+                alive: try!(body.read_i32::<BigEndian>()),
+            })))
+        },
+        ErrorCode::ReadTimeout => {
+            Ok(Some(ErrorDetails::ReadTimeout(ReadTimeout {
+                consistency: try!(Consistency::decode(body)),
+                received: try!(body.read_i32::<BigEndian>()),
+                block_for: try!(body.read_i32::<BigEndian>()),
+                data_present: try!(body.read_u8()) != 0,
+            })))
+        },
+        ErrorCode::WriteTimeout => {
+            let consistency = try!(Consistency::decode(body));
+            let received = try!(body.read_i32::<BigEndian>());
+            let block_for = try!(body.read_i32::<BigEndian>());
+            let write_type = WriteType::from_name(&try!(String::decode(body)));
+            let contentions = if write_type == WriteType::Cas { body.read_u16::<BigEndian>().ok() } else { None };
+            Ok(Some(ErrorDetails::WriteTimeout(WriteTimeout {
+                consistency: consistency,
+                received: received,
+                block_for: block_for,
+                write_type: write_type,
+                contentions: contentions,
+            })))
+        },
+        ErrorCode::FunctionFailure => {
+            Ok(Some(ErrorDetails::FunctionFailure(FunctionFailure {
+                keyspace: try!(String::decode(body)),
+                function: try!(String::decode(body)),
+                arg_types: try!(Vec::<String>::decode(body)),
+            })))
+        },
+        ErrorCode::AlreadyExists => {
+            let keyspace = try!(String::decode(body));
+            let table = try!(String::decode(body));
+            Ok(Some(ErrorDetails::AlreadyExists(AlreadyExists {
+                keyspace: keyspace,
+                table: if table.is_empty() { None } else { Some(table) },
+            })))
+        },
+        ErrorCode::Unprepared => {
+            let len = try!(body.read_u16::<BigEndian>());
+            let mut id = vec![0; len as usize];
+            try!(body.read_exact(&mut id));
+            Ok(Some(ErrorDetails::Unprepared(Unprepared { id: id })))
+        },
+        _ => Ok(None),
+    }
+}
+
+impl FromWire for Frame {
+    fn decode<T: Read>(buffer: &mut T) -> Result<Frame> {
+        let header = try!(Header::decode(buffer));
+        let mut body = vec![0; header.length as usize];
+        try!(buffer.read_exact(&mut body));
+        Ok(Frame {
+            header: header,
+            body: body,
+            tracing_id: None,
+            warnings: Vec::new(),
+            custom_payload: None,
+        })
+    }
+}
+
+/// The native protocol versions this driver speaks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+    V3,
+    V4,
+    /// Wraps frames in checksummed segments once the connection is ready;
+    /// see the `framing` module.
+    V5,
+}
+
+impl ProtocolVersion {
+    /// The version connections try first.
+    pub fn latest() -> ProtocolVersion {
+        ProtocolVersion::V5
+    }
+
+    pub fn number(&self) -> u8 {
+        match *self {
+            ProtocolVersion::V3 => 3,
+            ProtocolVersion::V4 => 4,
+            ProtocolVersion::V5 => 5,
+        }
+    }
+
+    pub fn from_number(number: u8) -> Option<ProtocolVersion> {
+        match number {
+            3 => Some(ProtocolVersion::V3),
+            4 => Some(ProtocolVersion::V4),
+            5 => Some(ProtocolVersion::V5),
+            _ => None,
+        }
+    }
+
+    /// The version to fall back to when a server rejects this one.
+    pub fn previous(&self) -> Option<ProtocolVersion> {
+        match *self {
+            ProtocolVersion::V3 => None,
+            ProtocolVersion::V4 => Some(ProtocolVersion::V3),
+            ProtocolVersion::V5 => Some(ProtocolVersion::V4),
         }
     }
 }
 
+/// The version byte of a frame header: the protocol version, with the
+/// high bit set on responses.
 #[derive(Debug, Copy, Clone)]
 pub enum Version {
-    Request,
-    Response,
+    Request(ProtocolVersion),
+    Response(ProtocolVersion),
+}
+
+impl Version {
+    pub fn protocol(&self) -> ProtocolVersion {
+        match *self {
+            Version::Request(version) | Version::Response(version) => version,
+        }
+    }
 }
 
 impl ToWire for Version {
     fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
         try!(buffer.write_u8(match *self {
-            Version::Request => 0x03,
-            Version::Response => 0x83,
+            Version::Request(version) => version.number(),
+            Version::Response(version) => 0x80 | version.number(),
         }));
         Ok(())
     }
@@ -76,10 +289,10 @@ impl ToWire for Version {
 impl FromWire for Version {
     fn decode<T: Read>(buffer: &mut T) -> Result<Version> {
         let version = try!(buffer.read_u8());
-        match version {
-            0x03 => Ok(Version::Request),
-            0x83 => Ok(Version::Response),
-            _ => Err(MyError::Protocol(format!("unknown version header: {:02x}", version))),
+        match ProtocolVersion::from_number(version & 0x7F) {
+            Some(protocol) if version & 0x80 != 0 => Ok(Version::Response(protocol)),
+            Some(protocol) => Ok(Version::Request(protocol)),
+            None => Err(MyError::Protocol(format!("unknown version header: {:02x}", version))),
         }
     }
 }
@@ -88,11 +301,13 @@ impl FromWire for Version {
 pub struct Flags {
     pub compression: bool,
     pub tracing: bool,
+    pub custom_payload: bool,
+    pub warning: bool,
 }
 
 impl Flags {
     fn new() -> Flags {
-        Flags { compression: false, tracing: false }
+        Flags { compression: false, tracing: false, custom_payload: false, warning: false }
     }
 }
 
@@ -100,7 +315,9 @@ impl ToWire for Flags {
     fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
         let compression = if self.compression { 0x01 } else { 0x00 };
         let tracing = if self.tracing { 0x02 } else { 0x00 };
-        try!(buffer.write_u8(compression | tracing));
+        let custom_payload = if self.custom_payload { 0x04 } else { 0x00 };
+        let warning = if self.warning { 0x08 } else { 0x00 };
+        try!(buffer.write_u8(compression | tracing | custom_payload | warning));
         Ok(())
     }
 }
@@ -111,13 +328,15 @@ impl FromWire for Flags {
         Ok(Flags {
             compression: (flags & 0x01) > 0,
             tracing: (flags & 0x02) > 0,
+            custom_payload: (flags & 0x04) > 0,
+            warning: (flags & 0x08) > 0,
         })
     }
 }
 
 macro_rules! opcodes {
     ( $( $val:expr => $var:ident, )* ) => {
-        #[derive(Debug, Copy, Clone, PartialEq)]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
         pub enum Opcode {
             $(
                 $var = $val,
@@ -169,6 +388,97 @@ opcodes!(
     0x10 => AuthSuccess,
 );
 
+/// How many replicas must answer a request. Statements, prepared
+/// executions and batches all take one, and run at `One` by default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Consistency {
+    Any,
+    One,
+    Two,
+    Three,
+    Quorum,
+    All,
+    LocalQuorum,
+    EachQuorum,
+    Serial,
+    LocalSerial,
+    LocalOne,
+}
+
+const CONSISTENCY_NAMES: [(Consistency, &'static str); 11] = [
+    (Consistency::Any, "ANY"),
+    (Consistency::One, "ONE"),
+    (Consistency::Two, "TWO"),
+    (Consistency::Three, "THREE"),
+    (Consistency::Quorum, "QUORUM"),
+    (Consistency::All, "ALL"),
+    (Consistency::LocalQuorum, "LOCAL_QUORUM"),
+    (Consistency::EachQuorum, "EACH_QUORUM"),
+    (Consistency::Serial, "SERIAL"),
+    (Consistency::LocalSerial, "LOCAL_SERIAL"),
+    (Consistency::LocalOne, "LOCAL_ONE"),
+];
+
+impl Consistency {
+    /// The name cqlsh and the server use, e.g. `LOCAL_QUORUM`.
+    pub fn name(&self) -> &'static str {
+        CONSISTENCY_NAMES.iter().find(|&&(c, _)| c == *self).unwrap().1
+    }
+
+    /// Reads a consistency by its name, in any case, e.g. from a config
+    /// file.
+    pub fn parse(name: &str) -> Result<Consistency> {
+        CONSISTENCY_NAMES.iter()
+            .find(|&&(_, n)| n.eq_ignore_ascii_case(name.trim()))
+            .map(|&(c, _)| c)
+            .ok_or_else(|| MyError::Protocol(format!("Unknown consistency level {:?}", name)))
+    }
+}
+
+impl fmt::Display for Consistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromWire for Consistency {
+    fn decode<T: Read>(buffer: &mut T) -> Result<Consistency> {
+        match try!(buffer.read_u16::<BigEndian>()) {
+            0x0000 => Ok(Consistency::Any),
+            0x0001 => Ok(Consistency::One),
+            0x0002 => Ok(Consistency::Two),
+            0x0003 => Ok(Consistency::Three),
+            0x0004 => Ok(Consistency::Quorum),
+            0x0005 => Ok(Consistency::All),
+            0x0006 => Ok(Consistency::LocalQuorum),
+            0x0007 => Ok(Consistency::EachQuorum),
+            0x0008 => Ok(Consistency::Serial),
+            0x0009 => Ok(Consistency::LocalSerial),
+            0x000A => Ok(Consistency::LocalOne),
+            code => Err(MyError::Protocol(format!("Unknown consistency 0x{:04X}", code))),
+        }
+    }
+}
+
+impl ToWire for Consistency {
+    fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
+        try!(buffer.write_u16::<BigEndian>(match *self {
+            Consistency::Any => 0x0000,
+            Consistency::One => 0x0001,
+            Consistency::Two => 0x0002,
+            Consistency::Three => 0x0003,
+            Consistency::Quorum => 0x0004,
+            Consistency::All => 0x0005,
+            Consistency::LocalQuorum => 0x0006,
+            Consistency::EachQuorum => 0x0007,
+            Consistency::Serial => 0x0008,
+            Consistency::LocalSerial => 0x0009,
+            Consistency::LocalOne => 0x000A,
+        }));
+        Ok(())
+    }
+}
+
 pub type StringMultiMap = HashMap<String, Vec<String>>;
 
 impl FromWire for StringMultiMap {
@@ -206,6 +516,45 @@ impl FromWire for String {
     }
 }
 
+/// [string list].
+impl FromWire for Vec<String> {
+    fn decode<T: Read>(buffer: &mut T) -> Result<Vec<String>> {
+        let count = try!(buffer.read_u16::<BigEndian>());
+        let mut strings = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            strings.push(try!(String::decode(buffer)));
+        }
+        Ok(strings)
+    }
+}
+
+/// [bytes map], with null values read as empty.
+impl FromWire for HashMap<String, Vec<u8>> {
+    fn decode<T: Read>(buffer: &mut T) -> Result<HashMap<String, Vec<u8>>> {
+        let count = try!(buffer.read_u16::<BigEndian>());
+        let mut map = HashMap::with_capacity(count as usize);
+        for _ in 0..count {
+            let key = try!(String::decode(buffer));
+            let value: Option<Vec<u8>> = try!(FromWire::decode(buffer));
+            map.insert(key, value.unwrap_or_default());
+        }
+        Ok(map)
+    }
+}
+
+/// [bytes], where a negative length means null.
+impl FromWire for Option<Vec<u8>> {
+    fn decode<T: Read>(buffer: &mut T) -> Result<Option<Vec<u8>>> {
+        let len = try!(buffer.read_i32::<BigEndian>());
+        if len < 0 {
+            return Ok(None);
+        }
+        let mut bytes = vec![0; len as usize];
+        try!(buffer.read_exact(&mut bytes));
+        Ok(Some(bytes))
+    }
+}
+
 pub struct OptionsRequest {
     header: Header
 }
@@ -214,7 +563,7 @@ impl OptionsRequest {
     pub fn new() -> OptionsRequest {
         OptionsRequest {
             header: Header {
-                version: Version::Request,
+                version: Version::Request(ProtocolVersion::V3),
                 flags: Flags::new(),
                 stream: 0,
                 opcode: Opcode::Options,
@@ -230,6 +579,16 @@ impl ToWire for OptionsRequest {
     }
 }
 
+impl Request for OptionsRequest {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
 type StringMap<'a> = HashMap<&'a str, &'a str>;
 
 impl<'a> ToWire for StringMap<'a> {
@@ -249,14 +608,17 @@ pub struct StartupRequest {
 }
 
 impl StartupRequest {
-    pub fn new(cql_version: &str) -> StartupRequest {
+    pub fn new(cql_version: &str, compression: Option<&str>) -> StartupRequest {
         let mut options = HashMap::new();
         options.insert("CQL_VERSION", cql_version);
+        if let Some(compression) = compression {
+            options.insert("COMPRESSION", compression);
+        }
         let mut body = Vec::new();
         options.encode(&mut body).unwrap();
         StartupRequest {
             header: Header {
-                version: Version::Request,
+                version: Version::Request(ProtocolVersion::V3),
                 flags: Flags::new(),
                 stream: 0,
                 opcode: Opcode::Startup,
@@ -275,145 +637,1016 @@ impl ToWire for StartupRequest {
     }
 }
 
+impl Request for StartupRequest {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
+/// The options shared by QUERY and EXECUTE requests.
+pub struct QueryParameters<'a> {
+    pub consistency: Consistency,
+    pub values: &'a [&'a ToCQL],
+    pub page_size: Option<i32>,
+    pub paging_state: Option<&'a [u8]>,
+    /// The write timestamp, in microseconds since the epoch, instead of
+    /// the coordinator's clock.
+    pub timestamp: Option<i64>,
+    /// Sent as a header flag rather than in the body.
+    pub tracing: bool,
+    /// Asks for rows without their column metadata, for EXECUTE requests
+    /// whose result columns are known from preparing.
+    pub skip_metadata: bool,
+    /// Extra key/value pairs for server plugins, sent ahead of the body.
+    pub custom_payload: Vec<(&'a str, &'a [u8])>,
+    /// The keyspace unqualified table names refer to, instead of the
+    /// connection's. Needs v5.
+    pub keyspace: Option<&'a str>,
+    /// The time the server should take as now, for TTLs and functions
+    /// like `now()`, in seconds since the epoch. Needs v5.
+    pub now_in_seconds: Option<i32>,
+}
+
+impl<'a> QueryParameters<'a> {
+    pub fn new(values: &'a [&'a ToCQL]) -> QueryParameters<'a> {
+        QueryParameters {
+            consistency: Consistency::One,
+            values: values,
+            page_size: None,
+            paging_state: None,
+            timestamp: None,
+            tracing: false,
+            skip_metadata: false,
+            custom_payload: Vec::new(),
+            keyspace: None,
+            now_in_seconds: None,
+        }
+    }
+
+    fn flags(&self) -> u32 {
+        let mut flags = 0x00;
+        if self.values.len() > 0 {
+            flags |= 0x01;
+        }
+        if self.skip_metadata {
+            flags |= 0x02;
+        }
+        if self.page_size.is_some() {
+            flags |= 0x04;
+        }
+        if self.paging_state.is_some() {
+            flags |= 0x08;
+        }
+        if self.timestamp.is_some() {
+            flags |= 0x20;
+        }
+        if self.keyspace.is_some() {
+            flags |= 0x80;
+        }
+        if self.now_in_seconds.is_some() {
+            flags |= 0x100;
+        }
+        flags
+    }
+
+    fn encoded_len(&self, version: ProtocolVersion) -> Result<usize> {
+        let mut len = 2 + flags_len(version);
+        if self.values.len() > 0 {
+            len += try!(values_len(self.values));
+        }
+        if self.page_size.is_some() {
+            len += 4;
+        }
+        if let Some(state) = self.paging_state {
+            len += 4 + state.len();
+        }
+        if self.timestamp.is_some() {
+            len += 8;
+        }
+        if let Some(keyspace) = self.keyspace {
+            len += 2 + keyspace.len();
+        }
+        if self.now_in_seconds.is_some() {
+            len += 4;
+        }
+        Ok(len)
+    }
+
+    fn payload_len(&self) -> usize {
+        if self.custom_payload.is_empty() {
+            return 0;
+        }
+        self.custom_payload.iter().fold(2, |len, &(key, value)| len + 2 + key.len() + 4 + value.len())
+    }
+
+    /// Sets the header flag and writes the custom payload, if there is one,
+    /// as a [bytes map]. Fails before writing anything if these options
+    /// need a newer protocol version than the header's.
+    fn encode_payload<T: Write>(&self, header: &mut Header, buffer: &mut T) -> Result<()> {
+        try!(check_values(self.values, header.version()));
+        if header.version() < ProtocolVersion::V5 && (self.keyspace.is_some() || self.now_in_seconds.is_some()) {
+            return Err(MyError::Protocol("Per-query keyspaces and now_in_seconds need native protocol v5".to_string()));
+        }
+        if self.custom_payload.is_empty() {
+            return header.encode(buffer);
+        }
+        if header.version() < ProtocolVersion::V4 {
+            return Err(MyError::Protocol("Custom payloads need native protocol v4 or later".to_string()));
+        }
+        header.flags.custom_payload = true;
+        try!(header.encode(buffer));
+        try!(buffer.write_u16::<BigEndian>(self.custom_payload.len() as u16));
+        for &(key, value) in &self.custom_payload {
+            try!(key.encode(buffer));
+            try!(buffer.write_i32::<BigEndian>(value.len() as i32));
+            try!(buffer.write_all(value));
+        }
+        Ok(())
+    }
+
+    /// Writes the options themselves; the flags grew from a byte to an
+    /// int in v5.
+    fn encode_for<T: Write>(&self, version: ProtocolVersion, buffer: &mut T) -> Result<()> {
+        try!(self.consistency.encode(buffer));
+        try!(encode_flags(self.flags(), version, buffer));
+        if self.values.len() > 0 {
+            try!(encode_values(self.values, buffer));
+        }
+        if let Some(page_size) = self.page_size {
+            try!(buffer.write_i32::<BigEndian>(page_size));
+        }
+        if let Some(state) = self.paging_state {
+            try!(buffer.write_i32::<BigEndian>(state.len() as i32));
+            try!(buffer.write_all(state));
+        }
+        if let Some(timestamp) = self.timestamp {
+            try!(buffer.write_i64::<BigEndian>(timestamp));
+        }
+        if let Some(keyspace) = self.keyspace {
+            try!(keyspace.encode(buffer));
+        }
+        if let Some(now) = self.now_in_seconds {
+            try!(buffer.write_i32::<BigEndian>(now));
+        }
+        Ok(())
+    }
+}
+
 pub struct QueryRequest<'a> {
     header: Header,
     query: &'a str,
-    consistency: u16,
-    flags: u8,
-    params: &'a [&'a ToCQL],
+    parameters: QueryParameters<'a>,
 }
 
 impl<'a> QueryRequest<'a> {
-    pub fn new(query: &'a str, params: &'a [&'a ToCQL]) -> QueryRequest<'a> {
-        let flags = match params.len() {
-            0 => 0x00,
-            _ => 0x01,
-        };
+    pub fn new(query: &'a str, parameters: QueryParameters<'a>) -> QueryRequest<'a> {
         QueryRequest {
             header: Header {
-                version: Version::Request,
+                version: Version::Request(ProtocolVersion::V3),
                 flags: Flags::new(),
                 stream: 0,
                 opcode: Opcode::Query,
                 length: 0,
             },
             query: query,
-            consistency: 0x0001,
-            flags: flags,
-            params: params,
+            parameters: parameters,
         }
     }
+
+    fn body_len(&self) -> Result<usize> {
+        Ok(self.parameters.payload_len() + 4 + self.query.len() +
+           try!(self.parameters.encoded_len(self.header.version())))
+    }
 }
 
 impl<'a> ToWire for QueryRequest<'a> {
     fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
-        let mut body = Vec::new();
         let mut header = self.header;
-        try!(body.write_u32::<BigEndian>(self.query.len() as u32));
-        try!(body.write_all(self.query.as_bytes()));
-        try!(body.write_u16::<BigEndian>(self.consistency));
-        try!(body.write_u8(self.flags));
-        if self.params.len() > 0 {
-            try!(body.write_u16::<BigEndian>(self.params.len() as u16));
-            for p in self.params {
-                let bytes = p.serialize();
-                try!(body.write_i32::<BigEndian>(bytes.len() as i32));
-                try!(body.write_all(&bytes));
-            }
+        header.length = try!(self.body_len()) as u32;
+        header.flags.tracing = self.parameters.tracing;
+        try!(self.parameters.encode_payload(&mut header, buffer));
+        try!(buffer.write_u32::<BigEndian>(self.query.len() as u32));
+        try!(buffer.write_all(self.query.as_bytes()));
+        self.parameters.encode_for(header.version(), buffer)
+    }
+}
+
+impl<'a> Request for QueryRequest<'a> {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
+pub struct PrepareRequest<'a> {
+    header: Header,
+    query: &'a str,
+}
+
+impl<'a> PrepareRequest<'a> {
+    pub fn new(query: &'a str) -> PrepareRequest<'a> {
+        PrepareRequest {
+            header: Header {
+                version: Version::Request(ProtocolVersion::V3),
+                flags: Flags::new(),
+                stream: 0,
+                opcode: Opcode::Prepare,
+                length: 0,
+            },
+            query: query,
         }
-        header.length = body.len() as u32;
+    }
+}
+
+impl<'a> ToWire for PrepareRequest<'a> {
+    fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
+        // v5 adds flags, which only say whether a keyspace follows.
+        let version = self.header.version();
+        let mut header = self.header;
+        header.length = (4 + self.query.len() + if version >= ProtocolVersion::V5 { 4 } else { 0 }) as u32;
         try!(header.encode(buffer));
-        try!(buffer.write_all(body.as_ref()));
+        try!(buffer.write_u32::<BigEndian>(self.query.len() as u32));
+        try!(buffer.write_all(self.query.as_bytes()));
+        if version >= ProtocolVersion::V5 {
+            try!(buffer.write_u32::<BigEndian>(0));
+        }
         Ok(())
     }
 }
 
-#[derive(Debug)]
-pub struct QueryResult {
+impl<'a> Request for PrepareRequest<'a> {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
+pub struct RegisterRequest<'a> {
+    header: Header,
+    events: &'a [EventType],
+}
+
+impl<'a> RegisterRequest<'a> {
+    pub fn new(events: &'a [EventType]) -> RegisterRequest<'a> {
+        let length = events.iter().fold(2, |len, event| len + 2 + event.name().len());
+        RegisterRequest {
+            header: Header {
+                version: Version::Request(ProtocolVersion::V3),
+                flags: Flags::new(),
+                stream: 0,
+                opcode: Opcode::Register,
+                length: length as u32,
+            },
+            events: events,
+        }
+    }
+}
+
+impl<'a> ToWire for RegisterRequest<'a> {
+    fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
+        try!(self.header.encode(buffer));
+        try!(buffer.write_u16::<BigEndian>(self.events.len() as u16));
+        for event in self.events {
+            try!(event.name().encode(buffer));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Request for RegisterRequest<'a> {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
+pub struct AuthResponseRequest<'a> {
+    header: Header,
+    token: &'a [u8],
+}
+
+impl<'a> AuthResponseRequest<'a> {
+    pub fn new(token: &'a [u8]) -> AuthResponseRequest<'a> {
+        AuthResponseRequest {
+            header: Header {
+                version: Version::Request(ProtocolVersion::V3),
+                flags: Flags::new(),
+                stream: 0,
+                opcode: Opcode::AuthResponse,
+                length: 4 + token.len() as u32,
+            },
+            token: token,
+        }
+    }
+}
+
+impl<'a> ToWire for AuthResponseRequest<'a> {
+    fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
+        try!(self.header.encode(buffer));
+        try!(buffer.write_i32::<BigEndian>(self.token.len() as i32));
+        try!(buffer.write_all(self.token));
+        Ok(())
+    }
+}
+
+impl<'a> Request for AuthResponseRequest<'a> {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
+pub struct ExecuteRequest<'a> {
+    header: Header,
+    prepared: &'a PreparedStatement,
+    parameters: QueryParameters<'a>,
+}
+
+impl<'a> ExecuteRequest<'a> {
+    pub fn new(prepared: &'a PreparedStatement, parameters: QueryParameters<'a>) -> ExecuteRequest<'a> {
+        ExecuteRequest {
+            header: Header {
+                version: Version::Request(ProtocolVersion::V3),
+                flags: Flags::new(),
+                stream: 0,
+                opcode: Opcode::Execute,
+                length: 0,
+            },
+            prepared: prepared,
+            parameters: parameters,
+        }
+    }
+
+    fn body_len(&self) -> Result<usize> {
+        let version = self.header.version();
+        let mut len = self.parameters.payload_len() + 2 + self.prepared.id().len();
+        if version >= ProtocolVersion::V5 {
+            len += 2 + self.prepared.result_metadata_id().len();
+        }
+        Ok(len + try!(self.parameters.encoded_len(version)))
+    }
+}
+
+impl<'a> ToWire for ExecuteRequest<'a> {
+    fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
+        let mut header = self.header;
+        header.length = try!(self.body_len()) as u32;
+        header.flags.tracing = self.parameters.tracing;
+        try!(self.parameters.encode_payload(&mut header, buffer));
+        try!(buffer.write_u16::<BigEndian>(self.prepared.id().len() as u16));
+        try!(buffer.write_all(self.prepared.id()));
+        if header.version() >= ProtocolVersion::V5 {
+            // Lets the server tell whether our idea of the result columns
+            // is out of date.
+            let metadata_id = self.prepared.result_metadata_id();
+            try!(buffer.write_u16::<BigEndian>(metadata_id.len() as u16));
+            try!(buffer.write_all(metadata_id));
+        }
+        self.parameters.encode_for(header.version(), buffer)
+    }
+}
+
+impl<'a> Request for ExecuteRequest<'a> {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
+pub struct BatchRequest<'a> {
     header: Header,
+    batch: &'a Batch<'a>,
+}
+
+impl<'a> BatchRequest<'a> {
+    pub fn new(batch: &'a Batch<'a>) -> BatchRequest<'a> {
+        BatchRequest {
+            header: Header {
+                version: Version::Request(ProtocolVersion::V3),
+                flags: Flags::new(),
+                stream: 0,
+                opcode: Opcode::Batch,
+                length: 0,
+            },
+            batch: batch,
+        }
+    }
+
+    fn body_len(&self) -> Result<usize> {
+        let mut len = 1 + 2;
+        for statement in self.batch.statements() {
+            len += 1;
+            len += match *statement {
+                BatchStatement::Query(query, _) => 4 + query.len(),
+                BatchStatement::Prepared(prepared, _) => 2 + prepared.id().len(),
+            };
+            len += try!(values_len(statement.params()));
+        }
+        let timestamp_len = if self.batch.timestamp().is_some() { 8 } else { 0 };
+        Ok(len + 2 + flags_len(self.header.version()) + timestamp_len)
+    }
+}
+
+impl<'a> ToWire for BatchRequest<'a> {
+    fn encode<T: Write>(&self, buffer: &mut T) -> Result<()> {
+        for statement in self.batch.statements() {
+            try!(check_values(statement.params(), self.header.version()));
+        }
+        let mut header = self.header;
+        header.length = try!(self.body_len()) as u32;
+        try!(header.encode(buffer));
+        try!(buffer.write_u8(match self.batch.batch_type() {
+            BatchType::Logged => 0,
+            BatchType::Unlogged => 1,
+            BatchType::Counter => 2,
+        }));
+        try!(buffer.write_u16::<BigEndian>(self.batch.statements().len() as u16));
+        for statement in self.batch.statements() {
+            match *statement {
+                BatchStatement::Query(query, _) => {
+                    try!(buffer.write_u8(0));
+                    try!(buffer.write_u32::<BigEndian>(query.len() as u32));
+                    try!(buffer.write_all(query.as_bytes()));
+                },
+                BatchStatement::Prepared(prepared, _) => {
+                    try!(buffer.write_u8(1));
+                    try!(buffer.write_u16::<BigEndian>(prepared.id().len() as u16));
+                    try!(buffer.write_all(prepared.id()));
+                },
+            }
+            try!(encode_values(statement.params(), buffer));
+        }
+        try!(self.batch.consistency().encode(buffer));
+        match self.batch.timestamp() {
+            Some(timestamp) => {
+                try!(encode_flags(0x20, self.header.version(), buffer));
+                try!(buffer.write_i64::<BigEndian>(timestamp));
+            },
+            None => try!(encode_flags(0x00, self.header.version(), buffer)),
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Request for BatchRequest<'a> {
+    fn opcode(&self) -> Opcode {
+        self.header.opcode
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.header.version = Version::Request(version);
+    }
+}
+
+/// The [value] length that marks a value as unset.
+const UNSET_LENGTH: i32 = -2;
+
+/// Encoded size of a list of bound values, including the count prefix.
+fn values_len(params: &[&ToCQL]) -> Result<usize> {
+    let mut len = 2;
+    for p in params {
+        len += 4 + try!(p.serialized_len());
+    }
+    Ok(len)
+}
+
+/// Writes bound values straight into the frame, so large values are never
+/// copied into a buffer of their own.
+fn encode_values<T: Write>(params: &[&ToCQL], buffer: &mut T) -> Result<()> {
+    try!(buffer.write_u16::<BigEndian>(params.len() as u16));
+    for p in params {
+        if p.is_unset() {
+            try!(buffer.write_i32::<BigEndian>(UNSET_LENGTH));
+            continue;
+        }
+        try!(buffer.write_i32::<BigEndian>(try!(p.serialized_len()) as i32));
+        try!(p.serialize(buffer));
+    }
+    Ok(())
+}
+
+/// Unset values are only understood from v4 on; older servers would read
+/// the length as null.
+fn check_values(params: &[&ToCQL], version: ProtocolVersion) -> Result<()> {
+    if version < ProtocolVersion::V4 && params.iter().any(|p| p.is_unset()) {
+        return Err(MyError::Protocol("Unset values need native protocol v4 or later".to_string()));
+    }
+    Ok(())
+}
+
+/// QUERY, EXECUTE and BATCH flags take a byte before v5 and an int from it.
+fn flags_len(version: ProtocolVersion) -> usize {
+    if version >= ProtocolVersion::V5 { 4 } else { 1 }
+}
+
+fn encode_flags<T: Write>(flags: u32, version: ProtocolVersion, buffer: &mut T) -> Result<()> {
+    if version >= ProtocolVersion::V5 {
+        try!(buffer.write_u32::<BigEndian>(flags));
+    } else {
+        try!(buffer.write_u8(flags as u8));
+    }
+    Ok(())
+}
+
+/// A writer that counts the bytes passing through it.
+pub struct CountingWriter<W> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner: inner, count: 0 }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryResult {
     kind: ResultKind, // TODO: always rows?
     flags: ResultFlags,
     table_spec: Option<TableSpec>,
+    paging_state: Option<Vec<u8>>,
+    new_metadata_id: Option<Vec<u8>>,
+    keyspace: Option<String>,
+    tracing_id: Option<Uuid>,
+    warnings: Vec<String>,
+    custom_payload: Option<HashMap<String, Vec<u8>>>,
+    columns: Vec<ColumnSpec>,
+    // Set on results served from `stale::StaleReads`, to how old they are.
+    stale: Option<Duration>,
     pub rows: Vec<Row>,
 }
 
 impl FromWire for QueryResult {
     fn decode<T: Read>(buffer: &mut T) -> Result<QueryResult> {
-        let header = try!(Header::decode(buffer));
-        let mut body_bytes = vec![0; header.length as usize];
-        try!(buffer.read_exact(&mut body_bytes));
-        let mut body = Cursor::new(body_bytes);
-        let kind = try!(ResultKind::decode(&mut body));
-        if kind != ResultKind::Rows {
-            panic!("Parsing for result of kind {:?} is unimplemented");
-        };
-        let flags = try!(ResultFlags::decode(&mut body));
-        if flags.has_more_pages {
-            println!("warning: has_more_pages set on result but paging is unimplemented");
-        };
-        if flags.no_metadata {
-            return Err(MyError::Protocol("Parsing results with no_metadata set is unimplemented".to_string()));
+        let mut body = Vec::new();
+        try!(buffer.read_to_end(&mut body));
+        QueryResult::from_body(body, None)
+    }
+}
+
+impl QueryResult {
+    /// Whether the server has more rows than were returned in this page.
+    pub fn has_more_pages(&self) -> bool {
+        self.paging_state.is_some()
+    }
+
+    /// The keyspace a USE statement switched to.
+    pub fn keyspace(&self) -> Option<&str> {
+        self.keyspace.as_ref().map(|k| k.as_ref())
+    }
+
+    /// Identifies the trace of a traced request in the
+    /// `system_traces.sessions` and `system_traces.events` tables.
+    pub fn tracing_id(&self) -> Option<Uuid> {
+        self.tracing_id
+    }
+
+    /// Warnings the server raised while running the request, such as a
+    /// batch being too large. Always empty before v4.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// The custom payload a server plugin returned, if any.
+    pub fn custom_payload(&self) -> Option<&HashMap<String, Vec<u8>>> {
+        self.custom_payload.as_ref()
+    }
+
+    /// How long ago the result was read, if it was served from
+    /// `stale::StaleReads` because the cluster couldn't be reached; `None`
+    /// for results fresh from the server.
+    pub fn stale(&self) -> Option<Duration> {
+        self.stale
+    }
+
+    pub fn set_stale(&mut self, stale: Option<Duration>) {
+        self.stale = stale;
+    }
+
+    /// The columns of each row, after any projection.
+    pub fn columns(&self) -> &[ColumnSpec] {
+        &self.columns
+    }
+
+    /// Null counts, numeric ranges and distinct counts for each column.
+    pub fn column_stats(&self) -> Vec<ColumnStats> {
+        self.columns.iter().map(|column| ColumnStats::compute(column, &self.rows)).collect()
+    }
+
+    /// Converts every row, e.g. into a struct declared with `cql_row!`.
+    pub fn rows_as<R: FromRow>(&self) -> Result<Vec<R>> {
+        self.rows.iter().map(R::from_row).collect()
+    }
+
+    /// Adds the rows of `other` to this result, e.g. to gather the results
+    /// of queries fanned out over token ranges or partitions. Both must be
+    /// rows results with the same column names and types. The merged result
+    /// has no paging state or tracing id, since those belong to a single
+    /// request. Warnings from both are kept.
+    pub fn append(&mut self, other: QueryResult) -> Result<()> {
+        if self.kind != ResultKind::Rows || other.kind != ResultKind::Rows {
+            return Err(MyError::Protocol(format!("Can only merge rows results, got {:?} and {:?}",
+                                                 self.kind, other.kind)));
+        }
+        let compatible = self.columns.len() == other.columns.len() &&
+            self.columns.iter().zip(other.columns.iter())
+                .all(|(a, b)| a.name == b.name && a.datatype == b.datatype);
+        if !compatible {
+            return Err(MyError::Protocol("Can't merge results with different columns".to_string()));
+        }
+        self.paging_state = None;
+        self.tracing_id = None;
+        self.custom_payload = None;
+        self.warnings.extend(other.warnings);
+        self.rows.extend(other.rows);
+        Ok(())
+    }
+
+    /// Concatenates `results` in order. See `append`.
+    pub fn merge(results: Vec<QueryResult>) -> Result<QueryResult> {
+        let mut results = results.into_iter();
+        let mut merged = match results.next() {
+            Some(first) => first,
+            None => return Err(MyError::Protocol("No results to merge".to_string())),
         };
-        let column_count = try!(body.read_i32::<BigEndian>());
-        let global_table_spec = if flags.global_table_spec {
-            Some(try!(TableSpec::decode(&mut body)))
-        } else {
-            None
+        for result in results {
+            try!(merged.append(result));
+        }
+        Ok(merged)
+    }
+
+    /// Decodes a RESULT frame, keeping its tracing id, warnings and custom
+    /// payload.
+    pub fn from_frame(frame: Frame, projection: Option<&[String]>) -> Result<QueryResult> {
+        QueryResult::from_frame_with(frame, projection, None)
+    }
+
+    /// Like `from_frame`, decoding rows sent without metadata as `columns`.
+    pub fn from_frame_with(frame: Frame, projection: Option<&[String]>, columns: Option<&[ColumnSpec]>)
+                           -> Result<QueryResult> {
+        let mut result = try!(QueryResult::from_body_with(frame.body, projection, columns));
+        result.tracing_id = frame.tracing_id;
+        result.warnings = frame.warnings;
+        result.custom_payload = frame.custom_payload;
+        Ok(result)
+    }
+
+    /// Opaque position to pass to `Statement::set_paging_state` to fetch
+    /// the next page.
+    pub fn paging_state(&self) -> Option<&[u8]> {
+        self.paging_state.as_ref().map(|s| s.as_ref())
+    }
+
+    /// Set when a v5 server found that the result columns of an executed
+    /// statement changed since it was prepared, e.g. after an ALTER TABLE.
+    pub fn new_metadata_id(&self) -> Option<&[u8]> {
+        self.new_metadata_id.as_ref().map(|id| id.as_ref())
+    }
+
+    /// Decodes a rows result, keeping only the columns named in `projection`
+    /// (or every column if there is none). Rows share `body` and only record
+    /// where each of their values lives in it.
+    pub fn from_body(body: Vec<u8>, projection: Option<&[String]>) -> Result<QueryResult> {
+        QueryResult::from_body_with(body, projection, None)
+    }
+
+    /// Like `from_body`. `columns` describes the rows when the server left
+    /// the metadata out, as it does for an EXECUTE with `skip_metadata`.
+    /// Only the number of columns can be checked against the result, so
+    /// before v5, which tells when the columns change, a statement should
+    /// be prepared again after its table is altered.
+    pub fn from_body_with(body: Vec<u8>, projection: Option<&[String]>, columns: Option<&[ColumnSpec]>)
+                          -> Result<QueryResult> {
+        let body = Arc::new(body);
+        let mut cursor = Cursor::new(&body[..]);
+        let kind = try!(ResultKind::decode(&mut cursor));
+        match kind {
+            ResultKind::Rows => {},
+            ResultKind::Prepared => {
+                return Err(MyError::Protocol("Unexpected Prepared result for a query".to_string()));
+            },
+            _ => {
+                let keyspace = if kind == ResultKind::SetKeyspace {
+                    Some(try!(String::decode(&mut cursor)))
+                } else {
+                    None
+                };
+                return Ok(QueryResult {
+                    kind: kind,
+                    flags: ResultFlags::default(),
+                    table_spec: None,
+                    paging_state: None,
+                    new_metadata_id: None,
+                    keyspace: keyspace,
+                    tracing_id: None,
+                    warnings: Vec::new(),
+                    custom_payload: None,
+                    columns: Vec::new(),
+                    stale: None,
+                    rows: Vec::new(),
+                });
+            },
         };
-        let mut column_specs = Vec::with_capacity(column_count as usize);
-        for _ in 0..column_count {
-            let table_spec = if flags.global_table_spec {
-                global_table_spec.clone().unwrap()
-            } else {
-                try!(TableSpec::decode(&mut body))
-            };
-            let spec = ColumnSpec {
-                table_spec: table_spec,
-                name: try!(String::decode(&mut body)),
-                datatype: try!(CQLType::decode(&mut body))
-            };
-            column_specs.push(spec);
+        let metadata = try!(Metadata::decode(&mut cursor));
+        let column_specs = match (metadata.flags.no_metadata, columns) {
+            (false, _) => metadata.columns,
+            (true, Some(columns)) if columns.len() == metadata.column_count => columns.to_vec(),
+            (true, Some(columns)) => {
+                return Err(MyError::Protocol(format!("Result has {} columns, but the statement was prepared with {}",
+                                                     metadata.column_count, columns.len())));
+            },
+            (true, None) => return Err(MyError::Protocol("Rows came without column metadata".to_string())),
         };
-        let row_count = try!(body.read_i32::<BigEndian>());
+        let wanted: Vec<bool> = column_specs.iter()
+            .map(|spec| projection.map_or(true, |p| p.contains(&spec.name)))
+            .collect();
+        let names: HashMap<String, usize> = column_specs.iter()
+            .zip(wanted.iter())
+            .filter(|&(_, &w)| w)
+            .enumerate()
+            .map(|(i, (spec, _))| (spec.name.clone(), i))
+            .collect();
+        let names = Arc::new(names);
+        let columns = column_specs.iter()
+            .zip(wanted.iter())
+            .filter(|&(_, &w)| w)
+            .map(|(spec, _)| spec.clone())
+            .collect();
+        let row_count = try!(cursor.read_i32::<BigEndian>());
         let mut rows = Vec::with_capacity(row_count as usize);
         for _ in 0..row_count {
-            let mut columns = HashMap::with_capacity(column_count as usize);
-            for column_spec in column_specs.iter() {
-                let size = try!(body.read_i32::<BigEndian>());
-                if size > 0 {
-                    let mut bytes = vec![0; size as usize];
-                    try!(body.read_exact(&mut bytes));
-                    columns.insert(column_spec.name.clone(), bytes);
+            let mut values = Vec::with_capacity(names.len());
+            for &w in wanted.iter() {
+                let size = try!(cursor.read_i32::<BigEndian>());
+                let start = cursor.position() as usize;
+                let value = if size > 0 {
+                    let end = start + size as usize;
+                    if end > body.len() {
+                        return Err(MyError::Protocol("Row value runs past the end of the frame".to_string()));
+                    }
+                    cursor.set_position(end as u64);
+                    Some(start..end)
                 } else {
                     // NULL or legacy "empty"
-                    columns.insert(column_spec.name.clone(), vec![]);
+                    None
+                };
+                if w {
+                    values.push(value);
                 }
             }
-            rows.push(Row { columns: columns });
+            rows.push(Row::new(body.clone(), names.clone(), values));
         };
         Ok(QueryResult {
-            header: header,
             kind: kind,
-            flags: flags,
-            table_spec: global_table_spec,
+            flags: metadata.flags,
+            table_spec: metadata.global_table_spec,
+            paging_state: metadata.paging_state,
+            new_metadata_id: metadata.new_metadata_id,
+            keyspace: None,
+            tracing_id: None,
+            warnings: Vec::new(),
+            custom_payload: None,
+            columns: columns,
+            stale: None,
             rows: rows,
         })
     }
 }
 
-#[derive(Debug)]
+/// A single result row. Values are kept as ranges into the shared frame body
+/// and decoded from it on every access, so reading a column twice decodes
+/// it twice. Rows are `Send` and `Sync`, so results can be handed to other
+/// threads, and clones share the frame body.
+#[derive(Clone)]
 pub struct Row {
-    pub columns: HashMap<String, Vec<u8>>,
+    body: Arc<Vec<u8>>,
+    names: Arc<HashMap<String, usize>>,
+    values: Vec<Option<Range<usize>>>,
 }
 
 impl Row {
-    pub fn get<T: FromCQL>(&self, col: &str) -> Option<T> {
-        let bytes = self.columns.get(col).unwrap().clone();
-        if bytes.len() > 0 {
-            Some(T::parse(bytes))
-        } else {
-            None
+    fn new(body: Arc<Vec<u8>>, names: Arc<HashMap<String, usize>>, values: Vec<Option<Range<usize>>>) -> Row {
+        Row {
+            body: body,
+            names: names,
+            values: values,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn column_names(&self) -> Vec<&str> {
+        let mut names: Vec<(&str, usize)> = self.names.iter().map(|(k, &v)| (k.as_ref(), v)).collect();
+        names.sort_by_key(|&(_, i)| i);
+        names.into_iter().map(|(name, _)| name).collect()
+    }
+
+    /// Reads a column, returning `None` if it is null.
+    pub fn get<T: FromCQL>(&self, col: &str) -> Result<Option<T>> {
+        let index = try!(self.index_of(col));
+        match self.values[index] {
+            Some(ref range) => T::parse(&self.body[range.clone()]).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Streams a column's raw bytes straight out of the frame buffer, which
+    /// avoids copying large blobs. Returns `None` if the column is null.
+    pub fn get_reader(&self, col: &str) -> Result<Option<Cursor<&[u8]>>> {
+        let index = try!(self.index_of(col));
+        Ok(self.values[index].as_ref().map(|range| Cursor::new(&self.body[range.clone()])))
+    }
+
+    fn index_of(&self, col: &str) -> Result<usize> {
+        match self.names.get(col) {
+            Some(&index) => Ok(index),
+            None => Err(MyError::Protocol(format!("No column named {} in row", col))),
+        }
+    }
+}
+
+impl fmt::Debug for Row {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.column_names().into_iter().zip(self.values.iter()) {
+            map.entry(&name, &value.as_ref().map(|r| &self.body[r.clone()]));
+        }
+        map.finish()
+    }
+}
+
+/// A type built from a whole result row.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self>;
+}
+
+/// A value read from a single named column: `Option<T>` for columns that
+/// may be null, or `T` for ones that must be set.
+pub trait FromColumn: Sized {
+    fn from_column(row: &Row, col: &str) -> Result<Self>;
+}
+
+impl<T: FromCQL> FromColumn for T {
+    fn from_column(row: &Row, col: &str) -> Result<T> {
+        match try!(row.get(col)) {
+            Some(value) => Ok(value),
+            None => Err(MyError::Protocol(format!("Column {} is null", col))),
+        }
+    }
+}
+
+impl<T: FromCQL> FromColumn for Option<T> {
+    fn from_column(row: &Row, col: &str) -> Result<Option<T>> {
+        row.get(col)
+    }
+}
+
+/// A statement parsed by the server, identified by the id it returned.
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+    id: Vec<u8>,
+    result_metadata_id: Vec<u8>,
+    query: String,
+    keyspace: Option<String>,
+    params: Vec<ColumnSpec>,
+    partition_key: Vec<u16>,
+    columns: Vec<ColumnSpec>,
+    idempotent: bool,
+    tags: Vec<(String, String)>,
+}
+
+impl PreparedStatement {
+    /// Decodes the body of a Prepared result for `query`, prepared while
+    /// `keyspace` was the connection's current keyspace.
+    pub fn decode<T: Read>(body: &mut T, query: &str, keyspace: Option<&str>,
+                           version: ProtocolVersion) -> Result<PreparedStatement> {
+        let kind = try!(ResultKind::decode(body));
+        if kind != ResultKind::Prepared {
+            return Err(MyError::Protocol(format!("Expected a Prepared result, got {:?}", kind)));
         }
+        let id_len = try!(body.read_u16::<BigEndian>());
+        let mut id = vec![0; id_len as usize];
+        try!(body.read_exact(&mut id));
+        let result_metadata_id = if version >= ProtocolVersion::V5 {
+            let len = try!(body.read_u16::<BigEndian>());
+            let mut metadata_id = vec![0; len as usize];
+            try!(body.read_exact(&mut metadata_id));
+            metadata_id
+        } else {
+            Vec::new()
+        };
+        let params = try!(Metadata::decode_with(body, version >= ProtocolVersion::V4));
+        let columns = try!(Metadata::decode(body));
+        Ok(PreparedStatement {
+            id: id,
+            result_metadata_id: result_metadata_id,
+            query: query.to_string(),
+            keyspace: keyspace.map(|k| k.to_string()),
+            params: params.columns,
+            partition_key: params.partition_key,
+            columns: columns.columns,
+            idempotent: false,
+            tags: Vec::new(),
+        })
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// Identifies the result columns as the server saw them when the
+    /// statement was prepared; sent back with each EXECUTE. Empty before
+    /// v5.
+    pub fn result_metadata_id(&self) -> &[u8] {
+        &self.result_metadata_id
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// The keyspace unqualified table names were resolved against. The id
+    /// is only valid while the connection is using the same keyspace.
+    pub fn keyspace(&self) -> Option<&str> {
+        self.keyspace.as_ref().map(|k| k.as_ref())
+    }
+
+    /// The bind markers, in order.
+    pub fn params(&self) -> &[ColumnSpec] {
+        &self.params
+    }
+
+    /// The columns of rows this statement returns, if any.
+    pub fn columns(&self) -> &[ColumnSpec] {
+        &self.columns
+    }
+
+    /// The indexes into `params` of the partition key columns, in key
+    /// order, when every one of them is bound. Only sent from v4 on.
+    pub fn partition_key(&self) -> &[u16] {
+        &self.partition_key
+    }
+
+    /// Marks executions of this statement as safe to retry after errors
+    /// that leave it unknown whether they were applied. See
+    /// `Statement::set_idempotent`.
+    pub fn set_idempotent(&mut self, idempotent: bool) {
+        self.idempotent = idempotent;
+    }
+
+    pub fn idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Tags executions of this statement. See `Statement::set_tag`.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        statement::set_tag(&mut self.tags, key, value);
+    }
+
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
     }
 }
 
@@ -440,11 +1673,12 @@ impl FromWire for ResultKind {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 struct ResultFlags {
     global_table_spec: bool,
     has_more_pages: bool,
     no_metadata: bool,
+    metadata_changed: bool,
 }
 
 impl FromWire for ResultFlags {
@@ -454,6 +1688,7 @@ impl FromWire for ResultFlags {
             global_table_spec: (flags & 0x01) > 0,
             has_more_pages: (flags & 0x02) > 0,
             no_metadata: (flags & 0x04) > 0,
+            metadata_changed: (flags & 0x08) > 0,
         })
     }
 }
@@ -473,13 +1708,122 @@ impl FromWire for TableSpec {
     }
 }
 
-#[derive(Debug)]
-struct ColumnSpec {
+#[derive(Debug, Clone)]
+pub struct ColumnSpec {
     table_spec: TableSpec,
     name: String,
     datatype: CQLType,
 }
 
+impl ColumnSpec {
+    pub fn keyspace(&self) -> &str {
+        &self.table_spec.keyspace
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table_spec.table
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn datatype(&self) -> &CQLType {
+        &self.datatype
+    }
+}
+
+/// The column description shared by rows results and prepared statements.
+#[derive(Debug, Clone)]
+struct Metadata {
+    flags: ResultFlags,
+    paging_state: Option<Vec<u8>>,
+    new_metadata_id: Option<Vec<u8>>,
+    partition_key: Vec<u16>,
+    global_table_spec: Option<TableSpec>,
+    /// Sent even when the columns themselves aren't.
+    column_count: usize,
+    columns: Vec<ColumnSpec>,
+}
+
+impl FromWire for Metadata {
+    fn decode<T: Read>(buffer: &mut T) -> Result<Metadata> {
+        Metadata::decode_with(buffer, false)
+    }
+}
+
+impl Metadata {
+    /// Bind marker metadata in v4 Prepared results also lists the
+    /// partition key columns, right after the column count.
+    fn decode_with<T: Read>(buffer: &mut T, partition_key: bool) -> Result<Metadata> {
+        let flags = try!(ResultFlags::decode(buffer));
+        let column_count = try!(buffer.read_i32::<BigEndian>());
+        let partition_key = if partition_key {
+            let count = try!(buffer.read_i32::<BigEndian>());
+            let mut indexes = Vec::with_capacity(count.max(0) as usize);
+            for _ in 0..count {
+                indexes.push(try!(buffer.read_u16::<BigEndian>()));
+            }
+            indexes
+        } else {
+            Vec::new()
+        };
+        let paging_state = if flags.has_more_pages {
+            let len = try!(buffer.read_i32::<BigEndian>());
+            let mut state = vec![0; len.max(0) as usize];
+            try!(buffer.read_exact(&mut state));
+            Some(state)
+        } else {
+            None
+        };
+        let new_metadata_id = if flags.metadata_changed {
+            let len = try!(buffer.read_u16::<BigEndian>());
+            let mut id = vec![0; len as usize];
+            try!(buffer.read_exact(&mut id));
+            Some(id)
+        } else {
+            None
+        };
+        if flags.no_metadata {
+            return Ok(Metadata {
+                flags: flags,
+                paging_state: paging_state,
+                new_metadata_id: new_metadata_id,
+                partition_key: partition_key,
+                global_table_spec: None,
+                column_count: column_count.max(0) as usize,
+                columns: Vec::new(),
+            });
+        }
+        let global_table_spec = if flags.global_table_spec {
+            Some(try!(TableSpec::decode(buffer)))
+        } else {
+            None
+        };
+        let mut columns = Vec::with_capacity(column_count as usize);
+        for _ in 0..column_count {
+            let table_spec = match global_table_spec {
+                Some(ref spec) => spec.clone(),
+                None => try!(TableSpec::decode(buffer)),
+            };
+            columns.push(ColumnSpec {
+                table_spec: table_spec,
+                name: try!(String::decode(buffer)),
+                datatype: try!(CQLType::decode(buffer)),
+            });
+        }
+        Ok(Metadata {
+            flags: flags,
+            paging_state: paging_state,
+            new_metadata_id: new_metadata_id,
+            partition_key: partition_key,
+            global_table_spec: global_table_spec,
+            column_count: column_count.max(0) as usize,
+            columns: columns,
+        })
+    }
+}
+
 impl FromWire for CQLType {
     fn decode<T: Read>(buffer: &mut T) -> Result<CQLType> {
         let option = try!(buffer.read_u16::<BigEndian>());
@@ -503,6 +1847,10 @@ impl FromWire for CQLType {
             0x000E => Ok(CQLType::Varint),
             0x000F => Ok(CQLType::Timeuuid),
             0x0010 => Ok(CQLType::Inet),
+            0x0011 => Ok(CQLType::Date),
+            0x0012 => Ok(CQLType::Time),
+            0x0013 => Ok(CQLType::Smallint),
+            0x0014 => Ok(CQLType::Tinyint),
             0x0020 => {
                 try!(CQLType::decode(buffer));
                 Ok(CQLType::List)
@@ -531,23 +1879,43 @@ impl FromWire for CQLType {
 
 #[derive(Debug)]
 pub struct NonRowResult {
-    header: Header,
     kind: ResultKind,
+    keyspace: Option<String>,
+    schema_change: Option<SchemaChange>,
+}
+
+impl NonRowResult {
+    /// The keyspace a USE statement switched to.
+    pub fn keyspace(&self) -> Option<&str> {
+        self.keyspace.as_ref().map(|k| k.as_ref())
+    }
+
+    /// What a CREATE, ALTER or DROP statement changed.
+    pub fn schema_change(&self) -> Option<&SchemaChange> {
+        self.schema_change.as_ref()
+    }
 }
 
 impl FromWire for NonRowResult {
-    fn decode<T: Read>(buffer: &mut T) -> Result<NonRowResult> {
-        let header = try!(Header::decode(buffer));
-        let mut body_bytes = vec![0; header.length as usize];
-        try!(buffer.read_exact(&mut body_bytes));
-        let mut body = Cursor::new(body_bytes);
-        let kind = try!(ResultKind::decode(&mut body));
-        if ![ResultKind::SchemaChange, ResultKind::Void].contains(&kind) {
+    fn decode<T: Read>(body: &mut T) -> Result<NonRowResult> {
+        let kind = try!(ResultKind::decode(body));
+        if ![ResultKind::SchemaChange, ResultKind::Void, ResultKind::SetKeyspace].contains(&kind) {
             return Err(MyError::Protocol(format!("Unexpected result kind {:?}", kind)))
         };
+        let keyspace = if kind == ResultKind::SetKeyspace {
+            Some(try!(String::decode(body)))
+        } else {
+            None
+        };
+        let schema_change = if kind == ResultKind::SchemaChange {
+            Some(try!(SchemaChange::decode(body)))
+        } else {
+            None
+        };
         Ok(NonRowResult {
-            header: header,
             kind: kind,
+            keyspace: keyspace,
+            schema_change: schema_change,
         })
     }
 }
@@ -0,0 +1,208 @@
+//! Deleting the rows of a table that match a predicate, for data that
+//! should have been written with a TTL but wasn't, or whose expiry
+//! depends on more than its age.
+//!
+//! ```ignore
+//! let cutoff = now_millis() - 30 * 24 * 60 * 60 * 1000;
+//! let mut options = PurgeOptions::default();
+//! options.max_rows_per_second = Some(500);
+//! let done = try!(session.purge("ks.events", |row: &Row| {
+//!     row.get::<i64>("created_at").ok().and_then(|created| created).map_or(false, |created| created < cutoff)
+//! }, &options));
+//! ```
+//!
+//! The table is scanned one token range at a time, like `backup::export`
+//! does, and matching rows are deleted by primary key as the scan goes.
+//! Deletes of rows in the same partition are sent together in UNLOGGED
+//! batches, which the coordinator applies as a single mutation.
+//!
+//! Every deleted row leaves a tombstone until it's compacted away after
+//! the table's `gc_grace_seconds`, and reads that cross many tombstones
+//! slow down or fail. Large purges are better spread out with
+//! `PurgeOptions::max_rows_per_second`, which also keeps them from
+//! crowding out the application's own traffic.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backup::{primary_key, token_ranges};
+use batch::{BatchBuilder, BatchType};
+use copy::DEFAULT_BATCH_SIZE;
+use ddl::quote_identifier;
+use errors::MyError;
+use estimates::{SizeEstimate, DEFAULT_SPLIT_SIZE};
+use protocol::{Consistency, PreparedStatement, Result, Row};
+use session::Session;
+use statement::Statement;
+use types::ToCQL;
+
+/// Rows fetched per page while scanning, unless configured otherwise.
+pub const DEFAULT_SCAN_PAGE_SIZE: i32 = 1000;
+
+/// How far a purge has got.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct PurgeProgress {
+    /// Rows read so far.
+    pub scanned: usize,
+    /// Rows deleted so far.
+    pub deleted: usize,
+    /// Requests sent so far, each a batch or a single row.
+    pub requests: usize,
+    /// Token ranges scanned so far, out of `ranges`.
+    pub ranges_done: usize,
+    pub ranges: usize,
+}
+
+#[derive(Clone)]
+pub struct PurgeOptions {
+    /// Most rows deleted in one batch.
+    pub batch_size: usize,
+    /// Deletes no more than this many rows a second, on average. `None`
+    /// deletes as fast as the cluster answers.
+    pub max_rows_per_second: Option<u32>,
+    pub page_size: i32,
+    /// Of the deletes; the scan reads at `One`.
+    pub consistency: Consistency,
+    /// Called after each token range is scanned.
+    pub progress: Option<Arc<Fn(&PurgeProgress) + Send + Sync>>,
+}
+
+impl Default for PurgeOptions {
+    fn default() -> PurgeOptions {
+        PurgeOptions {
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_rows_per_second: None,
+            page_size: DEFAULT_SCAN_PAGE_SIZE,
+            consistency: Consistency::LocalQuorum,
+            progress: None,
+        }
+    }
+}
+
+/// Deletes every row of `table`, which must be qualified with its
+/// keyspace, for which `predicate` is true. Stops at the first failed
+/// delete; rows before it stay deleted.
+pub fn purge<P>(session: &Session, table: &str, mut predicate: P, options: &PurgeOptions) -> Result<PurgeProgress>
+    where P: FnMut(&Row) -> bool
+{
+    let (keyspace, name) = match table.find('.') {
+        Some(dot) => (&table[..dot], &table[dot + 1..]),
+        None => return Err(MyError::Protocol(format!("Table {} must be qualified with its keyspace", table))),
+    };
+    let (partition_key, clustering) = try!(primary_key(session, keyspace, name));
+    if partition_key.is_empty() {
+        return Err(MyError::Protocol(format!("Table {} not found", table)));
+    }
+    let key: Vec<&String> = partition_key.iter().chain(clustering.iter()).collect();
+    let conditions: Vec<String> = key.iter().map(|column| format!("{} = ?", quote_identifier(column))).collect();
+    let mut delete = try!(session.prepare(&format!("DELETE FROM {} WHERE {}", table, conditions.join(" AND "))));
+    delete.set_idempotent(true);
+
+    let estimate = try!(SizeEstimate::fetch(session, keyspace, name));
+    let ranges = token_ranges(estimate.splits(DEFAULT_SPLIT_SIZE));
+    let quoted: Vec<String> = partition_key.iter().map(|column| quote_identifier(column)).collect();
+    let token = format!("token({})", quoted.join(", "));
+    let mut progress = PurgeProgress { ranges: ranges.len(), ..PurgeProgress::default() };
+    let mut pace = Pace::new(options.max_rows_per_second);
+    for (i, (start, end)) in ranges.into_iter().enumerate() {
+        // Ranges exclude their start, so the first takes in the lowest token.
+        let query = format!("SELECT * FROM {} WHERE {} {} ? AND {} <= ?",
+                            table, token, if i == 0 { ">=" } else { ">" }, token);
+        let mut statement = Statement::new(&query);
+        statement.set_page_size(options.page_size);
+        // The primary key values of matching rows in the partition being
+        // scanned, not deleted yet.
+        let mut pending: Vec<Vec<Vec<u8>>> = Vec::new();
+        loop {
+            let result = try!(session.query_statement(&statement, &[&start, &end]));
+            for row in &result.rows {
+                progress.scanned += 1;
+                if !predicate(row) {
+                    continue;
+                }
+                let values = try!(key_values(row, &key));
+                let same_partition = pending.first().map_or(true, |first| {
+                    first[..partition_key.len()] == values[..partition_key.len()]
+                });
+                if !same_partition || pending.len() >= options.batch_size.max(1) {
+                    try!(delete_rows(session, &delete, &pending, options, &mut progress, &mut pace));
+                    pending.clear();
+                }
+                pending.push(values);
+            }
+            match result.paging_state() {
+                Some(state) => statement.set_paging_state(Some(state)),
+                None => break,
+            }
+        }
+        if !pending.is_empty() {
+            try!(delete_rows(session, &delete, &pending, options, &mut progress, &mut pace));
+        }
+        progress.ranges_done += 1;
+        if let Some(ref callback) = options.progress {
+            callback(&progress);
+        }
+    }
+    Ok(progress)
+}
+
+/// The serialized values of `key`'s columns in `row`.
+fn key_values(row: &Row, key: &[&String]) -> Result<Vec<Vec<u8>>> {
+    let mut values = Vec::with_capacity(key.len());
+    for column in key {
+        match try!(row.get_reader(column)) {
+            Some(value) => values.push(value.get_ref().to_vec()),
+            None => return Err(MyError::Protocol(format!("Primary key column {} is null", column))),
+        }
+    }
+    Ok(values)
+}
+
+/// Deletes rows of one partition, as a batch if there's more than one.
+fn delete_rows(session: &Session, delete: &PreparedStatement, rows: &[Vec<Vec<u8>>], options: &PurgeOptions,
+               progress: &mut PurgeProgress, pace: &mut Pace) -> Result<()> {
+    let values: Vec<Vec<&ToCQL>> = rows.iter().map(|row| row.iter().map(|value| value as &ToCQL).collect()).collect();
+    if values.len() == 1 {
+        try!(session.execute_prepared_at(delete, &values[0], options.consistency));
+    } else {
+        let mut builder = BatchBuilder::new(BatchType::Unlogged).consistency(options.consistency).idempotent(true);
+        for row in &values {
+            builder = builder.prepared(delete, row);
+        }
+        try!(session.batch(&builder.build()));
+    }
+    progress.deleted += rows.len();
+    progress.requests += 1;
+    pace.wait(rows.len());
+    Ok(())
+}
+
+/// Sleeps as needed to keep to a number of rows a second, averaged over
+/// the whole purge.
+struct Pace {
+    rate: Option<u32>,
+    start: Instant,
+    rows: u64,
+}
+
+impl Pace {
+    fn new(rate: Option<u32>) -> Pace {
+        Pace {
+            rate: rate,
+            start: Instant::now(),
+            rows: 0,
+        }
+    }
+
+    fn wait(&mut self, rows: usize) {
+        self.rows += rows as u64;
+        if let Some(rate) = self.rate {
+            let due = Duration::from_millis(self.rows * 1000 / rate.max(1) as u64);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Turning contact points into addresses. By default names go through the
+//! system resolver, like any `ToSocketAddrs`, but a `Resolver` can look
+//! them up some other way, such as with an asynchronous DNS library or a
+//! service registry like Consul. Clients given one resolve their contact
+//! points again on every reconnect, so a cluster whose addresses change
+//! behind a stable name is followed.
+
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use errors::MyError;
+use protocol::Result;
+use warning::{Warning, WarningListener};
+
+/// The port contact points without one are assumed to listen on.
+pub const DEFAULT_PORT: u16 = 9042;
+
+pub trait Resolver: Send + Sync {
+    /// The addresses behind `contact_point`, which is whatever string the
+    /// session was given: usually `host:port` or a bare host name, but a
+    /// service discovery resolver may take a service name instead.
+    fn resolve(&self, contact_point: &str) -> Result<Vec<SocketAddr>>;
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Resolver")
+    }
+}
+
+/// Any function from a contact point to its addresses.
+impl<F> Resolver for F where F: Fn(&str) -> Result<Vec<SocketAddr>> + Send + Sync {
+    fn resolve(&self, contact_point: &str) -> Result<Vec<SocketAddr>> {
+        self(contact_point)
+    }
+}
+
+/// Looks names up with the system's blocking `getaddrinfo`, adding
+/// `DEFAULT_PORT` to contact points without a port.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, contact_point: &str) -> Result<Vec<SocketAddr>> {
+        let addrs = if has_port(contact_point) {
+            try!(contact_point.to_socket_addrs())
+        } else {
+            try!((contact_point.trim_matches(|c| c == '[' || c == ']'), DEFAULT_PORT).to_socket_addrs())
+        };
+        Ok(addrs.collect())
+    }
+}
+
+/// Resolves every contact point, skipping the ones that fail as long as
+/// another one resolves, and telling `warnings` about them. Addresses
+/// appear once, in contact point order.
+pub fn resolve_all(resolver: &Resolver, contact_points: &[String], warnings: Option<&WarningListener>)
+        -> Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    let mut last_err = None;
+    for contact_point in contact_points {
+        match resolver.resolve(contact_point) {
+            Ok(resolved) => {
+                for addr in resolved {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            },
+            Err(err) => {
+                if let Some(warnings) = warnings {
+                    warnings.on_warning(&Warning::ContactPointUnresolved {
+                        contact_point: contact_point.clone(),
+                        error: err.to_string(),
+                    });
+                }
+                last_err = Some(err);
+            },
+        }
+    }
+    match last_err {
+        Some(err) if addrs.is_empty() => Err(err),
+        _ if addrs.is_empty() => Err(MyError::Protocol("No addresses to connect to".to_string())),
+        _ => Ok(addrs),
+    }
+}
+
+/// Whether `contact_point` ends in a port: `host:port` or `[v6]:port`, but
+/// not a bare IPv6 address.
+fn has_port(contact_point: &str) -> bool {
+    match contact_point.rfind(':') {
+        Some(colon) => {
+            let host = &contact_point[..colon];
+            contact_point[colon + 1..].parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']'))
+        },
+        None => false,
+    }
+}
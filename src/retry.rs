@@ -0,0 +1,178 @@
+//! Deciding whether a failed request is sent again before the error
+//! reaches the caller. A client asks its `RetryPolicy` after every failed
+//! attempt that a retry could fix: server timeouts, unavailable replicas,
+//! lost connections and overloaded or failing coordinators. Errors in the
+//! request itself, such as syntax errors, are returned right away.
+//!
+//! Whether a write may be sent twice depends on the statement, so only
+//! statements marked with `Statement::set_idempotent` (or the same on a
+//! prepared statement or batch) are retried after errors that leave it
+//! unknown whether they were applied. The client enforces this whatever
+//! the policy decides; see `MyError::is_idempotent_safe_retry`.
+
+use std::fmt;
+
+use errors::{ErrorDetails, MyError, ReadTimeout, Unavailable, WriteTimeout, WriteType};
+use protocol::Consistency;
+
+/// What to do about a failed attempt.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Send the request again on the same connection.
+    Retry,
+    /// Send it again on the same connection at a lower consistency.
+    RetryAt(Consistency),
+    /// Reconnect, to another host if there is one, and send it again
+    /// there.
+    RetryNextHost,
+    /// Give up and return the error.
+    Rethrow,
+}
+
+/// Each method gets the number of times the request was already retried.
+pub trait RetryPolicy: Send + Sync {
+    /// The coordinator didn't hear from enough replicas in time.
+    fn on_read_timeout(&self, timeout: &ReadTimeout, retries: u32) -> RetryDecision;
+
+    /// The coordinator didn't hear from enough replicas in time. Unless the
+    /// batch log timed out, the write may have been applied on some.
+    fn on_write_timeout(&self, timeout: &WriteTimeout, idempotent: bool, retries: u32) -> RetryDecision;
+
+    /// Too few replicas were alive to try, so nothing was applied.
+    fn on_unavailable(&self, unavailable: &Unavailable, retries: u32) -> RetryDecision;
+
+    /// The connection failed, or the coordinator was overloaded,
+    /// bootstrapping or failed internally. The request may have been
+    /// applied.
+    fn on_request_error(&self, error: &MyError, idempotent: bool, retries: u32) -> RetryDecision;
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RetryPolicy")
+    }
+}
+
+/// Retries once, and only where that's likely to help: a read whose data
+/// replica was merely slow, a write that timed out on the batch log, and
+/// on another host, an unavailable error or a failure of an idempotent
+/// request. This is what the DataStax drivers do by default.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DefaultRetryPolicy;
+
+impl RetryPolicy for DefaultRetryPolicy {
+    fn on_read_timeout(&self, timeout: &ReadTimeout, retries: u32) -> RetryDecision {
+        if retries == 0 && timeout.worth_retrying() { RetryDecision::Retry } else { RetryDecision::Rethrow }
+    }
+
+    fn on_write_timeout(&self, timeout: &WriteTimeout, _idempotent: bool, retries: u32) -> RetryDecision {
+        if retries == 0 && timeout.worth_retrying() { RetryDecision::Retry } else { RetryDecision::Rethrow }
+    }
+
+    fn on_unavailable(&self, _unavailable: &Unavailable, retries: u32) -> RetryDecision {
+        // The coordinator may just have a stale view of which replicas are
+        // up; another one might not.
+        if retries == 0 { RetryDecision::RetryNextHost } else { RetryDecision::Rethrow }
+    }
+
+    fn on_request_error(&self, _error: &MyError, idempotent: bool, retries: u32) -> RetryDecision {
+        if retries == 0 && idempotent { RetryDecision::RetryNextHost } else { RetryDecision::Rethrow }
+    }
+}
+
+/// Trades consistency for availability: when too few replicas answered or
+/// were alive, retries once at the highest consistency the ones that did
+/// can still satisfy, such as `One` instead of `Quorum`. Reads may then
+/// miss recent writes, and writes are acknowledged by fewer replicas than
+/// asked for.
+///
+/// Write timeouts are only downgraded for unlogged batches, which may be
+/// retried as a whole. Other writes that timed out were applied on the
+/// replicas that answered and reach the rest eventually, so they're
+/// returned as errors. Otherwise this behaves like `DefaultRetryPolicy`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DowngradingConsistencyRetryPolicy;
+
+impl DowngradingConsistencyRetryPolicy {
+    fn downgrade(&self, consistency: Consistency, replicas: i32) -> RetryDecision {
+        match consistency {
+            // A lower serial consistency doesn't exist.
+            Consistency::Serial | Consistency::LocalSerial => RetryDecision::Rethrow,
+            _ if replicas >= 3 => RetryDecision::RetryAt(Consistency::Three),
+            _ if replicas == 2 => RetryDecision::RetryAt(Consistency::Two),
+            _ if replicas == 1 => RetryDecision::RetryAt(Consistency::One),
+            _ => RetryDecision::Rethrow,
+        }
+    }
+}
+
+impl RetryPolicy for DowngradingConsistencyRetryPolicy {
+    fn on_read_timeout(&self, timeout: &ReadTimeout, retries: u32) -> RetryDecision {
+        if retries > 0 {
+            RetryDecision::Rethrow
+        } else if timeout.received < timeout.block_for {
+            self.downgrade(timeout.consistency, timeout.received)
+        } else {
+            DefaultRetryPolicy.on_read_timeout(timeout, retries)
+        }
+    }
+
+    fn on_write_timeout(&self, timeout: &WriteTimeout, idempotent: bool, retries: u32) -> RetryDecision {
+        if retries > 0 {
+            RetryDecision::Rethrow
+        } else if timeout.write_type == WriteType::UnloggedBatch {
+            self.downgrade(timeout.consistency, timeout.received)
+        } else {
+            DefaultRetryPolicy.on_write_timeout(timeout, idempotent, retries)
+        }
+    }
+
+    fn on_unavailable(&self, unavailable: &Unavailable, retries: u32) -> RetryDecision {
+        if retries > 0 {
+            RetryDecision::Rethrow
+        } else {
+            self.downgrade(unavailable.consistency, unavailable.alive)
+        }
+    }
+
+    fn on_request_error(&self, error: &MyError, idempotent: bool, retries: u32) -> RetryDecision {
+        DefaultRetryPolicy.on_request_error(error, idempotent, retries)
+    }
+}
+
+/// Never retries, returning every error as it happens.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FallthroughRetryPolicy;
+
+impl RetryPolicy for FallthroughRetryPolicy {
+    fn on_read_timeout(&self, _timeout: &ReadTimeout, _retries: u32) -> RetryDecision {
+        RetryDecision::Rethrow
+    }
+
+    fn on_write_timeout(&self, _timeout: &WriteTimeout, _idempotent: bool, _retries: u32) -> RetryDecision {
+        RetryDecision::Rethrow
+    }
+
+    fn on_unavailable(&self, _unavailable: &Unavailable, _retries: u32) -> RetryDecision {
+        RetryDecision::Rethrow
+    }
+
+    fn on_request_error(&self, _error: &MyError, _idempotent: bool, _retries: u32) -> RetryDecision {
+        RetryDecision::Rethrow
+    }
+}
+
+/// Asks `policy` about `error`, or rethrows errors no retry can fix.
+/// Unprepared statements are prepared again where they're sent, and a
+/// passed deadline stays passed.
+pub fn decide(policy: &RetryPolicy, error: &MyError, idempotent: bool, retries: u32) -> RetryDecision {
+    match (error.root(), error.details()) {
+        (&MyError::Timeout(_), _) => RetryDecision::Rethrow,
+        (_, Some(&ErrorDetails::ReadTimeout(ref timeout))) => policy.on_read_timeout(timeout, retries),
+        (_, Some(&ErrorDetails::WriteTimeout(ref timeout))) => policy.on_write_timeout(timeout, idempotent, retries),
+        (_, Some(&ErrorDetails::Unavailable(ref unavailable))) => policy.on_unavailable(unavailable, retries),
+        (_, Some(&ErrorDetails::Unprepared(_))) => RetryDecision::Rethrow,
+        _ if error.is_retryable() => policy.on_request_error(error, idempotent, retries),
+        _ => RetryDecision::Rethrow,
+    }
+}
@@ -0,0 +1,895 @@
+use std::io::{BufRead, Write};
+use std::thread;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+use std::collections::hash_map::RandomState;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+
+use auth::{AuthProvider, Credentials};
+use backup;
+use capabilities::ServerCapabilities;
+use batch::{Batch, BatchBuilder, BatchType};
+use bus::{ClusterEvent, EventBus, PoolState};
+use client::Client;
+use coalesce::{self, Coalescer};
+use compression::Compression;
+use copy::{self, CopyOptions, CopyProgress};
+use ddl::quote_identifier;
+use diagnostics::ConnectionReport;
+use durability::{ConsistencyPolicy, ReplicationCache};
+use events::{EventType, SchemaChange};
+use explain::Explanation;
+use fanout::{self, SplitQuery};
+use graph::GraphOptions;
+use histogram::{Histogram, LatencyHistogram};
+use lint::Linter;
+use errors::{ErrorContext, MyError};
+use pool::{Pool, PoolMetrics, PooledClient};
+use protocol::{Consistency, PreparedStatement, ProtocolVersion, QueryResult, Result, Row};
+use purge::{self, PurgeOptions, PurgeProgress};
+use resolver::{self, Resolver, SystemResolver};
+use retry::{DefaultRetryPolicy, RetryPolicy};
+use socket::{Closer, SocketOptions};
+use stale::{self, StaleReads};
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+#[cfg(feature = "native-tls")]
+use nativetls::NativeTlsConfig;
+use statement::{self, Statement, Template};
+use stats::ConnectionStats;
+use types::{CQLType, ToCQL};
+use warning::{Warning, WarningListener};
+
+/// How long `check_connectivity` may take, waiting for a free connection
+/// and the round trip together.
+const CONNECTIVITY_CHECK_TIMEOUT_MS: u64 = 2000;
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Number of connections opened to the contact point. Each one is used
+    /// by a single request at a time.
+    pub connections: usize,
+    /// Run heartbeats and reconnection on a background thread. Embedders
+    /// that must stay single-threaded can turn this off.
+    pub background_thread: bool,
+    pub heartbeat_interval: Duration,
+    /// Before reconnecting a connection whose heartbeat failed, wait a
+    /// random time up to this long, so that the clients of a restarted
+    /// node don't all reconnect at the same moment. Zero reconnects right
+    /// away.
+    pub reconnect_jitter: Duration,
+    /// How long a request may wait for a free connection. `None` waits
+    /// indefinitely.
+    pub checkout_timeout: Option<Duration>,
+    /// How long to wait for each contact point to accept a connection, on
+    /// connecting and reconnecting. `None` leaves it to the operating
+    /// system, which may take minutes for an unreachable address.
+    pub connect_timeout: Option<Duration>,
+    /// How long the startup handshake of each connection may take,
+    /// including authentication. `None` waits indefinitely.
+    pub init_timeout: Option<Duration>,
+    /// How long a connection waits for each response, including to
+    /// heartbeats, before failing the request with `MyError::Timeout` and
+    /// reconnecting. `None` waits indefinitely.
+    pub request_timeout: Option<Duration>,
+    /// TCP options for each connection. Nagle's algorithm is off by
+    /// default.
+    pub socket_options: SocketOptions,
+    /// Sends statement tags to the server in the custom payload. See
+    /// `Client::set_send_tags`.
+    pub send_tags: bool,
+    /// Encrypts every connection, if set. Needs the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<TlsConfig>>,
+    /// Encrypts every connection with the platform's TLS library, if set
+    /// and `tls` isn't. Needs the `native-tls` feature.
+    #[cfg(feature = "native-tls")]
+    pub native_tls: Option<Arc<NativeTlsConfig>>,
+    /// Compression algorithms to negotiate, best first.
+    pub compression: Vec<Compression>,
+    /// Statements with longer query text fail instead of being sent.
+    pub max_statement_length: usize,
+    /// The newest protocol version to try; older ones are negotiated if a
+    /// server doesn't support it.
+    pub protocol_version: ProtocolVersion,
+    /// Used if the cluster requires authentication.
+    pub credentials: Option<Credentials>,
+    /// For SASL mechanisms other than PLAIN. Takes precedence over
+    /// `credentials`.
+    pub auth_provider: Option<Arc<AuthProvider>>,
+    /// How `with_contact_points` looks contact points up, on connecting
+    /// and again whenever a connection reconnects. The system resolver if
+    /// unset.
+    pub resolver: Option<Arc<Resolver>>,
+    /// Which failed requests each connection sends again.
+    pub retry_policy: Arc<RetryPolicy>,
+    /// Minimum consistency for writes to particular tables.
+    pub consistency_policy: ConsistencyPolicy,
+    /// Fail writes whose keyspace has too few replicas for their
+    /// consistency before sending them. Costs a schema query per keyspace
+    /// every `durability::REPLICATION_CACHE_SECS`.
+    pub check_replication: bool,
+    /// Checks each distinct statement against the schema before it's
+    /// first sent or prepared. Off by default.
+    pub linter: Option<Arc<Linter>>,
+    /// Reject statements with ALLOW FILTERING, which scan every partition,
+    /// unless `Statement::set_allow_filtering` allows them. Queries that
+    /// need the clause but leave it out are already rejected by the server.
+    pub forbid_allow_filtering: bool,
+    /// How each connection records request latencies. Only the fixed
+    /// buckets by default.
+    pub latency_histogram: LatencyHistogram,
+    /// Results of particular reads to serve, marked stale, while no node
+    /// can be reached. See the `stale` module. None by default.
+    pub stale_reads: Option<Arc<StaleReads>>,
+    /// Has identical reads that are in flight at the same time share one
+    /// request, as described in the `coalesce` module. Off by default.
+    pub coalesce_reads: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            connections: 1,
+            background_thread: true,
+            heartbeat_interval: Duration::from_secs(30),
+            reconnect_jitter: Duration::from_secs(1),
+            checkout_timeout: None,
+            connect_timeout: Some(Duration::from_secs(5)),
+            init_timeout: Some(Duration::from_secs(10)),
+            request_timeout: Some(Duration::from_secs(12)),
+            socket_options: SocketOptions::default(),
+            send_tags: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "native-tls")]
+            native_tls: None,
+            compression: Compression::available(),
+            max_statement_length: statement::DEFAULT_MAX_STATEMENT_LENGTH,
+            protocol_version: ProtocolVersion::latest(),
+            credentials: None,
+            auth_provider: None,
+            resolver: None,
+            retry_policy: Arc::new(DefaultRetryPolicy),
+            consistency_policy: ConsistencyPolicy::new(),
+            check_replication: false,
+            linter: None,
+            forbid_allow_filtering: false,
+            latency_histogram: LatencyHistogram::Buckets,
+            stale_reads: None,
+            coalesce_reads: false,
+        }
+    }
+}
+
+/// A handle to a set of initialized connections and the background work that
+/// keeps them healthy. Sessions are `Send` and `Sync`, and cloning one is
+/// cheap: every clone shares the same connections.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    addrs: Vec<SocketAddr>,
+    compression: Vec<Compression>,
+    auth: Option<Arc<AuthProvider>>,
+    pool: Arc<Pool>,
+    checkout_timeout: Option<Duration>,
+    capabilities: ServerCapabilities,
+    consistency_policy: ConsistencyPolicy,
+    replication: Option<ReplicationCache>,
+    linter: Option<Arc<Linter>>,
+    forbid_allow_filtering: bool,
+    stale_reads: Option<Arc<StaleReads>>,
+    coalescer: Option<Coalescer>,
+    background: Mutex<Option<Background>>,
+    bus: Arc<EventBus>,
+    /// Whether a connection is registered for server events and feeding
+    /// them to `bus`.
+    listening: Arc<AtomicBool>,
+    /// The event connection and the thread forwarding its events, to stop
+    /// them on close.
+    listener: Mutex<Option<(Arc<Closer>, thread::JoinHandle<()>)>>,
+}
+
+struct Background {
+    // Sender isn't Sync, and Session handles are shared between threads.
+    shutdown: Mutex<Sender<()>>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Session {
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Session> {
+        Session::with_config(addr, SessionConfig::default())
+    }
+
+    pub fn with_config<A: ToSocketAddrs>(addr: A, config: SessionConfig) -> Result<Session> {
+        let addrs: Vec<SocketAddr> = try!(addr.to_socket_addrs()).collect();
+        Session::open(addrs, None, config)
+    }
+
+    /// Connects to the cluster behind `contact_points`, such as
+    /// `["cassandra-1:9042", "cassandra-2"]`, looked up with
+    /// `SessionConfig::resolver`. Unlike `with_config`, connections resolve
+    /// them again when they reconnect, following address changes.
+    pub fn with_contact_points(contact_points: &[&str], config: SessionConfig) -> Result<Session> {
+        let resolver = config.resolver.clone().unwrap_or_else(|| Arc::new(SystemResolver) as Arc<Resolver>);
+        let contact_points: Vec<String> = contact_points.iter().map(|point| point.to_string()).collect();
+        let addrs = try!(resolver::resolve_all(&*resolver, &contact_points, None));
+        Session::open(addrs, Some((contact_points, resolver)), config)
+    }
+
+    fn open(addrs: Vec<SocketAddr>, resolve: Option<(Vec<String>, Arc<Resolver>)>, config: SessionConfig)
+            -> Result<Session> {
+        let auth = match config.auth_provider {
+            Some(ref provider) => Some(provider.clone()),
+            None => config.credentials.clone().map(|credentials| Arc::new(credentials) as Arc<AuthProvider>),
+        };
+        let bus = Arc::new(EventBus::new());
+        let mut connections = Vec::with_capacity(config.connections);
+        for _ in 0..config.connections.max(1) {
+            let mut client = try!(match config.connect_timeout {
+                Some(timeout) => Client::connect_timeout(&addrs[..], timeout),
+                None => Client::connect(&addrs[..]),
+            });
+            if let Some((ref contact_points, ref resolver)) = resolve {
+                client.set_resolver(contact_points.clone(), resolver.clone());
+            }
+            client.set_compression_preference(config.compression.clone());
+            client.set_max_statement_length(config.max_statement_length);
+            client.set_protocol_version(config.protocol_version);
+            client.set_auth_provider(auth.clone());
+            client.set_latency_histogram(config.latency_histogram);
+            client.set_retry_policy(config.retry_policy.clone());
+            try!(client.set_request_timeout(config.request_timeout));
+            client.set_init_timeout(config.init_timeout);
+            try!(client.set_socket_options(config.socket_options.clone()));
+            client.set_send_tags(config.send_tags);
+            client.set_warning_listener(Some(bus.clone() as Arc<WarningListener>));
+            #[cfg(feature = "tls")]
+            try!(client.set_tls(config.tls.clone()));
+            #[cfg(feature = "native-tls")]
+            try!(client.set_native_tls(config.native_tls.clone()));
+            try!(client.initialize());
+            connections.push(client);
+        }
+        let capabilities = connections[0].capabilities().clone();
+        let pool = Arc::new(Pool::new(connections));
+        let background = if config.background_thread {
+            Some(Background::spawn(pool.clone(), bus.clone(), config.heartbeat_interval, config.reconnect_jitter))
+        } else {
+            None
+        };
+        Ok(Session {
+            inner: Arc::new(Inner {
+                addrs: addrs,
+                compression: config.compression,
+                auth: auth,
+                pool: pool,
+                checkout_timeout: config.checkout_timeout,
+                capabilities: capabilities,
+                consistency_policy: config.consistency_policy,
+                replication: if config.check_replication { Some(ReplicationCache::new()) } else { None },
+                linter: config.linter,
+                forbid_allow_filtering: config.forbid_allow_filtering,
+                stale_reads: config.stale_reads,
+                coalescer: if config.coalesce_reads { Some(Coalescer::new()) } else { None },
+                background: Mutex::new(background),
+                bus: bus,
+                listening: Arc::new(AtomicBool::new(false)),
+                listener: Mutex::new(None),
+            }),
+        })
+    }
+
+    pub fn query(&self, query: &str, params: &[&ToCQL]) -> Result<QueryResult> {
+        self.query_statement(&Statement::new(query), params)
+    }
+
+    pub fn query_statement(&self, statement: &Statement, params: &[&ToCQL]) -> Result<QueryResult> {
+        try!(self.check_filtering(statement));
+        self.lint(Some(statement.query()));
+        let raised;
+        let statement = match self.enforce_statement(statement) {
+            Some(statement) => {
+                raised = statement;
+                &raised
+            },
+            None => statement,
+        };
+        try!(self.check_replication(Some(statement.query()), statement.keyspace(), statement.consistency()));
+        // Later pages are never kept, so there's nothing to serve for them.
+        let stale_reads = if statement.paging_state().is_none() { self.stale_reads(statement.query()) } else { None };
+        let send = || self.with_client(statement.query(), statement.deadline(), |client| {
+            let result = client.query_statement(statement, params);
+            keep_or_serve_stale(stale_reads, client, statement.query(), params, result)
+        });
+        match self.inner.coalescer {
+            Some(ref coalescer) if coalesce::coalescable(statement) => {
+                coalescer.run(statement.query(), params, statement.consistency(), statement.page_size(),
+                              statement.deadline(), send)
+            },
+            _ => send(),
+        }
+    }
+
+    pub fn query_template(&self, template: &Template, params: &[&ToCQL]) -> Result<QueryResult> {
+        let values = try!(template.values(params));
+        self.query_statement(template.statement(), &values)
+    }
+
+    /// Runs a query that restricts the partition key with `IN ?` as one
+    /// single-partition query per key, spread over the pool's connections,
+    /// and merges the results in key order. This is usually faster and
+    /// easier on the coordinator than a long IN list. `params` holds the
+    /// values for the query's other markers, in order.
+    pub fn query_split_in(&self, query: &str, keys: &[&ToCQL], params: &[&ToCQL]) -> Result<QueryResult> {
+        let split = try!(SplitQuery::parse(query));
+        fanout::run(self, &split, keys, params, self.inner.pool.connections().len())
+    }
+
+    /// Inserts the rows of a CSV file into `table`, as described in the
+    /// `copy` module.
+    pub fn copy_from_csv<R: BufRead>(&self, table: &str, reader: R, options: &CopyOptions) -> Result<CopyProgress> {
+        copy::copy_from_csv(self, table, reader, options)
+    }
+
+    /// Writes every row of `table` to `writer` as newline-delimited JSON,
+    /// as described in the `backup` module.
+    pub fn export<W: Write>(&self, table: &str, writer: W) -> Result<usize> {
+        backup::export(self, table, writer)
+    }
+
+    /// Restores rows written by `export` into `table`.
+    pub fn import<R: BufRead>(&self, table: &str, reader: R, options: &CopyOptions) -> Result<CopyProgress> {
+        backup::import(self, table, reader, options)
+    }
+
+    /// Deletes the rows of `table` that `predicate` picks, as described in
+    /// the `purge` module.
+    pub fn purge<P>(&self, table: &str, predicate: P, options: &PurgeOptions) -> Result<PurgeProgress>
+        where P: FnMut(&Row) -> bool
+    {
+        purge::purge(self, table, predicate, options)
+    }
+
+    /// Prepares `query`. The pool only connects to a single node, so the
+    /// returned statement is valid on every connection.
+    pub fn prepare(&self, query: &str) -> Result<PreparedStatement> {
+        self.prepare_statement(&Statement::new(query))
+    }
+
+    /// Like `prepare`, honoring `statement`'s `allow_filtering`. Its other
+    /// options don't apply to preparing.
+    pub fn prepare_statement(&self, statement: &Statement) -> Result<PreparedStatement> {
+        try!(self.check_filtering(statement));
+        let query = statement.query();
+        self.lint(Some(query));
+        self.with_client(query, None, |client| client.prepare(query))
+    }
+
+    pub fn execute_prepared(&self, prepared: &PreparedStatement, params: &[&ToCQL]) -> Result<QueryResult> {
+        self.execute_prepared_at(prepared, params, Consistency::One)
+    }
+
+    /// Like `execute_prepared`, at `consistency`.
+    pub fn execute_prepared_at(&self, prepared: &PreparedStatement, params: &[&ToCQL], consistency: Consistency)
+                               -> Result<QueryResult> {
+        let consistency = self.enforce(Some(prepared.query()), consistency);
+        try!(self.check_replication(Some(prepared.query()), None, consistency));
+        let stale_reads = self.stale_reads(prepared.query());
+        let send = || self.with_client(prepared.query(), None, |client| {
+            let result = client.execute_prepared_at(prepared, params, consistency);
+            keep_or_serve_stale(stale_reads, client, prepared.query(), params, result)
+        });
+        match self.inner.coalescer {
+            Some(ref coalescer) if coalesce::coalescable_prepared(prepared) => {
+                coalescer.run(prepared.query(), params, consistency, None, None, send)
+            },
+            _ => send(),
+        }
+    }
+
+    /// Runs a prepared point lookup for every key, a connection's worth at
+    /// a time, instead of one after another. Each key is the values to
+    /// bind, and the results come back in the same order as the keys, each
+    /// with its own error if that lookup failed.
+    pub fn get_many<'a, I>(&self, prepared: &PreparedStatement, keys: I) -> Vec<Result<QueryResult>>
+        where I: IntoIterator<Item = &'a [&'a ToCQL]>
+    {
+        fanout::get_many(self, prepared, keys, self.inner.pool.connections().len())
+    }
+
+    /// Describes how `query_statement` would run `statement`, after the
+    /// consistency policy, without sending it.
+    pub fn explain(&self, statement: &Statement, params: &[&ToCQL]) -> Result<Explanation> {
+        let raised;
+        let statement = match self.enforce_statement(statement) {
+            Some(statement) => {
+                raised = statement;
+                &raised
+            },
+            None => statement,
+        };
+        self.with_client(statement.query(), statement.deadline(), |client| client.explain(statement, params))
+    }
+
+    /// Describes how `execute_prepared_at` would run `prepared`, including
+    /// its routing key and token, without sending it.
+    pub fn explain_prepared(&self, prepared: &PreparedStatement, params: &[&ToCQL], consistency: Consistency)
+                            -> Result<Explanation> {
+        let consistency = self.enforce(Some(prepared.query()), consistency);
+        self.with_client(prepared.query(), None, |client| client.explain_prepared(prepared, params, consistency))
+    }
+
+    pub fn batch(&self, batch: &Batch) -> Result<QueryResult> {
+        self.lint(batch.queries());
+        let consistency = self.enforce(batch.queries(), batch.consistency());
+        try!(self.check_replication(batch.queries(), None, consistency));
+        if consistency != batch.consistency() {
+            let mut raised = batch.clone();
+            raised.set_consistency(consistency);
+            return self.with_client("BATCH", None, |client| client.batch(&raised));
+        }
+        self.with_client("BATCH", None, |client| client.batch(batch))
+    }
+
+    pub fn execute(&self, statement: &str, params: &[&ToCQL]) -> Result<()> {
+        self.execute_statement(&Statement::new(statement), params)
+    }
+
+    pub fn execute_statement(&self, statement: &Statement, params: &[&ToCQL]) -> Result<()> {
+        try!(self.check_filtering(statement));
+        self.lint(Some(statement.query()));
+        let raised;
+        let statement = match self.enforce_statement(statement) {
+            Some(statement) => {
+                raised = statement;
+                &raised
+            },
+            None => statement,
+        };
+        try!(self.check_replication(Some(statement.query()), statement.keyspace(), statement.consistency()));
+        self.with_client(statement.query(), statement.deadline(), |client| client.execute_statement(statement, params))
+    }
+
+    /// Runs the Gremlin script `script` on DSE Graph. It isn't CQL, so it
+    /// skips the linter and the statement checks. See the `graph` module.
+    pub fn execute_graph(&self, script: &str, params: &[&ToCQL], options: &GraphOptions) -> Result<QueryResult> {
+        self.with_client(script, None, |client| client.execute_graph(script, params, options))
+    }
+
+    /// Runs a CREATE, ALTER or DROP statement and waits for the cluster to
+    /// agree on the new schema. See `Client::alter_schema`, and the `ddl`
+    /// module for building the statements.
+    pub fn alter_schema(&self, statement: &str) -> Result<Option<SchemaChange>> {
+        self.lint(Some(statement));
+        self.with_client(statement, None, |client| client.alter_schema(statement))
+    }
+
+    /// Adds `delta`, which may be negative, to the counter `column` in the
+    /// row with the given primary key values. Counter updates aren't
+    /// idempotent, so after an error the increment may or may not have
+    /// been applied, and retrying may count it twice.
+    ///
+    /// `table`, which may be qualified as `keyspace.table`, and the column
+    /// names are quoted where needed, so they match as written, case
+    /// included.
+    pub fn increment(&self, table: &str, key: &[(&str, &ToCQL)], column: &str, delta: i64) -> Result<()> {
+        self.increment_at(table, key, column, delta, Consistency::One)
+    }
+
+    /// Like `increment`, at `consistency`. Counters can't be written at
+    /// `Any`, and the serial levels are only for conditional updates,
+    /// which counters don't support.
+    ///
+    /// The update is prepared first, which checks that `column` is a
+    /// counter; otherwise `c = c + ?` would append to a list or set column
+    /// instead.
+    pub fn increment_at(&self, table: &str, key: &[(&str, &ToCQL)], column: &str, delta: i64,
+                        consistency: Consistency) -> Result<()> {
+        match consistency {
+            Consistency::Any | Consistency::Serial | Consistency::LocalSerial => {
+                return Err(MyError::Protocol(format!("Counters can't be updated at consistency {:?}", consistency)));
+            },
+            _ => {},
+        }
+        if key.is_empty() {
+            return Err(MyError::Protocol(format!("No key columns given for {}", table)));
+        }
+        let table_name: Vec<String> = table.splitn(2, '.').map(quote_identifier).collect();
+        let conditions: Vec<String> = key.iter().map(|&(name, _)| format!("{} = ?", quote_identifier(name))).collect();
+        let column_name = quote_identifier(column);
+        let query = format!("UPDATE {} SET {} = {} + ? WHERE {}",
+                            table_name.join("."), column_name, column_name, conditions.join(" AND "));
+        let prepared = try!(self.prepare(&query));
+        match prepared.params().first().map(|param| param.datatype()) {
+            Some(&CQLType::Counter) => {},
+            datatype => {
+                return Err(MyError::Protocol(format!("Column {} of {} is {:?}, not a counter", column, table, datatype)));
+            },
+        }
+        let mut params: Vec<&ToCQL> = vec![&delta];
+        params.extend(key.iter().map(|&(_, value)| value));
+        let batch = BatchBuilder::new(BatchType::Counter).consistency(consistency).prepared(&prepared, &params).build();
+        try!(self.batch(&batch));
+        Ok(())
+    }
+
+    /// Shuts the session down for every handle: queued and future requests
+    /// fail with `MyError::Closed`, sockets are closed and the background
+    /// thread is stopped. This also happens when the last handle is dropped.
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    /// A receiver for every cluster event from now on: hosts going up or
+    /// down, joining or leaving, schema changes, the health of the pool,
+    /// and warnings, as described in the `warning` module. Each call gets
+    /// its own receiver, so components can subscribe independently.
+    ///
+    /// The first call opens a connection registered for server events,
+    /// outside the pool, which serves every subscriber. If it fails, the
+    /// next call opens another. Pool health is only reported with
+    /// `SessionConfig::background_thread` on.
+    pub fn subscribe(&self) -> Result<Receiver<ClusterEvent>> {
+        if self.inner.pool.is_closed() {
+            return Err(MyError::Closed);
+        }
+        let receiver = self.inner.bus.subscribe();
+        if !self.inner.listening.swap(true, Ordering::SeqCst) {
+            if let Err(err) = self.listen() {
+                self.inner.listening.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        }
+        Ok(receiver)
+    }
+
+    /// Forwards server events to the bus until the event connection fails,
+    /// or the session closes or loses its last subscriber.
+    fn listen(&self) -> Result<()> {
+        let events = {
+            let client = try!(self.checkout(None));
+            try!(client.subscribe_events(&[EventType::TopologyChange, EventType::StatusChange,
+                                           EventType::SchemaChange]))
+        };
+        let closer = events.closer();
+        let pool = self.inner.pool.clone();
+        let bus = self.inner.bus.clone();
+        let listening = self.inner.listening.clone();
+        let handle = thread::spawn(move || {
+            for event in events.iter() {
+                if pool.is_closed() {
+                    break;
+                }
+                bus.publish(ClusterEvent::from(event));
+                if bus.subscribers() == 0 {
+                    break;
+                }
+            }
+            listening.store(false, Ordering::SeqCst);
+        });
+        // A previous listener has stopped, or `listening` wouldn't have
+        // been cleared.
+        let previous = self.inner.listener.lock().unwrap().take();
+        if let Some((_, previous)) = previous {
+            let _ = previous.join();
+        }
+        *self.inner.listener.lock().unwrap() = Some((closer, handle));
+        if self.inner.pool.is_closed() {
+            self.inner.stop_listening();
+        }
+        Ok(())
+    }
+
+    /// Publishes `warning` to the session's subscribers, alongside the ones
+    /// the session runs into itself, for code built on the session to
+    /// report problems the same way.
+    pub fn warn(&self, warning: Warning) {
+        self.inner.bus.on_warning(&warning);
+    }
+
+    /// What the contact point said it supports when the session connected.
+    pub fn capabilities(&self) -> &ServerCapabilities {
+        &self.inner.capabilities
+    }
+
+    pub fn pool_metrics(&self) -> PoolMetrics {
+        self.inner.pool.metrics()
+    }
+
+    /// Reads answered with the result of an identical one in flight, with
+    /// `SessionConfig::coalesce_reads`.
+    pub fn coalesced_reads(&self) -> usize {
+        self.inner.coalescer.as_ref().map_or(0, |coalescer| coalescer.coalesced())
+    }
+
+    /// The latencies every connection recorded since the last call, merged,
+    /// for reporting percentiles over fixed intervals. `None` unless
+    /// `SessionConfig::latency_histogram` keeps HDR histograms. Like
+    /// `connection_stats`, waits for each connection's current request.
+    pub fn take_latency_histogram(&self) -> Option<Histogram> {
+        let mut merged: Option<Histogram> = None;
+        for connection in self.inner.pool.connections() {
+            if let Some(latency) = connection.lock().unwrap().take_latency_histogram() {
+                match merged {
+                    Some(ref mut merged) => merged.merge(&latency),
+                    None => merged = Some(latency),
+                }
+            }
+        }
+        merged
+    }
+
+    /// The counters of every connection in the pool, totalled. Waits for
+    /// each connection's current request to finish.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        let mut total = ConnectionStats::new();
+        for connection in self.inner.pool.connections() {
+            total.merge(connection.lock().unwrap().stats());
+        }
+        total
+    }
+
+    /// Runs a cheap query against `system.local` on one of the pool's
+    /// connections, for use in liveness and readiness checks. It goes
+    /// straight to the connection, so read coalescing, stale reads and the
+    /// consistency policy can't answer or alter it. Fails after two seconds,
+    /// whether waiting for a free connection or for the response.
+    pub fn check_connectivity(&self) -> Result<()> {
+        let mut statement = Statement::new("SELECT key FROM system.local");
+        let deadline = Instant::now() + Duration::from_millis(CONNECTIVITY_CHECK_TIMEOUT_MS);
+        statement.set_deadline(deadline);
+        self.with_client(statement.query(), Some(deadline), |client| client.query_statement(&statement, &[]))
+            .map(|_| ())
+    }
+
+    /// Connects to every address the contact point resolved to, outside the
+    /// pool, and reports how far the handshake got on each and why it
+    /// stopped. Meant for diagnosing connection problems.
+    pub fn connection_report(&self) -> ConnectionReport {
+        ConnectionReport::probe(&self.inner.addrs, &self.inner.compression, self.inner.auth.as_ref())
+    }
+
+    /// The consistency the consistency policy requires of `queries`, or
+    /// `requested` if that's enough.
+    fn enforce<'q, I>(&self, queries: I, requested: Consistency) -> Consistency
+        where I: IntoIterator<Item = &'q str>
+    {
+        self.inner.consistency_policy.enforce(queries, requested, &*self.inner.bus)
+    }
+
+    /// A copy of `statement` at the consistency the consistency policy
+    /// requires, if that's higher than its own.
+    fn enforce_statement(&self, statement: &Statement) -> Option<Statement> {
+        let consistency = self.enforce(Some(statement.query()), statement.consistency());
+        if consistency == statement.consistency() {
+            return None;
+        }
+        let mut raised = statement.clone();
+        raised.set_consistency(consistency);
+        Some(raised)
+    }
+
+    /// The session's stale reads, if they cover `query`.
+    fn stale_reads(&self, query: &str) -> Option<&StaleReads> {
+        match self.inner.stale_reads {
+            Some(ref stale_reads) if stale_reads.covers(query) => Some(stale_reads),
+            _ => None,
+        }
+    }
+
+    fn check_filtering(&self, statement: &Statement) -> Result<()> {
+        if self.inner.forbid_allow_filtering && !statement.allow_filtering() &&
+           statement::uses_allow_filtering(statement.query()) {
+            return Err(MyError::Protocol(format!("ALLOW FILTERING is forbidden on this session: {}",
+                                                 statement.query())));
+        }
+        Ok(())
+    }
+
+    fn lint<'q, I>(&self, queries: I)
+        where I: IntoIterator<Item = &'q str>
+    {
+        if let Some(ref linter) = self.inner.linter {
+            for query in queries {
+                let lints = linter.check(query);
+                if !lints.is_empty() {
+                    self.warn(Warning::Lint { query: query.to_string(), lints: lints });
+                }
+            }
+        }
+    }
+
+    fn check_replication<'q, I>(&self, queries: I, keyspace: Option<&str>, consistency: Consistency) -> Result<()>
+        where I: IntoIterator<Item = &'q str>
+    {
+        match self.inner.replication {
+            Some(ref cache) => cache.check(self, queries, keyspace, consistency),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `f` on a connection checked out of the pool for the duration of
+    /// the request.
+    fn with_client<T, F>(&self, statement: &str, deadline: Option<Instant>, f: F) -> Result<T>
+        where F: FnOnce(&mut Client) -> Result<T>
+    {
+        let start = Instant::now();
+        let mut client = match self.checkout(deadline) {
+            Ok(client) => client,
+            Err(err) => {
+                let context = ErrorContext::new(statement, None, 1, start.elapsed());
+                return Err(MyError::Request(context, Box::new(err)));
+            }
+        };
+        f(&mut client)
+    }
+
+    /// Waits for a connection, bounded by both the configured checkout
+    /// timeout and the request's deadline.
+    fn checkout<'a>(&'a self, deadline: Option<Instant>) -> Result<PooledClient<'a>> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = try!(remaining(deadline));
+                Some(self.inner.checkout_timeout.map_or(remaining, |t| t.min(remaining)))
+            },
+            None => self.inner.checkout_timeout,
+        };
+        let client = try!(self.inner.pool.checkout(timeout));
+        if let Some(deadline) = deadline {
+            try!(remaining(deadline));
+        }
+        Ok(client)
+    }
+}
+
+/// Keeps a successful `result` in `stale_reads`, or if the request
+/// couldn't reach any node, serves the kept result in its place.
+fn keep_or_serve_stale(stale_reads: Option<&StaleReads>, client: &Client, query: &str, params: &[&ToCQL],
+                       result: Result<QueryResult>) -> Result<QueryResult> {
+    let stale_reads = match stale_reads {
+        Some(stale_reads) => stale_reads,
+        None => return result,
+    };
+    match result {
+        Ok(result) => {
+            try!(stale_reads.store(query, params, &result));
+            Ok(result)
+        },
+        Err(err) => {
+            if stale::unreachable(&err) && client.all_hosts_down() {
+                if let Some(stale) = stale_reads.fetch(query, params) {
+                    return Ok(stale);
+                }
+            }
+            Err(err)
+        },
+    }
+}
+
+fn remaining(deadline: Instant) -> Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        Err(MyError::Timeout("request deadline passed".to_string()))
+    } else {
+        Ok(deadline - now)
+    }
+}
+
+impl Inner {
+    fn close(&self) {
+        if !self.pool.is_closed() {
+            self.pool.close();
+            self.bus.publish(ClusterEvent::PoolStateChanged(PoolState::Closed));
+        }
+        if let Some(background) = self.background.lock().unwrap().take() {
+            background.stop();
+        }
+        self.stop_listening();
+    }
+
+    fn stop_listening(&self) {
+        let listener = self.listener.lock().unwrap().take();
+        if let Some((closer, handle)) = listener {
+            closer.close();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Background {
+    fn spawn(pool: Arc<Pool>, bus: Arc<EventBus>, interval: Duration, reconnect_jitter: Duration) -> Background {
+        let (shutdown, shutdown_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut last_broken = 0;
+            loop {
+                match shutdown_rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {},
+                    _ => return,
+                }
+                // Once a reconnect fails the host is most likely still
+                // down, so the pool's other broken connections wait for the
+                // next round instead of each trying it too.
+                let mut reconnect_failed = false;
+                let mut broken = 0;
+                for index in 0..pool.connections().len() {
+                    // Connections in use are left to their requests, which
+                    // reconnect them if they fail.
+                    let failed = match pool.checkout_idle(index) {
+                        Some(mut client) => client.heartbeat().err(),
+                        None => None,
+                    };
+                    let err = match failed {
+                        Some(err) => err,
+                        None => continue,
+                    };
+                    if reconnect_failed {
+                        bus.publish(ClusterEvent::Warning(Warning::HeartbeatFailed {
+                            error: err.to_string(),
+                            reconnect_in: None,
+                        }));
+                        broken += 1;
+                        continue;
+                    }
+                    // The connection goes back to the pool while waiting,
+                    // so requests aren't held up by it.
+                    let delay = jitter(reconnect_jitter);
+                    bus.publish(ClusterEvent::Warning(Warning::HeartbeatFailed {
+                        error: err.to_string(),
+                        reconnect_in: Some(delay),
+                    }));
+                    match shutdown_rx.recv_timeout(delay) {
+                        Err(RecvTimeoutError::Timeout) => {},
+                        _ => return,
+                    }
+                    if let Some(mut client) = pool.checkout_idle(index) {
+                        if let Err(err) = client.reconnect() {
+                            bus.publish(ClusterEvent::Warning(Warning::ReconnectFailed { error: err.to_string() }));
+                            reconnect_failed = true;
+                            broken += 1;
+                        }
+                    }
+                }
+                if broken != last_broken {
+                    let state = if broken == 0 { PoolState::Healthy } else { PoolState::Degraded { broken: broken } };
+                    bus.publish(ClusterEvent::PoolStateChanged(state));
+                    last_broken = broken;
+                }
+            }
+        });
+        Background {
+            shutdown: Mutex::new(shutdown),
+            handle: handle,
+        }
+    }
+
+    fn stop(self) {
+        let _ = self.shutdown.lock().unwrap().send(());
+        let _ = self.handle.join();
+    }
+}
+
+/// A random duration between zero and `max`, to the millisecond. Every
+/// `RandomState` is seeded differently, which is random enough to spread
+/// reconnects out.
+fn jitter(max: Duration) -> Duration {
+    let max_ms = max.as_secs() * 1000 + max.subsec_nanos() as u64 / 1000000;
+    if max_ms == 0 {
+        return Duration::from_millis(0);
+    }
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(max_ms);
+    Duration::from_millis(hasher.finish() % (max_ms + 1))
+}
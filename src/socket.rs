@@ -0,0 +1,186 @@
+//! Options set on each TCP connection a client opens. Requests are small
+//! and answered one at a time, so Nagle's algorithm, which holds small
+//! writes back until the previous one is acknowledged, mostly adds
+//! latency; it's off unless turned back on here.
+//!
+//! Options left as `None` keep the operating system's defaults. Buffer
+//! sizes are set once the connection is open, so they don't change the
+//! window scale negotiated while connecting; raise the system defaults
+//! instead where that matters.
+//!
+//! Connections to clusters that require encryption go through TLS, with
+//! the `tls` feature, or through the platform's TLS library with the
+//! `native-tls` feature; see the `tls` and `nativetls` modules.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use net2::TcpStreamExt;
+#[cfg(feature = "native-tls")]
+use native_tls::TlsStream;
+#[cfg(feature = "tls")]
+use rustls::{ClientSession, Session, StreamOwned};
+
+/// The bytes to and from a node: the TCP stream itself, or TLS over it.
+pub enum Connection {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<StreamOwned<ClientSession, TcpStream>>),
+    #[cfg(feature = "native-tls")]
+    NativeTls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    /// The underlying socket, for its addresses, timeouts and options.
+    pub fn tcp(&self) -> &TcpStream {
+        match *self {
+            Connection::Plain(ref conn) => conn,
+            #[cfg(feature = "tls")]
+            Connection::Tls(ref stream) => &stream.sock,
+            #[cfg(feature = "native-tls")]
+            Connection::NativeTls(ref stream) => stream.get_ref(),
+        }
+    }
+
+    /// Closes the connection in both directions, telling the server first
+    /// if it's encrypted.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Plain(ref conn) => conn.shutdown(Shutdown::Both),
+            #[cfg(feature = "tls")]
+            Connection::Tls(ref mut stream) => {
+                stream.sess.send_close_notify();
+                let _ = stream.sess.write_tls(&mut stream.sock);
+                stream.sock.shutdown(Shutdown::Both)
+            },
+            #[cfg(feature = "native-tls")]
+            Connection::NativeTls(ref mut stream) => {
+                let _ = stream.shutdown();
+                stream.get_ref().shutdown(Shutdown::Both)
+            },
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut conn) => conn.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "native-tls")]
+            Connection::NativeTls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut conn) => conn.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "native-tls")]
+            Connection::NativeTls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Plain(ref mut conn) => conn.flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(ref mut stream) => stream.flush(),
+            #[cfg(feature = "native-tls")]
+            Connection::NativeTls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// Closes a client's connection from another thread, even while a request
+/// is blocked on it, and keeps the client from connecting again.
+#[derive(Debug, Default)]
+pub struct Closer {
+    closed: AtomicBool,
+    conn: Mutex<Option<TcpStream>>,
+}
+
+impl Closer {
+    pub fn new() -> Closer {
+        Closer::default()
+    }
+
+    /// Shuts `conn` down on `close`, instead of the socket tracked before
+    /// it, or right away if already closed.
+    pub fn track(&self, conn: &TcpStream) -> io::Result<()> {
+        let conn = try!(conn.try_clone());
+        // Checked under the lock, so a `close` racing with this either
+        // sees the new socket or is seen here.
+        let mut tracked = self.conn.lock().unwrap();
+        if self.is_closed() {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+        *tracked = Some(conn);
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(ref conn) = *self.conn.lock().unwrap() {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sends each write right away instead of coalescing small ones
+    /// (TCP_NODELAY).
+    pub nodelay: bool,
+    /// Probes an idle connection after this long, so a peer that vanished
+    /// is noticed (SO_KEEPALIVE).
+    pub keepalive: Option<Duration>,
+    /// SO_SNDBUF, in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF, in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// How long closing the connection waits for unsent data to go out
+    /// (SO_LINGER). `Some(Duration::from_secs(0))` resets it instead.
+    pub linger: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn apply(&self, conn: &TcpStream) -> io::Result<()> {
+        try!(conn.set_nodelay(self.nodelay));
+        if let Some(keepalive) = self.keepalive {
+            try!(TcpStreamExt::set_keepalive(conn, Some(keepalive)));
+        }
+        if let Some(size) = self.send_buffer_size {
+            try!(conn.set_send_buffer_size(size));
+        }
+        if let Some(size) = self.recv_buffer_size {
+            try!(conn.set_recv_buffer_size(size));
+        }
+        if let Some(linger) = self.linger {
+            try!(TcpStreamExt::set_linger(conn, Some(linger)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+//! Serving reads from the last result seen when the cluster can't be
+//! reached, for read paths that would rather show old data than fail.
+//!
+//! ```ignore
+//! let mut config = SessionConfig::default();
+//! config.stale_reads = Some(Arc::new(StaleReads::new(&["SELECT * FROM app.flags WHERE name = ?"],
+//!                                                    Duration::from_secs(3600))));
+//! ```
+//!
+//! Only the listed statements are kept, each by the hash of its query text
+//! and its bound values. A kept result is served in place of an error only
+//! when the request failed to reach any node: its connection failed or
+//! timed out, and every host the connection knows of is marked down.
+//! Errors from a node that answered, such as a read timeout or an
+//! unavailable error, are returned as usual. Served results are marked
+//! with `QueryResult::stale`, and the connections go on reconnecting as
+//! they would otherwise.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use errors::MyError;
+use protocol::{QueryResult, Result};
+use statement::statement_hash;
+use types::ToCQL;
+
+/// Results kept unless configured otherwise.
+pub const DEFAULT_MAX_STALE_ENTRIES: usize = 10000;
+
+#[derive(Debug)]
+pub struct StaleReads {
+    statements: HashSet<u64>,
+    max_age: Duration,
+    max_entries: usize,
+    results: Mutex<HashMap<(u64, Vec<Vec<u8>>), (QueryResult, Instant)>>,
+}
+
+impl StaleReads {
+    /// Keeps the results of `statements`, serving them for up to `max_age`
+    /// after they were read.
+    pub fn new(statements: &[&str], max_age: Duration) -> StaleReads {
+        StaleReads {
+            statements: statements.iter().map(|statement| statement_hash(statement)).collect(),
+            max_age: max_age,
+            max_entries: DEFAULT_MAX_STALE_ENTRIES,
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keeps at most `max_entries` results, dropping the oldest to make
+    /// room for new ones.
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+    }
+
+    /// Whether results of `query` are kept.
+    pub fn covers(&self, query: &str) -> bool {
+        self.statements.contains(&statement_hash(query))
+    }
+
+    /// Number of results currently kept.
+    pub fn len(&self) -> usize {
+        self.results.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keeps `result` as the latest for `query` with `params`.
+    pub fn store(&self, query: &str, params: &[&ToCQL], result: &QueryResult) -> Result<()> {
+        let key = (statement_hash(query), try!(serialize(params)));
+        let mut results = self.results.lock().unwrap();
+        if !results.contains_key(&key) && results.len() >= self.max_entries {
+            let oldest = results.iter().min_by_key(|&(_, &(_, read))| read).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                results.remove(&oldest);
+            }
+        }
+        if self.max_entries > 0 {
+            results.insert(key, (result.clone(), Instant::now()));
+        }
+        Ok(())
+    }
+
+    /// The kept result for `query` with `params`, marked stale, unless
+    /// there is none or it's older than the maximum age.
+    pub fn fetch(&self, query: &str, params: &[&ToCQL]) -> Option<QueryResult> {
+        let key = (statement_hash(query), match serialize(params) {
+            Ok(values) => values,
+            Err(_) => return None,
+        });
+        let results = self.results.lock().unwrap();
+        results.get(&key).and_then(|&(ref result, read)| {
+            let age = read.elapsed();
+            if age > self.max_age {
+                return None;
+            }
+            let mut stale = result.clone();
+            stale.set_stale(Some(age));
+            Some(stale)
+        })
+    }
+}
+
+/// Whether `error` means the request never reached a node, rather than
+/// that a node answered with an error.
+pub fn unreachable(error: &MyError) -> bool {
+    match *error.root() {
+        MyError::IO(_) | MyError::Timeout(_) | MyError::Closed => true,
+        _ => false,
+    }
+}
+
+fn serialize(params: &[&ToCQL]) -> Result<Vec<Vec<u8>>> {
+    let mut values = Vec::with_capacity(params.len());
+    for param in params {
+        let mut value = Vec::new();
+        try!(param.serialize(&mut value));
+        values.push(value);
+    }
+    Ok(values)
+}
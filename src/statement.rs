@@ -0,0 +1,505 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use errors::MyError;
+use protocol::{Consistency, QueryParameters, Result};
+use retry::RetryPolicy;
+use types::ToCQL;
+
+/// Custom payload key DSE reads the role to execute as from.
+const PROXY_EXECUTE_KEY: &'static str = "ProxyExecute";
+
+/// Longest query text sent unless configured otherwise.
+pub const DEFAULT_MAX_STATEMENT_LENGTH: usize = 1024 * 1024;
+
+/// A stable 64-bit FNV-1a hash of the query text. It doesn't change between
+/// runs or builds, so it can identify a statement in metrics, caches and
+/// logs without keeping the full text around.
+pub fn statement_hash(query: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in query.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// A CQL query along with the options used when running it.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    query: String,
+    consistency: Consistency,
+    projection: Option<Vec<String>>,
+    deadline: Option<Instant>,
+    page_size: Option<i32>,
+    paging_state: Option<Vec<u8>>,
+    timestamp: Option<i64>,
+    tracing: bool,
+    execute_as: Option<String>,
+    keyspace: Option<String>,
+    now_in_seconds: Option<i32>,
+    allow_filtering: bool,
+    idempotent: bool,
+    retry_policy: Option<Arc<RetryPolicy>>,
+    tags: Vec<(String, String)>,
+    payload: Vec<(String, Vec<u8>)>,
+}
+
+impl Statement {
+    pub fn new(query: &str) -> Statement {
+        Statement {
+            query: query.to_string(),
+            consistency: Consistency::One,
+            projection: None,
+            deadline: None,
+            page_size: None,
+            paging_state: None,
+            timestamp: None,
+            tracing: false,
+            execute_as: None,
+            keyspace: None,
+            now_in_seconds: None,
+            allow_filtering: false,
+            idempotent: false,
+            retry_policy: None,
+            tags: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn hash(&self) -> u64 {
+        statement_hash(&self.query)
+    }
+
+    /// How many replicas must answer, `One` by default. A session's
+    /// consistency policy may raise it for writes.
+    pub fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+
+    pub fn consistency(&self) -> Consistency {
+        self.consistency
+    }
+
+    /// Only decode the given columns from result rows. The bytes of any
+    /// other column are skipped without being copied.
+    pub fn project(&mut self, columns: &[&str]) {
+        self.projection = Some(columns.iter().map(|c| c.to_string()).collect());
+    }
+
+    pub fn projection(&self) -> Option<&[String]> {
+        self.projection.as_ref().map(|p| p.as_ref())
+    }
+
+    /// Bounds the total time spent on this statement: waiting for a
+    /// connection, then sending it and waiting for the response, as many
+    /// times as it's retried. Socket reads and writes time out when it
+    /// passes, and no retry starts after it; the statement fails with
+    /// `MyError::Timeout` instead. Reconnecting to another host to retry
+    /// on is bounded by the connect timeout rather than the deadline.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Limits how many rows the server returns per page.
+    pub fn set_page_size(&mut self, page_size: i32) {
+        self.page_size = Some(page_size);
+    }
+
+    pub fn page_size(&self) -> Option<i32> {
+        self.page_size
+    }
+
+    /// Continues from where a previous page ended; pass the state from
+    /// `QueryResult::paging_state`, or `None` to start from the beginning.
+    pub fn set_paging_state(&mut self, paging_state: Option<&[u8]>) {
+        self.paging_state = paging_state.map(|s| s.to_vec());
+    }
+
+    pub fn paging_state(&self) -> Option<&[u8]> {
+        self.paging_state.as_ref().map(|s| s.as_ref())
+    }
+
+    /// Writes with `timestamp`, in microseconds since the epoch, instead of
+    /// the coordinator's time. Cassandra keeps the write with the highest
+    /// timestamp, so a retried write that carries its original timestamp
+    /// can't overwrite a later one. See `TimestampGenerator`.
+    pub fn set_timestamp(&mut self, timestamp: Option<i64>) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    /// Asks the server to trace this statement. The trace's id is returned
+    /// as `QueryResult::tracing_id`.
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.tracing = tracing;
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.tracing
+    }
+
+    /// Runs the statement with the permissions of `role` instead of the
+    /// logged in user, using DSE proxy execution. The user needs the
+    /// PROXY.EXECUTE permission on `role`. Needs native protocol v4, since
+    /// the role is sent in the custom payload.
+    pub fn set_execute_as(&mut self, role: Option<&str>) {
+        self.execute_as = role.map(|r| r.to_string());
+    }
+
+    pub fn execute_as(&self) -> Option<&str> {
+        self.execute_as.as_ref().map(|r| r.as_ref())
+    }
+
+    /// Resolves unqualified table names in `keyspace` rather than the
+    /// connection's keyspace, without a USE. Needs native protocol v5.
+    pub fn set_keyspace(&mut self, keyspace: Option<&str>) {
+        self.keyspace = keyspace.map(|k| k.to_string());
+    }
+
+    pub fn keyspace(&self) -> Option<&str> {
+        self.keyspace.as_ref().map(|k| k.as_ref())
+    }
+
+    /// Has the server use `now` (in seconds since the epoch) as the
+    /// current time, e.g. to expire TTLs in tests. Needs native protocol
+    /// v5.
+    pub fn set_now_in_seconds(&mut self, now: Option<i32>) {
+        self.now_in_seconds = now;
+    }
+
+    pub fn now_in_seconds(&self) -> Option<i32> {
+        self.now_in_seconds
+    }
+
+    /// Lets this statement use ALLOW FILTERING on a session that forbids
+    /// it, for scans that are known to be small or rare.
+    pub fn set_allow_filtering(&mut self, allow: bool) {
+        self.allow_filtering = allow;
+    }
+
+    pub fn allow_filtering(&self) -> bool {
+        self.allow_filtering
+    }
+
+    /// Marks the statement as safe to apply more than once, such as a read
+    /// or an insert of fixed values, but not a counter update or an append
+    /// to a list. Only idempotent statements are retried after errors that
+    /// leave it unknown whether they were applied; see `retry`.
+    pub fn set_idempotent(&mut self, idempotent: bool) {
+        self.idempotent = idempotent;
+    }
+
+    pub fn idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Decides which failures of this statement are retried, instead of
+    /// the connection's policy. For example, a
+    /// `DowngradingConsistencyRetryPolicy` for reads that would rather
+    /// return stale data than fail.
+    pub fn set_retry_policy(&mut self, policy: Option<Arc<RetryPolicy>>) {
+        self.retry_policy = policy;
+    }
+
+    pub fn retry_policy(&self) -> Option<&Arc<RetryPolicy>> {
+        self.retry_policy.as_ref()
+    }
+
+    /// Tags the statement with `key` set to `value`, such as `team` and
+    /// `search`, replacing any earlier value for `key`. The connection's
+    /// stats count requests by tag, so the load can be attributed to
+    /// whatever made it; see `ConnectionStats::tags`. With
+    /// `Client::set_send_tags`, tags also go to the server in the custom
+    /// payload.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        set_tag(&mut self.tags, key, value);
+    }
+
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
+    }
+
+    /// Sends `value` under `key` in the custom payload, for server-side
+    /// plugins such as DSE Graph, replacing any earlier value for `key`.
+    /// Needs native protocol v4; the statement fails on older versions.
+    pub fn set_payload(&mut self, key: &str, value: &[u8]) {
+        match self.payload.iter().position(|&(ref existing, _)| existing == key) {
+            Some(index) => self.payload[index].1 = value.to_vec(),
+            None => self.payload.push((key.to_string(), value.to_vec())),
+        }
+    }
+
+    pub fn payload(&self) -> &[(String, Vec<u8>)] {
+        &self.payload
+    }
+
+    /// The request options for running this statement with `values`.
+    pub fn parameters<'a>(&'a self, values: &'a [&'a ToCQL]) -> QueryParameters<'a> {
+        let mut parameters = QueryParameters::new(values);
+        parameters.consistency = self.consistency;
+        parameters.page_size = self.page_size;
+        parameters.paging_state = self.paging_state();
+        parameters.timestamp = self.timestamp;
+        parameters.tracing = self.tracing;
+        parameters.keyspace = self.keyspace();
+        parameters.now_in_seconds = self.now_in_seconds;
+        if let Some(ref role) = self.execute_as {
+            parameters.custom_payload.push((PROXY_EXECUTE_KEY, role.as_bytes()));
+        }
+        parameters.custom_payload.extend(self.payload.iter().map(|&(ref key, ref value)| (&key[..], &value[..])));
+        parameters
+    }
+}
+
+impl<'a> From<&'a str> for Statement {
+    fn from(query: &'a str) -> Statement {
+        Statement::new(query)
+    }
+}
+
+/// Hands out write timestamps in microseconds since the epoch. Each one is
+/// later than the one before, even if the system clock steps back or two
+/// are taken in the same microsecond, so writes made one after another
+/// through the same generator are applied in that order.
+#[derive(Debug, Default)]
+pub struct TimestampGenerator {
+    last: Mutex<i64>,
+}
+
+impl TimestampGenerator {
+    pub fn new() -> TimestampGenerator {
+        TimestampGenerator::default()
+    }
+
+    pub fn next(&self) -> i64 {
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64 * 1000000 + elapsed.subsec_nanos() as i64 / 1000,
+            Err(_) => 0,
+        };
+        let mut last = self.last.lock().unwrap();
+        *last = now.max(*last + 1);
+        *last
+    }
+}
+
+/// Sets `key` to `value` in `tags`, keeping the order tags were first set
+/// in.
+pub fn set_tag(tags: &mut Vec<(String, String)>, key: &str, value: &str) {
+    match tags.iter().position(|&(ref existing, _)| existing == key) {
+        Some(index) => tags[index].1 = value.to_string(),
+        None => tags.push((key.to_string(), value.to_string())),
+    }
+}
+
+/// Counts the bind markers in `query`, both `?` and named ones like `:id`,
+/// ignoring any inside string literals, quoted identifiers and comments.
+pub fn count_markers(query: &str) -> usize {
+    let code = blank_quoted(query);
+    let bytes = code.as_bytes();
+    (0..bytes.len()).filter(|&i| match bytes[i] {
+        b'?' => true,
+        // A named marker's name may be quoted, as in `:"Id"`.
+        b':' => bytes.get(i + 1).map_or(false, |&next| next.is_ascii_alphabetic() || next == b'_' || next == b'"'),
+        _ => false,
+    }).count()
+}
+
+/// Whether `query` has an ALLOW FILTERING clause outside of string
+/// literals, quoted identifiers and comments.
+pub fn uses_allow_filtering(query: &str) -> bool {
+    let code = blank_quoted(query);
+    let words: Vec<&str> = code.split(|c: char| c.is_whitespace() || c == ';').filter(|w| !w.is_empty()).collect();
+    words.windows(2).any(|pair| pair[0].eq_ignore_ascii_case("allow") && pair[1].eq_ignore_ascii_case("filtering"))
+}
+
+/// `query` with whatever is inside its string literals (`'...'` and
+/// `$$...$$`) and quoted identifiers replaced by spaces, and its comments
+/// blanked out entirely, so only the statement's own syntax is left. The
+/// quotes themselves are kept.
+fn blank_quoted(query: &str) -> String {
+    let mut blanked = String::with_capacity(query.len());
+    let mut rest = query;
+    while let Some(c) = rest.chars().next() {
+        let (open, close, comment) = if rest.starts_with("$$") {
+            ("$$", "$$", false)
+        } else if rest.starts_with("--") || rest.starts_with("//") {
+            (&rest[..2], "\n", true)
+        } else if rest.starts_with("/*") {
+            ("/*", "*/", true)
+        } else if c == '\'' || c == '"' {
+            (&rest[..1], &rest[..1], false)
+        } else {
+            blanked.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        let inside = &rest[open.len()..];
+        let (content, closed, after) = match inside.find(close) {
+            Some(end) => (&inside[..end], true, &inside[end + close.len()..]),
+            None => (inside, false, ""),
+        };
+        let blank = |text: &str| text.chars().map(|_| ' ').collect::<String>();
+        blanked.push_str(&if comment { blank(open) } else { open.to_string() });
+        blanked.push_str(&blank(content));
+        if closed {
+            blanked.push_str(&if comment { blank(close) } else { close.to_string() });
+        }
+        rest = after;
+    }
+    blanked
+}
+
+/// A statement with some of its values bound ahead of time, such as a
+/// tenant id shared by every request. Bound values are serialized once, so
+/// binding the rest per request only has to fill in the gaps.
+#[derive(Debug, Clone)]
+pub struct Template {
+    statement: Statement,
+    bound: Vec<Option<BoundValue>>,
+}
+
+/// A value bound in a template, kept serialized.
+#[derive(Debug, Clone)]
+enum BoundValue {
+    Value(Vec<u8>),
+    /// `types::Unset`, which has to go out as the protocol's unset marker
+    /// rather than as an empty value.
+    Unset,
+}
+
+impl ToCQL for BoundValue {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        if let BoundValue::Value(ref value) = *self {
+            try!(buf.write_all(value));
+        }
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        match *self {
+            BoundValue::Value(ref value) => Ok(value.len()),
+            BoundValue::Unset => Ok(0),
+        }
+    }
+
+    fn is_unset(&self) -> bool {
+        match *self {
+            BoundValue::Value(_) => false,
+            BoundValue::Unset => true,
+        }
+    }
+}
+
+impl Template {
+    pub fn new(statement: Statement) -> Template {
+        let markers = count_markers(statement.query());
+        Template {
+            statement: statement,
+            bound: vec![None; markers],
+        }
+    }
+
+    /// A template with every bind marker bound, in order. Fails unless
+    /// there is exactly one value per marker.
+    pub fn with_values(statement: Statement, values: &[&ToCQL]) -> Result<Template> {
+        let mut template = Template::new(statement);
+        if values.len() != template.bound.len() {
+            return Err(MyError::Protocol(format!("Statement has {} bind markers, got {} values",
+                                                 template.bound.len(), values.len())));
+        }
+        for (index, value) in values.iter().enumerate() {
+            try!(template.bind(index, *value));
+        }
+        Ok(template)
+    }
+
+    pub fn statement(&self) -> &Statement {
+        &self.statement
+    }
+
+    /// Binds `value` to the bind marker at `index`, counting from zero.
+    /// Binding `types::Unset` leaves the column untouched on every
+    /// execution, as it would bound per request.
+    pub fn bind(&mut self, index: usize, value: &ToCQL) -> Result<()> {
+        if index >= self.bound.len() {
+            return Err(MyError::Protocol(format!("Statement has {} bind markers, can't bind index {}",
+                                                 self.bound.len(), index)));
+        }
+        if value.is_unset() {
+            self.bound[index] = Some(BoundValue::Unset);
+            return Ok(());
+        }
+        let mut serialized = Vec::new();
+        try!(value.serialize(&mut serialized));
+        self.bound[index] = Some(BoundValue::Value(serialized));
+        Ok(())
+    }
+
+    /// Number of values still to be given per request.
+    pub fn unbound(&self) -> usize {
+        self.bound.iter().filter(|b| b.is_none()).count()
+    }
+
+    /// All of the statement's values: the bound ones, with `params` filling
+    /// the remaining markers in order.
+    pub fn values<'a>(&'a self, params: &[&'a ToCQL]) -> Result<Vec<&'a ToCQL>> {
+        if params.len() != self.unbound() {
+            return Err(MyError::Protocol(format!("Template expects {} values, got {}",
+                                                 self.unbound(), params.len())));
+        }
+        let mut params = params.iter();
+        Ok(self.bound.iter().map(|bound| match *bound {
+            Some(ref value) => value as &ToCQL,
+            None => *params.next().unwrap(),
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_markers, uses_allow_filtering};
+
+    #[test]
+    fn counts_positional_and_named_markers() {
+        assert_eq!(count_markers("SELECT * FROM t WHERE a = ? AND b IN ?"), 2);
+        assert_eq!(count_markers("INSERT INTO t (a, b) VALUES (:a, :b_2)"), 2);
+        assert_eq!(count_markers("UPDATE t SET v = :\"Value\" WHERE k = ?"), 2);
+        assert_eq!(count_markers("SELECT * FROM t"), 0);
+    }
+
+    #[test]
+    fn skips_markers_in_literals_identifiers_and_comments() {
+        assert_eq!(count_markers("SELECT * FROM t WHERE a = 'what?' AND b = ?"), 1);
+        assert_eq!(count_markers("SELECT * FROM t WHERE a = 'it''s ?' AND b = ?"), 1);
+        assert_eq!(count_markers("SELECT * FROM t WHERE a = $$why? :not$$ AND b = ?"), 1);
+        assert_eq!(count_markers("SELECT \"quoted?\" FROM t WHERE \"k:ey\" = ?"), 1);
+        assert_eq!(count_markers("SELECT * FROM t /* any ? here */ WHERE a = ?"), 1);
+        assert_eq!(count_markers("SELECT * FROM t -- or ?\nWHERE a = ? // and :this"), 1);
+        assert_eq!(count_markers("SELECT * FROM t WHERE m = {'a': 1, 'b': ?}"), 1);
+        assert_eq!(count_markers("SELECT * FROM t WHERE a = 'unterminated ?"), 0);
+    }
+
+    #[test]
+    fn finds_allow_filtering_outside_literals_and_comments() {
+        assert!(uses_allow_filtering("SELECT * FROM t WHERE v = 1 ALLOW FILTERING"));
+        assert!(uses_allow_filtering("select * from t where v = 1 allow\n  filtering;"));
+        assert!(!uses_allow_filtering("SELECT * FROM t WHERE v = 'allow filtering'"));
+        assert!(!uses_allow_filtering("SELECT * FROM t /* ALLOW FILTERING */ WHERE v = 1"));
+        assert!(!uses_allow_filtering("SELECT * FROM t WHERE v = 1 -- ALLOW FILTERING"));
+        assert!(!uses_allow_filtering("SELECT \"allow filtering\" FROM t"));
+        assert!(!uses_allow_filtering("SELECT allow_filtering FROM t"));
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::time::Duration;
+use byteorder::{BigEndian, ReadBytesExt};
+
+use errors::{ErrorCode, MyError};
+use histogram::Histogram;
+use protocol::{ColumnSpec, Opcode, Row};
+use types::CQLType;
+
+/// Results with more rows than this don't get a distinct count, since
+/// every value has to be held in memory to count them.
+pub const MAX_DISTINCT_ROWS: usize = 10000;
+
+/// Upper bounds, in milliseconds, of the request latency buckets.
+pub const LATENCY_BUCKETS_MS: &'static [u64] = &[1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Running protocol counters for a single connection.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    /// Bytes written to the socket, as compressed and framed.
+    pub bytes_sent: u64,
+    /// Bytes read from the socket, as compressed and framed.
+    pub bytes_received: u64,
+    /// Frame bodies sent while compression was on, before compressing.
+    /// Bodies compression didn't shrink count the same in both.
+    pub uncompressed_bytes_sent: u64,
+    /// The same bodies as they were sent.
+    pub compressed_bytes_sent: u64,
+    /// Frame bodies received while compression was on, after
+    /// decompressing.
+    pub uncompressed_bytes_received: u64,
+    /// The same bodies as they were received.
+    pub compressed_bytes_received: u64,
+    pub frames_sent: HashMap<Opcode, u64>,
+    pub frames_received: HashMap<Opcode, u64>,
+    pub decode_errors: u64,
+    /// Requests made through the connection, including failed ones.
+    pub requests: u64,
+    /// Requests that fell in each of `LATENCY_BUCKETS_MS`, counting each
+    /// request only in the first bucket it fits. Slower requests are only
+    /// counted in `requests`.
+    pub latency_buckets: Vec<u64>,
+    pub total_latency: Duration,
+    /// Every request's latency, if the connection keeps an HDR histogram.
+    /// See `histogram::LatencyHistogram`.
+    pub latency: Option<Histogram>,
+    /// Failed requests by kind of error, such as `io` or `read_timeout`.
+    pub errors: HashMap<String, u64>,
+    /// Requests sent again, as the retry policy decided or after the
+    /// server lost a prepared statement.
+    pub retries: u64,
+    pub prepared_cache_hits: u64,
+    pub prepared_cache_misses: u64,
+    /// Requests by the tables their statements read or write, named
+    /// `keyspace.table`, or just `table` if the statement didn't say and
+    /// no keyspace was selected. A batch counts for every table in it.
+    pub tables: HashMap<String, TableStats>,
+    /// Requests by the tags of their statements, keyed by tag and value. A
+    /// request counts once for each of its tags. See `Statement::set_tag`.
+    pub tags: HashMap<(String, String), TableStats>,
+    /// Requests by the `statement::statement_hash` of their query text, so
+    /// statements can be told apart without keeping their text. Batches
+    /// count under the hash of `BATCH of n statements`, as they are named
+    /// in `errors::ErrorContext`.
+    pub statements: HashMap<u64, TableStats>,
+}
+
+impl ConnectionStats {
+    pub fn new() -> ConnectionStats {
+        ConnectionStats::default()
+    }
+
+    pub fn record_sent(&mut self, opcode: Opcode, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+        *self.frames_sent.entry(opcode).or_insert(0) += 1;
+    }
+
+    pub fn record_received(&mut self, opcode: Opcode, bytes: usize) {
+        self.bytes_received += bytes as u64;
+        *self.frames_received.entry(opcode).or_insert(0) += 1;
+    }
+
+    pub fn record_compressed_sent(&mut self, uncompressed: usize, compressed: usize) {
+        self.uncompressed_bytes_sent += uncompressed as u64;
+        self.compressed_bytes_sent += compressed as u64;
+    }
+
+    pub fn record_compressed_received(&mut self, uncompressed: usize, compressed: usize) {
+        self.uncompressed_bytes_received += uncompressed as u64;
+        self.compressed_bytes_received += compressed as u64;
+    }
+
+    /// Compressed bytes over uncompressed bytes, both ways, so 0.25 means
+    /// compression cut traffic to a quarter. `None` until a body has been
+    /// sent or received with compression on.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        let uncompressed = self.uncompressed_bytes_sent + self.uncompressed_bytes_received;
+        if uncompressed == 0 {
+            return None;
+        }
+        Some((self.compressed_bytes_sent + self.compressed_bytes_received) as f64 / uncompressed as f64)
+    }
+
+    pub fn record_decode_error(&mut self) {
+        self.decode_errors += 1;
+    }
+
+    pub fn record_request(&mut self, elapsed: Duration, error: Option<&MyError>) {
+        self.requests += 1;
+        self.total_latency += elapsed;
+        let millis = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+        if let Some(bucket) = LATENCY_BUCKETS_MS.iter().position(|&bound| millis < bound) {
+            self.latency_buckets.resize(LATENCY_BUCKETS_MS.len(), 0);
+            self.latency_buckets[bucket] += 1;
+        }
+        if let Some(ref mut latency) = self.latency {
+            latency.record(elapsed);
+        }
+        if let Some(error) = error {
+            *self.errors.entry(error_kind(error)).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_table_request(&mut self, table: &str, elapsed: Duration, failed: bool) {
+        if !self.tables.contains_key(table) {
+            self.tables.insert(table.to_string(), TableStats::default());
+        }
+        self.tables.get_mut(table).unwrap().record(elapsed, failed);
+    }
+
+    pub fn record_tagged_request(&mut self, key: &str, value: &str, elapsed: Duration, failed: bool) {
+        let tag = (key.to_string(), value.to_string());
+        self.tags.entry(tag).or_insert_with(TableStats::default).record(elapsed, failed);
+    }
+
+    pub fn record_statement_request(&mut self, hash: u64, elapsed: Duration, failed: bool) {
+        self.statements.entry(hash).or_insert_with(TableStats::default).record(elapsed, failed);
+    }
+
+    pub fn record_retry(&mut self) {
+        self.retries += 1;
+    }
+
+    pub fn record_prepared_lookup(&mut self, hit: bool) {
+        if hit {
+            self.prepared_cache_hits += 1;
+        } else {
+            self.prepared_cache_misses += 1;
+        }
+    }
+
+    /// Adds another connection's counters to these, to total them over a
+    /// pool.
+    pub fn merge(&mut self, other: &ConnectionStats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.uncompressed_bytes_sent += other.uncompressed_bytes_sent;
+        self.compressed_bytes_sent += other.compressed_bytes_sent;
+        self.uncompressed_bytes_received += other.uncompressed_bytes_received;
+        self.compressed_bytes_received += other.compressed_bytes_received;
+        for (&opcode, &count) in &other.frames_sent {
+            *self.frames_sent.entry(opcode).or_insert(0) += count;
+        }
+        for (&opcode, &count) in &other.frames_received {
+            *self.frames_received.entry(opcode).or_insert(0) += count;
+        }
+        self.decode_errors += other.decode_errors;
+        self.requests += other.requests;
+        if !other.latency_buckets.is_empty() {
+            self.latency_buckets.resize(LATENCY_BUCKETS_MS.len(), 0);
+            for (total, &count) in self.latency_buckets.iter_mut().zip(&other.latency_buckets) {
+                *total += count;
+            }
+        }
+        self.total_latency += other.total_latency;
+        if let Some(ref latency) = other.latency {
+            self.latency.get_or_insert_with(|| Histogram::new(latency.significant_figures())).merge(latency);
+        }
+        for (kind, &count) in &other.errors {
+            *self.errors.entry(kind.clone()).or_insert(0) += count;
+        }
+        self.retries += other.retries;
+        self.prepared_cache_hits += other.prepared_cache_hits;
+        self.prepared_cache_misses += other.prepared_cache_misses;
+        for (table, stats) in &other.tables {
+            self.tables.entry(table.clone()).or_insert_with(TableStats::default).merge(stats);
+        }
+        for (tag, stats) in &other.tags {
+            self.tags.entry(tag.clone()).or_insert_with(TableStats::default).merge(stats);
+        }
+        for (&hash, stats) in &other.statements {
+            self.statements.entry(hash).or_insert_with(TableStats::default).merge(stats);
+        }
+    }
+
+    /// The tables with the most time spent on their requests first, which
+    /// are usually the ones costing the cluster the most.
+    pub fn busiest_tables(&self) -> Vec<(&str, &TableStats)> {
+        let mut tables: Vec<(&str, &TableStats)> = self.tables.iter().map(|(name, stats)| (&name[..], stats)).collect();
+        tables.sort_by(|a, b| b.1.total_latency.cmp(&a.1.total_latency).then(a.0.cmp(b.0)));
+        tables
+    }
+}
+
+/// Requests to a single table, or with a single tag.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableStats {
+    /// Requests made, including failed ones and each retry.
+    pub requests: u64,
+    pub errors: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl TableStats {
+    pub fn record(&mut self, elapsed: Duration, failed: bool) {
+        self.requests += 1;
+        if failed {
+            self.errors += 1;
+        }
+        self.total_latency += elapsed;
+        self.max_latency = self.max_latency.max(elapsed);
+    }
+
+    pub fn mean_latency(&self) -> Option<Duration> {
+        if self.requests == 0 {
+            None
+        } else {
+            let nanos = self.total_latency.as_secs() * 1_000_000_000 + self.total_latency.subsec_nanos() as u64;
+            Some(Duration::from_nanos(nanos / self.requests))
+        }
+    }
+
+    pub fn merge(&mut self, other: &TableStats) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.total_latency += other.total_latency;
+        self.max_latency = self.max_latency.max(other.max_latency);
+    }
+}
+
+/// A short label for the kind of `error`: the error code for server
+/// errors, such as `read_timeout`, and otherwise `io`, `protocol`,
+/// `timeout` or `closed`.
+fn error_kind(error: &MyError) -> String {
+    match *error.root() {
+        MyError::IO(_) => "io".to_string(),
+        MyError::Protocol(_) => "protocol".to_string(),
+        MyError::Timeout(_) => "timeout".to_string(),
+        MyError::Closed => "closed".to_string(),
+        MyError::Server(code, _, _) => match ErrorCode::from_code(code).description() {
+            Some(description) => description.to_lowercase().replace(' ', "_"),
+            None => "other_server_error".to_string(),
+        },
+        MyError::Request(_, ref cause) => error_kind(cause),
+    }
+}
+
+/// A summary of one column of a rows result.
+#[derive(Debug, Clone)]
+pub struct ColumnStats {
+    pub name: String,
+    pub datatype: CQLType,
+    pub nulls: usize,
+    /// Smallest and largest value of int, bigint, counter, float, double
+    /// and timestamp columns. Timestamps are in milliseconds since the
+    /// epoch. `None` for other types or when every value is null.
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Number of distinct non-null values, counted by their serialized
+    /// form. `None` when the result has more than `MAX_DISTINCT_ROWS` rows.
+    pub distinct: Option<usize>,
+}
+
+impl ColumnStats {
+    pub fn compute(column: &ColumnSpec, rows: &[Row]) -> ColumnStats {
+        let mut stats = ColumnStats {
+            name: column.name().to_string(),
+            datatype: column.datatype().clone(),
+            nulls: 0,
+            min: None,
+            max: None,
+            distinct: None,
+        };
+        let mut distinct = HashSet::new();
+        for row in rows {
+            let value = match row.get_reader(column.name()) {
+                Ok(Some(reader)) => *reader.get_ref(),
+                _ => {
+                    stats.nulls += 1;
+                    continue;
+                },
+            };
+            if rows.len() <= MAX_DISTINCT_ROWS {
+                distinct.insert(value);
+            }
+            if let Some(number) = numeric_value(&stats.datatype, value) {
+                stats.min = Some(stats.min.map_or(number, |min| min.min(number)));
+                stats.max = Some(stats.max.map_or(number, |max| max.max(number)));
+            }
+        }
+        if rows.len() <= MAX_DISTINCT_ROWS {
+            stats.distinct = Some(distinct.len());
+        }
+        stats
+    }
+}
+
+/// Decodes numeric and timestamp values; anything else, or a value of the
+/// wrong length, gives `None`.
+fn numeric_value(datatype: &CQLType, value: &[u8]) -> Option<f64> {
+    let mut reader = Cursor::new(value);
+    let number = match (datatype, value.len()) {
+        (&CQLType::Tinyint, 1) => reader.read_i8().ok().map(|v| v as f64),
+        (&CQLType::Smallint, 2) => reader.read_i16::<BigEndian>().ok().map(|v| v as f64),
+        (&CQLType::Int, 4) => reader.read_i32::<BigEndian>().ok().map(|v| v as f64),
+        (&CQLType::Bigint, 8) | (&CQLType::Counter, 8) | (&CQLType::Timestamp, 8) => {
+            reader.read_i64::<BigEndian>().ok().map(|v| v as f64)
+        },
+        (&CQLType::Float, 4) => reader.read_f32::<BigEndian>().ok().map(|v| v as f64),
+        (&CQLType::Double, 8) => reader.read_f64::<BigEndian>().ok(),
+        _ => None,
+    };
+    number.and_then(|n| if n.is_nan() { None } else { Some(n) })
+}
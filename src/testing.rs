@@ -0,0 +1,517 @@
+//! Helpers for integration tests against a real cluster.
+
+use std::fmt;
+use std::io::Cursor;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use byteorder::{BigEndian, ReadBytesExt};
+use uuid::Uuid;
+
+use backup::json_string;
+use copy::parse_csv_line;
+use errors::MyError;
+use protocol::{QueryResult, Result};
+use session::Session;
+use types::{CQLType, FromCQL, ToCQL};
+use warning::Warning;
+
+/// Keyspace names are limited to 48 characters.
+const MAX_KEYSPACE_LENGTH: usize = 48;
+
+static FIXTURE_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// An expected column value. Comparisons follow the column's type rather
+/// than its exact bytes: text is accepted for uuid columns in any case,
+/// timestamps are compared to the millisecond, integers and floats match
+/// columns of any width, and sets match in any order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Null,
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bool(bool),
+    Uuid(Uuid),
+    /// Milliseconds since the epoch.
+    Timestamp(i64),
+    Inet(IpAddr),
+    Bytes(Vec<u8>),
+    Set(Vec<Cell>),
+}
+
+impl Cell {
+    fn matches(&self, datatype: Option<&CQLType>, value: Option<&[u8]>) -> bool {
+        let value = match (self, value) {
+            (&Cell::Null, None) => return true,
+            (_, None) | (&Cell::Null, _) => return false,
+            (_, Some(value)) => value,
+        };
+        match *self {
+            Cell::Null => false,
+            Cell::Int(expected) => read_int(value) == Some(expected),
+            Cell::Timestamp(expected) => read_int(value) == Some(expected),
+            Cell::Float(expected) => match value.len() {
+                4 => Cursor::new(value).read_f32::<BigEndian>().ok() == Some(expected as f32),
+                8 => Cursor::new(value).read_f64::<BigEndian>().ok() == Some(expected),
+                _ => false,
+            },
+            Cell::Text(ref expected) => {
+                let is_uuid = match datatype {
+                    Some(&CQLType::Uuid) | Some(&CQLType::Timeuuid) => true,
+                    Some(_) => false,
+                    None => value.len() == 16,
+                };
+                match Uuid::parse_str(&expected.to_lowercase()) {
+                    Ok(ref uuid) if is_uuid => uuid.as_bytes() == value,
+                    _ => expected.as_bytes() == value,
+                }
+            },
+            Cell::Bool(expected) => bool::parse(value).ok() == Some(expected),
+            Cell::Uuid(ref expected) => expected.as_bytes() == value,
+            Cell::Inet(ref expected) => IpAddr::parse(value).ok().as_ref() == Some(expected),
+            Cell::Bytes(ref expected) => &expected[..] == value,
+            Cell::Set(ref expected) => {
+                let mut elements = match read_elements(value) {
+                    Some(elements) => elements,
+                    None => return false,
+                };
+                if elements.len() != expected.len() {
+                    return false;
+                }
+                // Each expected element has to match a different actual one.
+                for cell in expected {
+                    match elements.iter().position(|element| cell.matches(None, Some(element))) {
+                        Some(index) => {
+                            elements.remove(index);
+                        },
+                        None => return false,
+                    }
+                }
+                true
+            },
+        }
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cell::Null => write!(f, "null"),
+            Cell::Int(value) => write!(f, "{}", value),
+            Cell::Float(value) => write!(f, "{}", value),
+            Cell::Text(ref value) => write!(f, "{:?}", value),
+            Cell::Bool(value) => write!(f, "{}", value),
+            Cell::Uuid(ref value) => write!(f, "{}", value),
+            Cell::Timestamp(value) => write!(f, "{}ms", value),
+            Cell::Inet(ref value) => write!(f, "{}", value),
+            Cell::Bytes(ref value) => write_hex(f, value),
+            Cell::Set(ref cells) => {
+                try!(write!(f, "{{"));
+                for (i, cell) in cells.iter().enumerate() {
+                    try!(write!(f, "{}{}", if i > 0 { ", " } else { "" }, cell));
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+impl From<i32> for Cell {
+    fn from(value: i32) -> Cell {
+        Cell::Int(value as i64)
+    }
+}
+
+impl From<i64> for Cell {
+    fn from(value: i64) -> Cell {
+        Cell::Int(value)
+    }
+}
+
+impl From<f32> for Cell {
+    fn from(value: f32) -> Cell {
+        Cell::Float(value as f64)
+    }
+}
+
+impl From<f64> for Cell {
+    fn from(value: f64) -> Cell {
+        Cell::Float(value)
+    }
+}
+
+impl From<bool> for Cell {
+    fn from(value: bool) -> Cell {
+        Cell::Bool(value)
+    }
+}
+
+impl<'a> From<&'a str> for Cell {
+    fn from(value: &'a str) -> Cell {
+        Cell::Text(value.to_string())
+    }
+}
+
+impl From<String> for Cell {
+    fn from(value: String) -> Cell {
+        Cell::Text(value)
+    }
+}
+
+impl From<Uuid> for Cell {
+    fn from(value: Uuid) -> Cell {
+        Cell::Uuid(value)
+    }
+}
+
+impl From<IpAddr> for Cell {
+    fn from(value: IpAddr) -> Cell {
+        Cell::Inet(value)
+    }
+}
+
+impl From<Vec<u8>> for Cell {
+    fn from(value: Vec<u8>) -> Cell {
+        Cell::Bytes(value)
+    }
+}
+
+/// Truncated to the millisecond precision Cassandra stores.
+impl From<SystemTime> for Cell {
+    fn from(value: SystemTime) -> Cell {
+        let millis = match value.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() * 1000) as i64 + (since.subsec_nanos() / 1_000_000) as i64,
+            Err(err) => {
+                let before = err.duration();
+                // Round towards negative infinity, like the server does.
+                let nanos = before.as_secs() as i64 * 1_000_000_000 + before.subsec_nanos() as i64;
+                -((nanos + 999_999) / 1_000_000)
+            },
+        };
+        Cell::Timestamp(millis)
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(value: Option<T>) -> Cell {
+        match value {
+            Some(value) => value.into(),
+            None => Cell::Null,
+        }
+    }
+}
+
+impl<T: Into<Cell> + Eq + Hash> From<HashSet<T>> for Cell {
+    fn from(value: HashSet<T>) -> Cell {
+        Cell::Set(value.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Sets up a throwaway keyspace with tables and rows for one test.
+///
+/// ```ignore
+/// let fixture = try!(FixtureBuilder::new("people_by_name")
+///     .table("people", "id int PRIMARY KEY, name text")
+///     .row("people", &["id", "name"], &[&1, &"Ann"])
+///     .csv("people", "id,name\n2,Bob\n3,")
+///     .build(&session));
+/// let result = try!(session.query(&format!("SELECT * FROM {}", fixture.table("people")), &[]));
+/// ```
+pub struct FixtureBuilder<'a> {
+    name: String,
+    tables: Vec<(String, String)>,
+    rows: Vec<(String, Vec<&'a str>, Vec<&'a ToCQL>)>,
+    csv: Vec<(String, String)>,
+}
+
+impl<'a> FixtureBuilder<'a> {
+    /// `name` identifies the test. The keyspace is named after it and a
+    /// per-process counter, so reruns use the same names.
+    pub fn new(name: &str) -> FixtureBuilder<'a> {
+        FixtureBuilder {
+            name: name.to_string(),
+            tables: Vec::new(),
+            rows: Vec::new(),
+            csv: Vec::new(),
+        }
+    }
+
+    /// Adds a table; `definition` is the column list of CREATE TABLE.
+    pub fn table(mut self, name: &str, definition: &str) -> FixtureBuilder<'a> {
+        self.tables.push((name.to_string(), definition.to_string()));
+        self
+    }
+
+    pub fn row(mut self, table: &str, columns: &[&'a str], values: &[&'a ToCQL]) -> FixtureBuilder<'a> {
+        self.rows.push((table.to_string(), columns.to_vec(), values.to_vec()));
+        self
+    }
+
+    /// Adds the rows of a CSV document whose first line names the columns.
+    /// See `Fixture::load_csv`.
+    pub fn csv(mut self, table: &str, csv: &str) -> FixtureBuilder<'a> {
+        self.csv.push((table.to_string(), csv.to_string()));
+        self
+    }
+
+    /// Creates the keyspace, replacing any left over from an earlier run
+    /// that didn't clean up, then the tables and rows.
+    pub fn build(self, session: &Session) -> Result<Fixture> {
+        let count = FIXTURE_COUNT.fetch_add(1, Ordering::SeqCst);
+        let suffix = format!("_{}", count);
+        let mut keyspace = format!("test_{}", sanitize(&self.name));
+        keyspace.truncate(MAX_KEYSPACE_LENGTH - suffix.len());
+        keyspace.push_str(&suffix);
+
+        try!(session.execute(&format!("DROP KEYSPACE IF EXISTS {}", keyspace), &[]));
+        try!(session.execute(&format!("CREATE KEYSPACE {} WITH replication = \
+                                       {{'class': 'SimpleStrategy', 'replication_factor': 1}}", keyspace), &[]));
+        let fixture = Fixture {
+            session: session.clone(),
+            keyspace: keyspace,
+        };
+        for &(ref name, ref definition) in &self.tables {
+            try!(session.execute(&format!("CREATE TABLE {} ({})", fixture.table(name), definition), &[]));
+        }
+        for &(ref table, ref columns, ref values) in &self.rows {
+            if columns.len() != values.len() {
+                return Err(MyError::Protocol(format!("Fixture row for {} has {} columns but {} values",
+                                                     table, columns.len(), values.len())));
+            }
+            let markers: Vec<&str> = columns.iter().map(|_| "?").collect();
+            let insert = format!("INSERT INTO {} ({}) VALUES ({})", fixture.table(table), columns.join(", "), markers.join(", "));
+            try!(session.execute(&insert, values));
+        }
+        for &(ref table, ref csv) in &self.csv {
+            try!(fixture.load_csv(table, csv));
+        }
+        Ok(fixture)
+    }
+}
+
+/// A keyspace created by `FixtureBuilder`, dropped again when this goes
+/// out of scope. Failing to drop it is published as a warning to the
+/// session's subscribers.
+pub struct Fixture {
+    session: Session,
+    keyspace: String,
+}
+
+impl Fixture {
+    pub fn keyspace(&self) -> &str {
+        &self.keyspace
+    }
+
+    /// The keyspace-qualified name of one of the fixture's tables.
+    pub fn table(&self, name: &str) -> String {
+        format!("{}.{}", self.keyspace, name)
+    }
+
+    /// Inserts the rows of a CSV document whose first line names the
+    /// columns, returning how many rows were inserted. Fields may be quoted
+    /// with `"`; empty unquoted fields are null. Rows are inserted as JSON,
+    /// so every field is given as text and the server converts it to the
+    /// column's type.
+    pub fn load_csv(&self, table: &str, csv: &str) -> Result<usize> {
+        let mut lines = csv.lines().filter(|line| !line.trim().is_empty());
+        let header = match lines.next() {
+            Some(header) => parse_csv_line(header),
+            None => return Ok(0),
+        };
+        if header.iter().any(|column| column.is_none()) {
+            return Err(MyError::Protocol("CSV header has an empty column name".to_string()));
+        }
+        let insert = format!("INSERT INTO {} JSON ?", self.table(table));
+        let mut count = 0;
+        for line in lines {
+            let fields = parse_csv_line(line);
+            if fields.len() != header.len() {
+                return Err(MyError::Protocol(format!("CSV line has {} fields, header has {}: {}",
+                                                     fields.len(), header.len(), line)));
+            }
+            let mut json = String::from("{");
+            for (i, (column, field)) in header.iter().zip(fields).enumerate() {
+                if i > 0 {
+                    json.push_str(", ");
+                }
+                json.push_str(&json_string(column.as_ref().unwrap()));
+                json.push_str(": ");
+                match field {
+                    Some(value) => json.push_str(&json_string(&value)),
+                    None => json.push_str("null"),
+                }
+            }
+            json.push('}');
+            try!(self.session.execute(&insert, &[&json]));
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        if let Err(err) = self.session.execute(&format!("DROP KEYSPACE IF EXISTS {}", self.keyspace), &[]) {
+            self.session.warn(Warning::FixtureNotDropped {
+                keyspace: self.keyspace.clone(),
+                error: err.to_string(),
+            });
+        }
+    }
+}
+
+/// Keyspace names may only hold letters, digits and underscores.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .flat_map(|c| if (c as u32) < 128 && c.is_alphanumeric() { c.to_lowercase().collect::<Vec<_>>() } else { vec!['_'] })
+        .collect()
+}
+
+/// One mismatch between the expected rows and a result.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Difference {
+    RowCount { expected: usize, actual: usize },
+    MissingColumn(String),
+    Value { row: usize, column: String, expected: Cell, actual: String },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Difference::RowCount { expected, actual } => write!(f, "expected {} rows, got {}", expected, actual),
+            Difference::MissingColumn(ref column) => write!(f, "result has no column {}", column),
+            Difference::Value { row, ref column, ref expected, ref actual } => {
+                write!(f, "row {}, column {}: expected {}, got {}", row, column, expected, actual)
+            },
+        }
+    }
+}
+
+/// The differences between expected rows and a query result, compared row
+/// by row in order. Usually used through `assert_rows_eq!`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowDiff {
+    pub differences: Vec<Difference>,
+}
+
+impl RowDiff {
+    /// Compares the given columns of `result` with `expected`, which holds
+    /// one value per column for each row.
+    pub fn compare(result: &QueryResult, columns: &[&str], expected: &[Vec<Cell>]) -> RowDiff {
+        let mut differences = Vec::new();
+        if result.rows.len() != expected.len() {
+            differences.push(Difference::RowCount { expected: expected.len(), actual: result.rows.len() });
+        }
+        for (index, column) in columns.iter().enumerate() {
+            let datatype = match result.columns().iter().find(|spec| spec.name() == *column) {
+                Some(spec) => spec.datatype(),
+                None => {
+                    differences.push(Difference::MissingColumn(column.to_string()));
+                    continue;
+                },
+            };
+            for (row_index, (row, cells)) in result.rows.iter().zip(expected).enumerate() {
+                let expected = match cells.get(index) {
+                    Some(cell) => cell,
+                    None => continue,
+                };
+                let value = match row.get_reader(column) {
+                    Ok(reader) => reader.map(|reader| *reader.get_ref()),
+                    Err(_) => continue,
+                };
+                if !expected.matches(Some(datatype), value) {
+                    differences.push(Difference::Value {
+                        row: row_index,
+                        column: column.to_string(),
+                        expected: expected.clone(),
+                        actual: describe(datatype, value),
+                    });
+                }
+            }
+        }
+        RowDiff { differences: differences }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+impl fmt::Display for RowDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for difference in &self.differences {
+            try!(writeln!(f, "  {}", difference));
+        }
+        Ok(())
+    }
+}
+
+fn read_int(value: &[u8]) -> Option<i64> {
+    let mut reader = Cursor::new(value);
+    match value.len() {
+        1 => reader.read_i8().ok().map(|v| v as i64),
+        2 => reader.read_i16::<BigEndian>().ok().map(|v| v as i64),
+        4 => reader.read_i32::<BigEndian>().ok().map(|v| v as i64),
+        8 => reader.read_i64::<BigEndian>().ok(),
+        _ => None,
+    }
+}
+
+/// Splits a set or list value into its serialized elements.
+fn read_elements(value: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut reader = Cursor::new(value);
+    let count = match reader.read_i32::<BigEndian>() {
+        Ok(count) if count >= 0 => count,
+        _ => return None,
+    };
+    let mut elements = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let len = match reader.read_i32::<BigEndian>() {
+            Ok(len) if len >= 0 => len as usize,
+            _ => return None,
+        };
+        let start = reader.position() as usize;
+        if start + len > value.len() {
+            return None;
+        }
+        elements.push(&value[start..start + len]);
+        reader.set_position((start + len) as u64);
+    }
+    Some(elements)
+}
+
+/// Renders an actual value for failure messages.
+fn describe(datatype: &CQLType, value: Option<&[u8]>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return "null".to_string(),
+    };
+    let described = match *datatype {
+        CQLType::Tinyint | CQLType::Smallint | CQLType::Int | CQLType::Bigint | CQLType::Counter => read_int(value).map(|v| v.to_string()),
+        CQLType::Timestamp => read_int(value).map(|v| format!("{}ms", v)),
+        CQLType::Ascii | CQLType::Varchar => String::parse(value).ok().map(|v| format!("{:?}", v)),
+        CQLType::Uuid | CQLType::Timeuuid => Uuid::parse(value).ok().map(|v| v.to_string()),
+        CQLType::Boolean => bool::parse(value).ok().map(|v| v.to_string()),
+        CQLType::Inet => IpAddr::parse(value).ok().map(|v| v.to_string()),
+        CQLType::Set | CQLType::List => read_elements(value).map(|elements| {
+            let elements: Vec<String> = elements.iter().map(|element| match String::parse(element) {
+                Ok(ref text) if !text.chars().any(char::is_control) => format!("{:?}", text),
+                _ => format!("{}", Cell::Bytes(element.to_vec())),
+            }).collect();
+            format!("{{{}}}", elements.join(", "))
+        }),
+        _ => None,
+    };
+    described.unwrap_or_else(|| format!("{}", Cell::Bytes(value.to_vec())))
+}
+
+fn write_hex(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    try!(write!(f, "0x"));
+    for byte in bytes {
+        try!(write!(f, "{:02x}", byte));
+    }
+    Ok(())
+}
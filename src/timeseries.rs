@@ -0,0 +1,71 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use errors::MyError;
+use protocol::{QueryResult, Result};
+use session::Session;
+use types::ToCQL;
+
+/// How much time each partition of a time-series table covers. Bucket
+/// keys are the number of whole buckets since the Unix epoch, so they fit
+/// in an `int` column and sort in time order.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Bucket {
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    pub fn seconds(&self) -> i64 {
+        match *self {
+            Bucket::Hour => 60 * 60,
+            Bucket::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// The key of the bucket containing `time`.
+    pub fn key(&self, time: SystemTime) -> i32 {
+        let secs = epoch_seconds(time);
+        // Round down for times before the epoch too.
+        let key = if secs >= 0 { secs / self.seconds() } else { (secs + 1) / self.seconds() - 1 };
+        key as i32
+    }
+
+    /// The keys of every bucket overlapping `start..end`, oldest first.
+    pub fn keys(&self, start: SystemTime, end: SystemTime) -> Vec<i32> {
+        if end <= start {
+            return Vec::new();
+        }
+        let first = self.key(start);
+        // `end` is exclusive, so a range ending exactly on a bucket boundary
+        // doesn't include the next bucket.
+        let last = self.key(end - Duration::new(0, 1));
+        (first..last + 1).collect()
+    }
+}
+
+/// Runs a query over every bucket in `start..end` and merges the results,
+/// oldest bucket first. The query must restrict its bucket column with
+/// `IN ?`, like `SELECT * FROM events WHERE bucket IN ? AND ts >= ? AND ts < ?`.
+/// Each bucket becomes its own single-partition query, see
+/// `Session::query_split_in`. `params` holds the values for the other
+/// markers.
+pub fn query_range(session: &Session, query: &str, bucket: Bucket, start: SystemTime, end: SystemTime,
+                   params: &[&ToCQL]) -> Result<QueryResult> {
+    let keys = bucket.keys(start, end);
+    if keys.is_empty() {
+        return Err(MyError::Protocol("Time range is empty".to_string()));
+    }
+    let keys: Vec<&ToCQL> = keys.iter().map(|k| k as &ToCQL).collect();
+    session.query_split_in(query, &keys, params)
+}
+
+fn epoch_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(err) => {
+            let before = err.duration();
+            let secs = before.as_secs() as i64;
+            if before.subsec_nanos() > 0 { -secs - 1 } else { -secs }
+        },
+    }
+}
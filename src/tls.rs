@@ -0,0 +1,124 @@
+//! Encrypting connections with TLS, for clusters with
+//! `client_encryption_options` enabled.
+//!
+//! ```ignore
+//! let mut tls = TlsConfig::new("cassandra.example.com");
+//! try!(tls.add_root_certificates(&mut BufReader::new(try!(File::open("ca.pem")))));
+//! let mut config = SessionConfig::default();
+//! config.tls = Some(Arc::new(tls));
+//! ```
+//!
+//! Nodes are verified against the server name given here rather than the
+//! address connected to, since every connection only knows the node's IP
+//! address. Certificates for a cluster are usually issued for one name
+//! covering all of its nodes.
+
+use std::fmt;
+use std::io::{BufRead, Read};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::{self, ClientConfig, ClientSession, Session, StreamOwned};
+use rustls::internal::pemfile;
+use webpki::DNSNameRef;
+
+use errors::MyError;
+use protocol::Result;
+use socket::Connection;
+
+/// A TLS protocol version to allow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol_version(&self) -> rustls::ProtocolVersion {
+        match *self {
+            TlsVersion::Tls12 => rustls::ProtocolVersion::TLSv1_2,
+            TlsVersion::Tls13 => rustls::ProtocolVersion::TLSv1_3,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ClientConfig>,
+    server_name: String,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsConfig({})", self.server_name)
+    }
+}
+
+impl TlsConfig {
+    /// Verifies nodes as `server_name`, allowing TLS 1.2 and 1.3. No
+    /// certificate is trusted until roots are added.
+    pub fn new(server_name: &str) -> TlsConfig {
+        TlsConfig {
+            config: Arc::new(ClientConfig::new()),
+            server_name: server_name.to_string(),
+        }
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Trusts the certificates in `pem`, usually the CA that signed the
+    /// nodes' certificates. Returns how many were added.
+    pub fn add_root_certificates<R: BufRead>(&mut self, pem: &mut R) -> Result<usize> {
+        let certificates = try!(pemfile::certs(pem)
+            .map_err(|_| MyError::Protocol("Couldn't read root certificates as PEM".to_string())));
+        let config = Arc::make_mut(&mut self.config);
+        for certificate in &certificates {
+            try!(config.root_store.add(certificate)
+                .map_err(|err| MyError::Protocol(format!("Invalid root certificate: {:?}", err))));
+        }
+        Ok(certificates.len())
+    }
+
+    /// Only negotiates `versions`, such as just TLS 1.3 where policy
+    /// requires it.
+    pub fn set_versions(&mut self, versions: &[TlsVersion]) {
+        Arc::make_mut(&mut self.config).versions = versions.iter().map(|version| version.protocol_version()).collect();
+    }
+
+    /// Presents a client certificate, for clusters with
+    /// `require_client_auth`. `certificates` holds the certificate chain,
+    /// and `key` its PKCS#8 or RSA private key, both as PEM.
+    pub fn set_client_certificate<C: BufRead, K: BufRead>(&mut self, certificates: &mut C, key: &mut K)
+                                                          -> Result<()> {
+        let chain = try!(pemfile::certs(certificates)
+            .map_err(|_| MyError::Protocol("Couldn't read client certificates as PEM".to_string())));
+        let mut pem = Vec::new();
+        try!(key.read_to_end(&mut pem));
+        let mut keys = try!(pemfile::pkcs8_private_keys(&mut &pem[..])
+            .map_err(|_| MyError::Protocol("Couldn't read the client key as PEM".to_string())));
+        if keys.is_empty() {
+            keys = try!(pemfile::rsa_private_keys(&mut &pem[..])
+                .map_err(|_| MyError::Protocol("Couldn't read the client key as PEM".to_string())));
+        }
+        let key = match keys.into_iter().next() {
+            Some(key) => key,
+            None => return Err(MyError::Protocol("No private key found for the client certificate".to_string())),
+        };
+        try!(Arc::make_mut(&mut self.config).set_single_client_cert(chain, key)
+            .map_err(|err| MyError::Protocol(format!("Invalid client certificate: {:?}", err))));
+        Ok(())
+    }
+
+    /// Runs the TLS handshake on `conn`, bounded by its read timeout.
+    pub fn connect(&self, mut conn: TcpStream) -> Result<Connection> {
+        let name = try!(DNSNameRef::try_from_ascii_str(&self.server_name)
+            .map_err(|_| MyError::Protocol(format!("Invalid TLS server name {}", self.server_name))));
+        let mut session = ClientSession::new(&self.config, name);
+        while session.is_handshaking() {
+            try!(session.complete_io(&mut conn));
+        }
+        Ok(Connection::Tls(Box::new(StreamOwned::new(session, conn))))
+    }
+}
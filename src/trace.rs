@@ -0,0 +1,101 @@
+use std::thread;
+use std::net::IpAddr;
+use std::time::Duration;
+use uuid::Uuid;
+
+use client::Client;
+use errors::MyError;
+use protocol::{Result, Row};
+use types::FromCQL;
+
+/// Traces are written asynchronously after the response is sent, so they
+/// may take a moment to show up.
+const FETCH_ATTEMPTS: u32 = 5;
+const FETCH_RETRY_DELAY_MS: u64 = 200;
+
+const SESSION_QUERY: &'static str =
+    "SELECT coordinator, duration, request FROM system_traces.sessions WHERE session_id = ?";
+const EVENTS_QUERY: &'static str =
+    "SELECT activity, source, source_elapsed, thread FROM system_traces.events WHERE session_id = ?";
+
+/// What the server recorded while running a traced request.
+#[derive(Debug, Clone)]
+pub struct QueryTrace {
+    pub id: Uuid,
+    pub coordinator: IpAddr,
+    /// Total time the coordinator spent on the request.
+    pub duration: Duration,
+    pub request: Option<String>,
+    pub events: Vec<TraceEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub activity: String,
+    pub source: IpAddr,
+    /// Time since the request reached `source`.
+    pub source_elapsed: Duration,
+    pub thread: Option<String>,
+}
+
+impl QueryTrace {
+    /// Reads the trace with the given id from `system_traces`, retrying
+    /// until the coordinator has finished writing it.
+    pub fn fetch(client: &mut Client, id: Uuid) -> Result<QueryTrace> {
+        for attempt in 0..FETCH_ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(Duration::from_millis(FETCH_RETRY_DELAY_MS));
+            }
+            if let Some(trace) = try!(QueryTrace::try_fetch(client, id)) {
+                return Ok(trace);
+            }
+        }
+        Err(MyError::Timeout(format!("trace {} was not complete after {} attempts", id, FETCH_ATTEMPTS)))
+    }
+
+    /// Returns `None` while the session row is missing or has no duration
+    /// yet, which is written last.
+    fn try_fetch(client: &mut Client, id: Uuid) -> Result<Option<QueryTrace>> {
+        let sessions = try!(client.query(SESSION_QUERY, &[&id]));
+        let session = match sessions.rows.first() {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        let duration: i32 = match try!(session.get("duration")) {
+            Some(duration) => duration,
+            None => return Ok(None),
+        };
+        let coordinator = try!(required(session, "coordinator"));
+        let request = try!(session.get("request"));
+        let events = try!(client.query(EVENTS_QUERY, &[&id]));
+        let mut trace_events = Vec::with_capacity(events.rows.len());
+        for event in &events.rows {
+            let elapsed: i32 = try!(required(event, "source_elapsed"));
+            trace_events.push(TraceEvent {
+                activity: try!(required(event, "activity")),
+                source: try!(required(event, "source")),
+                source_elapsed: micros(elapsed),
+                thread: try!(event.get("thread")),
+            });
+        }
+        Ok(Some(QueryTrace {
+            id: id,
+            coordinator: coordinator,
+            duration: micros(duration),
+            request: request,
+            events: trace_events,
+        }))
+    }
+}
+
+fn required<T: FromCQL>(row: &Row, column: &str) -> Result<T> {
+    match try!(row.get(column)) {
+        Some(value) => Ok(value),
+        None => Err(MyError::Protocol(format!("Trace column {} is null", column))),
+    }
+}
+
+fn micros(micros: i32) -> Duration {
+    let micros = micros.max(0) as u64;
+    Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000)
+}
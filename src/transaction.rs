@@ -0,0 +1,309 @@
+//! A constrained form of transaction built from a logged batch.
+//!
+//! Cassandra has no multi-statement transactions. What it does offer is:
+//!
+//! - Logged batches: once the batch is accepted, every statement in it is
+//!   eventually applied, even if the coordinator fails part way. Other
+//!   clients may see some statements applied before the rest, and nothing
+//!   is rolled back.
+//! - Lightweight transactions: a statement with an `IF` clause is only
+//!   applied if the condition holds, checked with Paxos. A batch holding
+//!   conditional statements is applied as a whole or not at all, but every
+//!   statement in it must be for the same partition of the same table.
+//!
+//! `Transaction` stages writes on the client and sends them as one logged
+//! batch on `commit`. Savepoints only drop staged writes; once committed,
+//! a transaction can't be undone. There is no isolation from concurrent
+//! writers except through conditions, and reads are never part of it.
+//!
+//! `VersionedUpdate` packages the usual read-modify-write pattern on top of
+//! a condition: read a row and its version, change it, and write it back
+//! only if the version is still the same, retrying if it isn't.
+
+use batch::{BatchBuilder, BatchStatement, BatchType};
+use errors::MyError;
+use protocol::{Consistency, PreparedStatement, QueryResult, Result, Row};
+use session::Session;
+use types::ToCQL;
+
+/// Column LWT results use to say whether the condition held.
+pub const APPLIED_COLUMN: &'static str = "[applied]";
+/// How many times `VersionedUpdate` reads and writes before giving up,
+/// unless configured otherwise.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// A position in a transaction's staged writes to roll back to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Savepoint(usize);
+
+/// What happened to a committed transaction.
+#[derive(Debug)]
+pub enum Outcome {
+    Applied,
+    /// A condition didn't hold, so nothing was written. The result holds
+    /// the current values of the conditional columns.
+    NotApplied(QueryResult),
+}
+
+impl Outcome {
+    pub fn is_applied(&self) -> bool {
+        match *self {
+            Outcome::Applied => true,
+            Outcome::NotApplied(_) => false,
+        }
+    }
+}
+
+/// INSERT, UPDATE and DELETE statements staged to be written together.
+/// Counter updates can't be part of one, since logged batches don't
+/// allow them.
+pub struct Transaction<'a> {
+    statements: Vec<BatchStatement<'a>>,
+    consistency: Consistency,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new() -> Transaction<'a> {
+        Transaction {
+            statements: Vec::new(),
+            consistency: Consistency::Quorum,
+        }
+    }
+
+    /// The consistency the batch is written at. Defaults to `Quorum`.
+    pub fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+
+    pub fn write(&mut self, query: &'a str, params: &[&'a ToCQL]) {
+        self.statements.push(BatchStatement::Query(query, params.to_vec()));
+    }
+
+    pub fn write_prepared(&mut self, prepared: &'a PreparedStatement, params: &[&'a ToCQL]) {
+        self.statements.push(BatchStatement::Prepared(prepared, params.to_vec()));
+    }
+
+    /// Like `write`, but checks that the statement has an `IF` clause,
+    /// such as `IF NOT EXISTS` or `IF balance = ?`. Once the transaction
+    /// has one, every statement in it must write to the same partition,
+    /// and the whole batch is only applied if every condition holds.
+    pub fn write_if(&mut self, query: &'a str, params: &[&'a ToCQL]) -> Result<()> {
+        if !has_condition(query) {
+            return Err(MyError::Protocol(format!("Expected an IF clause in {}", query)));
+        }
+        self.write(query, params);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Whether any staged statement has a condition.
+    pub fn is_conditional(&self) -> bool {
+        self.statements.iter().any(|statement| match *statement {
+            BatchStatement::Query(query, _) => has_condition(query),
+            BatchStatement::Prepared(prepared, _) => has_condition(prepared.query()),
+        })
+    }
+
+    /// Marks the writes staged so far.
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.statements.len())
+    }
+
+    /// Drops every write staged after `savepoint`. Nothing has been sent
+    /// yet, so there is nothing to undo on the server.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        self.statements.truncate(savepoint.0);
+    }
+
+    /// Drops every staged write.
+    pub fn rollback(&mut self) {
+        self.rollback_to(Savepoint(0));
+    }
+
+    /// Sends the staged writes as one logged batch. Without conditions the
+    /// outcome is always `Applied`; an error means the batch may or may
+    /// not have been written, as with any write that times out.
+    pub fn commit(&self, session: &Session) -> Result<Outcome> {
+        if self.statements.is_empty() {
+            return Err(MyError::Protocol("Can't commit an empty transaction".to_string()));
+        }
+        let mut builder = BatchBuilder::new(BatchType::Logged).consistency(self.consistency);
+        for statement in &self.statements {
+            builder = match *statement {
+                BatchStatement::Query(query, ref params) => builder.query(query, params),
+                BatchStatement::Prepared(prepared, ref params) => builder.prepared(prepared, params),
+            };
+        }
+        let result = try!(session.batch(&builder.build()));
+        if !self.is_conditional() {
+            return Ok(Outcome::Applied);
+        }
+        if try!(applied(&result)) {
+            Ok(Outcome::Applied)
+        } else {
+            Ok(Outcome::NotApplied(result))
+        }
+    }
+}
+
+impl<'a> Default for Transaction<'a> {
+    fn default() -> Transaction<'a> {
+        Transaction::new()
+    }
+}
+
+/// Whether `query` has an `IF` clause, going by whole words outside of
+/// string literals.
+fn has_condition(query: &str) -> bool {
+    let mut in_string = false;
+    let mut word = String::new();
+    for c in query.chars().chain(Some(' ')) {
+        if c == '\'' {
+            in_string = !in_string;
+            word.clear();
+        } else if !in_string && (c.is_alphanumeric() || c == '_') {
+            word.push(c);
+        } else {
+            if word.to_uppercase() == "IF" {
+                return true;
+            }
+            word.clear();
+        }
+    }
+    false
+}
+
+/// New values for some of a row's columns.
+pub struct Changes {
+    columns: Vec<(String, Box<ToCQL>)>,
+}
+
+impl Changes {
+    pub fn new() -> Changes {
+        Changes { columns: Vec::new() }
+    }
+
+    pub fn set<T: ToCQL + 'static>(mut self, column: &str, value: T) -> Changes {
+        self.columns.retain(|&(ref name, _)| name != column);
+        self.columns.push((column.to_string(), Box::new(value)));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+}
+
+impl Default for Changes {
+    fn default() -> Changes {
+        Changes::new()
+    }
+}
+
+/// Optimistic concurrency for a single row with an `int` version column.
+/// `apply` reads the row, asks a closure for the changes, and writes them
+/// with the version incremented, `IF <version> = <version read>`. When
+/// another writer got there first the condition fails, and the row is read
+/// again and the closure called again, up to `max_attempts` times.
+///
+/// ```ignore
+/// let update = VersionedUpdate::new("bank.accounts", "version").key("id", &id);
+/// try!(update.apply(&session, |row| {
+///     let balance: i32 = try!(row.get("balance")).unwrap_or(0);
+///     Ok(Changes::new().set("balance", balance - 10))
+/// }));
+/// ```
+///
+/// The version must already be set; insert rows with a version of 0. The
+/// read isn't serial, so it may miss a recent write, which only costs an
+/// extra attempt.
+pub struct VersionedUpdate<'a> {
+    table: &'a str,
+    version_column: &'a str,
+    key: Vec<(&'a str, &'a ToCQL)>,
+    max_attempts: u32,
+}
+
+impl<'a> VersionedUpdate<'a> {
+    pub fn new(table: &'a str, version_column: &'a str) -> VersionedUpdate<'a> {
+        VersionedUpdate {
+            table: table,
+            version_column: version_column,
+            key: Vec::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    /// Adds a primary key column; every one of them must be given.
+    pub fn key(mut self, column: &'a str, value: &'a ToCQL) -> VersionedUpdate<'a> {
+        self.key.push((column, value));
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> VersionedUpdate<'a> {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Runs the read-modify-write cycle, returning the version written. An
+    /// error from `f` stops it without writing anything.
+    pub fn apply<F>(&self, session: &Session, mut f: F) -> Result<i32>
+        where F: FnMut(&Row) -> Result<Changes>
+    {
+        if self.key.is_empty() {
+            return Err(MyError::Protocol(format!("No key columns given for {}", self.table)));
+        }
+        let key_values: Vec<&ToCQL> = self.key.iter().map(|&(_, value)| value).collect();
+        let select = format!("SELECT * FROM {} WHERE {}", self.table, self.key_clause());
+        for _ in 0..self.max_attempts {
+            let result = try!(session.query(&select, &key_values));
+            let row = match result.rows.first() {
+                Some(row) => row,
+                None => return Err(MyError::Protocol(format!("No row in {} with the given key", self.table))),
+            };
+            let version: i32 = match try!(row.get(self.version_column)) {
+                Some(version) => version,
+                None => return Err(MyError::Protocol(format!("Version column {} is null", self.version_column))),
+            };
+            let changes = try!(f(row));
+            let next_version = version + 1;
+            let mut params: Vec<&ToCQL> = changes.columns.iter().map(|&(_, ref value)| &**value).collect();
+            params.push(&next_version);
+            params.extend(key_values.iter().cloned());
+            params.push(&version);
+            let result = try!(session.query(&self.update_query(&changes), &params));
+            if try!(applied(&result)) {
+                return Ok(next_version);
+            }
+        }
+        Err(MyError::Protocol(format!("Gave up updating {} after {} attempts; the row kept changing",
+                                      self.table, self.max_attempts)))
+    }
+
+    fn key_clause(&self) -> String {
+        let conditions: Vec<String> = self.key.iter().map(|&(column, _)| format!("{} = ?", column)).collect();
+        conditions.join(" AND ")
+    }
+
+    fn update_query(&self, changes: &Changes) -> String {
+        let mut assignments: Vec<String> = changes.columns.iter().map(|&(ref column, _)| format!("{} = ?", column)).collect();
+        assignments.push(format!("{} = ?", self.version_column));
+        format!("UPDATE {} SET {} WHERE {} IF {} = ?",
+                self.table, assignments.join(", "), self.key_clause(), self.version_column)
+    }
+}
+
+/// Reads the `[applied]` column of a conditional statement's result.
+pub fn applied(result: &QueryResult) -> Result<bool> {
+    match result.rows.first() {
+        Some(row) => Ok(try!(row.get::<bool>(APPLIED_COLUMN)).unwrap_or(false)),
+        None => Err(MyError::Protocol("Conditional statement returned no [applied] row".to_string())),
+    }
+}
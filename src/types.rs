@@ -1,10 +1,14 @@
 use uuid::Uuid;
 use std::hash::Hash;
-use std::collections::HashSet;
-use std::io::{Cursor, Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io::{self, Cursor, Write};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-#[derive(Debug)]
+use errors::MyError;
+use protocol::{CountingWriter, Result};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum CQLType {
     Custom,
     Ascii,
@@ -22,6 +26,10 @@ pub enum CQLType {
     Varint,
     Timeuuid,
     Inet,
+    Date,
+    Time,
+    Smallint,
+    Tinyint,
     List,
     Map,
     Set,
@@ -29,101 +37,412 @@ pub enum CQLType {
     Tuple,
 }
 
-pub trait FromCQL {
-    fn parse(buf: Vec<u8>) -> Self;
+pub trait FromCQL: Sized {
+    /// Decodes a value from its bytes, borrowed from the frame body. The
+    /// fixed-size types don't allocate; `benches/row_decode.rs` checks.
+    fn parse(buf: &[u8]) -> Result<Self>;
 }
 
 pub trait ToCQL {
-    fn serialize(&self) -> Vec<u8>;
+    fn serialize(&self, buf: &mut Write) -> Result<()>;
+
+    /// Number of bytes `serialize` will write. The default serializes into
+    /// a counter; types that know their size should override it.
+    fn serialized_len(&self) -> Result<usize> {
+        let mut counter = CountingWriter::new(io::sink());
+        try!(self.serialize(&mut counter));
+        Ok(counter.count())
+    }
+
+    /// Whether this is `Unset`, which is sent as a special length rather
+    /// than as bytes.
+    fn is_unset(&self) -> bool {
+        false
+    }
 }
 
 impl FromCQL for i32 {
-    fn parse(buf: Vec<u8>) -> Self {
-        assert_eq!(buf.len(), 4);
-        Cursor::new(buf).read_i32::<BigEndian>().unwrap()
+    fn parse(buf: &[u8]) -> Result<i32> {
+        if buf.len() != 4 {
+            return Err(MyError::Protocol(format!("Expected 4 bytes for int, got {}", buf.len())));
+        }
+        Ok(try!(Cursor::new(buf).read_i32::<BigEndian>()))
     }
 }
 
 impl ToCQL for i32 {
-    fn serialize(&self) -> Vec<u8> {
-        let mut ret = Vec::with_capacity(4);
-        ret.write_i32::<BigEndian>(*self).unwrap();
-        ret
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_i32::<BigEndian>(*self));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(4)
+    }
+}
+
+impl FromCQL for i64 {
+    fn parse(buf: &[u8]) -> Result<i64> {
+        if buf.len() != 8 {
+            return Err(MyError::Protocol(format!("Expected 8 bytes for bigint, got {}", buf.len())));
+        }
+        Ok(try!(Cursor::new(buf).read_i64::<BigEndian>()))
+    }
+}
+
+impl ToCQL for i64 {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_i64::<BigEndian>(*self));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(8)
+    }
+}
+
+impl FromCQL for i16 {
+    fn parse(buf: &[u8]) -> Result<i16> {
+        if buf.len() != 2 {
+            return Err(MyError::Protocol(format!("Expected 2 bytes for smallint, got {}", buf.len())));
+        }
+        Ok(try!(Cursor::new(buf).read_i16::<BigEndian>()))
+    }
+}
+
+impl ToCQL for i16 {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_i16::<BigEndian>(*self));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(2)
+    }
+}
+
+impl FromCQL for i8 {
+    fn parse(buf: &[u8]) -> Result<i8> {
+        if buf.len() != 1 {
+            return Err(MyError::Protocol(format!("Expected 1 byte for tinyint, got {}", buf.len())));
+        }
+        Ok(try!(Cursor::new(buf).read_i8()))
+    }
+}
+
+impl ToCQL for i8 {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_i8(*self));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(1)
     }
 }
 
 impl FromCQL for String {
-    fn parse(buf: Vec<u8>) -> String {
-        String::from_utf8(buf).unwrap()
+    fn parse(buf: &[u8]) -> Result<String> {
+        String::from_utf8(buf.to_vec()).map_err(|e| MyError::Protocol(format!("{}", e)))
     }
 }
 
 impl ToCQL for String {
-    fn serialize(&self) -> Vec<u8> {
-        self.clone().into_bytes()
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_all(self.as_bytes()));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(self.len())
     }
 }
 
 impl<'a> ToCQL for &'a str {
-    fn serialize(&self) -> Vec<u8> {
-        self.as_bytes().to_owned()
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_all(self.as_bytes()));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(self.len())
+    }
+}
+
+impl FromCQL for Vec<u8> {
+    fn parse(buf: &[u8]) -> Result<Vec<u8>> {
+        Ok(buf.to_vec())
+    }
+}
+
+impl ToCQL for Vec<u8> {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_all(self));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(self.len())
+    }
+}
+
+impl<'a> ToCQL for &'a [u8] {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_all(self));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(self.len())
     }
 }
 
 impl FromCQL for Uuid {
-    fn parse(buf: Vec<u8>) -> Uuid {
-        Uuid::from_bytes(buf.as_ref()).unwrap()
+    fn parse(buf: &[u8]) -> Result<Uuid> {
+        Uuid::from_bytes(buf).map_err(|e| MyError::Protocol(format!("Invalid uuid: {:?}", e)))
     }
 }
 
 impl ToCQL for Uuid {
-    fn serialize(&self) -> Vec<u8> {
-        self.as_bytes().to_owned()
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_all(self.as_bytes()));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(16)
     }
 }
 
 impl FromCQL for bool {
-    fn parse(buf: Vec<u8>) -> bool {
-        match buf[0] {
-            0 => false,
-            _ => true,
+    fn parse(buf: &[u8]) -> Result<bool> {
+        match buf.first() {
+            Some(&0) => Ok(false),
+            Some(_) => Ok(true),
+            None => Err(MyError::Protocol("Expected 1 byte for boolean, got 0".to_string())),
         }
     }
 }
 
 impl ToCQL for bool {
-    fn serialize(&self) -> Vec<u8> {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_u8(if *self { 1 } else { 0 }));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(1)
+    }
+}
+
+impl FromCQL for IpAddr {
+    fn parse(buf: &[u8]) -> Result<IpAddr> {
+        match buf.len() {
+            4 => Ok(IpAddr::V4(Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]))),
+            16 => {
+                let mut segments = [0u16; 8];
+                let mut cursor = Cursor::new(buf);
+                for segment in segments.iter_mut() {
+                    *segment = try!(cursor.read_u16::<BigEndian>());
+                }
+                Ok(IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                            segments[4], segments[5], segments[6], segments[7])))
+            },
+            len => Err(MyError::Protocol(format!("Expected 4 or 16 bytes for inet, got {}", len))),
+        }
+    }
+}
+
+impl ToCQL for IpAddr {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
         match *self {
-            true => vec![1],
-            false => vec![0],
+            IpAddr::V4(ref addr) => try!(buf.write_all(&addr.octets())),
+            IpAddr::V6(ref addr) => {
+                for segment in addr.segments().iter() {
+                    try!(buf.write_u16::<BigEndian>(*segment));
+                }
+            },
         }
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        match *self {
+            IpAddr::V4(_) => Ok(4),
+            IpAddr::V6(_) => Ok(16),
+        }
+    }
+}
+
+/// Leaves a bound column untouched instead of writing null to it, so a
+/// prepared statement can be reused when only some values are known.
+/// Needs native protocol v4.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Unset;
+
+impl ToCQL for Unset {
+    fn serialize(&self, _buf: &mut Write) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn is_unset(&self) -> bool {
+        true
+    }
+}
+
+/// A CQL `date`: days since the Unix epoch, which may be negative.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date(pub i32);
+
+/// Dates are sent unsigned, with the epoch in the middle of the range.
+const DATE_EPOCH: u32 = 1 << 31;
+
+impl FromCQL for Date {
+    fn parse(buf: &[u8]) -> Result<Date> {
+        if buf.len() != 4 {
+            return Err(MyError::Protocol(format!("Expected 4 bytes for date, got {}", buf.len())));
+        }
+        let days = try!(Cursor::new(buf).read_u32::<BigEndian>());
+        Ok(Date(days.wrapping_sub(DATE_EPOCH) as i32))
+    }
+}
+
+impl ToCQL for Date {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_u32::<BigEndian>((self.0 as u32).wrapping_add(DATE_EPOCH)));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(4)
+    }
+}
+
+/// A CQL `time`: nanoseconds since midnight.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Time(pub i64);
+
+const NANOS_PER_DAY: i64 = 24 * 60 * 60 * 1_000_000_000;
+
+impl FromCQL for Time {
+    fn parse(buf: &[u8]) -> Result<Time> {
+        if buf.len() != 8 {
+            return Err(MyError::Protocol(format!("Expected 8 bytes for time, got {}", buf.len())));
+        }
+        Ok(Time(try!(Cursor::new(buf).read_i64::<BigEndian>())))
+    }
+}
+
+impl ToCQL for Time {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        if self.0 < 0 || self.0 >= NANOS_PER_DAY {
+            return Err(MyError::Protocol(format!("Time of {}ns is outside of a day", self.0)));
+        }
+        try!(buf.write_i64::<BigEndian>(self.0));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(8)
     }
 }
 
 impl<T: FromCQL + PartialEq + Eq + Hash> FromCQL for HashSet<T> {
-    fn parse(buf: Vec<u8>) -> HashSet<T> {
+    fn parse(buf: &[u8]) -> Result<HashSet<T>> {
         let mut bytes = Cursor::new(buf);
         let mut set = HashSet::new();
-        let count = bytes.read_i32::<BigEndian>().unwrap();
+        let count = try!(bytes.read_i32::<BigEndian>());
+        for _ in 0..count {
+            let len = try!(bytes.read_i32::<BigEndian>());
+            let start = bytes.position() as usize;
+            let end = start + len.max(0) as usize;
+            if len < 0 || end > buf.len() {
+                return Err(MyError::Protocol("Set element runs past the end of the value".to_string()));
+            }
+            set.insert(try!(T::parse(&buf[start..end])));
+            bytes.set_position(end as u64);
+        }
+        Ok(set)
+    }
+}
+
+impl<K: FromCQL + Eq + Hash, V: FromCQL> FromCQL for HashMap<K, V> {
+    fn parse(buf: &[u8]) -> Result<HashMap<K, V>> {
+        let mut bytes = Cursor::new(buf);
+        let mut map = HashMap::new();
+        let count = try!(bytes.read_i32::<BigEndian>());
         for _ in 0..count {
-            let len = bytes.read_i32::<BigEndian>().unwrap();
-            let mut buf = vec![0; len as usize];
-            bytes.read_exact(&mut buf).unwrap();
-            set.insert(T::parse(buf));
+            let key = try!(K::parse(try!(map_element(buf, &mut bytes))));
+            let value = try!(V::parse(try!(map_element(buf, &mut bytes))));
+            map.insert(key, value);
         }
-        set
+        Ok(map)
+    }
+}
+
+fn map_element<'a>(buf: &'a [u8], bytes: &mut Cursor<&[u8]>) -> Result<&'a [u8]> {
+    let len = try!(bytes.read_i32::<BigEndian>());
+    let start = bytes.position() as usize;
+    let end = start + len.max(0) as usize;
+    if len < 0 || end > buf.len() {
+        return Err(MyError::Protocol("Map entry runs past the end of the value".to_string()));
     }
+    bytes.set_position(end as u64);
+    Ok(&buf[start..end])
 }
 
 impl<T: ToCQL + PartialEq + Eq + Hash> ToCQL for HashSet<T> {
-    fn serialize(&self) -> Vec<u8> {
-        let mut ret = Vec::new();
-        ret.write_i32::<BigEndian>(self.len() as i32).unwrap();
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_i32::<BigEndian>(self.len() as i32));
         for item in self.iter() {
-            let bytes = ToCQL::serialize(item);
-            ret.write_i32::<BigEndian>(bytes.len() as i32).unwrap();
-            ret.write_all(&bytes).unwrap();
+            try!(buf.write_i32::<BigEndian>(try!(item.serialized_len()) as i32));
+            try!(item.serialize(buf));
+        }
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        let mut len = 4;
+        for item in self.iter() {
+            len += 4 + try!(item.serialized_len());
+        }
+        Ok(len)
+    }
+}
+
+/// The original `Vec<u8>` based conversion traits. Types that still
+/// implement these can be wrapped in `Legacy` to be bound as parameters
+/// or read from rows.
+pub mod compat {
+    use std::io::Write;
+
+    use protocol::Result;
+
+    pub trait FromCQL {
+        fn parse(buf: Vec<u8>) -> Self;
+    }
+
+    pub trait ToCQL {
+        fn serialize(&self) -> Vec<u8>;
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Legacy<T>(pub T);
+
+    impl<T: FromCQL> super::FromCQL for Legacy<T> {
+        fn parse(buf: &[u8]) -> Result<Legacy<T>> {
+            Ok(Legacy(T::parse(buf.to_vec())))
+        }
+    }
+
+    impl<T: ToCQL> super::ToCQL for Legacy<T> {
+        fn serialize(&self, buf: &mut Write) -> Result<()> {
+            try!(buf.write_all(&self.0.serialize()));
+            Ok(())
         }
-        ret
     }
 }
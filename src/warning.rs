@@ -0,0 +1,112 @@
+//! Problems the client works around rather than fails on, and warnings
+//! from the server and the linter. The library never prints them: a
+//! `Client` hands them to its `WarningListener`, if it has one, and a
+//! session publishes them to its subscribers as `ClusterEvent::Warning`,
+//! for the application to log however it logs.
+//!
+//! ```ignore
+//! for event in try!(session.subscribe()) {
+//!     if let ClusterEvent::Warning(warning) = event {
+//!         log::warn!("cassandra: {}", warning);
+//!     }
+//! }
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use codegen::Lint;
+use protocol::Consistency;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// The linter found problems in a statement, the first time it was
+    /// sent or prepared.
+    Lint { query: String, lints: Vec<Lint> },
+    /// The server warned about a statement run with `execute`, whose
+    /// result isn't returned to carry `QueryResult::warnings`.
+    Server { query: String, message: String },
+    /// The server pushed an event that didn't decode, and it was dropped.
+    EventDropped { error: String },
+    /// An event subscription's connection failed, ending the subscription.
+    EventConnectionFailed { error: String },
+    /// A contact point didn't resolve and was skipped, since others did.
+    ContactPointUnresolved { contact_point: String, error: String },
+    /// None of the contact points resolved on reconnect, so the addresses
+    /// they resolved to before are kept.
+    ResolveFailed { error: String },
+    /// The retry policy asked to retry a request on another host, but no
+    /// host could be reconnected to, so its error was returned instead.
+    RetryReconnectFailed { error: String },
+    /// The nodes still disagreed on the schema `timeout` after a schema
+    /// change, so statements using it may fail on some of them for now.
+    SchemaDisagreement { query: String, timeout: Duration },
+    /// An idle connection of a session's pool failed its heartbeat. It is
+    /// reconnected after `reconnect_in`, or on the next round if `None`,
+    /// because another connection just failed to reconnect.
+    HeartbeatFailed { error: String, reconnect_in: Option<Duration> },
+    /// Reconnecting a connection whose heartbeat failed didn't work either.
+    ReconnectFailed { error: String },
+    /// A write was sent at `enforced` rather than `requested`, as the
+    /// session's consistency policy requires for the table it writes to.
+    ConsistencyRaised { query: String, requested: Consistency, enforced: Consistency },
+    /// A test fixture's keyspace couldn't be dropped when it went out of
+    /// scope, and is left behind.
+    FixtureNotDropped { keyspace: String, error: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::Lint { ref query, ref lints } => {
+                let lints: Vec<String> = lints.iter().map(|lint| lint.to_string()).collect();
+                write!(f, "{} in query: {}", lints.join("; "), query)
+            },
+            Warning::Server { ref query, ref message } => write!(f, "server warned about {:?}: {}", query, message),
+            Warning::EventDropped { ref error } => write!(f, "dropping event that failed to decode: {}", error),
+            Warning::EventConnectionFailed { ref error } => write!(f, "event connection failed: {}", error),
+            Warning::ContactPointUnresolved { ref contact_point, ref error } => {
+                write!(f, "couldn't resolve {}: {}", contact_point, error)
+            },
+            Warning::ResolveFailed { ref error } => {
+                write!(f, "couldn't resolve contact points again ({}), keeping previous addresses", error)
+            },
+            Warning::RetryReconnectFailed { ref error } => write!(f, "couldn't reconnect to retry ({}), giving up", error),
+            Warning::SchemaDisagreement { ref query, timeout } => {
+                write!(f, "nodes didn't agree on the schema within {:?} of {:?}", timeout, query)
+            },
+            Warning::HeartbeatFailed { ref error, reconnect_in: Some(delay) } => {
+                write!(f, "heartbeat failed ({}), reconnecting in {}ms", error,
+                       delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1000000)
+            },
+            Warning::HeartbeatFailed { ref error, reconnect_in: None } => {
+                write!(f, "heartbeat failed ({}), reconnecting next round", error)
+            },
+            Warning::ReconnectFailed { ref error } => write!(f, "reconnect failed: {}", error),
+            Warning::ConsistencyRaised { ref query, requested, enforced } => {
+                write!(f, "raising consistency of {:?} from {} to {}, as required by the consistency policy",
+                       query, requested, enforced)
+            },
+            Warning::FixtureNotDropped { ref keyspace, ref error } => {
+                write!(f, "failed to drop fixture keyspace {}: {}", keyspace, error)
+            },
+        }
+    }
+}
+
+/// Receives warnings as they happen, on the thread that ran into them.
+pub trait WarningListener: Send + Sync {
+    fn on_warning(&self, warning: &Warning);
+}
+
+impl fmt::Debug for WarningListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WarningListener")
+    }
+}
+
+impl<F: Fn(&Warning) + Send + Sync> WarningListener for F {
+    fn on_warning(&self, warning: &Warning) {
+        self(warning)
+    }
+}
@@ -0,0 +1,208 @@
+//! Paging through a single partition by clustering key.
+//!
+//! Protocol paging (see `pager`) hands back an opaque paging state that is
+//! only good for resuming the exact same statement, and only on the same
+//! major version of Cassandra. `PartitionPager` instead restricts each page
+//! to rows after the clustering key of the last row it returned:
+//!
+//! ```ignore
+//! let pager = PartitionPager::new("chat.messages", &["sent_at", "id"]).key("room", &room).page_size(50);
+//! let page = try!(pager.fetch(&session, None));
+//! // Later, maybe from another process:
+//! let cursor = try!(PageCursor::from_bytes(&saved));
+//! let page = try!(pager.fetch(&session, Some(&cursor)));
+//! ```
+//!
+//! The cursor holds nothing but those clustering values, so it can be kept
+//! by the application and stays valid across restarts and schema changes
+//! that don't touch the clustering key. Rows inserted behind the cursor are
+//! skipped, and rows inserted ahead of it are picked up.
+
+use std::io::{Cursor, Read};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::MyError;
+use protocol::{Result, Row};
+use session::Session;
+use types::ToCQL;
+
+/// Rows per page unless configured otherwise.
+pub const DEFAULT_PAGE_SIZE: i32 = 100;
+
+/// The direction to page through a partition in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+impl Order {
+    pub fn keyword(&self) -> &'static str {
+        match *self {
+            Order::Ascending => "ASC",
+            Order::Descending => "DESC",
+        }
+    }
+
+    fn comparison(&self) -> &'static str {
+        match *self {
+            Order::Ascending => ">",
+            Order::Descending => "<",
+        }
+    }
+}
+
+/// Where the next page starts: the serialized clustering values of the last
+/// row returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageCursor {
+    values: Vec<Vec<u8>>,
+}
+
+impl PageCursor {
+    /// Encodes the cursor for storing outside the process, e.g. in an API
+    /// response. It isn't encrypted or signed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u16::<BigEndian>(self.values.len() as u16).unwrap();
+        for value in &self.values {
+            bytes.write_i32::<BigEndian>(value.len() as i32).unwrap();
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PageCursor> {
+        let mut cursor = Cursor::new(bytes);
+        let count = try!(cursor.read_u16::<BigEndian>());
+        let mut values = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = try!(cursor.read_i32::<BigEndian>());
+            if len < 0 || cursor.position() as usize + len as usize > bytes.len() {
+                return Err(MyError::Protocol("Page cursor value runs past the end of the cursor".to_string()));
+            }
+            let mut value = vec![0; len as usize];
+            try!(cursor.read_exact(&mut value));
+            values.push(value);
+        }
+        if cursor.position() as usize != bytes.len() {
+            return Err(MyError::Protocol("Trailing bytes after page cursor".to_string()));
+        }
+        Ok(PageCursor { values: values })
+    }
+}
+
+/// One page of a partition.
+#[derive(Debug)]
+pub struct Page {
+    pub rows: Vec<Row>,
+    /// Set when the page was full, so there may be more rows. The last
+    /// page of a partition may come back empty.
+    pub next: Option<PageCursor>,
+}
+
+/// Pages through the rows of one partition in clustering order.
+pub struct PartitionPager<'a> {
+    table: &'a str,
+    key: Vec<(&'a str, &'a ToCQL)>,
+    clustering: Vec<&'a str>,
+    columns: &'a str,
+    page_size: i32,
+    order: Order,
+}
+
+impl<'a> PartitionPager<'a> {
+    /// `clustering` lists every clustering column of `table`, in order.
+    /// They must all have the same clustering order.
+    pub fn new(table: &'a str, clustering: &[&'a str]) -> PartitionPager<'a> {
+        PartitionPager {
+            table: table,
+            key: Vec::new(),
+            clustering: clustering.to_vec(),
+            columns: "*",
+            page_size: DEFAULT_PAGE_SIZE,
+            order: Order::Ascending,
+        }
+    }
+
+    /// Adds a partition key column; every one of them must be given.
+    pub fn key(mut self, column: &'a str, value: &'a ToCQL) -> PartitionPager<'a> {
+        self.key.push((column, value));
+        self
+    }
+
+    /// The select list, `*` by default. It must include the clustering
+    /// columns.
+    pub fn columns(mut self, columns: &'a str) -> PartitionPager<'a> {
+        self.columns = columns;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: i32) -> PartitionPager<'a> {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> PartitionPager<'a> {
+        self.order = order;
+        self
+    }
+
+    /// Fetches the page after `after`, or the first page.
+    pub fn fetch(&self, session: &Session, after: Option<&PageCursor>) -> Result<Page> {
+        if self.key.is_empty() || self.clustering.is_empty() {
+            return Err(MyError::Protocol(format!("Paging through {} needs its partition key and clustering columns",
+                                                 self.table)));
+        }
+        if self.page_size <= 0 {
+            return Err(MyError::Protocol(format!("Page size must be positive, got {}", self.page_size)));
+        }
+        if let Some(after) = after {
+            if after.values.len() != self.clustering.len() {
+                return Err(MyError::Protocol(format!("Page cursor has {} clustering values, expected {}",
+                                                     after.values.len(), self.clustering.len())));
+            }
+        }
+        let mut params: Vec<&ToCQL> = self.key.iter().map(|&(_, value)| value).collect();
+        if let Some(after) = after {
+            params.extend(after.values.iter().map(|value| value as &ToCQL));
+        }
+        let result = try!(session.query(&self.query(after.is_some()), &params));
+        let next = if result.rows.len() >= self.page_size as usize {
+            match result.rows.last() {
+                Some(row) => Some(try!(self.cursor(row))),
+                None => None,
+            }
+        } else {
+            None
+        };
+        Ok(Page { rows: result.rows, next: next })
+    }
+
+    /// The cursor for the rows after `row`. Clustering values are bound
+    /// back exactly as the server sent them.
+    fn cursor(&self, row: &Row) -> Result<PageCursor> {
+        let mut values = Vec::with_capacity(self.clustering.len());
+        for &column in &self.clustering {
+            match try!(row.get::<Vec<u8>>(column)) {
+                Some(value) => values.push(value),
+                None => return Err(MyError::Protocol(format!("Clustering column {} is null", column))),
+            }
+        }
+        Ok(PageCursor { values: values })
+    }
+
+    fn query(&self, after: bool) -> String {
+        let mut conditions: Vec<String> = self.key.iter().map(|&(column, _)| format!("{} = ?", column)).collect();
+        if after {
+            let markers: Vec<&str> = self.clustering.iter().map(|_| "?").collect();
+            conditions.push(format!("({}) {} ({})", self.clustering.join(", "), self.order.comparison(),
+                                    markers.join(", ")));
+        }
+        let ordering: Vec<String> = self.clustering.iter()
+            .map(|column| format!("{} {}", column, self.order.keyword()))
+            .collect();
+        format!("SELECT {} FROM {} WHERE {} ORDER BY {} LIMIT {}",
+                self.columns, self.table, conditions.join(" AND "), ordering.join(", "), self.page_size)
+    }
+}
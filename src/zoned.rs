@@ -0,0 +1,77 @@
+//! Converting CQL timestamps to and from chrono date-times, with the
+//! `timezones` feature.
+//!
+//! A timestamp is an instant, milliseconds since the Unix epoch in UTC, so
+//! any `DateTime` can be bound to a timestamp column, whatever its zone,
+//! and columns read as `DateTime<Utc>`. To show one in a user's zone, read
+//! it with `get_in`:
+//!
+//! ```ignore
+//! let zone = try!(zoned::zone("Europe/Berlin"));
+//! let due = try!(zoned::get_in(&row, "due", &zone));
+//! ```
+//!
+//! `NaiveDateTime`s can't be bound, since they don't say which instant
+//! they are; binding one is a compile error rather than a value off by the
+//! server's or the client's UTC offset. Resolve them in their zone with
+//! `from_local` first. Timestamps written into a query without a zone are
+//! reported by the linter, as `codegen::Lint::NaiveTimestamp`.
+
+use std::io::{Cursor, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use errors::MyError;
+use protocol::{Result, Row};
+use types::{FromCQL, ToCQL};
+
+impl<Z: TimeZone> ToCQL for DateTime<Z> {
+    fn serialize(&self, buf: &mut Write) -> Result<()> {
+        try!(buf.write_i64::<BigEndian>(self.timestamp_millis()));
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Result<usize> {
+        Ok(8)
+    }
+}
+
+impl FromCQL for DateTime<Utc> {
+    fn parse(buf: &[u8]) -> Result<DateTime<Utc>> {
+        if buf.len() != 8 {
+            return Err(MyError::Protocol(format!("Expected 8 bytes for timestamp, got {}", buf.len())));
+        }
+        from_timestamp(try!(Cursor::new(buf).read_i64::<BigEndian>()), &Utc)
+    }
+}
+
+/// The time zone with the IANA name `name`, such as `America/New_York`.
+pub fn zone(name: &str) -> Result<Tz> {
+    name.parse().map_err(|_| MyError::Protocol(format!("Unknown time zone {}", name)))
+}
+
+/// The instant `millis` after the Unix epoch, in `zone`.
+pub fn from_timestamp<Z: TimeZone>(millis: i64, zone: &Z) -> Result<DateTime<Z>> {
+    match zone.timestamp_millis_opt(millis) {
+        LocalResult::Single(datetime) => Ok(datetime),
+        _ => Err(MyError::Protocol(format!("Timestamp {} is out of range", millis))),
+    }
+}
+
+/// The timestamp column `col` of `row`, in `zone`.
+pub fn get_in<Z: TimeZone>(row: &Row, col: &str, zone: &Z) -> Result<Option<DateTime<Z>>> {
+    let utc: Option<DateTime<Utc>> = try!(row.get(col));
+    Ok(utc.map(|utc| utc.with_timezone(zone)))
+}
+
+/// The instant the wall-clock time `local` names in `zone`. Fails for
+/// times skipped or repeated when the zone's clocks change, since those
+/// name no instant or two.
+pub fn from_local<Z: TimeZone>(local: &NaiveDateTime, zone: &Z) -> Result<DateTime<Z>> {
+    match zone.from_local_datetime(local) {
+        LocalResult::Single(datetime) => Ok(datetime),
+        LocalResult::Ambiguous(_, _) => Err(MyError::Protocol(format!("{} happens twice in this time zone", local))),
+        LocalResult::None => Err(MyError::Protocol(format!("{} doesn't exist in this time zone", local))),
+    }
+}
@@ -0,0 +1,136 @@
+//! Closing or dropping a session must not leave threads or sockets behind,
+//! even with a request in flight and a connection registered for events.
+
+extern crate cassandra;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cassandra::errors::MyError;
+use cassandra::protocol::ProtocolVersion;
+use cassandra::session::{Session, SessionConfig};
+
+/// Serves the handshake, REGISTER and queries on a local port, except for
+/// queries containing HANG, which are never answered. `open` counts the
+/// connections the client hasn't closed yet.
+fn serve(open: Arc<AtomicUsize>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let conn = conn.unwrap();
+            let open = open.clone();
+            open.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                answer(conn);
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    addr
+}
+
+/// Answers requests until the client closes the connection.
+fn answer(mut conn: TcpStream) {
+    loop {
+        let mut header = [0; 9];
+        if conn.read_exact(&mut header).is_err() {
+            return;
+        }
+        let length = (header[5] as usize) << 24 | (header[6] as usize) << 16 | (header[7] as usize) << 8 |
+                     header[8] as usize;
+        let mut body = vec![0; length];
+        if conn.read_exact(&mut body).is_err() {
+            return;
+        }
+        let (opcode, reply) = match header[4] {
+            // OPTIONS, answered with SUPPORTED {"CQL_VERSION": ["3.0.0"]}.
+            0x05 => (0x06, b"\x00\x01\x00\x0bCQL_VERSION\x00\x01\x00\x053.0.0".to_vec()),
+            // STARTUP and REGISTER, answered with READY.
+            0x01 | 0x0b => (0x02, Vec::new()),
+            0x07 if String::from_utf8_lossy(&body).contains("HANG") => continue,
+            // Any other QUERY, answered with a void RESULT.
+            0x07 => (0x08, vec![0, 0, 0, 1]),
+            opcode => panic!("unexpected opcode {}", opcode),
+        };
+        let len = reply.len();
+        let mut frame = vec![0x80 | header[0], 0, header[2], header[3], opcode,
+                             (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        frame.extend(reply);
+        if conn.write_all(&frame).is_err() {
+            return;
+        }
+    }
+}
+
+fn config() -> SessionConfig {
+    SessionConfig {
+        connections: 2,
+        protocol_version: ProtocolVersion::V4,
+        heartbeat_interval: Duration::from_millis(50),
+        // Only closing the session ends the hanging request.
+        request_timeout: None,
+        ..SessionConfig::default()
+    }
+}
+
+fn threads() -> usize {
+    fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+/// Waits up to five seconds for `done` to hold.
+fn wait_for<F: Fn() -> bool>(what: &str, done: F) {
+    let start = Instant::now();
+    while !done() {
+        if start.elapsed() > Duration::from_secs(5) {
+            panic!("timed out waiting for {}", what);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Both cases run in one test, as other tests' threads would throw off the
+// count.
+#[test]
+#[cfg(target_os = "linux")]
+fn closing_leaves_no_threads_or_sockets() {
+    let open = Arc::new(AtomicUsize::new(0));
+    let addr = serve(open.clone());
+    wait_for("the server to listen", || threads() > 1);
+    let baseline = threads();
+
+    // Closed with a request in flight.
+    let session = Session::with_config(addr, config()).unwrap();
+    let events = session.subscribe().unwrap();
+    let hanging = {
+        let session = session.clone();
+        thread::spawn(move || session.execute("HANG", &[]))
+    };
+    wait_for("the event connection and the request", || open.load(Ordering::SeqCst) == 3);
+    thread::sleep(Duration::from_millis(100));
+    session.close();
+    let err = hanging.join().unwrap().unwrap_err();
+    match *err.root() {
+        MyError::Closed => {},
+        ref other => panic!("expected Closed, got {:?}", other),
+    }
+    wait_for("every socket to close", || open.load(Ordering::SeqCst) == 0);
+    drop(events);
+    drop(session);
+    wait_for("every thread to finish", || threads() == baseline);
+
+    // Dropped while subscribed to events.
+    let session = Session::with_config(addr, config()).unwrap();
+    let events = session.subscribe().unwrap();
+    session.execute("CREATE TABLE t (k int PRIMARY KEY)", &[]).unwrap();
+    wait_for("the event connection", || open.load(Ordering::SeqCst) == 3);
+    drop(session);
+    wait_for("every socket to close", || open.load(Ordering::SeqCst) == 0);
+    drop(events);
+    wait_for("every thread to finish", || threads() == baseline);
+}